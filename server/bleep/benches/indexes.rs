@@ -1,7 +1,7 @@
 use bleep::{
     indexes::{reader::ContentReader, DocumentRead, File},
     intelligence::TreeSitterFile,
-    semantic::Semantic,
+    semantic::{store::QdrantStore, Semantic},
     symbol::SymbolLocations,
     Application, Configuration, Environment,
 };
@@ -57,9 +57,13 @@ pub fn criterion_benchmark(c: &mut Criterion) {
         let file = File::new(
             app.config.clone(),
             Some(
-                Semantic::initialize(&model_dir, "http://127.0.0.1:6334", Arc::clone(&app.config))
-                    .await
-                    .unwrap(),
+                Semantic::initialize(
+                    &model_dir,
+                    Arc::new(QdrantStore::connect("http://127.0.0.1:6334").await.unwrap()),
+                    Arc::clone(&app.config),
+                )
+                .await
+                .unwrap(),
             ),
         );
 
@@ -1,14 +1,27 @@
 use anyhow::bail;
-use tracing::{debug, error, info};
+use tracing::{debug, error, info, warn};
 
 use crate::{
     indexes,
     remotes::RemoteError,
     repo::{RepoRef, Repository, SyncStatus},
+    semantic::Semantic,
     Application, Configuration,
 };
 
-use std::{future::Future, pin::Pin, sync::Arc, thread};
+use std::{
+    future::Future,
+    pin::Pin,
+    sync::Arc,
+    thread,
+    time::{Duration, Instant},
+};
+
+use tokio::sync::Semaphore;
+
+mod runs;
+use runs::Acquire;
+pub(crate) use runs::{IndexRuns, RunState};
 
 type Task = Pin<Box<dyn Future<Output = ()> + Send + Sync>>;
 
@@ -96,25 +109,117 @@ impl IndexWriter {
         background.wait_for(job).await
     }
 
+    /// Sync and index each of `repositories`, at most `parallelism` at a time.
+    ///
+    /// The outcome of each repository is sent on the returned channel as soon as it is done,
+    /// along with how long it took. The channel is closed once all of them are done.
+    pub(crate) fn sync_and_index_each(
+        self,
+        repositories: Vec<RepoRef>,
+        parallelism: usize,
+    ) -> flume::Receiver<(RepoRef, Duration, anyhow::Result<()>)> {
+        let Self(app) = self;
+        let background = app.background.clone();
+        let (sender, receiver) = flume::unbounded();
+        let permits = Arc::new(Semaphore::new(parallelism.max(1)));
+
+        background.spawn(async move {
+            let mut set = tokio::task::JoinSet::new();
+
+            for reporef in repositories {
+                let writer = IndexWriter(app.clone());
+                let permits = Arc::clone(&permits);
+                let sender = sender.clone();
+
+                set.spawn(async move {
+                    let Ok(_permit) = permits.acquire_owned().await else {
+                        return;
+                    };
+
+                    let started = Instant::now();
+                    let indexed = writer.sync_and_index_call(reporef.clone()).await;
+                    _ = sender.send((reporef, started.elapsed(), indexed));
+                });
+            }
+
+            while set.join_next().await.is_some() {}
+        });
+
+        receiver
+    }
+
     async fn sync_and_index_call(self, reporef: RepoRef) -> anyhow::Result<()> {
+        let mut run = match self.0.index_runs.acquire(&reporef) {
+            Acquire::Acquired(run) => run,
+            Acquire::Queued(run_id) => {
+                debug!(?reporef, run_id, "queued another indexing run");
+                return Ok(());
+            }
+        };
+
+        // other processes sharing the index directory, such as `bleep index`, take the same
+        // lock before touching the repository
+        let lock_name = blake3::hash(reporef.to_string().as_bytes()).to_string();
+        let _lock = indexes::lock_across_processes(&self.0.config.index_dir, &lock_name).await?;
+
+        loop {
+            self.sync_and_index_once(&reporef).await?;
+
+            match run.next_run() {
+                Some(next) => run = next,
+                None => return Ok(()),
+            }
+        }
+    }
+
+    async fn sync_and_index_once(&self, reporef: &RepoRef) -> anyhow::Result<()> {
+        let Self(Application {
+            repo_pool,
+            notifications,
+            ..
+        }) = self;
+
+        let synced = self.sync_and_index_repo(reporef).await;
+
+        // indexing errors are recorded on the repository, rather than returned
+        let (status, last_success) = repo_pool
+            .read_async(reporef, |_, repo| {
+                (repo.sync_status.clone(), repo.last_index_unix_secs)
+            })
+            .await
+            .unwrap_or((SyncStatus::Uninitialized, 0));
+
+        let failure = match (&synced, status) {
+            (Err(err), _) => Some(format!("{err:#}")),
+            (Ok(()), SyncStatus::Error { message }) => Some(message),
+            (Ok(()), _) => None,
+        };
+        notifications.record_run(reporef, failure, last_success);
+
+        synced
+    }
+
+    async fn sync_and_index_repo(&self, reporef: &RepoRef) -> anyhow::Result<()> {
         debug!(?reporef, "syncing repo");
-        let Self(Application { ref repo_pool, .. }) = self;
+        let Self(Application { repo_pool, .. }) = self;
 
-        // skip indexing if the repo has been marked as removed
+        // skip syncing if the repo has been marked as removed, or evicted until it's restored
         // if the ref is non-existent, sync it and add it to the pool
-        let removed = repo_pool
-            .read_async(&reporef, |_k, v| v.sync_status == SyncStatus::Removed)
+        let skip_sync = repo_pool
+            .read_async(reporef, |_k, v| {
+                matches!(v.sync_status, SyncStatus::Removed | SyncStatus::Evicted)
+            })
             .await
             .unwrap_or(false);
 
-        if !removed {
-            if let Err(err) = self.sync_repo(&reporef).await {
+        if !skip_sync {
+            if let Err(err) = self.sync_repo(reporef).await {
                 error!(?err, ?reporef, "failed to sync repository");
                 return Err(err);
             }
         }
 
-        if let Err(err) = self.index_repo(&reporef).await {
+        if let Err(err) = self.index_repo(reporef).await {
             error!(?err, ?reporef, "failed to index repository");
             return Err(err);
         }
@@ -142,6 +247,8 @@ impl IndexWriter {
             config,
             indexes,
             repo_pool,
+            workspaces,
+            semantic,
             ..
         }) = &self;
 
@@ -152,13 +259,14 @@ impl IndexWriter {
             .unwrap();
 
         let indexed = match repo.sync_status {
-            Uninitialized | Syncing | Indexing => return Ok(()),
+            Uninitialized | Syncing | Indexing | Evicted => return Ok(()),
             Removed => {
                 repo_pool.remove(reporef);
                 let deleted = self.delete_repo_indexes(reporef, &repo, &writers).await;
                 if deleted.is_ok() {
                     writers.commit().await?;
                     config.source.save_pool(repo_pool.clone())?;
+                    workspaces.remove_repo(reporef)?;
                 }
                 return deleted;
             }
@@ -184,6 +292,10 @@ impl IndexWriter {
         writers.commit().await?;
         config.source.save_pool(repo_pool.clone())?;
 
+        if let (Ok(_), Some(semantic)) = (&indexed, semantic) {
+            retry_chunk_failures(semantic, reporef).await;
+        }
+
         repo_pool
             .update_async(reporef, move |_k, repo| match indexed {
                 Ok(state) => {
@@ -281,3 +393,26 @@ impl IndexWriter {
         Ok(())
     }
 }
+
+/// Retry the chunks of `reporef` that failed to make it into the semantic index during this or
+/// earlier runs.
+async fn retry_chunk_failures(semantic: &Semantic, reporef: &RepoRef) {
+    let repo_ref = reporef.to_string();
+    match semantic.retry_failures(&repo_ref, false).await {
+        Ok(report) if report.retried > 0 => {
+            info!(?reporef, ?report, "retried chunk failures")
+        }
+        Ok(_) => {}
+        Err(err) => error!(?err, ?reporef, "failed to retry chunk failures"),
+    }
+
+    let counts = semantic.failures().counts(&repo_ref);
+    if counts.pending > 0 || counts.permanent > 0 {
+        warn!(
+            ?reporef,
+            pending = counts.pending,
+            permanent = counts.permanent,
+            "some chunks are missing from the semantic index"
+        );
+    }
+}
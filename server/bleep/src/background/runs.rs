@@ -0,0 +1,176 @@
+use std::{
+    collections::HashMap,
+    sync::{Mutex, MutexGuard, PoisonError},
+    time::SystemTime,
+};
+
+use serde::Serialize;
+use utoipa::ToSchema;
+
+use crate::repo::RepoRef;
+
+/// An in-progress sync & index run of a repository.
+#[derive(Serialize, ToSchema, Clone, Debug, PartialEq, Eq)]
+pub(crate) struct RunState {
+    pub(crate) run_id: u64,
+
+    /// Unix timestamp in seconds
+    pub(crate) started_at: u64,
+
+    /// Whether another run was requested while this one was in progress
+    pub(crate) queued: bool,
+}
+
+pub(crate) enum Acquire<'a> {
+    /// The caller owns the repository until the guard is dropped
+    Acquired(RunGuard<'a>),
+
+    /// The repository is held by `run_id`, which will run once more when it finishes
+    Queued(u64),
+}
+
+/// Ensures each repository is synced and indexed by at most one task at a time.
+///
+/// Requests for a repository that is already being indexed are coalesced into a single
+/// follow-up run, executed by the task holding the repository.
+#[derive(Default)]
+pub(crate) struct IndexRuns {
+    runs: Mutex<HashMap<RepoRef, RunState>>,
+}
+
+impl IndexRuns {
+    pub(crate) fn acquire(&self, reporef: &RepoRef) -> Acquire<'_> {
+        let mut runs = self.lock();
+
+        if let Some(run) = runs.get_mut(reporef) {
+            run.queued = true;
+            return Acquire::Queued(run.run_id);
+        }
+
+        let run_id = rand::random();
+        runs.insert(
+            reporef.clone(),
+            RunState {
+                run_id,
+                started_at: unix_now(),
+                queued: false,
+            },
+        );
+
+        Acquire::Acquired(RunGuard {
+            runs: self,
+            reporef: reporef.clone(),
+            run_id,
+        })
+    }
+
+    /// The run currently holding `reporef`, if any.
+    pub(crate) fn get(&self, reporef: &RepoRef) -> Option<RunState> {
+        self.lock().get(reporef).cloned()
+    }
+
+    fn lock(&self) -> MutexGuard<'_, HashMap<RepoRef, RunState>> {
+        // none of the critical sections can panic, but the guard must still release the
+        // repository while unwinding
+        self.runs.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+/// Releases the repository when dropped, including on panic or cancellation.
+pub(crate) struct RunGuard<'a> {
+    runs: &'a IndexRuns,
+    reporef: RepoRef,
+    run_id: u64,
+}
+
+impl<'a> RunGuard<'a> {
+    /// Finish the current run.
+    ///
+    /// If another run was queued in the meantime, the repository stays held for it and the
+    /// guard is returned. Otherwise the repository is released.
+    pub(crate) fn next_run(self) -> Option<Self> {
+        let index_runs = self.runs;
+        let mut runs = index_runs.lock();
+
+        let run = runs
+            .get_mut(&self.reporef)
+            .filter(|run| run.run_id == self.run_id)?;
+
+        if run.queued {
+            run.queued = false;
+            run.started_at = unix_now();
+            drop(runs);
+            return Some(self);
+        }
+
+        // release while holding the lock, so no request is queued behind a run that is about
+        // to exit
+        runs.remove(&self.reporef);
+        None
+    }
+}
+
+impl Drop for RunGuard<'_> {
+    fn drop(&mut self) {
+        let mut runs = self.runs.lock();
+        if runs.get(&self.reporef).map(|run| run.run_id) == Some(self.run_id) {
+            runs.remove(&self.reporef);
+        }
+    }
+}
+
+fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .expect("system time error")
+        .as_secs()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::repo::Backend;
+
+    fn reporef(name: &str) -> RepoRef {
+        RepoRef::new(Backend::Github, name).unwrap()
+    }
+
+    #[test]
+    fn concurrent_runs_are_queued_once() {
+        let runs = IndexRuns::default();
+        let repo = reporef("bloopai/bloop");
+
+        let Acquire::Acquired(first) = runs.acquire(&repo) else {
+            panic!("repo should be free");
+        };
+        assert!(matches!(runs.acquire(&repo), Acquire::Queued(id) if id == first.run_id));
+        assert!(matches!(runs.acquire(&repo), Acquire::Queued(id) if id == first.run_id));
+
+        // other repositories are unaffected
+        assert!(matches!(
+            runs.acquire(&reporef("bloopai/other")),
+            Acquire::Acquired(_)
+        ));
+
+        // both requests coalesce into a single follow-up
+        let second = first.next_run().expect("a follow-up run was queued");
+        assert!(!runs.get(&repo).unwrap().queued);
+        assert!(second.next_run().is_none());
+        assert_eq!(runs.get(&repo), None);
+    }
+
+    #[test]
+    fn guard_is_released_on_panic() {
+        let runs = IndexRuns::default();
+        let repo = reporef("bloopai/bloop");
+
+        let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+            let _run = runs.acquire(&repo);
+            panic!("indexing failed");
+        }));
+
+        assert!(result.is_err());
+        assert_eq!(runs.get(&repo), None);
+        assert!(matches!(runs.acquire(&repo), Acquire::Acquired(_)));
+    }
+}
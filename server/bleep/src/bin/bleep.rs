@@ -1,16 +1,28 @@
 use anyhow::Result;
-use bleep::{Application, Configuration, Environment};
+use bleep::{Application, Command, Configuration, Environment};
 
 #[tokio::main]
 async fn main() -> Result<()> {
     Application::install_logging();
-    let app = Application::initialize(
-        Environment::server(),
-        Configuration::cli_overriding_config_file()?,
-        None,
-        None,
-    )
-    .await?;
+
+    let config = Configuration::cli_overriding_config_file()?;
+    if config.encrypt_store {
+        return Application::encrypt_store(config);
+    }
+
+    let dry_run = config.dry_run.clone();
+    let command = config.command.clone();
+    let app = Application::initialize(Environment::server(), config, None, None).await?;
+
+    if let Some(Command::Index(args)) = command {
+        return app.index(&args).await;
+    }
+
+    if let Some(reporef) = dry_run {
+        let report = app.dry_run(&reporef).await?;
+        println!("{}", serde_json::to_string_pretty(&report)?);
+        return Ok(());
+    }
 
     app.initialize_sentry();
     app.run().await
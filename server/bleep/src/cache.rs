@@ -0,0 +1,161 @@
+use std::{
+    borrow::Borrow,
+    collections::{HashMap, VecDeque},
+    hash::Hash,
+    sync::Mutex,
+};
+
+/// How a request uses the caches it goes through.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum CachePolicy {
+    /// Serve cached entries, and cache what is computed
+    #[default]
+    Reuse,
+    /// Compute everything afresh, but cache it for later requests
+    Refresh,
+    /// Compute everything afresh, without caching it
+    Bypass,
+}
+
+impl CachePolicy {
+    /// Whether cached entries may be served.
+    pub fn reads(self) -> bool {
+        self == Self::Reuse
+    }
+
+    /// Whether computed values may be cached.
+    pub fn writes(self) -> bool {
+        self != Self::Bypass
+    }
+}
+
+/// A small, thread-safe cache that holds at most `capacity` entries.
+///
+/// Once full, inserting a new key evicts the oldest entry. Values are
+/// cloned out on every hit, so they should be cheap to clone (e.g. an
+/// `Arc`).
+pub struct BoundedCache<K, V> {
+    capacity: usize,
+    inner: Mutex<Inner<K, V>>,
+}
+
+struct Inner<K, V> {
+    entries: HashMap<K, V>,
+    order: VecDeque<K>,
+}
+
+impl<K: Hash + Eq + Clone, V: Clone> BoundedCache<K, V> {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity: capacity.max(1),
+            inner: Mutex::new(Inner {
+                entries: HashMap::new(),
+                order: VecDeque::new(),
+            }),
+        }
+    }
+
+    pub fn get<Q>(&self, key: &Q) -> Option<V>
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        self.inner.lock().unwrap().entries.get(key).cloned()
+    }
+
+    pub fn insert(&self, key: K, value: V) {
+        let mut inner = self.inner.lock().unwrap();
+
+        if inner.entries.insert(key.clone(), value).is_some() {
+            return;
+        }
+
+        inner.order.push_back(key);
+        while inner.order.len() > self.capacity {
+            if let Some(oldest) = inner.order.pop_front() {
+                inner.entries.remove(&oldest);
+            }
+        }
+    }
+
+    /// Return the cached value for `key`, or compute it with `f` and
+    /// cache it if it succeeds.
+    ///
+    /// The lock is not held while `f` runs, so concurrent misses on the
+    /// same key may compute the value more than once.
+    pub fn get_or_try_insert_with<E>(
+        &self,
+        key: K,
+        f: impl FnOnce() -> Result<V, E>,
+    ) -> Result<V, E> {
+        self.get_or_try_insert_with_policy(key, CachePolicy::Reuse, f)
+    }
+
+    /// Like [`BoundedCache::get_or_try_insert_with`], but only reading and writing the cache as
+    /// `policy` allows.
+    pub fn get_or_try_insert_with_policy<E>(
+        &self,
+        key: K,
+        policy: CachePolicy,
+        f: impl FnOnce() -> Result<V, E>,
+    ) -> Result<V, E> {
+        if let Some(value) = self.get(&key).filter(|_| policy.reads()) {
+            return Ok(value);
+        }
+
+        let value = f()?;
+        if policy.writes() {
+            self.insert(key, value.clone());
+        }
+        Ok(value)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn evicts_oldest_entry() {
+        let cache = BoundedCache::new(2);
+        cache.insert("a", 1);
+        cache.insert("b", 2);
+        cache.insert("c", 3);
+
+        assert_eq!(cache.get("a"), None);
+        assert_eq!(cache.get("b"), Some(2));
+        assert_eq!(cache.get("c"), Some(3));
+    }
+
+    #[test]
+    fn policies_skip_reading_or_writing() {
+        let cache = BoundedCache::<&str, usize>::new(2);
+        let ok = |value| move || Ok::<_, ()>(value);
+        cache.insert("a", 1);
+
+        assert_eq!(
+            cache.get_or_try_insert_with_policy("a", CachePolicy::Reuse, ok(2)),
+            Ok(1)
+        );
+        assert_eq!(
+            cache.get_or_try_insert_with_policy("a", CachePolicy::Bypass, ok(2)),
+            Ok(2)
+        );
+        assert_eq!(cache.get("a"), Some(1));
+
+        assert_eq!(
+            cache.get_or_try_insert_with_policy("a", CachePolicy::Refresh, ok(3)),
+            Ok(3)
+        );
+        assert_eq!(cache.get("a"), Some(3));
+    }
+
+    #[test]
+    fn failed_computations_are_not_cached() {
+        let cache = BoundedCache::<&str, usize>::new(2);
+        let result: Result<usize, ()> = cache.get_or_try_insert_with("a", || Err(()));
+
+        assert!(result.is_err());
+        assert_eq!(cache.get("a"), None);
+    }
+}
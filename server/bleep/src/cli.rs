@@ -0,0 +1,204 @@
+//! `bleep index`, which syncs and indexes repositories through the same pipeline as the
+//! webserver, without starting it.
+//!
+//! Repositories are held with the same locks as the webserver, so a running server and
+//! `bleep index` sharing an index directory wait for each other instead of clobbering the
+//! index.
+
+use std::{
+    collections::{HashMap, HashSet},
+    path::Path,
+    str::FromStr,
+};
+
+use anyhow::{bail, Context, Result};
+use tokio::sync::broadcast::{self, error::RecvError};
+
+use crate::{
+    config::IndexArgs,
+    indexes::Progress,
+    repo::{Backend, RepoRef, SyncStatus},
+    Application,
+};
+
+/// Width of the progress bars, in characters
+const BAR_WIDTH: usize = 30;
+
+/// Progress is reported in steps of this many percent
+const PROGRESS_STEP: u8 = 10;
+
+pub(crate) async fn index(app: &Application, args: &IndexArgs) -> Result<()> {
+    let repos = repositories(app, args).await?;
+    let total = repos.len();
+    eprintln!(
+        "indexing {total} repositories, {} at a time",
+        args.parallelism.max(1)
+    );
+
+    // subscribe before starting, so no early progress is missed
+    let progress = tokio::spawn(report_progress(app.indexes.subscribe()));
+    let outcomes = app
+        .write_index()
+        .sync_and_index_each(repos, args.parallelism);
+
+    let mut failed = 0;
+    let mut finished = 0;
+    while let Ok((reporef, took, indexed)) = outcomes.recv_async().await {
+        finished += 1;
+
+        // failures to index are recorded on the repository, rather than returned
+        let outcome = match indexed {
+            Ok(()) => {
+                let status = app
+                    .repo_pool
+                    .read_async(&reporef, |_, repo| repo.sync_status.clone())
+                    .await;
+                outcome(status)
+            }
+            Err(err) => Err(format!("{err:#}")),
+        };
+
+        let took = took.as_secs_f32();
+        match outcome {
+            Ok(()) => eprintln!("[{finished}/{total}] done    {reporef} in {took:.1}s"),
+            Err(err) => {
+                failed += 1;
+                eprintln!("[{finished}/{total}] failed  {reporef} after {took:.1}s: {err}");
+            }
+        }
+    }
+
+    progress.abort();
+
+    // a repository whose task panicked never reports back
+    failed += total - finished;
+    eprintln!("indexed {} of {total} repositories", total - failed);
+
+    if failed > 0 {
+        bail!("{failed} of {total} repositories failed to index");
+    }
+
+    Ok(())
+}
+
+/// The repositories named on the command line, followed by the repository pool if
+/// `--all-configured` is set, without duplicates.
+async fn repositories(app: &Application, args: &IndexArgs) -> Result<Vec<RepoRef>> {
+    let mut repos = args
+        .repos
+        .iter()
+        .map(|arg| parse_repo(arg))
+        .collect::<Result<Vec<_>>>()?;
+
+    if args.all_configured {
+        app.repo_pool
+            .scan_async(|reporef, _| repos.push(reporef.clone()))
+            .await;
+    }
+
+    let mut seen = HashSet::new();
+    repos.retain(|reporef| seen.insert(reporef.clone()));
+
+    if repos.is_empty() {
+        bail!("no repositories to index, pass `--repo` or `--all-configured`");
+    }
+
+    Ok(repos)
+}
+
+/// Read `arg` as a repository reference, or else as the path of a local repository.
+fn parse_repo(arg: &str) -> Result<RepoRef> {
+    if let Ok(reporef) = RepoRef::from_str(arg) {
+        return Ok(reporef);
+    }
+
+    let path = crate::canonicalize(Path::new(arg)).with_context(|| {
+        format!("`{arg}` is neither a repository reference nor an existing path")
+    })?;
+
+    Ok(RepoRef::new(Backend::Local, &path.to_string_lossy())?)
+}
+
+/// Whether a repository that went through the pipeline without error ended up indexed.
+fn outcome(status: Option<SyncStatus>) -> Result<(), String> {
+    match status {
+        Some(SyncStatus::Error { message }) => Err(message),
+        Some(SyncStatus::RemoteRemoved) => Err("the remote repository was removed".into()),
+        // repositories marked as removed leave the pool once their indexes are deleted
+        _ => Ok(()),
+    }
+}
+
+/// Print a progress bar for each index of each repository, every `PROGRESS_STEP` percent.
+async fn report_progress(mut progress: broadcast::Receiver<Progress>) {
+    let mut reported = HashMap::new();
+
+    loop {
+        let (reporef, index, percent) = match progress.recv().await {
+            Ok(update) => update,
+            Err(RecvError::Lagged(_)) => continue,
+            Err(RecvError::Closed) => return,
+        };
+
+        let step = percent.min(100) / PROGRESS_STEP * PROGRESS_STEP;
+        let last = reported.insert((reporef.clone(), index), step);
+        if last.map_or(true, |last| last < step) {
+            let name = if index == 0 { "repo" } else { "files" };
+            eprintln!("{} {name:<5} {reporef}", bar(step));
+        }
+    }
+}
+
+fn bar(percent: u8) -> String {
+    let filled = BAR_WIDTH * usize::from(percent.min(100)) / 100;
+    format!(
+        "[{}{}] {percent:>3}%",
+        "#".repeat(filled),
+        "-".repeat(BAR_WIDTH - filled)
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn repositories_are_read_as_refs_or_paths() {
+        assert_eq!(
+            parse_repo("github.com/bloopai/bloop").unwrap(),
+            RepoRef::new(Backend::Github, "bloopai/bloop").unwrap()
+        );
+
+        let dir = tempdir::TempDir::new("bleep-index").unwrap();
+        let path = crate::canonicalize(dir.path()).unwrap();
+        assert_eq!(
+            parse_repo(&dir.path().to_string_lossy()).unwrap(),
+            RepoRef::new(Backend::Local, &path.to_string_lossy()).unwrap()
+        );
+
+        assert!(parse_repo("no/such/path").is_err());
+    }
+
+    #[test]
+    fn indexing_errors_recorded_on_the_repository_fail_it() {
+        assert_eq!(outcome(Some(SyncStatus::Done)), Ok(()));
+        assert_eq!(outcome(None), Ok(()));
+        assert_eq!(
+            outcome(Some(SyncStatus::Error {
+                message: "disk full".into()
+            })),
+            Err("disk full".into())
+        );
+        assert!(outcome(Some(SyncStatus::RemoteRemoved)).is_err());
+    }
+
+    #[test]
+    fn progress_bars_fill_up() {
+        assert_eq!(bar(0), format!("[{}]   0%", "-".repeat(BAR_WIDTH)));
+        assert_eq!(
+            bar(50),
+            format!("[{}{}]  50%", "#".repeat(15), "-".repeat(15))
+        );
+        assert_eq!(bar(100), format!("[{}] 100%", "#".repeat(BAR_WIDTH)));
+    }
+}
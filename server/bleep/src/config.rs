@@ -1,11 +1,18 @@
-use crate::{semantic::chunk::OverlapStrategy, state::StateSource};
-use anyhow::{Context, Result};
-use clap::Parser;
+use crate::{
+    repo::RepoRef,
+    semantic::{chunk::OverlapStrategy, store::VectorStoreKind},
+    state::StateSource,
+};
+use anyhow::{bail, Context, Result};
+use clap::{Args, Parser, Subcommand};
 
 use secrecy::{ExposeSecret, SecretString};
 use serde::{Deserialize, Serialize, Serializer};
 use std::path::{Path, PathBuf};
 
+/// Environment variable the state store key is read from, if it isn't configured otherwise
+const STORE_KEY_VAR: &str = "BLOOP_STORE_KEY";
+
 #[derive(Serialize, Deserialize, Parser, Debug)]
 #[clap(author, version, about, long_about = None)]
 pub struct Configuration {
@@ -31,6 +38,20 @@ pub struct Configuration {
     /// Quit after indexing the specified repos
     pub index_only: bool,
 
+    #[clap(long, default_value_t = false)]
+    #[serde(skip)]
+    /// Encrypt the existing state store with the store key, keeping a plaintext backup, then quit
+    pub encrypt_store: bool,
+
+    #[clap(long, value_name = "REPO_REF")]
+    #[serde(skip)]
+    /// Report what indexing the repository would do, without writing to any index, then quit
+    pub dry_run: Option<RepoRef>,
+
+    #[clap(subcommand)]
+    #[serde(skip)]
+    pub command: Option<Command>,
+
     #[clap(long, default_value_t = false)]
     #[serde(default)]
     /// Disable periodic reindexing, and `git pull` on remote repositories.
@@ -41,6 +62,56 @@ pub struct Configuration {
     /// Disable system-native notification backends to detect new git commits immediately.
     pub disable_fsevents: bool,
 
+    #[clap(long, default_value_t = false)]
+    #[serde(default)]
+    /// Reject sync requests for a repository that is already being indexed, instead of queueing
+    /// another run after the current one.
+    pub reject_concurrent_sync: bool,
+
+    #[clap(long, default_value_t = false)]
+    #[serde(default)]
+    /// Do not record executed searches in the local search history.
+    pub disable_search_history: bool,
+
+    #[clap(long, default_value_t = default_maintenance_interval_hours())]
+    #[serde(default = "default_maintenance_interval_hours")]
+    /// How often to garbage collect orphaned semantic index points and compact the search index,
+    /// in hours. Set to 0 to only run maintenance on request.
+    pub maintenance_interval_hours: u64,
+
+    #[clap(long)]
+    /// Evict the least recently searched repositories once their clones and the search indexes
+    /// take up more than this many megabytes. Off by default
+    pub eviction_budget_mb: Option<u64>,
+
+    #[clap(long, default_value_t = default_eviction_interval_hours())]
+    #[serde(default = "default_eviction_interval_hours")]
+    /// How often to check disk usage against `eviction_budget_mb`, in hours
+    pub eviction_interval_hours: u64,
+
+    #[clap(long, default_value_t = false)]
+    #[serde(default)]
+    /// Delete the semantic index points of evicted repositories as well. They are kept by
+    /// default, so semantic searches that aren't scoped to a repository still find them
+    pub evict_semantic_points: bool,
+
+    #[clap(long = "notification-webhook", value_name = "URL")]
+    #[serde(default)]
+    /// URL to post an alert to when a repository fails to sync or index, or goes stale. Can be
+    /// repeated
+    pub notification_webhooks: Vec<String>,
+
+    #[clap(long, default_value_t = default_notify_after_failures())]
+    #[serde(default = "default_notify_after_failures")]
+    /// Send an extra alert once a repository fails this many runs in a row
+    pub notify_after_failures: u32,
+
+    #[clap(long, default_value_t = default_notify_stale_after_hours())]
+    #[serde(default = "default_notify_stale_after_hours")]
+    /// Alert when a remote repository was not indexed successfully for this many hours. Set to
+    /// 0 to disable
+    pub notify_stale_after_hours: u64,
+
     #[clap(short, long, default_value_t = default_buffer_size())]
     #[serde(default = "default_buffer_size")]
     /// Size of memory to use for file indexes
@@ -97,10 +168,48 @@ pub struct Configuration {
     //
     // Semantic values
     //
+    #[clap(long, value_enum, default_value_t = VectorStoreKind::Qdrant)]
+    #[serde(default)]
+    /// Where to keep the semantic index: `qdrant`, on the server at `qdrant-url`, or `local`, in
+    /// `index-dir`. The local store searches exhaustively, which is fine for up to a few hundred
+    /// thousand chunks
+    pub vector_store: VectorStoreKind,
+
     #[clap(long)]
     /// URL for the qdrant server
     pub qdrant_url: Option<String>,
 
+    #[clap(long, default_value_t = default_qdrant_collection())]
+    #[serde(default = "default_qdrant_collection")]
+    /// Qdrant collection to keep the semantic index in. Instances sharing a qdrant server need
+    /// a collection each, as they can't see each other's points
+    pub qdrant_collection: String,
+
+    #[clap(long = "qdrant-search-collection", value_name = "COLLECTION")]
+    #[serde(default)]
+    /// Qdrant collection to search, for indexes sharded across several collections. Can be
+    /// repeated. Searches only cover `qdrant-collection` if none are given
+    pub qdrant_search_collections: Vec<String>,
+
+    #[clap(long, default_value_t = default_qdrant_breaker_failures())]
+    #[serde(default = "default_qdrant_breaker_failures")]
+    /// Consecutive failed qdrant searches after which further searches fail fast, without
+    /// reaching qdrant, for `qdrant-breaker-cooldown-secs`. 0 never stops searching
+    pub qdrant_breaker_failures: u32,
+
+    #[clap(long, default_value_t = default_qdrant_breaker_cooldown_secs())]
+    #[serde(default = "default_qdrant_breaker_cooldown_secs")]
+    /// How long searches fail fast once qdrant keeps failing, before a single search is let
+    /// through to probe whether it recovered
+    pub qdrant_breaker_cooldown_secs: u64,
+
+    #[clap(long = "payload-key", value_name = "FIELD=KEY")]
+    #[serde(default)]
+    /// Payload key a chunk field is stored under, such as `snippet=content`, for collections
+    /// written by another indexer. Can be repeated. Fields are read, filtered on and written at
+    /// keys of their own name unless mapped
+    pub payload_keys: Vec<String>,
+
     #[clap(long, default_value_os_t = default_model_dir())]
     #[serde(default = "default_model_dir")]
     /// Path to the embedding model directory
@@ -115,6 +224,115 @@ pub struct Configuration {
     /// Chunking strategy
     pub overlap: Option<OverlapStrategy>,
 
+    #[clap(long, default_value_t = default_embedding_batch_window_ms())]
+    #[serde(default = "default_embedding_batch_window_ms")]
+    /// How long concurrent embedding requests are queued to be batched together, in milliseconds
+    pub embedding_batch_window_ms: u64,
+
+    #[clap(long, default_value_t = default_embedding_batch_size())]
+    #[serde(default = "default_embedding_batch_size")]
+    /// Maximum number of queued embedding requests in a single batch
+    pub embedding_batch_size: usize,
+
+    #[clap(long, default_value_t = default_parallelism())]
+    #[serde(default = "default_parallelism")]
+    /// Maximum number of embeddings run through the model at once, further ones wait their turn
+    pub embedding_concurrency: usize,
+
+    #[clap(long, default_value_t = default_embedding_wait_timeout_secs())]
+    #[serde(default = "default_embedding_wait_timeout_secs")]
+    /// How long an embedding waits for its turn through the model, before its search or indexing
+    /// batch fails
+    pub embedding_wait_timeout_secs: u64,
+
+    #[clap(long, default_value_t = default_min_query_chars())]
+    #[serde(default = "default_min_query_chars")]
+    /// Queries whose search target has fewer characters than this are rejected before embedding
+    pub min_query_chars: usize,
+
+    #[clap(long)]
+    /// Reuse `/semantic/chunks` responses for identical searches for this many seconds, as long
+    /// as the semantic index is unchanged. Off by default
+    pub semantic_cache_ttl_secs: Option<u64>,
+
+    #[clap(long, default_value_t = default_semantic_cache_entries())]
+    #[serde(default = "default_semantic_cache_entries")]
+    /// Maximum number of cached `/semantic/chunks` responses
+    pub semantic_cache_entries: usize,
+
+    #[clap(long, default_value_t = default_repo_boost())]
+    #[serde(default = "default_repo_boost")]
+    /// Score multiplier for the chunks of the `boost_repos` of a `/semantic/chunks` search
+    pub repo_boost: f32,
+
+    #[clap(long, default_value_t = default_query_embedding_cache_entries())]
+    #[serde(default = "default_query_embedding_cache_entries")]
+    /// Maximum number of cached query embeddings, reused by every search for the same query. Set
+    /// to 0 to embed every query
+    pub query_embedding_cache_entries: usize,
+
+    #[clap(long, default_value_t = false)]
+    #[serde(default)]
+    /// Drop common English words, such as `the` or `how`, from queries before embedding them
+    pub strip_stopwords: bool,
+
+    #[clap(long, default_value_t = false)]
+    #[serde(default)]
+    /// Match `path:` filters and keyword matches regardless of case. Chunks indexed before this
+    /// was set are still matched verbatim until they are indexed again, as are quoted phrases.
+    /// Vector similarity is unaffected
+    pub fold_filter_case: bool,
+
+    #[clap(long, default_value_t = false)]
+    #[serde(default)]
+    /// Match `path:` filters and keyword matches regardless of accents, so `cafe` matches
+    /// `café`. Like `fold-filter-case`, this takes effect as chunks are indexed again
+    pub fold_filter_accents: bool,
+
+    #[clap(long, default_value_t = false)]
+    #[serde(default)]
+    /// Embed markdown cells of Jupyter notebooks, in addition to code cells
+    pub index_notebook_markdown: bool,
+
+    #[clap(long, default_value_t = default_keyword_fallback_min_results())]
+    #[serde(default = "default_keyword_fallback_min_results")]
+    /// Fill in answer snippets with keyword matches when semantic search finds fewer than this
+    pub keyword_fallback_min_results: usize,
+
+    #[clap(long, default_value_t = default_dedup_max_candidates())]
+    #[serde(default = "default_dedup_max_candidates")]
+    /// Most candidate snippets of an answer that are compared with each other to drop near
+    /// duplicates, the best scoring ones are kept. Comparisons grow with the square of this.
+    /// Answers draw on no more snippets than this, so it should stay well above their limit
+    pub dedup_max_candidates: usize,
+
+    #[clap(long, default_value_t = default_sse_keep_alive_secs())]
+    #[serde(default = "default_sse_keep_alive_secs")]
+    /// Interval between keep-alive comments on idle event streams, in seconds
+    pub sse_keep_alive_secs: u64,
+
+    #[clap(long, default_value_t = default_index_status_replay_events())]
+    #[serde(default = "default_index_status_replay_events")]
+    /// Index status events kept for clients resuming with `Last-Event-ID`
+    pub index_status_replay_events: usize,
+
+    #[clap(long, default_value_t = default_answer_replay_streams())]
+    #[serde(default = "default_answer_replay_streams")]
+    /// Answer streams kept for clients resuming with `Last-Event-ID`, including finished ones
+    pub answer_replay_streams: usize,
+
+    #[clap(long, default_value_t = default_answer_replay_events())]
+    #[serde(default = "default_answer_replay_events")]
+    /// Events of each answer stream kept for clients resuming with `Last-Event-ID`. Clients
+    /// resuming from an older event are told to restart
+    pub answer_replay_events: usize,
+
+    #[clap(long, default_value_t = default_answer_abandon_secs())]
+    #[serde(default = "default_answer_abandon_secs")]
+    /// How long an answer keeps being generated without any client following it, in seconds,
+    /// before it is cancelled
+    pub answer_abandon_secs: u64,
+
     //
     // Installation-specific values
     //
@@ -145,6 +363,16 @@ pub struct Configuration {
     /// Bot secret token
     pub bot_secret: Option<SecretString>,
 
+    #[clap(long)]
+    #[serde(serialize_with = "serialize_secret_opt_str", default)]
+    /// Key to encrypt the state store with. If not given, it's read from `store_key_file`, or
+    /// the `BLOOP_STORE_KEY` environment variable
+    pub store_key: Option<SecretString>,
+
+    #[clap(long)]
+    /// Path to a file containing the state store key
+    pub store_key_file: Option<PathBuf>,
+
     //
     // Cloud deployment values
     //
@@ -157,6 +385,29 @@ pub struct Configuration {
     pub frontend_dist: Option<PathBuf>,
 }
 
+/// Commands run instead of the webserver
+#[derive(Subcommand, Debug, Clone, PartialEq, Eq)]
+pub enum Command {
+    /// Sync and index repositories without starting the webserver, then quit
+    Index(IndexArgs),
+}
+
+#[derive(Args, Debug, Clone, PartialEq, Eq)]
+pub struct IndexArgs {
+    /// A local path, or a repository reference such as `github.com/bloopai/bloop`. Can be
+    /// repeated
+    #[clap(long = "repo", value_name = "PATH_OR_REF")]
+    pub repos: Vec<String>,
+
+    /// Index every repository already in the repository pool as well
+    #[clap(long, default_value_t = false)]
+    pub all_configured: bool,
+
+    /// How many repositories to index at the same time
+    #[clap(long, default_value_t = 1)]
+    pub parallelism: usize,
+}
+
 macro_rules! right_if_default {
     ($left:expr, $right:expr, $default:expr) => {
         if $left == $default {
@@ -181,6 +432,29 @@ impl Configuration {
         self.index_dir.join(name)
     }
 
+    /// The state store key, taken from `store_key`, `store_key_file` or the `BLOOP_STORE_KEY`
+    /// environment variable, in that order.
+    pub fn store_key(&self) -> Result<Option<SecretString>> {
+        let key = if let Some(ref key) = self.store_key {
+            key.expose_secret().to_owned()
+        } else if let Some(ref path) = self.store_key_file {
+            std::fs::read_to_string(path)
+                .with_context(|| format!("failed to read store key file `{}`", path.display()))?
+                .trim()
+                .to_owned()
+        } else if let Ok(key) = std::env::var(STORE_KEY_VAR) {
+            key
+        } else {
+            return Ok(None);
+        };
+
+        if key.is_empty() {
+            bail!("the store key is empty");
+        }
+
+        Ok(Some(SecretString::new(key)))
+    }
+
     pub fn github_client_id_and_secret(&self) -> Option<(&str, &str)> {
         let id = self.github_client_id.as_ref()?.expose_secret();
         let secret = self.github_client_secret.as_ref()?.expose_secret();
@@ -213,10 +487,54 @@ impl Configuration {
 
             index_only: b.index_only | a.index_only,
 
+            encrypt_store: b.encrypt_store | a.encrypt_store,
+
+            dry_run: b.dry_run.or(a.dry_run),
+
+            command: b.command.or(a.command),
+
             disable_background: b.disable_background | a.disable_background,
 
             disable_fsevents: b.disable_fsevents | a.disable_fsevents,
 
+            reject_concurrent_sync: b.reject_concurrent_sync | a.reject_concurrent_sync,
+
+            disable_search_history: b.disable_search_history | a.disable_search_history,
+
+            maintenance_interval_hours: right_if_default!(
+                b.maintenance_interval_hours,
+                a.maintenance_interval_hours,
+                default_maintenance_interval_hours()
+            ),
+
+            eviction_budget_mb: b.eviction_budget_mb.or(a.eviction_budget_mb),
+
+            eviction_interval_hours: right_if_default!(
+                b.eviction_interval_hours,
+                a.eviction_interval_hours,
+                default_eviction_interval_hours()
+            ),
+
+            evict_semantic_points: b.evict_semantic_points | a.evict_semantic_points,
+
+            notification_webhooks: right_if_default!(
+                b.notification_webhooks,
+                a.notification_webhooks,
+                Vec::<String>::new()
+            ),
+
+            notify_after_failures: right_if_default!(
+                b.notify_after_failures,
+                a.notify_after_failures,
+                default_notify_after_failures()
+            ),
+
+            notify_stale_after_hours: right_if_default!(
+                b.notify_stale_after_hours,
+                a.notify_stale_after_hours,
+                default_notify_stale_after_hours()
+            ),
+
             buffer_size: right_if_default!(b.buffer_size, a.buffer_size, default_buffer_size()),
 
             repo_buffer_size: right_if_default!(
@@ -241,10 +559,138 @@ impl Configuration {
 
             overlap: b.overlap.or(a.overlap),
 
+            embedding_batch_window_ms: right_if_default!(
+                b.embedding_batch_window_ms,
+                a.embedding_batch_window_ms,
+                default_embedding_batch_window_ms()
+            ),
+
+            embedding_batch_size: right_if_default!(
+                b.embedding_batch_size,
+                a.embedding_batch_size,
+                default_embedding_batch_size()
+            ),
+
+            embedding_concurrency: right_if_default!(
+                b.embedding_concurrency,
+                a.embedding_concurrency,
+                default_parallelism()
+            ),
+
+            embedding_wait_timeout_secs: right_if_default!(
+                b.embedding_wait_timeout_secs,
+                a.embedding_wait_timeout_secs,
+                default_embedding_wait_timeout_secs()
+            ),
+
+            min_query_chars: right_if_default!(
+                b.min_query_chars,
+                a.min_query_chars,
+                default_min_query_chars()
+            ),
+
+            semantic_cache_ttl_secs: b.semantic_cache_ttl_secs.or(a.semantic_cache_ttl_secs),
+
+            semantic_cache_entries: right_if_default!(
+                b.semantic_cache_entries,
+                a.semantic_cache_entries,
+                default_semantic_cache_entries()
+            ),
+
+            repo_boost: right_if_default!(b.repo_boost, a.repo_boost, default_repo_boost()),
+
+            query_embedding_cache_entries: right_if_default!(
+                b.query_embedding_cache_entries,
+                a.query_embedding_cache_entries,
+                default_query_embedding_cache_entries()
+            ),
+
+            strip_stopwords: b.strip_stopwords | a.strip_stopwords,
+
+            fold_filter_case: b.fold_filter_case | a.fold_filter_case,
+
+            fold_filter_accents: b.fold_filter_accents | a.fold_filter_accents,
+
+            index_notebook_markdown: b.index_notebook_markdown | a.index_notebook_markdown,
+
+            dedup_max_candidates: right_if_default!(
+                b.dedup_max_candidates,
+                a.dedup_max_candidates,
+                default_dedup_max_candidates()
+            ),
+
+            keyword_fallback_min_results: right_if_default!(
+                b.keyword_fallback_min_results,
+                a.keyword_fallback_min_results,
+                default_keyword_fallback_min_results()
+            ),
+
+            sse_keep_alive_secs: right_if_default!(
+                b.sse_keep_alive_secs,
+                a.sse_keep_alive_secs,
+                default_sse_keep_alive_secs()
+            ),
+
+            index_status_replay_events: right_if_default!(
+                b.index_status_replay_events,
+                a.index_status_replay_events,
+                default_index_status_replay_events()
+            ),
+
+            answer_replay_streams: right_if_default!(
+                b.answer_replay_streams,
+                a.answer_replay_streams,
+                default_answer_replay_streams()
+            ),
+
+            answer_replay_events: right_if_default!(
+                b.answer_replay_events,
+                a.answer_replay_events,
+                default_answer_replay_events()
+            ),
+
+            answer_abandon_secs: right_if_default!(
+                b.answer_abandon_secs,
+                a.answer_abandon_secs,
+                default_answer_abandon_secs()
+            ),
+
             frontend_dist: b.frontend_dist.or(a.frontend_dist),
 
+            vector_store: right_if_default!(
+                b.vector_store,
+                a.vector_store,
+                VectorStoreKind::default()
+            ),
+
             qdrant_url: b.qdrant_url.or(a.qdrant_url),
 
+            qdrant_collection: right_if_default!(
+                b.qdrant_collection,
+                a.qdrant_collection,
+                default_qdrant_collection()
+            ),
+
+            qdrant_search_collections: right_if_default!(
+                b.qdrant_search_collections,
+                a.qdrant_search_collections,
+                Vec::<String>::new()
+            ),
+
+            qdrant_breaker_failures: right_if_default!(
+                b.qdrant_breaker_failures,
+                a.qdrant_breaker_failures,
+                default_qdrant_breaker_failures()
+            ),
+
+            qdrant_breaker_cooldown_secs: right_if_default!(
+                b.qdrant_breaker_cooldown_secs,
+                a.qdrant_breaker_cooldown_secs,
+                default_qdrant_breaker_cooldown_secs()
+            ),
+
+            payload_keys: right_if_default!(b.payload_keys, a.payload_keys, Vec::<String>::new()),
+
             answer_api_url: right_if_default!(
                 b.answer_api_url,
                 a.answer_api_url,
@@ -265,6 +711,10 @@ impl Configuration {
 
             bot_secret: b.bot_secret.or(a.bot_secret),
 
+            store_key: b.store_key.or(a.store_key),
+
+            store_key_file: b.store_key_file.or(a.store_key_file),
+
             analytics_key: b.analytics_key.or(a.analytics_key),
             analytics_key_fe: b.analytics_key_fe.or(a.analytics_key_fe),
 
@@ -327,6 +777,22 @@ const fn default_repo_buffer_size() -> usize {
     30_000_000
 }
 
+const fn default_maintenance_interval_hours() -> u64 {
+    24
+}
+
+const fn default_eviction_interval_hours() -> u64 {
+    6
+}
+
+const fn default_notify_after_failures() -> u32 {
+    3
+}
+
+const fn default_notify_stale_after_hours() -> u64 {
+    24
+}
+
 const fn default_port() -> u16 {
     7878
 }
@@ -335,6 +801,22 @@ fn default_host() -> String {
     String::from("127.0.0.1")
 }
 
+fn default_qdrant_collection() -> String {
+    crate::semantic::LEGACY_COLLECTION.to_owned()
+}
+
+const fn default_qdrant_breaker_failures() -> u32 {
+    5
+}
+
+const fn default_qdrant_breaker_cooldown_secs() -> u64 {
+    30
+}
+
+const fn default_embedding_wait_timeout_secs() -> u64 {
+    30
+}
+
 fn default_answer_api_url() -> String {
     String::from("http://127.0.0.1:7879")
 }
@@ -342,3 +824,55 @@ fn default_answer_api_url() -> String {
 fn default_max_chunk_tokens() -> usize {
     256
 }
+
+const fn default_embedding_batch_window_ms() -> u64 {
+    5
+}
+
+const fn default_embedding_batch_size() -> usize {
+    32
+}
+
+const fn default_min_query_chars() -> usize {
+    2
+}
+
+const fn default_semantic_cache_entries() -> usize {
+    256
+}
+
+const fn default_repo_boost() -> f32 {
+    1.2
+}
+
+const fn default_query_embedding_cache_entries() -> usize {
+    1024
+}
+
+const fn default_keyword_fallback_min_results() -> usize {
+    3
+}
+
+const fn default_dedup_max_candidates() -> usize {
+    256
+}
+
+const fn default_sse_keep_alive_secs() -> u64 {
+    5
+}
+
+const fn default_index_status_replay_events() -> usize {
+    256
+}
+
+const fn default_answer_replay_streams() -> usize {
+    32
+}
+
+const fn default_answer_replay_events() -> usize {
+    1024
+}
+
+const fn default_answer_abandon_secs() -> u64 {
+    30
+}
@@ -0,0 +1,337 @@
+use std::{
+    path::Path,
+    time::{Duration, SystemTime},
+};
+
+use anyhow::Result;
+use serde::Serialize;
+use tokio::time::sleep;
+use tracing::{error, info, warn};
+
+use crate::{
+    indexes,
+    repo::{RepoRef, Repository, SyncStatus, UsageKind},
+    Application,
+};
+
+const SECS_PER_HOUR: u64 = 60 * 60;
+
+const BYTES_PER_MB: u64 = 1024 * 1024;
+
+/// Usage is persisted with the repository state when it moved on by at least this much, rather
+/// than on every search
+const USAGE_PERSIST_INTERVAL_SECS: u64 = SECS_PER_HOUR;
+
+#[derive(Serialize, Debug)]
+pub struct EvictionReport {
+    /// Disk used by clones and search indexes before the run, in bytes
+    pub used_bytes: u64,
+    /// Disk used once the evicted repositories were deleted, in bytes
+    pub remaining_bytes: u64,
+    pub budget_bytes: u64,
+    /// Evicted repositories, least recently used first
+    pub evicted: Vec<String>,
+}
+
+/// Record that a search or an answer drew on `repos`, for picking the repositories to evict.
+pub(crate) fn record_use(app: &Application, repos: &[String], kind: UsageKind) {
+    let now = unix_time_sec();
+    let mut persist = false;
+
+    for repo_ref in repos {
+        let Ok(reporef) = repo_ref.parse::<RepoRef>() else {
+            continue;
+        };
+
+        if let Some(last) = app
+            .repo_pool
+            .update(&reporef, |_, repo| repo.usage.record(kind, now))
+        {
+            persist |= now.saturating_sub(last) >= USAGE_PERSIST_INTERVAL_SECS;
+        }
+    }
+
+    if persist {
+        let (config, pool) = (app.config.clone(), app.repo_pool.clone());
+        tokio::task::spawn_blocking(move || {
+            if let Err(err) = config.source.save_pool(pool) {
+                warn!(?err, "failed to persist repository usage");
+            }
+        });
+    }
+}
+
+/// Evict the least recently used repositories, until the clones and search indexes fit in
+/// `eviction_budget_mb`.
+///
+/// Evicted repositories keep their metadata, and their semantic index points unless
+/// `evict_semantic_points` is set. The search index is compacted after each eviction, so the
+/// space it frees is measured before evicting another repository.
+pub(crate) async fn run(app: &Application) -> Result<EvictionReport> {
+    let budget_bytes = app
+        .config
+        .eviction_budget_mb
+        .unwrap_or(u64::MAX / BYTES_PER_MB)
+        .saturating_mul(BYTES_PER_MB);
+
+    let used_bytes = disk_usage(app).await?;
+    let mut report = EvictionReport {
+        used_bytes,
+        remaining_bytes: used_bytes,
+        budget_bytes,
+        evicted: vec![],
+    };
+
+    if used_bytes <= budget_bytes {
+        return Ok(report);
+    }
+
+    let mut repos = vec![];
+    app.repo_pool
+        .scan_async(|k, v| repos.push((k.clone(), v.clone())))
+        .await;
+
+    for reporef in candidates(repos) {
+        if report.remaining_bytes <= budget_bytes {
+            break;
+        }
+
+        if evict(app, &reporef).await? {
+            info!(%reporef, "evicted repository");
+            report.evicted.push(reporef.to_string());
+            app.indexes.writers().await?.compact().await?;
+            report.remaining_bytes = disk_usage(app).await?;
+        }
+    }
+
+    if report.remaining_bytes > budget_bytes {
+        warn!(
+            ?report,
+            "no repositories left to evict, disk usage is still over budget"
+        );
+    }
+
+    Ok(report)
+}
+
+/// Check disk usage against `eviction_budget_mb` every `eviction_interval_hours`.
+pub(crate) async fn periodic_eviction(app: Application) {
+    let hours = app.config.eviction_interval_hours;
+    if app.config.eviction_budget_mb.is_none() || hours == 0 {
+        return;
+    }
+
+    loop {
+        sleep(Duration::from_secs(hours * SECS_PER_HOUR)).await;
+
+        match run(&app).await {
+            Ok(report) if !report.evicted.is_empty() => info!(?report, "eviction finished"),
+            Ok(_) => {}
+            Err(err) => error!(?err, "repository eviction failed"),
+        }
+    }
+}
+
+/// The repositories that may be evicted, least recently used first.
+///
+/// Only indexed repositories are evicted, and never pinned ones.
+fn candidates(repos: Vec<(RepoRef, Repository)>) -> Vec<RepoRef> {
+    let mut candidates = repos
+        .into_iter()
+        .filter(|(_, repo)| evictable(repo))
+        .map(|(reporef, repo)| (repo.last_used_unix_secs(), reporef))
+        .collect::<Vec<_>>();
+
+    candidates.sort_by(|a, b| {
+        a.0.cmp(&b.0)
+            .then_with(|| a.1.to_string().cmp(&b.1.to_string()))
+    });
+    candidates.into_iter().map(|(_, reporef)| reporef).collect()
+}
+
+fn evictable(repo: &Repository) -> bool {
+    !repo.pinned
+        && matches!(
+            repo.sync_status,
+            SyncStatus::Done | SyncStatus::Error { .. }
+        )
+}
+
+/// Delete the clone and search index of `reporef`, returning whether it was evicted.
+///
+/// Repositories that started indexing, or were pinned, meanwhile are left alone.
+async fn evict(app: &Application, reporef: &RepoRef) -> Result<bool> {
+    // the same lock as indexing runs of the repository, see `IndexWriter::sync_and_index_call`
+    let lock_name = blake3::hash(reporef.to_string().as_bytes()).to_string();
+    let _lock = indexes::lock_across_processes(&app.config.index_dir, &lock_name).await?;
+    let writers = app.indexes.writers().await?;
+
+    let Some(repo) = app
+        .repo_pool
+        .read_async(reporef, |_, repo| evictable(repo).then(|| repo.clone()))
+        .await
+        .flatten()
+    else {
+        return Ok(false);
+    };
+
+    if let Some(semantic) = app.semantic.as_ref() {
+        if app.config.evict_semantic_points {
+            semantic
+                .delete_points_by_path(&reporef.to_string(), std::iter::empty())
+                .await;
+        }
+    }
+
+    // without its file cache, the next index of the repository processes every file again
+    if let Err(err) = repo.delete_file_cache(&app.config.index_dir) {
+        warn!(?err, %reporef, "failed to delete the file cache of an evicted repository");
+    }
+
+    // local repositories are never touched, only their clones are ours to delete
+    if !reporef.is_local() {
+        if let Err(err) = tokio::fs::remove_dir_all(&repo.disk_path).await {
+            if err.kind() != std::io::ErrorKind::NotFound {
+                return Err(err.into());
+            }
+        }
+    }
+
+    for handle in writers.iter() {
+        handle.delete(&repo);
+    }
+    writers.commit().await?;
+
+    app.repo_pool
+        .update_async(reporef, |_, repo| repo.mark_evicted())
+        .await;
+    app.config.source.save_pool(app.repo_pool.clone())?;
+
+    Ok(true)
+}
+
+/// Disk used by the search indexes and the clones of remote repositories, in bytes.
+///
+/// Local repositories are checkouts of their own, which are never deleted.
+async fn disk_usage(app: &Application) -> Result<u64> {
+    let index_dir = app.config.index_dir.clone();
+    let mut clones = vec![];
+    app.repo_pool
+        .scan_async(|k, v| {
+            // clones are kept under the index directory by default, and counted with it then
+            if !k.is_local() && !v.disk_path.starts_with(&index_dir) {
+                clones.push(v.disk_path.clone());
+            }
+        })
+        .await;
+
+    Ok(tokio::task::spawn_blocking(move || {
+        dir_size(&index_dir) + clones.iter().map(|clone| dir_size(clone)).sum::<u64>()
+    })
+    .await?)
+}
+
+/// The size of the files under `path`, without following symlinks.
+fn dir_size(path: &Path) -> u64 {
+    let Ok(meta) = std::fs::symlink_metadata(path) else {
+        return 0;
+    };
+
+    if !meta.is_dir() {
+        return meta.len();
+    }
+
+    std::fs::read_dir(path)
+        .map(|entries| {
+            entries
+                .filter_map(Result::ok)
+                .map(|entry| dir_size(&entry.path()))
+                .sum()
+        })
+        .unwrap_or_default()
+}
+
+fn unix_time_sec() -> u64 {
+    SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap()
+        .as_secs()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::repo::{RepoRemote, RepoUsage};
+
+    fn repo(status: SyncStatus, last_search: u64, last_index: u64) -> Repository {
+        Repository {
+            disk_path: "/unused".into(),
+            remote: RepoRemote::None,
+            sync_status: status,
+            last_commit_unix_secs: 0,
+            indexed_commit: None,
+            last_index_unix_secs: last_index,
+            most_common_lang: None,
+            lang_stats: Default::default(),
+            pinned: false,
+            usage: RepoUsage {
+                last_search_unix_secs: last_search,
+                last_answer_unix_secs: 0,
+            },
+        }
+    }
+
+    #[test]
+    fn least_recently_used_repos_are_evicted_first() {
+        let reporef = |name: &str| RepoRef::from(format!("github.com/org/{name}").as_str());
+        let pinned = Repository {
+            pinned: true,
+            ..repo(SyncStatus::Done, 0, 0)
+        };
+        let answered = Repository {
+            usage: RepoUsage {
+                last_search_unix_secs: 0,
+                last_answer_unix_secs: 500,
+            },
+            ..repo(SyncStatus::Done, 0, 100)
+        };
+
+        let repos = vec![
+            (reporef("searched"), repo(SyncStatus::Done, 300, 100)),
+            (reporef("answered"), answered),
+            (reporef("pinned"), pinned),
+            (
+                reporef("failed"),
+                repo(
+                    SyncStatus::Error {
+                        message: "x".into(),
+                    },
+                    0,
+                    200,
+                ),
+            ),
+            (reporef("indexing"), repo(SyncStatus::Indexing, 0, 0)),
+            (reporef("evicted"), repo(SyncStatus::Evicted, 0, 0)),
+            (reporef("unused"), repo(SyncStatus::Done, 0, 100)),
+        ];
+
+        assert_eq!(
+            candidates(repos)
+                .iter()
+                .map(|r| r.display_name())
+                .collect::<Vec<_>>(),
+            ["org/unused", "org/failed", "org/searched", "org/answered"]
+        );
+    }
+
+    #[test]
+    fn usage_only_moves_forward() {
+        let mut usage = RepoUsage::default();
+        assert_eq!(usage.record(UsageKind::Search, 100), 0);
+        assert_eq!(usage.record(UsageKind::Search, 50), 100);
+        assert_eq!(usage.last_search_unix_secs, 100);
+
+        assert_eq!(usage.record(UsageKind::Answer, 70), 0);
+        assert_eq!(usage.last_answer_unix_secs, 70);
+    }
+}
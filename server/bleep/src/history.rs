@@ -0,0 +1,328 @@
+use std::{
+    collections::{HashMap, HashSet, VecDeque},
+    sync::RwLock,
+    time::SystemTime,
+};
+
+use serde::{Deserialize, Serialize};
+use tracing::warn;
+
+use crate::{
+    query::parser::{Literal, NLQuery, Query},
+    state::{PersistedState, StateSource},
+};
+
+/// Upper bound on the number of searches kept in the store. The oldest
+/// entries are dropped first.
+const MAX_ENTRIES: usize = 5_000;
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum SearchKind {
+    Semantic,
+    Lexical,
+}
+
+/// A single search, as executed by one of the search endpoints.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct SearchEntry {
+    pub kind: SearchKind,
+
+    /// Query text, with whitespace collapsed and lowercased
+    pub query: String,
+
+    /// Filters applied to the query, e.g. `repo:bloop`
+    pub filters: Vec<String>,
+
+    pub result_count: usize,
+    pub top_score: Option<f32>,
+
+    /// Unix timestamp in seconds
+    pub timestamp: u64,
+
+    /// The user who ran this search, if known
+    pub user: Option<String>,
+
+    /// Repositories the results came from, e.g. `github.com/bloopai/bloop`
+    #[serde(default)]
+    pub repos: Vec<String>,
+}
+
+/// An aggregate over all searches with the same query text.
+#[derive(Serialize, Debug, PartialEq, Eq)]
+pub struct TopSearch {
+    pub query: String,
+    pub count: usize,
+    pub last_searched: u64,
+}
+
+/// The history of searches executed on this instance.
+#[derive(Clone)]
+pub struct SearchHistory {
+    entries: PersistedState<RwLock<VecDeque<SearchEntry>>>,
+}
+
+impl SearchEntry {
+    pub fn semantic(
+        raw_query: &str,
+        parsed: &NLQuery<'_>,
+        result_count: usize,
+        top_score: Option<f32>,
+        user: Option<String>,
+    ) -> Self {
+        let mut filters = parsed
+            .repos
+            .iter()
+            .map(|r| literal_filter("repo", r))
+            .chain(parsed.paths.iter().map(|p| literal_filter("path", p)))
+            .chain(parsed.langs.iter().map(|l| format!("lang:{l}")))
+            .chain(parsed.branch.iter().map(|b| literal_filter("branch", b)))
+            .collect::<Vec<_>>();
+        filters.sort();
+
+        Self::new(
+            SearchKind::Semantic,
+            raw_query,
+            filters,
+            result_count,
+            top_score,
+            user,
+        )
+    }
+
+    pub fn lexical(
+        raw_query: &str,
+        parsed: &[Query<'_>],
+        result_count: usize,
+        user: Option<String>,
+    ) -> Self {
+        let mut filters = parsed
+            .iter()
+            .flat_map(|q| {
+                [
+                    q.org.as_ref().map(|o| literal_filter("org", o)),
+                    q.repo.as_ref().map(|r| literal_filter("repo", r)),
+                    q.path.as_ref().map(|p| literal_filter("path", p)),
+                    q.lang.as_ref().map(|l| format!("lang:{l}")),
+                    q.branch.as_ref().map(|b| literal_filter("branch", b)),
+                ]
+            })
+            .flatten()
+            .collect::<HashSet<_>>()
+            .into_iter()
+            .collect::<Vec<_>>();
+        filters.sort();
+
+        Self::new(
+            SearchKind::Lexical,
+            raw_query,
+            filters,
+            result_count,
+            None,
+            user,
+        )
+    }
+
+    fn new(
+        kind: SearchKind,
+        raw_query: &str,
+        filters: Vec<String>,
+        result_count: usize,
+        top_score: Option<f32>,
+        user: Option<String>,
+    ) -> Self {
+        Self {
+            kind,
+            query: normalize(raw_query),
+            filters,
+            result_count,
+            top_score,
+            timestamp: unix_time_sec(),
+            user,
+            repos: vec![],
+        }
+    }
+
+    /// Record the repositories the results came from.
+    pub fn with_repos(mut self, repos: impl IntoIterator<Item = String>) -> Self {
+        self.repos = repos.into_iter().collect();
+        self.repos.sort();
+        self.repos.dedup();
+        self
+    }
+}
+
+impl SearchHistory {
+    pub fn load(source: &StateSource) -> anyhow::Result<Self> {
+        Ok(Self {
+            entries: source.load_or_default("search_history")?,
+        })
+    }
+
+    /// Append a search to the history, and persist it in the background.
+    pub fn record(&self, entry: SearchEntry) {
+        let entries = self.entries.clone();
+
+        tokio::task::spawn_blocking(move || {
+            {
+                let mut log = entries.write().unwrap();
+                log.push_back(entry);
+                while log.len() > MAX_ENTRIES {
+                    log.pop_front();
+                }
+            }
+
+            if let Err(err) = entries.store() {
+                warn!(?err, "failed to persist search history");
+            }
+        });
+    }
+
+    /// Most recent distinct queries by `user`, most recent first.
+    pub fn recent(&self, user: Option<&str>, limit: usize) -> Vec<SearchEntry> {
+        let mut seen = HashSet::new();
+
+        self.entries
+            .read()
+            .unwrap()
+            .iter()
+            .rev()
+            .filter(|e| e.user.as_deref() == user)
+            .filter(|e| seen.insert(e.query.clone()))
+            .take(limit)
+            .cloned()
+            .collect()
+    }
+
+    /// Most frequent queries since the unix timestamp `since`.
+    pub fn top(&self, since: u64, limit: usize) -> Vec<TopSearch> {
+        let mut counts = HashMap::<&str, TopSearch>::new();
+        let log = self.entries.read().unwrap();
+
+        for entry in log.iter().filter(|e| e.timestamp >= since) {
+            let top = counts.entry(entry.query.as_str()).or_insert_with(|| TopSearch {
+                query: entry.query.clone(),
+                count: 0,
+                last_searched: 0,
+            });
+
+            top.count += 1;
+            top.last_searched = top.last_searched.max(entry.timestamp);
+        }
+
+        let mut top = counts.into_values().collect::<Vec<_>>();
+        top.sort_by(|a, b| {
+            b.count
+                .cmp(&a.count)
+                .then(b.last_searched.cmp(&a.last_searched))
+        });
+        top.truncate(limit);
+        top
+    }
+}
+
+fn literal_filter(name: &str, literal: &Literal<'_>) -> String {
+    match literal {
+        Literal::Plain(text) => format!("{name}:{text}"),
+        Literal::Regex(regex) => format!("{name}:/{regex}/"),
+    }
+}
+
+fn normalize(query: &str) -> String {
+    query
+        .split_whitespace()
+        .collect::<Vec<_>>()
+        .join(" ")
+        .to_lowercase()
+}
+
+fn unix_time_sec() -> u64 {
+    SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap()
+        .as_secs()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::query::parser;
+    use tempdir::TempDir;
+
+    fn history(dir: &TempDir) -> SearchHistory {
+        let mut source = StateSource::default();
+        source.set_default_dir(dir.path());
+        SearchHistory::load(&source).unwrap()
+    }
+
+    fn entry(query: &str, user: Option<&str>, timestamp: u64) -> SearchEntry {
+        let parsed = parser::parse_nl(query).unwrap();
+        SearchEntry {
+            timestamp,
+            ..SearchEntry::semantic(query, &parsed, 1, Some(0.5), user.map(ToOwned::to_owned))
+        }
+    }
+
+    #[test]
+    fn normalizes_and_extracts_filters() {
+        let parsed = parser::parse_nl("What  IS this?  repo:bloop lang:rust").unwrap();
+        let entry = SearchEntry::semantic("What  IS this?", &parsed, 3, None, None);
+
+        assert_eq!(entry.query, "what is this?");
+        assert_eq!(entry.filters, vec!["lang:rust", "repo:bloop"]);
+    }
+
+    #[test]
+    fn recent_is_distinct_and_per_user() {
+        let dir = TempDir::new("search-history").unwrap();
+        let history = history(&dir);
+
+        {
+            let mut log = history.entries.write().unwrap();
+            log.push_back(entry("foo", Some("alice"), 1));
+            log.push_back(entry("bar", Some("alice"), 2));
+            log.push_back(entry("foo", Some("alice"), 3));
+            log.push_back(entry("baz", Some("bob"), 4));
+        }
+
+        let recent = history
+            .recent(Some("alice"), 10)
+            .into_iter()
+            .map(|e| e.query)
+            .collect::<Vec<_>>();
+
+        assert_eq!(recent, vec!["foo", "bar"]);
+        assert_eq!(history.recent(Some("alice"), 1).len(), 1);
+    }
+
+    #[test]
+    fn top_counts_within_window() {
+        let dir = TempDir::new("search-history").unwrap();
+        let history = history(&dir);
+
+        {
+            let mut log = history.entries.write().unwrap();
+            log.push_back(entry("old", None, 1));
+            log.push_back(entry("old", None, 2));
+            log.push_back(entry("foo", None, 10));
+            log.push_back(entry("bar", Some("alice"), 11));
+            log.push_back(entry("foo", Some("bob"), 12));
+        }
+
+        assert_eq!(
+            history.top(10, 10),
+            vec![
+                TopSearch {
+                    query: "foo".into(),
+                    count: 2,
+                    last_searched: 12
+                },
+                TopSearch {
+                    query: "bar".into(),
+                    count: 1,
+                    last_searched: 11
+                },
+            ]
+        );
+    }
+}
@@ -1,10 +1,17 @@
-use std::{fs, ops::Deref, path::Path, sync::Arc};
+use std::{
+    fs,
+    ops::Deref,
+    path::{Path, PathBuf},
+    sync::Arc,
+};
 
 use anyhow::{Context, Result};
 use async_trait::async_trait;
 use smallvec::SmallVec;
 use tantivy::{
     collector::{Collector, MultiFruit},
+    directory::{Directory, DirectoryLock, Lock, MmapDirectory},
+    query::BooleanQuery,
     schema::Schema,
     tokenizer::NgramTokenizer,
     DocAddress, Document, IndexReader, IndexWriter, Score,
@@ -15,7 +22,7 @@ pub mod file;
 pub mod reader;
 pub mod repo;
 
-pub use file::File;
+pub use file::{DryRunReport, File};
 pub use repo::Repo;
 use tracing::debug;
 
@@ -28,11 +35,30 @@ use crate::{
 };
 
 pub type Progress = (RepoRef, usize, u8);
+
+/// Take the lock `name`, shared by every process using `index_dir`, waiting for its current
+/// holder to release it.
+///
+/// The lock is released when the returned guard is dropped, or when the process exits.
+pub(crate) async fn lock_across_processes(index_dir: &Path, name: &str) -> Result<DirectoryLock> {
+    let directory = index_dir.join("locks");
+    let lock = Lock {
+        filepath: PathBuf::from(name).with_extension("lock"),
+        is_blocking: true,
+    };
+
+    tokio::task::spawn_blocking(move || {
+        fs::create_dir_all(&directory).context("failed to create lock dir")?;
+        Ok(MmapDirectory::open(&directory)?.acquire_lock(&lock)?)
+    })
+    .await?
+}
 pub type GlobalWriteHandleRef<'a> = [IndexWriteHandle<'a>];
 
 pub struct GlobalWriteHandle<'a> {
     handles: Vec<IndexWriteHandle<'a>>,
     _write_lock: tokio::sync::MutexGuard<'a, ()>,
+    _process_lock: DirectoryLock,
 }
 
 impl<'a> Deref for GlobalWriteHandle<'a> {
@@ -58,12 +84,23 @@ impl<'a> GlobalWriteHandle<'a> {
 
         Ok(())
     }
+
+    /// Compact every index, returning the total number of deleted documents reclaimed.
+    pub async fn compact(self) -> Result<u32> {
+        let mut reclaimed = 0;
+        for mut handle in self.handles {
+            reclaimed += handle.compact().await?;
+        }
+
+        Ok(reclaimed)
+    }
 }
 
 pub struct Indexes {
     pub repo: Indexer<Repo>,
     pub file: Indexer<File>,
     write_mutex: tokio::sync::Mutex<()>,
+    index_dir: PathBuf,
 
     progress: tokio::sync::broadcast::Sender<Progress>,
 }
@@ -103,6 +140,7 @@ impl Indexes {
                 config.max_threads,
             )?,
             write_mutex: Default::default(),
+            index_dir: config.index_dir.clone(),
             progress,
         })
     }
@@ -111,6 +149,9 @@ impl Indexes {
         let id: u64 = rand::random();
         debug!(id, "waiting for other writers to finish");
         let _write_lock = self.write_mutex.lock().await;
+        // tantivy refuses a second writer outright, so wait for writers in other processes
+        // sharing the index, such as `bleep index`
+        let _process_lock = lock_across_processes(&self.index_dir, "writers").await?;
         debug!(id, "lock acquired");
 
         Ok(GlobalWriteHandle {
@@ -119,12 +160,28 @@ impl Indexes {
                 self.file.write_handle(1, self.progress.clone())?,
             ],
             _write_lock,
+            _process_lock,
         })
     }
 
     pub fn subscribe(&self) -> tokio::sync::broadcast::Receiver<Progress> {
         self.progress.subscribe()
     }
+
+    /// Report what indexing `repo` would do, without writing to any index.
+    ///
+    /// Progress is broadcast as for the file index of a regular run.
+    pub fn dry_run(
+        &self,
+        reporef: &RepoRef,
+        repo: &Repository,
+        metadata: &RepoMetadata,
+    ) -> Result<DryRunReport> {
+        // same id as the file index writer, see `writers`
+        self.file.source.dry_run(reporef, repo, metadata, &|p: u8| {
+            _ = self.progress.send((reporef.clone(), 1, p));
+        })
+    }
 }
 
 pub trait Indexable: Send + Sync {
@@ -140,6 +197,9 @@ pub trait Indexable: Send + Sync {
 
     fn delete_by_repo(&self, writer: &IndexWriter, repo: &Repository);
 
+    /// Return a query matching every document of the repository at `disk_path`
+    fn repo_query(&self, disk_path: &Path) -> Box<dyn tantivy::query::Query>;
+
     /// Return the tantivy `Schema` of the current index
     fn schema(&self) -> Schema;
 }
@@ -207,6 +267,32 @@ impl<'a> IndexWriteHandle<'a> {
         self.writer.rollback()?;
         Ok(())
     }
+
+    /// Merge all segments that contain deleted documents, and remove files which are no longer
+    /// used by the index.
+    ///
+    /// Returns the number of deleted documents reclaimed by the merge.
+    pub async fn compact(&mut self) -> Result<u32> {
+        let segments = self
+            .index
+            .searchable_segment_metas()?
+            .into_iter()
+            .filter(|meta| meta.has_deletes())
+            .collect::<Vec<_>>();
+
+        if segments.is_empty() {
+            return Ok(0);
+        }
+
+        let reclaimed = segments.iter().map(|meta| meta.num_deleted_docs()).sum();
+        let ids = segments.iter().map(|meta| meta.id()).collect::<Vec<_>>();
+
+        self.writer.merge(&ids).await?;
+        self.writer.garbage_collect_files().await?;
+        self.refresh_reader().await?;
+
+        Ok(reclaimed)
+    }
 }
 
 /// A wrapper around `tantivy::IndexReader`.
@@ -269,10 +355,14 @@ impl<T: Indexable> Indexer<T> {
         Ok(instance)
     }
 
+    /// Run `queries` through `doc_reader`.
+    ///
+    /// If `scope` is set, only documents of the repositories at those disk paths are matched.
     pub async fn query<'a, R, I, C>(
         &'a self,
         queries: I,
         doc_reader: &'a R,
+        scope: Option<&[PathBuf]>,
         collector: C,
     ) -> Result<SearchResults<'_, R::Document>>
     where
@@ -284,9 +374,21 @@ impl<T: Indexable> Indexer<T> {
         let queries = queries
             .filter(|q| doc_reader.query_matches(q))
             .collect::<SmallVec<[_; 2]>>();
-        let compiled_query =
+        let mut compiled_query =
             doc_reader.compile(&self.source, queries.iter().copied(), &self.index)?;
 
+        if let Some(disk_paths) = scope {
+            let repos = disk_paths
+                .iter()
+                .map(|p| self.source.repo_query(p))
+                .collect();
+
+            compiled_query = Box::new(BooleanQuery::intersection(vec![
+                compiled_query,
+                Box::new(BooleanQuery::union(repos)),
+            ]));
+        }
+
         let (top_k, metadata) = searcher
             .search(&compiled_query, &collector)
             .context("failed to execute search query")?;
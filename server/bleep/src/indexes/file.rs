@@ -1,7 +1,7 @@
 use std::{
-    collections::HashSet,
+    collections::{HashMap, HashSet},
     ops::Not,
-    path::{Path, PathBuf, MAIN_SEPARATOR},
+    path::{Path, PathBuf},
     sync::{
         atomic::{AtomicU64, Ordering},
         Arc,
@@ -37,12 +37,19 @@ use super::{
 };
 use crate::{
     intelligence::TreeSitterFile,
-    repo::{iterator::*, FileCache, RepoMetadata, RepoRef, RepoRemote, Repository},
+    repo::{
+        iterator::*, language_stats, normalize_relative_path, relative_path_str, CacheEntry,
+        FileCache, FileStats, RepoMetadata, RepoRef, RepoRemote, Repository, PATH_SEPARATOR,
+    },
     semantic::Semantic,
     symbol::SymbolLocations,
+    text_range::slice_chars,
     Configuration,
 };
 
+mod dry_run;
+pub use dry_run::DryRunReport;
+
 struct Workload<'a> {
     repo_disk_path: &'a Path,
     repo_ref: String,
@@ -200,15 +207,7 @@ impl Indexable for File {
         };
 
         let start = std::time::Instant::now();
-        if reporef.is_remote() && matches!(repo.remote, RepoRemote::Git { .. }) {
-            let walker = GitWalker::open_repository(&repo.disk_path, None)?;
-            let count = walker.len();
-            walker.for_each(file_worker(count));
-        } else {
-            let walker = FileWalker::index_directory(&repo.disk_path);
-            let count = walker.len();
-            walker.for_each(file_worker(count));
-        };
+        walk(reporef, repo, file_worker)?;
 
         info!(?repo.disk_path, "repo file indexing finished, took {:?}", start.elapsed());
 
@@ -225,7 +224,7 @@ impl Indexable for File {
 
                 // delete from qdrant
                 if let Ok(relative_path) = k.strip_prefix(&repo.disk_path) {
-                    qdrant_remove_list.push(relative_path.to_string_lossy().to_string());
+                    qdrant_remove_list.push(relative_path_str(relative_path));
                 }
             }
 
@@ -248,6 +247,8 @@ impl Indexable for File {
             }
         }
 
+        _ = repo_metadata.lang_stats.set(language_stats(&file_cache));
+
         progress(100);
         repo.save_file_cache(&self.config.index_dir, file_cache)?;
         Ok(())
@@ -260,6 +261,13 @@ impl Indexable for File {
         ));
     }
 
+    fn repo_query(&self, disk_path: &Path) -> Box<dyn tantivy::query::Query> {
+        Box::new(TermQuery::new(
+            Term::from_field_text(self.repo_disk_path, &disk_path.to_string_lossy()),
+            IndexRecordOption::Basic,
+        ))
+    }
+
     fn schema(&self) -> Schema {
         self.schema.clone()
     }
@@ -292,6 +300,7 @@ impl Indexer<File> {
         let searcher = reader.searcher();
 
         let file_index = searcher.index();
+        let relative_path = normalize_relative_path(relative_path);
 
         // query the `relative_path` field of the `File` index, using tantivy's query language
         //
@@ -410,31 +419,26 @@ impl File {
         let entry_pathbuf = repo_disk_path.join(&relative_path);
 
         let relative_path_str = if file.kind.is_dir() {
-            format!("{}{MAIN_SEPARATOR}", relative_path.to_string_lossy())
+            format!("{}{PATH_SEPARATOR}", relative_path_str(&relative_path))
         } else {
-            relative_path.to_string_lossy().to_string()
+            relative_path_str(&relative_path)
         };
         trace!("processing file");
 
-        let content_hash = {
-            let mut hash = blake3::Hasher::new();
-            hash.update(crate::state::SCHEMA_VERSION.as_bytes());
-            hash.update(file.buffer.as_bytes());
-            hash.finalize().to_hex().to_string()
-        };
+        let content_hash = content_hash(&file.buffer);
         trace!("adding cache entry");
 
         match cache.entry(entry_pathbuf.clone()) {
-            Entry::Occupied(mut val) if val.get().value == content_hash => {
+            Entry::Occupied(mut val) if val.get().value.content_hash == content_hash => {
                 // skip processing if contents are up-to-date in the cache
                 val.get_mut().fresh = true;
                 return Ok(());
             }
             Entry::Occupied(mut val) => {
-                _ = val.insert(content_hash.into());
+                _ = val.insert(CacheEntry::new(content_hash).into());
             }
             Entry::Vacant(val) => {
-                _ = val.insert_entry(content_hash.into());
+                _ = val.insert_entry(CacheEntry::new(content_hash).into());
             }
         }
         trace!("added cache entry");
@@ -474,7 +478,7 @@ impl File {
         let symbols = symbol_locations
             .list()
             .iter()
-            .map(|sym| file.buffer[sym.range.start.byte..sym.range.end.byte].to_owned())
+            .map(|sym| slice_chars(&file.buffer, sym.range.into()).0.to_owned())
             .collect::<HashSet<_>>()
             .into_iter()
             .collect::<Vec<_>>()
@@ -504,8 +508,8 @@ impl File {
 
         // produce vectors for this document if it is a file
         if file.kind.is_file() {
-            if let Some(semantic) = &self.semantic {
-                tokio::task::block_in_place(|| {
+            let chunks = match &self.semantic {
+                Some(semantic) => tokio::task::block_in_place(|| {
                     Handle::current().block_on(semantic.insert_points_for_buffer(
                         repo_name,
                         &repo_ref,
@@ -513,9 +517,20 @@ impl File {
                         &file.buffer,
                         lang_str,
                         &file.branches,
+                        &symbol_locations,
                     ))
-                });
-            }
+                }),
+                None => 0,
+            };
+
+            let stats = FileStats {
+                lang: lang_str.to_owned(),
+                bytes: file.buffer.len(),
+                chunks,
+            };
+            _ = cache.update(&entry_pathbuf, move |_, entry| {
+                entry.value.stats = Some(stats)
+            });
         }
 
         trace!("writing document");
@@ -573,3 +588,146 @@ impl File {
         Ok(())
     }
 }
+
+/// Walk the files of `repo`, calling the worker built by `file_worker` with the file count on
+/// each of them.
+///
+/// Returns the number of files walked, and the number of files skipped during discovery by
+/// reason.
+fn walk<W>(
+    reporef: &RepoRef,
+    repo: &Repository,
+    file_worker: impl FnOnce(usize) -> W,
+) -> Result<(usize, HashMap<SkipReason, usize>)>
+where
+    W: Fn(RepoFile) + Sync + Send,
+{
+    // bare repositories have no working tree, so their files are read from the object database,
+    // which yields the same paths and contents that a checkout of `HEAD` would
+    if (reporef.is_remote() && matches!(repo.remote, RepoRemote::Git { .. })) || repo.is_bare() {
+        let walker = GitWalker::open_repository(&repo.disk_path, None)?;
+        let (count, skipped) = (walker.len(), walker.skipped().clone());
+        walker.for_each(file_worker(count));
+        Ok((count, skipped))
+    } else {
+        let walker = FileWalker::index_directory(&repo.disk_path);
+        let (count, skipped) = (walker.len(), walker.skipped().clone());
+        walker.for_each(file_worker(count));
+        Ok((count, skipped))
+    }
+}
+
+/// Hash of the contents of a file, as stored in the file cache.
+fn content_hash(buffer: &str) -> String {
+    let mut hash = blake3::Hasher::new();
+    hash.update(crate::state::SCHEMA_VERSION.as_bytes());
+    hash.update(buffer.as_bytes());
+    hash.finalize().to_hex().to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{collections::BTreeSet, sync::Mutex};
+
+    use git2::{build::RepoBuilder, IndexAddOption, Signature};
+
+    use crate::semantic::chunk;
+
+    const FIXTURE: &[(&str, &str)] = &[
+        (
+            "README.md",
+            "# Fixture\n\nIndexed from a checkout and from a mirror.\n",
+        ),
+        (
+            "src/lib.rs",
+            "pub mod parse;\n\npub fn run() {\n    parse::query(\"a b\");\n}\n",
+        ),
+        // not newline-terminated
+        (
+            "src/parse.rs",
+            "pub fn query(q: &str) -> Vec<&str> {\n    q.split(' ').collect()\n}",
+        ),
+        ("docs/guide/usage.md", "Run it.\n"),
+    ];
+
+    /// A chunk, as `(relative_path, text, start_line, end_line, start_byte, end_byte)`
+    type ChunkPayload = (String, String, usize, usize, usize, usize);
+
+    /// The chunks of the files `walk` finds in `repo`.
+    fn chunks(repo: &Repository) -> BTreeSet<ChunkPayload> {
+        let reporef = RepoRef::from(&repo.disk_path);
+        let chunks = Mutex::new(BTreeSet::new());
+
+        walk(&reporef, repo, |_| {
+            let chunks = &chunks;
+            move |mut file: RepoFile| {
+                if !file.kind.is_file() {
+                    return;
+                }
+
+                let relative_path =
+                    relative_path_str(Path::new(&file.path).strip_prefix(&repo.disk_path).unwrap());
+                if !file.buffer.ends_with('\n') {
+                    file.buffer += "\n";
+                }
+
+                let mut chunks = chunks.lock().unwrap();
+                for chunk in chunk::by_lines(&file.buffer, 2) {
+                    chunks.insert((
+                        relative_path.clone(),
+                        chunk.data.to_owned(),
+                        chunk.range.start.line,
+                        chunk.range.end.line,
+                        chunk.range.start.byte,
+                        chunk.range.end.byte,
+                    ));
+                }
+            }
+        })
+        .unwrap();
+
+        chunks.into_inner().unwrap()
+    }
+
+    #[test]
+    fn bare_clones_are_indexed_like_their_working_tree() {
+        let dir = tempdir::TempDir::new("bare").unwrap();
+        let root = crate::canonicalize(dir.path()).unwrap();
+
+        let workdir = root.join("work");
+        let git = git2::Repository::init(&workdir).unwrap();
+        for (path, contents) in FIXTURE {
+            let path = workdir.join(path);
+            std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+            std::fs::write(path, contents).unwrap();
+        }
+
+        let mut index = git.index().unwrap();
+        index.add_all(["*"], IndexAddOption::DEFAULT, None).unwrap();
+        let tree = git.find_tree(index.write_tree().unwrap()).unwrap();
+        let signature = Signature::now("alice", "dev@bloop.ai").unwrap();
+        git.commit(Some("HEAD"), &signature, &signature, "fixture", &tree, &[])
+            .unwrap();
+
+        let mirror = root.join("mirror.git");
+        RepoBuilder::new()
+            .bare(true)
+            .clone(workdir.to_str().unwrap(), &mirror)
+            .unwrap();
+
+        let working_tree = Repository::local_from(&RepoRef::from(&workdir));
+        let mirror = Repository::local_from(&RepoRef::from(&mirror));
+        assert!(!working_tree.is_bare());
+        assert!(mirror.is_bare());
+
+        let expected = chunks(&working_tree);
+        let files = expected
+            .iter()
+            .map(|(path, ..)| path.as_str())
+            .collect::<BTreeSet<_>>();
+        assert_eq!(files.len(), FIXTURE.len());
+
+        assert_eq!(chunks(&mirror), expected);
+    }
+}
@@ -0,0 +1,400 @@
+use std::{
+    cmp::Reverse,
+    collections::{BTreeMap, BinaryHeap, HashMap},
+    path::PathBuf,
+    sync::Mutex,
+};
+
+use clap::ValueEnum;
+use serde::Serialize;
+use utoipa::ToSchema;
+
+use super::*;
+use crate::{
+    repo::{LanguageCount, OTHER_LANG},
+    semantic::chunk::OverlapStrategy,
+};
+
+/// Number of files listed in [`DryRunReport::largest_files`]
+const LARGEST_FILES: usize = 20;
+
+/// What indexing a repository would do, as found by a dry run.
+#[derive(Serialize, ToSchema, Debug, Default, PartialEq)]
+pub struct DryRunReport {
+    /// Files found during discovery, including the ones that would be skipped
+    pub discovered_files: usize,
+    /// Files that would be indexed
+    pub indexed_files: usize,
+    pub indexed_bytes: usize,
+    /// Directories and other entries that are indexed without contents
+    pub directories: usize,
+    /// Files that would not be indexed, by reason
+    pub skipped: BTreeMap<SkipReason, usize>,
+    /// Indexed files by lowercase language, with chunks counted for the configured strategy
+    pub languages: BTreeMap<String, LanguageCount>,
+    /// Chunks each overlap strategy would produce, or empty without a semantic index
+    pub chunks_by_strategy: BTreeMap<String, usize>,
+    /// The configured overlap strategy, if there is a semantic index
+    pub overlap_strategy: Option<String>,
+    /// Embeddings the configured overlap strategy would compute
+    pub estimated_embeddings: Option<usize>,
+    /// The largest files that would be indexed, largest first
+    pub largest_files: Vec<FileSummary>,
+}
+
+#[derive(Serialize, ToSchema, Debug, PartialEq, Eq)]
+pub struct FileSummary {
+    pub relative_path: String,
+    pub bytes: usize,
+}
+
+/// What indexing a single file would do.
+#[derive(Debug)]
+enum Outcome {
+    Directory,
+    Skipped(SkipReason),
+    Indexed {
+        relative_path: String,
+        stats: FileStats,
+        /// Chunks for each strategy of the run, in order
+        chunks: Vec<usize>,
+    },
+}
+
+/// Accumulates the outcomes of a dry run into its report.
+struct Tally {
+    strategies: Vec<OverlapStrategy>,
+    visited: usize,
+    largest: BinaryHeap<Reverse<(usize, String)>>,
+    report: DryRunReport,
+}
+
+impl Tally {
+    /// `strategies` start with the configured overlap strategy, if chunks are counted at all.
+    fn new(strategies: Vec<OverlapStrategy>) -> Self {
+        Self {
+            report: DryRunReport {
+                overlap_strategy: strategies.first().map(ToString::to_string),
+                ..Default::default()
+            },
+            strategies,
+            visited: 0,
+            largest: BinaryHeap::new(),
+        }
+    }
+
+    fn add(&mut self, outcome: Outcome) {
+        let report = &mut self.report;
+        self.visited += 1;
+
+        match outcome {
+            Outcome::Directory => report.directories += 1,
+            Outcome::Skipped(reason) => *report.skipped.entry(reason).or_default() += 1,
+            Outcome::Indexed {
+                relative_path,
+                stats,
+                chunks,
+            } => {
+                report.indexed_files += 1;
+                report.indexed_bytes += stats.bytes;
+
+                let lang = if stats.lang.is_empty() {
+                    OTHER_LANG.to_owned()
+                } else {
+                    stats.lang.to_ascii_lowercase()
+                };
+                report.languages.entry(lang).or_default().add(&stats);
+
+                for (strategy, count) in self.strategies.iter().zip(chunks) {
+                    *report
+                        .chunks_by_strategy
+                        .entry(strategy.to_string())
+                        .or_default() += count;
+                }
+
+                self.largest.push(Reverse((stats.bytes, relative_path)));
+                if self.largest.len() > LARGEST_FILES {
+                    self.largest.pop();
+                }
+            }
+        }
+    }
+
+    /// Complete the report of a run that walked `walked` files, with `discovery_skips` left out
+    /// before the walk.
+    fn finish(self, walked: usize, discovery_skips: &HashMap<SkipReason, usize>) -> DryRunReport {
+        let Self {
+            strategies,
+            visited,
+            largest,
+            mut report,
+        } = self;
+
+        // the walkers drop files they can't read, or blobs that turn out too large
+        let unreadable = walked.saturating_sub(visited);
+        if unreadable > 0 {
+            *report.skipped.entry(SkipReason::Unreadable).or_default() += unreadable;
+        }
+
+        for (reason, count) in discovery_skips {
+            *report.skipped.entry(*reason).or_default() += count;
+        }
+
+        report.discovered_files = walked + discovery_skips.values().sum::<usize>();
+        report.estimated_embeddings = strategies.first().map(|configured| {
+            report
+                .chunks_by_strategy
+                .get(&configured.to_string())
+                .copied()
+                .unwrap_or_default()
+        });
+        report.largest_files = largest
+            .into_sorted_vec()
+            .into_iter()
+            .map(|Reverse((bytes, relative_path))| FileSummary {
+                relative_path,
+                bytes,
+            })
+            .collect();
+
+        report
+    }
+}
+
+/// The configured overlap strategy, followed by every other supported strategy.
+fn strategies(configured: OverlapStrategy) -> Vec<OverlapStrategy> {
+    std::iter::once(configured)
+        .chain(
+            OverlapStrategy::value_variants()
+                .iter()
+                .copied()
+                .filter(|s| *s != configured),
+        )
+        .collect()
+}
+
+impl File {
+    /// Report what indexing `repo` would do, without embedding anything or writing to the
+    /// indexes, the file cache or the repository metadata.
+    pub fn dry_run(
+        &self,
+        reporef: &RepoRef,
+        repo: &Repository,
+        repo_metadata: &RepoMetadata,
+        progress: &(dyn Fn(u8) + Sync),
+    ) -> Result<DryRunReport> {
+        let file_cache = repo.open_file_cache(&self.config.index_dir)?;
+        let repo_name = reporef.indexed_name();
+        let processed = &AtomicU64::new(0);
+
+        let strategies = match &self.semantic {
+            Some(semantic) => strategies(semantic.overlap_strategy()),
+            None => vec![],
+        };
+        let tally = Mutex::new(Tally::new(strategies.clone()));
+
+        let file_worker = |count: usize| {
+            let (file_cache, strategies, tally) = (&file_cache, &strategies, &tally);
+            let repo_name = &repo_name;
+            move |file: RepoFile| {
+                let completed = processed.fetch_add(1, Ordering::Relaxed);
+                progress(((completed as f32 / count as f32) * 100f32) as u8);
+
+                let outcome = self.evaluate(
+                    file,
+                    &repo.disk_path,
+                    repo_name,
+                    repo_metadata,
+                    file_cache,
+                    strategies,
+                );
+                tally.lock().unwrap().add(outcome);
+            }
+        };
+
+        let start = std::time::Instant::now();
+        let (walked, discovery_skips) = walk(reporef, repo, file_worker)?;
+        info!(?repo.disk_path, "repo dry run finished, took {:?}", start.elapsed());
+
+        progress(100);
+        Ok(tally.into_inner().unwrap().finish(walked, &discovery_skips))
+    }
+
+    /// What [`File::worker`] would do with `file`.
+    fn evaluate(
+        &self,
+        mut file: RepoFile,
+        repo_disk_path: &Path,
+        repo_name: &str,
+        repo_metadata: &RepoMetadata,
+        cache: &FileCache,
+        strategies: &[OverlapStrategy],
+    ) -> Outcome {
+        let relative_path = {
+            let entry_srcpath = PathBuf::from(&file.path);
+            entry_srcpath
+                .strip_prefix(repo_disk_path)
+                .map(ToOwned::to_owned)
+                .unwrap_or(entry_srcpath)
+        };
+        let entry_pathbuf = repo_disk_path.join(&relative_path);
+
+        if !file.kind.is_file() {
+            return Outcome::Directory;
+        }
+
+        let content_hash = content_hash(&file.buffer);
+        let unchanged = cache
+            .read(&entry_pathbuf, |_, entry| {
+                entry.value.content_hash == content_hash
+            })
+            .unwrap_or_default();
+
+        if unchanged {
+            return Outcome::Skipped(SkipReason::Unchanged);
+        }
+
+        if !file.buffer.ends_with('\n') {
+            file.buffer += "\n";
+        }
+
+        if file.buffer.matches('\n').count() > MAX_LINE_COUNT as usize {
+            return Outcome::Skipped(SkipReason::TooManyLines);
+        }
+
+        let relative_path = relative_path_str(&relative_path);
+        let lang = repo_metadata
+            .langs
+            .get(&entry_pathbuf, file.buffer.as_ref())
+            .unwrap_or_default();
+
+        let chunks = match &self.semantic {
+            Some(semantic) => strategies
+                .iter()
+                .map(|strategy| {
+                    semantic.count_chunks(repo_name, &relative_path, &file.buffer, *strategy)
+                })
+                .collect(),
+            None => vec![],
+        };
+
+        Outcome::Indexed {
+            stats: FileStats {
+                lang: lang.to_owned(),
+                bytes: file.buffer.len(),
+                chunks: chunks.first().copied().unwrap_or_default(),
+            },
+            relative_path,
+            chunks,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn indexed(relative_path: &str, lang: &str, bytes: usize, chunks: Vec<usize>) -> Outcome {
+        Outcome::Indexed {
+            relative_path: relative_path.to_owned(),
+            stats: FileStats {
+                lang: lang.to_owned(),
+                bytes,
+                chunks: chunks[0],
+            },
+            chunks,
+        }
+    }
+
+    #[test]
+    fn configured_strategy_comes_first() {
+        assert_eq!(
+            strategies(OverlapStrategy::Partial(0.5)),
+            vec![OverlapStrategy::Partial(0.5), OverlapStrategy::ByLines(1)]
+        );
+        assert_eq!(
+            strategies(OverlapStrategy::ByLines(3)),
+            vec![
+                OverlapStrategy::ByLines(3),
+                OverlapStrategy::ByLines(1),
+                OverlapStrategy::Partial(0.5)
+            ]
+        );
+    }
+
+    #[test]
+    fn outcomes_are_tallied() {
+        let mut tally = Tally::new(strategies(OverlapStrategy::Partial(0.5)));
+        tally.add(indexed("src/main.rs", "Rust", 300, vec![4, 3]));
+        tally.add(indexed("src/lib.rs", "Rust", 100, vec![2, 1]));
+        tally.add(indexed("README", "", 50, vec![1, 1]));
+        tally.add(Outcome::Directory);
+        tally.add(Outcome::Skipped(SkipReason::TooManyLines));
+
+        let report = tally.finish(7, &HashMap::from([(SkipReason::Vendored, 10)]));
+
+        assert_eq!(report.discovered_files, 17);
+        assert_eq!(report.indexed_files, 3);
+        assert_eq!(report.indexed_bytes, 450);
+        assert_eq!(report.directories, 1);
+        assert_eq!(
+            report.skipped,
+            BTreeMap::from([
+                (SkipReason::Vendored, 10),
+                (SkipReason::TooManyLines, 1),
+                (SkipReason::Unreadable, 2),
+            ])
+        );
+        assert_eq!(
+            report.languages,
+            BTreeMap::from([
+                (
+                    "other".to_owned(),
+                    LanguageCount {
+                        files: 1,
+                        bytes: 50,
+                        chunks: 1
+                    }
+                ),
+                (
+                    "rust".to_owned(),
+                    LanguageCount {
+                        files: 2,
+                        bytes: 400,
+                        chunks: 6
+                    }
+                ),
+            ])
+        );
+        assert_eq!(
+            report.chunks_by_strategy,
+            BTreeMap::from([("50%".to_owned(), 7), ("1".to_owned(), 5)])
+        );
+        assert_eq!(report.overlap_strategy.as_deref(), Some("50%"));
+        assert_eq!(report.estimated_embeddings, Some(7));
+        assert_eq!(
+            report
+                .largest_files
+                .iter()
+                .map(|f| f.relative_path.as_str())
+                .collect::<Vec<_>>(),
+            vec!["src/main.rs", "src/lib.rs", "README"]
+        );
+    }
+
+    #[test]
+    fn only_the_largest_files_are_kept() {
+        let mut tally = Tally::new(vec![]);
+        for i in 0..50 {
+            tally.add(indexed(&format!("{i}.rs"), "Rust", i, vec![0]));
+        }
+
+        let report = tally.finish(50, &HashMap::new());
+
+        assert_eq!(report.largest_files.len(), LARGEST_FILES);
+        assert_eq!(report.largest_files[0].bytes, 49);
+        assert_eq!(report.largest_files[LARGEST_FILES - 1].bytes, 30);
+        assert_eq!(report.estimated_embeddings, None);
+        assert!(report.chunks_by_strategy.is_empty());
+    }
+}
@@ -1,5 +1,3 @@
-use std::path::MAIN_SEPARATOR;
-
 use anyhow::Result;
 use async_trait::async_trait;
 use tantivy::{
@@ -13,6 +11,7 @@ use crate::{
         compiler::Compiler,
         parser::{self, Query, Target},
     },
+    repo::PATH_SEPARATOR,
     symbol::SymbolLocations,
 };
 
@@ -317,7 +316,7 @@ impl DocumentRead for OpenReader {
 /// - `"bar/" -> "bar/"`
 /// - `"foo.txt" -> ""`
 pub fn base_name(path: &str) -> &str {
-    path.rfind(MAIN_SEPARATOR)
+    path.rfind(PATH_SEPARATOR)
         .map(|i| &path[..i + 1])
         .unwrap_or("")
 }
@@ -349,9 +348,8 @@ mod test {
 
     #[test]
     fn test_base_name() {
-        let s = MAIN_SEPARATOR;
-        assert_eq!(base_name(&format!("bar{s}foo.txt")), format!("bar{s}"));
-        assert_eq!(base_name(&format!("bar{s}")), format!("bar{s}"));
+        assert_eq!(base_name("bar/foo.txt"), "bar/");
+        assert_eq!(base_name("bar/"), "bar/");
         assert_eq!(base_name("foo.txt"), "");
     }
 }
@@ -1,7 +1,10 @@
+use std::path::Path;
+
 use anyhow::Result;
 use async_trait::async_trait;
 use tantivy::{
     doc,
+    query::TermQuery,
     schema::{
         Field, IndexRecordOption, Schema, SchemaBuilder, TextFieldIndexing, TextOptions, FAST,
         STRING,
@@ -103,6 +106,13 @@ impl Indexable for Repo {
         ));
     }
 
+    fn repo_query(&self, disk_path: &Path) -> Box<dyn tantivy::query::Query> {
+        Box::new(TermQuery::new(
+            Term::from_field_text(self.disk_path, &disk_path.to_string_lossy()),
+            IndexRecordOption::Basic,
+        ))
+    }
+
     fn schema(&self) -> Schema {
         self.schema.clone()
     }
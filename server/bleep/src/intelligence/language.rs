@@ -4,8 +4,10 @@ mod cpp;
 mod go;
 mod java;
 mod javascript;
+mod kotlin;
 mod python;
 mod rust;
+mod swift;
 mod typescript;
 
 #[cfg(test)]
@@ -26,6 +28,8 @@ pub static ALL_LANGUAGES: &[&TSLanguageConfig] = &[
     &c_sharp::C_SHARP,
     &java::JAVA,
     &cpp::CPP,
+    &kotlin::KOTLIN,
+    &swift::SWIFT,
 ];
 
 /// A generic language wrapper type.
@@ -0,0 +1,137 @@
+use crate::intelligence::{MemoizedQuery, TSLanguageConfig};
+
+pub static KOTLIN: TSLanguageConfig = TSLanguageConfig {
+    language_ids: &["Kotlin"],
+    file_extensions: &["kt", "kts"],
+    grammar: tree_sitter_kotlin::language,
+    scope_query: MemoizedQuery::new(include_str!("./scopes.scm")),
+    namespaces: &[
+        // variables
+        &["variable", "parameter", "enumEntry"],
+        // functions
+        &["function"],
+        // types
+        &["class", "interface", "object", "typeAlias", "typeParameter"],
+        // namespacing
+        &["package"],
+    ],
+};
+
+#[cfg(test)]
+mod tests {
+    use crate::intelligence::language::test_utils::*;
+
+    // tests the following constructs:
+    //
+    // - nested classes
+    // - inner classes
+    // - companion objects
+    // - properties and parameters
+    #[test]
+    fn nested_classes() {
+        let src = r#"
+            class Outer {
+                val count = 0
+
+                class Nested {
+                    fun greet(name: String) = "hello $name"
+                }
+
+                inner class Inner {
+                    fun outerCount() = count
+                }
+
+                companion object Factory {
+                    fun create(): Outer = Outer()
+                }
+            }
+        "#;
+
+        assert_eq_defs(
+            src.as_bytes(),
+            "Kotlin",
+            vec![
+                ("Outer", "class"),
+                ("count", "variable"),
+                ("Nested", "class"),
+                ("greet", "function"),
+                ("name", "parameter"),
+                ("Inner", "class"),
+                ("outerCount", "function"),
+                ("Factory", "object"),
+                ("create", "function"),
+            ],
+        );
+    }
+
+    // tests the following constructs:
+    //
+    // - package headers
+    // - extension functions
+    // - generic extension functions
+    // - extension properties
+    #[test]
+    fn extension_functions() {
+        let src = r#"
+            package com.example.text
+
+            import kotlin.math.max
+
+            fun String.shout(): String = this.uppercase()
+
+            fun <T> List<T>.second(): T = this[1]
+
+            val String.initial: Char
+                get() = this[0]
+        "#;
+
+        assert_eq_defs(
+            src.as_bytes(),
+            "Kotlin",
+            vec![
+                ("text", "package"),
+                ("shout", "function"),
+                ("T", "typeParameter"),
+                ("second", "function"),
+                ("initial", "variable"),
+            ],
+        );
+    }
+
+    // tests the following constructs:
+    //
+    // - interfaces
+    // - enum classes
+    // - type aliases
+    // - lambda parameters
+    #[test]
+    fn interfaces_enums_lambdas() {
+        let src = r#"
+            interface Shape {
+                fun area(): Double
+            }
+
+            enum class Color { RED, GREEN }
+
+            typealias Palette = List<Color>
+
+            fun total(shapes: List<Shape>) = shapes.map { s -> s.area() }.sum()
+        "#;
+
+        assert_eq_defs(
+            src.as_bytes(),
+            "Kotlin",
+            vec![
+                ("Shape", "interface"),
+                ("area", "function"),
+                ("Color", "class"),
+                ("RED", "enumEntry"),
+                ("GREEN", "enumEntry"),
+                ("Palette", "typeAlias"),
+                ("total", "function"),
+                ("shapes", "parameter"),
+                ("s", "parameter"),
+            ],
+        );
+    }
+}
@@ -0,0 +1,233 @@
+;; scopes
+
+[
+ ;; class items
+ (class_declaration)
+ (object_declaration)
+ (companion_object)
+ (object_literal)
+ (secondary_constructor)
+ (anonymous_initializer)
+
+ ;; functions
+ (function_declaration)
+ (anonymous_function)
+ (lambda_literal)
+ (getter)
+ (setter)
+
+ ;; control flow
+ (for_statement)
+ (while_statement)
+ (do_while_statement)
+ (control_structure_body)
+ (when_entry)
+
+ ;; try-catch
+ (catch_block)
+ (finally_block)
+] @local.scope
+
+
+;; defs
+
+;; package com.example.foo
+;;
+;; defines `foo` as a package
+(package_header
+  (identifier
+    (simple_identifier) @local.definition.package .))
+
+;; imports are defs
+;;
+;; import com.example.Foo
+;;                    ^^^ is a def
+(import_header
+  (identifier
+    (simple_identifier) @local.definition .))
+
+;; import com.example.Foo as Bar
+;;                           ^^^ is a def
+(import_header
+  (import_alias
+    (type_identifier) @local.definition))
+
+;; class Main { .. }
+(class_declaration
+  "class"
+  (type_identifier) @hoist.definition.class)
+
+;; interface Iface { .. }
+(class_declaration
+  "interface"
+  (type_identifier) @hoist.definition.interface)
+
+;; object Singleton { .. }
+(object_declaration
+  (type_identifier) @hoist.definition.object)
+
+;; companion object Factory { .. }
+(companion_object
+  (type_identifier) @hoist.definition.object)
+
+;; typealias Handler = (Int) -> Unit
+(type_alias
+  (type_identifier) @local.definition.typeAlias)
+
+;; class Main<T, U> { .. }
+;; fun <T> id(t: T): T
+(type_parameter
+  (type_identifier) @local.definition.typeParameter)
+
+;; enum variants
+(enum_entry
+  (simple_identifier) @local.definition.enumEntry)
+
+;; fun main() { .. }
+;; fun String.shout() { .. }
+;;
+;; the receiver type of an extension function is a `user_type`,
+;; so only the function name is captured here
+(function_declaration
+  (simple_identifier) @hoist.definition.function)
+
+;; fun f(a: Int, b: Int)
+(function_value_parameters
+  (parameter
+    (simple_identifier) @local.definition.parameter))
+
+;; class Main(val a: Int, b: Int)
+(class_parameter
+  (simple_identifier) @local.definition.parameter)
+
+;; { a, (b, c) -> .. }
+(lambda_parameters
+  (variable_declaration
+    (simple_identifier) @local.definition.parameter))
+(lambda_parameters
+  (multi_variable_declaration
+    (variable_declaration
+      (simple_identifier) @local.definition.parameter)))
+
+;; val a = ..
+(property_declaration
+  (variable_declaration
+    (simple_identifier) @local.definition.variable))
+
+;; val (a, b) = ..
+(property_declaration
+  (multi_variable_declaration
+    (variable_declaration
+      (simple_identifier) @local.definition.variable)))
+
+;; for (item in iterator) { .. }
+(for_statement
+  (variable_declaration
+    (simple_identifier) @local.definition.variable))
+(for_statement
+  (multi_variable_declaration
+    (variable_declaration
+      (simple_identifier) @local.definition.variable)))
+
+;; catch (e: Exception) { .. }
+(catch_block
+  (simple_identifier) @local.definition.variable)
+
+
+;; refs
+
+;; a
+(statements
+  (simple_identifier) @local.reference)
+
+;; a.b
+;;
+;; only the leftmost ident is a ref
+(navigation_expression
+  .
+  (simple_identifier) @local.reference)
+
+;; a()
+(call_expression
+  .
+  (simple_identifier) @local.reference)
+
+;; _(x, y, z)
+(value_argument
+  (simple_identifier) @local.reference)
+
+;; a op b
+(additive_expression (simple_identifier) @local.reference)
+(multiplicative_expression (simple_identifier) @local.reference)
+(comparison_expression (simple_identifier) @local.reference)
+(equality_expression (simple_identifier) @local.reference)
+(conjunction_expression (simple_identifier) @local.reference)
+(disjunction_expression (simple_identifier) @local.reference)
+(elvis_expression (simple_identifier) @local.reference)
+(range_expression (simple_identifier) @local.reference)
+(infix_expression (simple_identifier) @local.reference)
+
+;; !a, a++
+(prefix_expression (simple_identifier) @local.reference)
+(postfix_expression (simple_identifier) @local.reference)
+
+;; (a)
+(parenthesized_expression (simple_identifier) @local.reference)
+
+;; a[b]
+(indexing_expression (simple_identifier) @local.reference)
+(indexing_suffix (simple_identifier) @local.reference)
+
+;; a as T, a is T
+(as_expression (simple_identifier) @local.reference)
+(check_expression (simple_identifier) @local.reference)
+
+;; a = b;
+;;
+;; both `a` and `b` are refs
+(assignment
+  (directly_assignable_expression
+    (simple_identifier) @local.reference))
+(assignment
+  (simple_identifier) @local.reference)
+
+;; rhs of a decl. is a ref
+;;
+;; val _ = b
+(property_declaration
+  (simple_identifier) @local.reference)
+
+;; return a
+(jump_expression
+  (simple_identifier) @local.reference)
+
+;; fun f() = a
+(function_body
+  (simple_identifier) @local.reference)
+
+;; if (a) .., while (a) ..
+(if_expression (simple_identifier) @local.reference)
+(while_statement (simple_identifier) @local.reference)
+(do_while_statement (simple_identifier) @local.reference)
+(control_structure_body (simple_identifier) @local.reference)
+
+;; for (_ in iterator) { .. }
+;;
+;; `iterator` is a ref
+(for_statement
+  (simple_identifier) @local.reference)
+
+;; when (a) { b -> .. }
+(when_subject (simple_identifier) @local.reference)
+(when_condition (simple_identifier) @local.reference)
+
+;; "$a"
+(interpolated_identifier) @local.reference
+
+
+;; type refs
+
+;; covers parameter types, return types, supertypes,
+;; type arguments and receivers of extension functions
+(user_type
+  (type_identifier) @local.reference)
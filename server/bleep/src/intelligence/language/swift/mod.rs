@@ -0,0 +1,153 @@
+use crate::intelligence::{MemoizedQuery, TSLanguageConfig};
+
+pub static SWIFT: TSLanguageConfig = TSLanguageConfig {
+    language_ids: &["Swift"],
+    file_extensions: &["swift"],
+    grammar: tree_sitter_swift::language,
+    scope_query: MemoizedQuery::new(include_str!("./scopes.scm")),
+    namespaces: &[
+        // variables
+        &["var", "parameter", "enumEntry"],
+        // functions
+        &["function"],
+        // types
+        &[
+            "class",
+            "struct",
+            "enum",
+            "actor",
+            "protocol",
+            "typealias",
+            "associatedtype",
+            "typeParameter",
+        ],
+    ],
+};
+
+#[cfg(test)]
+mod tests {
+    use crate::intelligence::language::test_utils::*;
+
+    // tests the following constructs:
+    //
+    // - nested classes, structs and enums
+    // - properties
+    // - argument labels
+    #[test]
+    fn nested_types() {
+        let src = r#"
+            class Outer {
+                let count = 0
+
+                struct Nested {
+                    func greet(to name: String) -> String {
+                        return "hello \(name)"
+                    }
+                }
+
+                enum Kind {
+                    case small, large
+                }
+            }
+        "#;
+
+        assert_eq_defs(
+            src.as_bytes(),
+            "Swift",
+            vec![
+                ("Outer", "class"),
+                ("count", "var"),
+                ("Nested", "struct"),
+                ("greet", "function"),
+                ("name", "parameter"),
+                ("Kind", "enum"),
+                ("small", "enumEntry"),
+                ("large", "enumEntry"),
+            ],
+        );
+    }
+
+    // tests the following constructs:
+    //
+    // - protocols and their requirements
+    // - associated types
+    // - protocol extensions
+    // - closure parameters
+    #[test]
+    fn protocol_extensions() {
+        let src = r#"
+            protocol Shape {
+                associatedtype Unit
+                var name: String { get }
+                func area() -> Double
+            }
+
+            extension Shape {
+                func describe() -> String {
+                    let size = area()
+                    return "\(name): \(size)"
+                }
+            }
+
+            extension Array where Element: Shape {
+                func totalArea() -> Double {
+                    return map { shape in shape.area() }.reduce(0, +)
+                }
+            }
+        "#;
+
+        assert_eq_defs(
+            src.as_bytes(),
+            "Swift",
+            vec![
+                ("Shape", "protocol"),
+                ("Unit", "associatedtype"),
+                ("name", "var"),
+                ("area", "function"),
+                ("describe", "function"),
+                ("size", "var"),
+                ("totalArea", "function"),
+                ("shape", "parameter"),
+            ],
+        );
+    }
+
+    // tests the following constructs:
+    //
+    // - generic structs
+    // - type aliases
+    // - for loops
+    #[test]
+    fn generics_and_loops() {
+        let src = r#"
+            typealias Scores = [Int]
+
+            struct Box<T> {
+                var items: [T]
+
+                func sum(of scores: Scores) -> Int {
+                    var total = 0
+                    for score in scores {
+                        total += score
+                    }
+                    return total
+                }
+            }
+        "#;
+
+        assert_eq_defs(
+            src.as_bytes(),
+            "Swift",
+            vec![
+                ("Scores", "typealias"),
+                ("Box", "struct"),
+                ("T", "typeParameter"),
+                ("items", "var"),
+                ("sum", "function"),
+                ("scores", "parameter"),
+                ("total", "var"),
+                ("score", "var"),
+            ],
+        );
+    }
+}
@@ -0,0 +1,212 @@
+;; scopes
+
+[
+ ;; type items
+ (class_declaration)
+ (protocol_declaration)
+
+ ;; functions
+ (function_declaration)
+ (protocol_function_declaration)
+ (init_declaration)
+ (deinit_declaration)
+ (subscript_declaration)
+ (computed_property)
+ (lambda_literal)
+
+ ;; control flow
+ (if_statement)
+ (guard_statement)
+ (for_statement)
+ (while_statement)
+ (repeat_while_statement)
+ (switch_entry)
+
+ ;; do-catch
+ (do_statement)
+ (catch_block)
+] @local.scope
+
+
+;; defs
+
+;; imports are defs
+;;
+;; import Foundation
+;;        ^^^^^^^^^^ is a def
+(import_declaration
+  (identifier
+    (simple_identifier) @local.definition .))
+
+;; class Main { .. }
+(class_declaration
+  declaration_kind: "class"
+  name: (type_identifier) @hoist.definition.class)
+
+;; struct Point { .. }
+(class_declaration
+  declaration_kind: "struct"
+  name: (type_identifier) @hoist.definition.struct)
+
+;; enum Color { .. }
+(class_declaration
+  declaration_kind: "enum"
+  name: (type_identifier) @hoist.definition.enum)
+
+;; actor Counter { .. }
+(class_declaration
+  declaration_kind: "actor"
+  name: (type_identifier) @hoist.definition.actor)
+
+;; extension Collection { .. }
+;;
+;; extensions do not define a new type, the extended
+;; type is a `user_type` and is picked up as a ref below
+
+;; protocol Shape { .. }
+(protocol_declaration
+  name: (type_identifier) @hoist.definition.protocol)
+
+;; typealias Handler = (Int) -> Void
+(typealias_declaration
+  name: (type_identifier) @local.definition.typealias)
+
+;; associatedtype Element
+(associatedtype_declaration
+  name: (type_identifier) @local.definition.associatedtype)
+
+;; struct Box<T> { .. }
+(type_parameter
+  (type_identifier) @local.definition.typeParameter)
+
+;; enum variants
+;;
+;; case red, green
+(enum_entry
+  name: (simple_identifier) @local.definition.enumEntry)
+
+;; func main() { .. }
+(function_declaration
+  name: (simple_identifier) @hoist.definition.function)
+
+;; protocol requirements
+;;
+;; func area() -> Double
+(protocol_function_declaration
+  name: (simple_identifier) @hoist.definition.function)
+
+;; func f(label a: Int, b: Int)
+;;
+;; the argument label is not a def
+(parameter
+  name: (simple_identifier) @local.definition.parameter)
+
+;; { a, b in .. }
+(lambda_parameter
+  name: (simple_identifier) @local.definition.parameter)
+
+;; let a = ..
+;; var b: Int
+(property_declaration
+  (pattern
+    (simple_identifier) @local.definition.var))
+
+;; protocol requirements
+;;
+;; var name: String { get }
+(protocol_property_declaration
+  (pattern
+    (simple_identifier) @local.definition.var))
+
+;; for item in iterator { .. }
+(for_statement
+  (pattern
+    (simple_identifier) @local.definition.var))
+
+
+;; refs
+
+;; a
+(statements
+  (simple_identifier) @local.reference)
+
+;; a.b
+;;
+;; only the leftmost ident is a ref
+(navigation_expression
+  .
+  (simple_identifier) @local.reference)
+
+;; a()
+(call_expression
+  .
+  (simple_identifier) @local.reference)
+
+;; _(x, label: y)
+(value_argument
+  (simple_identifier) @local.reference)
+
+;; a op b
+(additive_expression (simple_identifier) @local.reference)
+(multiplicative_expression (simple_identifier) @local.reference)
+(comparison_expression (simple_identifier) @local.reference)
+(equality_expression (simple_identifier) @local.reference)
+(conjunction_expression (simple_identifier) @local.reference)
+(disjunction_expression (simple_identifier) @local.reference)
+(nil_coalescing_expression (simple_identifier) @local.reference)
+(range_expression (simple_identifier) @local.reference)
+
+;; !a, a!
+(prefix_expression (simple_identifier) @local.reference)
+(postfix_expression (simple_identifier) @local.reference)
+
+;; (a, b), [a, b]
+(tuple_expression (simple_identifier) @local.reference)
+(array_literal (simple_identifier) @local.reference)
+
+;; a = b
+;;
+;; both `a` and `b` are refs
+(assignment
+  (directly_assignable_expression
+    (simple_identifier) @local.reference))
+(assignment
+  (simple_identifier) @local.reference)
+
+;; rhs of a decl. is a ref
+;;
+;; let _ = b
+(property_declaration
+  (simple_identifier) @local.reference)
+
+;; return a
+(control_transfer_statement
+  (simple_identifier) @local.reference)
+
+;; if a { .. }, guard a else { .. }, while a { .. }
+(if_statement (simple_identifier) @local.reference)
+(guard_statement (simple_identifier) @local.reference)
+(while_statement (simple_identifier) @local.reference)
+(repeat_while_statement (simple_identifier) @local.reference)
+
+;; for _ in iterator { .. }
+;;
+;; `iterator` is a ref
+(for_statement
+  (simple_identifier) @local.reference)
+
+;; switch a { .. }
+(switch_statement
+  (simple_identifier) @local.reference)
+
+;; "\(a)"
+(interpolated_expression
+  (simple_identifier) @local.reference)
+
+
+;; type refs
+
+;; covers annotations, return types, inheritance clauses,
+;; generic arguments and extended types
+(user_type
+  (type_identifier) @local.reference)
@@ -319,6 +319,18 @@ impl ScopeGraph {
             .collect()
     }
 
+    /// Ranges of every scope nested in the root scope, such as function bodies
+    pub fn nested_scopes(&self) -> Vec<TextRange> {
+        self.graph
+            .node_indices()
+            .filter(|&idx| idx != self.root_idx)
+            .filter_map(|idx| match &self.graph[idx] {
+                NodeKind::Scope(scope) => Some(scope.range),
+                _ => None,
+            })
+            .collect()
+    }
+
     // produce a stringified name of a def/ref's symbol
     pub fn symbol_name_of(&self, idx: NodeIndex<u32>) -> Option<&'static str> {
         let namespaces = ALL_LANGUAGES[self.lang_id].namespaces;
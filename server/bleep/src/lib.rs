@@ -26,7 +26,11 @@ use scc::hash_map::Entry;
 use std::fs::canonicalize;
 
 use crate::{
-    background::BackgroundExecutor, indexes::Indexes, semantic::Semantic, state::RepositoryPool,
+    background::BackgroundExecutor,
+    indexes::Indexes,
+    repo::{RepoRef, Repository},
+    semantic::Semantic,
+    state::RepositoryPool,
 };
 use anyhow::{bail, Result};
 use axum::extract::FromRef;
@@ -39,12 +43,19 @@ use tracing::{debug, error, info, warn, Level};
 use tracing_subscriber::EnvFilter;
 
 mod background;
+mod cache;
+mod cli;
 mod collector;
 mod config;
 mod env;
+mod eviction;
+mod history;
+mod maintenance;
+mod notifications;
 mod remotes;
 mod repo;
 mod webserver;
+mod workspaces;
 
 pub mod analytics;
 pub mod indexes;
@@ -56,7 +67,7 @@ pub mod state;
 pub mod symbol;
 pub mod text_range;
 
-pub use config::{default_parallelism, minimum_parallelism, Configuration};
+pub use config::{default_parallelism, minimum_parallelism, Command, Configuration, IndexArgs};
 pub use env::Environment;
 
 const LOG_ENV_VAR: &str = "BLOOP_LOG";
@@ -96,6 +107,21 @@ pub struct Application {
 
     /// Analytics backend -- may be unintialized
     analytics: Option<Arc<analytics::RudderHub>>,
+
+    /// Executed searches -- disabled with `disable_search_history`
+    search_history: Option<history::SearchHistory>,
+
+    /// Index garbage collection & compaction
+    maintenance: maintenance::Maintenance,
+
+    /// In-progress sync & index runs, by repository
+    index_runs: Arc<background::IndexRuns>,
+
+    /// Named sets of repositories to scope searches to
+    workspaces: workspaces::Workspaces,
+
+    /// Alerts on failing and stale repositories
+    notifications: notifications::Notifications,
 }
 
 impl Application {
@@ -113,13 +139,16 @@ impl Application {
         config.repo_buffer_size = config.repo_buffer_size.max(threads * 3_000_000);
         config.source.set_default_dir(&config.index_dir);
 
+        let store_key = config.store_key()?;
+        config.source.set_store_key(store_key.as_ref())?;
+
         let config = Arc::new(config);
         debug!(?config, "effective configuration");
 
-        // Initialise Semantic index if `qdrant_url` set in config
-        let semantic = match config.qdrant_url {
-            Some(ref url) => {
-                match Semantic::initialize(&config.model_dir, url, Arc::clone(&config)).await {
+        // Initialise Semantic index if the local store or `qdrant_url` is set in config
+        let semantic = match semantic::store::open(&config).await? {
+            Some(store) => {
+                match Semantic::initialize(&config.model_dir, store, Arc::clone(&config)).await {
                     Ok(semantic) => Some(semantic),
                     Err(e) => {
                         bail!("Qdrant initialization failed: {}", e);
@@ -147,22 +176,64 @@ impl Application {
             }
         };
 
+        let search_history = if config.disable_search_history {
+            info!("Search history disabled");
+            None
+        } else {
+            Some(history::SearchHistory::load(&config.source)?)
+        };
+
         let repo_pool = config.source.initialize_pool()?;
 
         Ok(Self {
             indexes: Indexes::new(repo_pool.clone(), config.clone(), semantic.clone())?.into(),
             background: BackgroundExecutor::start(config.clone()),
+            maintenance: maintenance::Maintenance::load(&config.source)?,
+            index_runs: Arc::default(),
+            workspaces: workspaces::Workspaces::load(&config.source)?,
+            notifications: notifications::Notifications::load(&config)?,
             prior_conversational_store: Arc::default(),
             cookie_key: config.source.initialize_cookie_key()?,
             credentials: config.source.initialize_credentials()?.into(),
             repo_pool,
             analytics,
+            search_history,
             semantic,
             config,
             env,
         })
     }
 
+    /// Filter or reorder search results with `processor`, see [`ResultPostProcessor`].
+    ///
+    /// [`ResultPostProcessor`]: semantic::post_process::ResultPostProcessor
+    pub fn with_post_processor(
+        mut self,
+        processor: impl semantic::post_process::ResultPostProcessor + 'static,
+    ) -> Self {
+        self.semantic = self
+            .semantic
+            .map(|semantic| semantic.with_post_processor(processor));
+        self
+    }
+
+    /// Encrypt an existing plaintext state store with the configured store key.
+    pub fn encrypt_store(mut config: Configuration) -> Result<()> {
+        config.source.set_default_dir(&config.index_dir);
+
+        let Some(key) = config.store_key()? else {
+            bail!("no store key configured");
+        };
+
+        let backup = config.source.encrypt_store(&key)?;
+        info!(
+            backup = %backup.display(),
+            "state store encrypted; delete the plaintext backup once bleep starts with the key"
+        );
+
+        Ok(())
+    }
+
     pub fn initialize_sentry(&self) {
         let Some(ref dsn) = self.config.sentry_dsn else {
             info!("Sentry DSN missing, skipping initialization");
@@ -208,6 +279,19 @@ impl Application {
         }
     }
 
+    pub(crate) fn record_search(&self, entry: history::SearchEntry) {
+        eviction::record_use(self, &entry.repos, repo::UsageKind::Search);
+
+        if let Some(history) = self.search_history.as_ref() {
+            history.record(entry);
+        }
+    }
+
+    /// Record that an answer drew on snippets of `repos`.
+    pub(crate) fn record_answer(&self, repos: &[String]) {
+        eviction::record_use(self, repos, repo::UsageKind::Answer);
+    }
+
     pub async fn run(self) -> Result<()> {
         Self::install_logging();
 
@@ -220,6 +304,9 @@ impl Application {
                 tokio::spawn(remotes::sync_repositories(self.clone()));
                 tokio::spawn(remotes::check_credentials(self.clone()));
                 tokio::spawn(remotes::check_repo_updates(self.clone()));
+                tokio::spawn(maintenance::periodic_maintenance(self.clone()));
+                tokio::spawn(eviction::periodic_eviction(self.clone()));
+                tokio::spawn(notifications::check_staleness(self.clone()));
             }
 
             joins.spawn(webserver::start(self));
@@ -258,6 +345,33 @@ impl Application {
         background::IndexWriter(self.clone())
     }
 
+    /// Preview indexing `reporef`, without syncing it or writing to any index.
+    ///
+    /// Local repositories don't need to be added first, as long as their path is allowed.
+    pub async fn dry_run(&self, reporef: &RepoRef) -> Result<indexes::DryRunReport> {
+        let repo = match self.repo_pool.read_async(reporef, |_, v| v.clone()).await {
+            Some(repo) => repo,
+            None => match reporef.local_path() {
+                Some(path) if self.allow_path(path) => Repository::local_from(reporef),
+                Some(_) => bail!("path not authorized {reporef}"),
+                None => bail!("unknown repository {reporef}"),
+            },
+        };
+
+        if !repo.disk_path.exists() {
+            bail!("repository {reporef} must be synced before a dry run");
+        }
+
+        Ok(repo.dry_run(reporef, &self.indexes).await?)
+    }
+
+    /// Sync and index repositories as a one-off, reporting progress on stderr.
+    ///
+    /// Fails if any of the repositories failed to sync or index.
+    pub async fn index(&self, args: &IndexArgs) -> Result<()> {
+        cli::index(self, args).await
+    }
+
     /// This gets the prior conversation. Be sure to drop the borrow before calling
     /// [`add_conversation_entry`], lest we deadlock.
     pub fn with_prior_conversation<T>(
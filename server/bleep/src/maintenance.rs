@@ -0,0 +1,259 @@
+use std::{
+    collections::HashMap,
+    path::Path,
+    sync::{Arc, RwLock},
+    time::Duration,
+};
+
+use anyhow::Result;
+use qdrant_client::qdrant::{point_id::PointIdOptions, PointId};
+use serde::Serialize;
+use tokio::time::sleep;
+use tracing::{debug, error, info};
+
+use crate::{
+    repo::{FileCache, RepoRef, SyncStatus},
+    semantic::Semantic,
+    state::{PersistedState, StateSource},
+    Application,
+};
+
+/// Number of points fetched from qdrant in a single page.
+const SCROLL_BATCH_SIZE: u32 = 256;
+
+/// Pause between pages, so maintenance doesn't starve concurrent searches.
+const BATCH_DELAY: Duration = Duration::from_millis(50);
+
+const SECS_PER_HOUR: u64 = 60 * 60;
+
+#[derive(Serialize, Debug, Default)]
+pub struct MaintenanceReport {
+    pub repos: Vec<RepoReport>,
+
+    /// Deleted documents purged from the tantivy indexes
+    pub reclaimed_documents: u32,
+}
+
+#[derive(Serialize, Debug)]
+pub struct RepoReport {
+    pub repo_ref: String,
+    pub scanned_points: usize,
+
+    /// Points whose file is no longer part of the indexed repository
+    pub deleted_points: usize,
+}
+
+/// Garbage collection of orphaned semantic index points, and compaction of the tantivy indexes.
+///
+/// Points are orphaned when the deletion issued during reindexing fails, or the process exits
+/// before it completes. Progress through each repository is persisted, so an interrupted run
+/// picks up where it left off.
+#[derive(Clone)]
+pub struct Maintenance {
+    running: Arc<tokio::sync::Mutex<()>>,
+
+    /// The next point to scan, by repository
+    cursors: PersistedState<RwLock<HashMap<String, String>>>,
+}
+
+impl Maintenance {
+    pub fn load(source: &StateSource) -> Result<Self> {
+        Ok(Self {
+            running: Arc::default(),
+            cursors: source.load_or_default("maintenance")?,
+        })
+    }
+
+    /// Run maintenance over all indexed repositories.
+    ///
+    /// Returns `None` if a run is already in progress.
+    pub async fn run(&self, app: &Application) -> Result<Option<MaintenanceReport>> {
+        let Ok(_running) = self.running.try_lock() else {
+            return Ok(None);
+        };
+
+        info!("starting index maintenance");
+        let mut report = MaintenanceReport::default();
+
+        if let Some(semantic) = app.semantic.as_ref() {
+            let mut repos = vec![];
+            app.repo_pool
+                .scan_async(|k, v| {
+                    if v.sync_status == SyncStatus::Done {
+                        repos.push(k.clone())
+                    }
+                })
+                .await;
+
+            for reporef in repos {
+                if let Some(repo_report) = self.collect_orphans(app, semantic, &reporef).await? {
+                    report.repos.push(repo_report);
+                }
+            }
+        }
+
+        report.reclaimed_documents = app.indexes.writers().await?.compact().await?;
+
+        info!(?report, "index maintenance finished");
+        Ok(Some(report))
+    }
+
+    async fn collect_orphans(
+        &self,
+        app: &Application,
+        semantic: &Semantic,
+        reporef: &RepoRef,
+    ) -> Result<Option<RepoReport>> {
+        // the file cache must not change under us, so hold off any reindexing
+        let _writers = app.indexes.writers().await?;
+
+        // the repository may have been removed or reindexed while we waited for the lock
+        let Some((disk_path, cache)) = app
+            .repo_pool
+            .read_async(reporef, |_, repo| {
+                (repo.sync_status == SyncStatus::Done).then(|| {
+                    (
+                        repo.disk_path.clone(),
+                        repo.open_file_cache(&app.config.index_dir),
+                    )
+                })
+            })
+            .await
+            .flatten()
+        else {
+            return Ok(None);
+        };
+
+        // an empty cache would mark every point as orphaned
+        let cache = cache?;
+        if cache.is_empty() {
+            return Ok(None);
+        }
+
+        let repo_ref = reporef.to_string();
+        let mut report = RepoReport {
+            repo_ref: repo_ref.clone(),
+            scanned_points: 0,
+            deleted_points: 0,
+        };
+
+        let mut offset = self.cursor(&repo_ref);
+        debug!(?reporef, ?offset, "collecting orphaned points");
+
+        loop {
+            let (points, next) = semantic
+                .scroll_paths(&repo_ref, offset, SCROLL_BATCH_SIZE)
+                .await?;
+
+            report.scanned_points += points.len();
+
+            let orphans = orphans(&cache, &disk_path, points);
+            if !orphans.is_empty() {
+                report.deleted_points += orphans.len();
+                semantic.delete_points(orphans).await?;
+            }
+
+            self.set_cursor(&repo_ref, next.as_ref().and_then(point_uuid))?;
+
+            match next {
+                Some(next) => offset = Some(next),
+                None => break,
+            }
+
+            sleep(BATCH_DELAY).await;
+        }
+
+        Ok(Some(report))
+    }
+
+    fn cursor(&self, repo_ref: &str) -> Option<PointId> {
+        self.cursors
+            .read()
+            .unwrap()
+            .get(repo_ref)
+            .cloned()
+            .map(PointId::from)
+    }
+
+    fn set_cursor(&self, repo_ref: &str, cursor: Option<String>) -> Result<()> {
+        {
+            let mut cursors = self.cursors.write().unwrap();
+            match cursor {
+                Some(cursor) => cursors.insert(repo_ref.to_owned(), cursor),
+                None => cursors.remove(repo_ref),
+            };
+        }
+
+        self.cursors.store()
+    }
+}
+
+/// Run maintenance every `maintenance_interval_hours`.
+pub(crate) async fn periodic_maintenance(app: Application) {
+    let hours = app.config.maintenance_interval_hours;
+    if hours == 0 {
+        return;
+    }
+
+    loop {
+        sleep(Duration::from_secs(hours * SECS_PER_HOUR)).await;
+
+        if let Err(err) = app.maintenance.run(&app).await {
+            error!(?err, "index maintenance failed");
+        }
+    }
+}
+
+/// Select the points that refer to files missing from the repository's file cache.
+fn orphans(cache: &FileCache, disk_path: &Path, points: Vec<(PointId, String)>) -> Vec<PointId> {
+    points
+        .into_iter()
+        .filter(|(_, relative_path)| !cache.contains(&disk_path.join(relative_path)))
+        .map(|(id, _)| id)
+        .collect()
+}
+
+fn point_uuid(id: &PointId) -> Option<String> {
+    match id.point_id_options.as_ref()? {
+        PointIdOptions::Uuid(uuid) => Some(uuid.clone()),
+        PointIdOptions::Num(_) => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::repo::CacheEntry;
+
+    #[test]
+    fn points_without_cache_entries_are_orphans() {
+        let disk_path = Path::new("/repos/bloop");
+        let cache = FileCache::default();
+        for (path, hash) in [("src/main.rs", "a"), ("README.md", "b")] {
+            _ = cache.insert(disk_path.join(path), CacheEntry::new(hash.into()).into());
+        }
+
+        let ids = (0..4)
+            .map(|_| PointId::from(uuid::Uuid::new_v4().to_string()))
+            .collect::<Vec<_>>();
+        let points = vec![
+            (ids[0].clone(), "src/main.rs".to_owned()),
+            (ids[1].clone(), "src/removed.rs".to_owned()),
+            (ids[2].clone(), "README.md".to_owned()),
+            (ids[3].clone(), "docs/old.md".to_owned()),
+        ];
+
+        assert_eq!(
+            orphans(&cache, disk_path, points),
+            vec![ids[1].clone(), ids[3].clone()]
+        );
+    }
+
+    #[test]
+    fn cursors_are_uuids() {
+        let uuid = uuid::Uuid::new_v4().to_string();
+
+        assert_eq!(point_uuid(&PointId::from(uuid.clone())), Some(uuid));
+        assert_eq!(point_uuid(&PointId::from(7)), None);
+    }
+}
@@ -0,0 +1,555 @@
+//! Alerts on repositories that fail to sync or index, or have not been indexed for too long.
+//!
+//! Every alert is kept in an event log, and posted as JSON to each configured webhook.
+//! Deliveries are retried with exponential backoff, and the ones that still fail are kept in a
+//! dead-letter log.
+
+use std::{
+    collections::{HashMap, VecDeque},
+    sync::{Arc, RwLock},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+use tokio::time::sleep;
+use tracing::{debug, warn};
+
+use crate::{
+    repo::{RepoRef, SyncStatus},
+    state::{PersistedState, StateSource},
+    Application, Configuration,
+};
+
+/// Events kept in the log. The oldest are dropped first
+const MAX_EVENTS: usize = 1_000;
+
+/// Undelivered events kept in the dead-letter log. The oldest are dropped first
+const MAX_DEAD_LETTERS: usize = 1_000;
+
+/// Attempts to deliver an event to a webhook, before it is dead-lettered
+const DELIVERY_ATTEMPTS: u32 = 5;
+
+/// Pause before the first retry of a delivery, doubled after every attempt
+const RETRY_BACKOFF: Duration = Duration::from_secs(1);
+
+const DELIVERY_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// How often repositories are checked for staleness
+const STALENESS_CHECK_INTERVAL: Duration = Duration::from_secs(10 * 60);
+
+const SECS_PER_HOUR: u64 = 60 * 60;
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum EventKind {
+    /// A sync or index run of a repository failed
+    RunFailed,
+    /// A repository failed `notify_after_failures` runs in a row
+    RepeatedFailures,
+    /// A remote repository was not indexed successfully for `notify_stale_after_hours`
+    Stale,
+    /// Sent on request, to check the webhook configuration
+    Test,
+}
+
+/// A single alert, as posted to webhooks.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct Event {
+    pub kind: EventKind,
+    pub repo_ref: Option<String>,
+
+    /// Summary of the last error of the repository
+    pub error: Option<String>,
+
+    /// Runs of the repository that failed in a row, up to this event
+    pub consecutive_failures: u32,
+
+    /// Unix timestamp of the last successful index of the repository, if there was one
+    pub last_success: Option<u64>,
+
+    /// Unix timestamp in seconds
+    pub timestamp: u64,
+}
+
+/// An event that could not be delivered to a webhook.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct DeadLetter {
+    pub url: String,
+    pub event: Event,
+
+    /// The error of the last attempt
+    pub error: String,
+    pub attempts: u32,
+
+    /// Unix timestamp of the last attempt
+    pub timestamp: u64,
+}
+
+/// The outcome of posting a test event to a webhook.
+#[derive(Serialize, Debug, PartialEq, Eq)]
+pub struct Delivery {
+    pub url: String,
+
+    /// `None` if the webhook accepted the event
+    pub error: Option<String>,
+}
+
+/// When to alert, and where to.
+#[derive(Clone, Debug)]
+struct Policy {
+    webhooks: Vec<String>,
+    failure_threshold: u32,
+
+    /// 0 if staleness is not alerted on
+    stale_after_secs: u64,
+    retry_backoff: Duration,
+}
+
+#[derive(Default, Debug)]
+struct Health {
+    consecutive_failures: u32,
+
+    /// Whether staleness was alerted on since the last successful run
+    stale: bool,
+}
+
+#[derive(Clone)]
+pub struct Notifications {
+    policy: Arc<Policy>,
+    client: reqwest::Client,
+    events: PersistedState<RwLock<VecDeque<Event>>>,
+    dead_letters: PersistedState<RwLock<VecDeque<DeadLetter>>>,
+
+    /// Repositories that failed or went stale since their last successful run
+    health: Arc<RwLock<HashMap<RepoRef, Health>>>,
+}
+
+impl Notifications {
+    pub fn load(config: &Configuration) -> Result<Self> {
+        let policy = Policy {
+            webhooks: config.notification_webhooks.clone(),
+            failure_threshold: config.notify_after_failures,
+            stale_after_secs: config
+                .notify_stale_after_hours
+                .saturating_mul(SECS_PER_HOUR),
+            retry_backoff: RETRY_BACKOFF,
+        };
+
+        Self::with_policy(&config.source, policy)
+    }
+
+    fn with_policy(source: &StateSource, policy: Policy) -> Result<Self> {
+        Ok(Self {
+            policy: policy.into(),
+            client: reqwest::Client::builder()
+                .timeout(DELIVERY_TIMEOUT)
+                .build()?,
+            events: source.load_or_default("notification_events")?,
+            dead_letters: source.load_or_default("notification_dead_letters")?,
+            health: Arc::default(),
+        })
+    }
+
+    /// Record the outcome of a sync and index run of `reporef`, alerting on failures.
+    ///
+    /// `last_success` is the unix timestamp of the last successful index, 0 if there was none.
+    pub(crate) fn record_run(&self, reporef: &RepoRef, failure: Option<String>, last_success: u64) {
+        let Some(error) = failure else {
+            self.health.write().unwrap().remove(reporef);
+            return;
+        };
+
+        let consecutive_failures = {
+            let mut health = self.health.write().unwrap();
+            let health = health.entry(reporef.clone()).or_default();
+            health.consecutive_failures += 1;
+            health.consecutive_failures
+        };
+
+        let event = |kind| Event {
+            kind,
+            repo_ref: Some(reporef.to_string()),
+            error: Some(error.clone()),
+            consecutive_failures,
+            last_success: (last_success > 0).then_some(last_success),
+            timestamp: unix_time_sec(),
+        };
+
+        self.emit(event(EventKind::RunFailed));
+        if consecutive_failures == self.policy.failure_threshold {
+            self.emit(event(EventKind::RepeatedFailures));
+        }
+    }
+
+    /// Alert on the repositories of `last_success` that were last indexed longer ago than the
+    /// staleness threshold, at most once between successful runs.
+    ///
+    /// Repositories that were never indexed successfully are left to the failure alerts.
+    fn check_staleness(&self, last_success: impl IntoIterator<Item = (RepoRef, u64)>, now: u64) {
+        let threshold = self.policy.stale_after_secs;
+        if threshold == 0 {
+            return;
+        }
+
+        for (reporef, last_success) in last_success {
+            if last_success == 0 || now.saturating_sub(last_success) <= threshold {
+                continue;
+            }
+
+            let consecutive_failures = {
+                let mut health = self.health.write().unwrap();
+                let health = health.entry(reporef.clone()).or_default();
+                if std::mem::replace(&mut health.stale, true) {
+                    continue;
+                }
+                health.consecutive_failures
+            };
+
+            self.emit(Event {
+                kind: EventKind::Stale,
+                repo_ref: Some(reporef.to_string()),
+                error: None,
+                consecutive_failures,
+                last_success: Some(last_success),
+                timestamp: now,
+            });
+        }
+    }
+
+    /// Log a test event, and post it once to every webhook.
+    pub async fn test(&self) -> Vec<Delivery> {
+        let event = Event {
+            kind: EventKind::Test,
+            repo_ref: None,
+            error: None,
+            consecutive_failures: 0,
+            last_success: None,
+            timestamp: unix_time_sec(),
+        };
+        self.log(event.clone());
+
+        let deliveries = self.policy.webhooks.iter().map(|url| {
+            let event = &event;
+            async move {
+                Delivery {
+                    url: url.clone(),
+                    error: self.post(url, event).await.err(),
+                }
+            }
+        });
+
+        futures::future::join_all(deliveries).await
+    }
+
+    /// The most recent events, most recent first.
+    pub fn events(&self, limit: usize) -> Vec<Event> {
+        self.events
+            .read()
+            .unwrap()
+            .iter()
+            .rev()
+            .take(limit)
+            .cloned()
+            .collect()
+    }
+
+    /// The most recent undelivered events, most recent first.
+    pub fn dead_letters(&self, limit: usize) -> Vec<DeadLetter> {
+        self.dead_letters
+            .read()
+            .unwrap()
+            .iter()
+            .rev()
+            .take(limit)
+            .cloned()
+            .collect()
+    }
+
+    /// Log `event`, and deliver it to every webhook in the background.
+    fn emit(&self, event: Event) {
+        warn!(?event, "repository alert");
+        self.log(event.clone());
+
+        for url in &self.policy.webhooks {
+            let this = self.clone();
+            let url = url.clone();
+            let event = event.clone();
+            tokio::spawn(async move { this.deliver(url, event).await });
+        }
+    }
+
+    fn log(&self, event: Event) {
+        push_bounded(&self.events, event, MAX_EVENTS, "notification events");
+    }
+
+    /// Post `event` to `url`, retrying with exponential backoff, and dead-letter it if every
+    /// attempt fails.
+    async fn deliver(&self, url: String, event: Event) {
+        let mut backoff = self.policy.retry_backoff;
+
+        for attempt in 1..=DELIVERY_ATTEMPTS {
+            let error = match self.post(&url, &event).await {
+                Ok(()) => return,
+                Err(error) => error,
+            };
+
+            if attempt == DELIVERY_ATTEMPTS {
+                warn!(%url, %error, "failed to deliver alert, giving up");
+                let dead_letter = DeadLetter {
+                    url,
+                    event,
+                    error,
+                    attempts: attempt,
+                    timestamp: unix_time_sec(),
+                };
+                push_bounded(
+                    &self.dead_letters,
+                    dead_letter,
+                    MAX_DEAD_LETTERS,
+                    "notification dead letters",
+                );
+                return;
+            }
+
+            debug!(%url, %error, attempt, "failed to deliver alert, retrying");
+            sleep(backoff).await;
+            backoff *= 2;
+        }
+    }
+
+    async fn post(&self, url: &str, event: &Event) -> Result<(), String> {
+        self.client
+            .post(url)
+            .json(event)
+            .send()
+            .await
+            .and_then(|response| response.error_for_status())
+            .map(drop)
+            .map_err(|err| err.to_string())
+    }
+}
+
+/// Append an entry to a bounded log, and persist it in the background.
+fn push_bounded<T>(log: &PersistedState<RwLock<VecDeque<T>>>, entry: T, max: usize, name: &str)
+where
+    T: Serialize + serde::de::DeserializeOwned + Send + Sync + 'static,
+{
+    {
+        let mut log = log.write().unwrap();
+        log.push_back(entry);
+        while log.len() > max {
+            log.pop_front();
+        }
+    }
+
+    let log = log.clone();
+    let name = name.to_owned();
+    tokio::task::spawn_blocking(move || {
+        if let Err(err) = log.store() {
+            warn!(?err, "failed to persist {name}");
+        }
+    });
+}
+
+/// Alert on stale remote repositories every `STALENESS_CHECK_INTERVAL`.
+///
+/// Local repositories are only reindexed when they change, so they are never stale.
+pub(crate) async fn check_staleness(app: Application) {
+    if app.config.notify_stale_after_hours == 0 {
+        return;
+    }
+
+    loop {
+        sleep(STALENESS_CHECK_INTERVAL).await;
+
+        let mut repos = vec![];
+        app.repo_pool
+            .scan_async(|reporef, repo| {
+                // evicted repositories are not expected to be kept up to date
+                if !reporef.is_local() && repo.sync_status != SyncStatus::Evicted {
+                    repos.push((reporef.clone(), repo.last_index_unix_secs));
+                }
+            })
+            .await;
+
+        app.notifications.check_staleness(repos, unix_time_sec());
+    }
+}
+
+fn unix_time_sec() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_secs()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::repo::Backend;
+    use axum::{http::StatusCode, routing::post, Json, Router};
+    use std::{
+        net::{SocketAddr, TcpListener},
+        sync::atomic::{AtomicU32, Ordering},
+    };
+    use tempdir::TempDir;
+
+    fn notifications(dir: &TempDir, webhooks: Vec<String>) -> Notifications {
+        let mut source = StateSource::default();
+        source.set_default_dir(dir.path());
+
+        let policy = Policy {
+            webhooks,
+            failure_threshold: 3,
+            stale_after_secs: 60,
+            retry_backoff: Duration::from_millis(1),
+        };
+        Notifications::with_policy(&source, policy).unwrap()
+    }
+
+    fn kinds(notifications: &Notifications) -> Vec<EventKind> {
+        let mut kinds = notifications
+            .events(usize::MAX)
+            .into_iter()
+            .map(|e| e.kind)
+            .collect::<Vec<_>>();
+        kinds.reverse();
+        kinds
+    }
+
+    /// Serve a webhook that fails the first `failures` deliveries, and counts all of them.
+    fn webhook(failures: u32) -> (String, Arc<AtomicU32>) {
+        let received = Arc::new(AtomicU32::new(0));
+        let counter = Arc::clone(&received);
+        let app = Router::new().route(
+            "/alerts",
+            post(move |Json(_): Json<Event>| async move {
+                if counter.fetch_add(1, Ordering::SeqCst) < failures {
+                    StatusCode::INTERNAL_SERVER_ERROR
+                } else {
+                    StatusCode::OK
+                }
+            }),
+        );
+
+        let listener = TcpListener::bind(SocketAddr::from(([127, 0, 0, 1], 0))).unwrap();
+        let url = format!("http://{}/alerts", listener.local_addr().unwrap());
+        tokio::spawn(
+            axum::Server::from_tcp(listener)
+                .unwrap()
+                .serve(app.into_make_service()),
+        );
+
+        (url, received)
+    }
+
+    async fn wait_until(mut done: impl FnMut() -> bool) {
+        for _ in 0..500 {
+            if done() {
+                return;
+            }
+            sleep(Duration::from_millis(10)).await;
+        }
+        panic!("timed out");
+    }
+
+    #[tokio::test]
+    async fn repeated_failures_are_alerted_once() {
+        let dir = TempDir::new("notifications").unwrap();
+        let notifications = notifications(&dir, vec![]);
+        let reporef = RepoRef::new(Backend::Github, "bloopai/bloop").unwrap();
+
+        for _ in 0..4 {
+            notifications.record_run(&reporef, Some("token expired".into()), 1_700_000_000);
+        }
+
+        assert_eq!(
+            kinds(&notifications),
+            vec![
+                EventKind::RunFailed,
+                EventKind::RunFailed,
+                EventKind::RunFailed,
+                EventKind::RepeatedFailures,
+                EventKind::RunFailed,
+            ]
+        );
+
+        let latest = &notifications.events(1)[0];
+        assert_eq!(latest.repo_ref.as_deref(), Some("github.com/bloopai/bloop"));
+        assert_eq!(latest.error.as_deref(), Some("token expired"));
+        assert_eq!(latest.consecutive_failures, 4);
+        assert_eq!(latest.last_success, Some(1_700_000_000));
+
+        // a successful run starts the count over
+        notifications.record_run(&reporef, None, 1_700_000_100);
+        notifications.record_run(&reporef, Some("disk full".into()), 1_700_000_100);
+        assert_eq!(notifications.events(1)[0].consecutive_failures, 1);
+    }
+
+    #[tokio::test]
+    async fn stale_repositories_are_alerted_once() {
+        let dir = TempDir::new("notifications").unwrap();
+        let notifications = notifications(&dir, vec![]);
+        let stale = RepoRef::new(Backend::Github, "bloopai/bloop").unwrap();
+        let fresh = RepoRef::new(Backend::Github, "bloopai/bleep").unwrap();
+        let never = RepoRef::new(Backend::Github, "bloopai/other").unwrap();
+
+        let repos = || {
+            [
+                (stale.clone(), 1_000),
+                (fresh.clone(), 1_150),
+                (never.clone(), 0),
+            ]
+        };
+        notifications.check_staleness(repos(), 1_100);
+        notifications.check_staleness(repos(), 1_200);
+        assert_eq!(kinds(&notifications), vec![EventKind::Stale]);
+
+        let event = &notifications.events(1)[0];
+        assert_eq!(event.repo_ref.as_deref(), Some("github.com/bloopai/bloop"));
+        assert_eq!(event.last_success, Some(1_000));
+
+        // going stale again after a successful run is alerted on again
+        notifications.record_run(&stale, None, 1_000);
+        notifications.check_staleness([(stale.clone(), 1_000)], 1_300);
+        assert_eq!(notifications.events(usize::MAX).len(), 2);
+    }
+
+    #[tokio::test]
+    async fn deliveries_are_retried_then_dead_lettered() {
+        let dir = TempDir::new("notifications").unwrap();
+        let (flaky, flaky_received) = webhook(2);
+        let (broken, broken_received) = webhook(u32::MAX);
+        let notifications = notifications(&dir, vec![flaky.clone(), broken.clone()]);
+
+        let reporef = RepoRef::new(Backend::Github, "bloopai/bloop").unwrap();
+        notifications.record_run(&reporef, Some("token expired".into()), 0);
+
+        wait_until(|| !notifications.dead_letters(1).is_empty()).await;
+        wait_until(|| flaky_received.load(Ordering::SeqCst) == 3).await;
+        assert_eq!(broken_received.load(Ordering::SeqCst), DELIVERY_ATTEMPTS);
+
+        let dead_letters = notifications.dead_letters(usize::MAX);
+        assert_eq!(dead_letters.len(), 1);
+        assert_eq!(dead_letters[0].url, broken);
+        assert_eq!(dead_letters[0].attempts, DELIVERY_ATTEMPTS);
+        assert_eq!(dead_letters[0].event.kind, EventKind::RunFailed);
+        assert!(dead_letters[0].error.contains("500"));
+
+        // test events are posted once, and the outcome of each is reported back
+        let deliveries = notifications.test().await;
+        assert_eq!(deliveries.len(), 2);
+        assert_eq!(
+            (deliveries[0].url.as_str(), &deliveries[0].error),
+            (flaky.as_str(), &None)
+        );
+        assert_eq!(deliveries[1].url, broken);
+        assert!(deliveries[1].error.is_some());
+        assert_eq!(
+            broken_received.load(Ordering::SeqCst),
+            DELIVERY_ATTEMPTS + 1
+        );
+        assert_eq!(kinds(&notifications).last(), Some(&EventKind::Test));
+    }
+}
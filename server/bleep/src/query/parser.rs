@@ -1,7 +1,10 @@
 use pest::{iterators::Pair, Parser};
 use regex::Regex;
 use smallvec::{smallvec, SmallVec};
-use std::{borrow::Cow, collections::HashSet, mem};
+use once_cell::sync::Lazy;
+use std::{borrow::Cow, collections::HashSet, mem, sync::Arc};
+
+use crate::cache::{BoundedCache, CachePolicy};
 
 #[derive(Default, Clone, Debug, PartialEq, Eq)]
 pub struct Query<'a> {
@@ -30,6 +33,9 @@ pub struct NLQuery<'a> {
     pub langs: HashSet<Cow<'a, str>>,
     pub branch: HashSet<Literal<'a>>,
     pub target: Option<Literal<'a>>,
+    /// Double-quoted phrases that matching chunks must contain verbatim, in query order. These
+    /// are still part of `target`, so they also rank the chunks.
+    pub phrases: Vec<Cow<'a, str>>,
 }
 
 impl<'a> NLQuery<'a> {
@@ -52,6 +58,30 @@ impl<'a> NLQuery<'a> {
     pub fn branch(&self) -> impl Iterator<Item = &Cow<'_, str>> {
         self.branch.iter().filter_map(|t| t.as_plain())
     }
+
+    pub fn phrases(&self) -> impl Iterator<Item = &Cow<'_, str>> {
+        self.phrases.iter()
+    }
+
+    /// Detach this query from the input string it was parsed from.
+    pub fn into_owned(self) -> NLQuery<'static> {
+        NLQuery {
+            repos: self.repos.into_iter().map(Literal::into_owned).collect(),
+            paths: self.paths.into_iter().map(Literal::into_owned).collect(),
+            langs: self
+                .langs
+                .into_iter()
+                .map(|l| Cow::Owned(l.into_owned()))
+                .collect(),
+            branch: self.branch.into_iter().map(Literal::into_owned).collect(),
+            target: self.target.map(Literal::into_owned),
+            phrases: self
+                .phrases
+                .into_iter()
+                .map(|p| Cow::Owned(p.into_owned()))
+                .collect(),
+        }
+    }
 }
 
 impl<'a> Query<'a> {
@@ -227,6 +257,13 @@ impl Literal<'_> {
         }
     }
 
+    pub fn into_owned(self) -> Literal<'static> {
+        match self {
+            Self::Plain(s) => Literal::Plain(Cow::Owned(s.into_owned())),
+            Self::Regex(s) => Literal::Regex(Cow::Owned(s.into_owned())),
+        }
+    }
+
     /// Force this literal into the `Regex` variant.
     fn make_regex(&mut self) {
         *self = match std::mem::take(self) {
@@ -416,6 +453,37 @@ pub fn parse(query: &str) -> Result<Vec<Query<'_>>, ParseError> {
     Ok(qs.into_vec())
 }
 
+/// Number of distinct natural language queries kept by [`parse_nl_cached`].
+const NL_QUERY_CACHE_SIZE: usize = 1024;
+
+static NL_QUERY_CACHE: Lazy<BoundedCache<String, Arc<NLQuery<'static>>>> =
+    Lazy::new(|| BoundedCache::new(NL_QUERY_CACHE_SIZE));
+
+/// Like [`parse_nl`], but shares the result between identical inputs.
+///
+/// Parsing is pure, so cached entries never need invalidating.
+pub fn parse_nl_cached(query: &str) -> Result<Arc<NLQuery<'static>>, ParseError> {
+    parse_nl_with_cache(&NL_QUERY_CACHE, query, CachePolicy::Reuse)
+}
+
+/// Like [`parse_nl_cached`], but only using the cache as `policy` allows.
+pub fn parse_nl_with_policy(
+    query: &str,
+    policy: CachePolicy,
+) -> Result<Arc<NLQuery<'static>>, ParseError> {
+    parse_nl_with_cache(&NL_QUERY_CACHE, query, policy)
+}
+
+fn parse_nl_with_cache(
+    cache: &BoundedCache<String, Arc<NLQuery<'static>>>,
+    query: &str,
+    policy: CachePolicy,
+) -> Result<Arc<NLQuery<'static>>, ParseError> {
+    cache.get_or_try_insert_with_policy(query.to_owned(), policy, || {
+        parse_nl(query).map(|q| Arc::new(q.into_owned()))
+    })
+}
+
 pub fn parse_nl(query: &str) -> Result<NLQuery<'_>, ParseError> {
     let pairs = PestParser::parse(Rule::nl_query, query).map_err(Box::new)?;
 
@@ -424,6 +492,7 @@ pub fn parse_nl(query: &str) -> Result<NLQuery<'_>, ParseError> {
     let mut langs = HashSet::new();
     let mut branch = HashSet::new();
     let mut target: Option<Literal> = None;
+    let mut phrases = vec![];
     for pair in pairs {
         match pair.as_rule() {
             Rule::repo => {
@@ -443,7 +512,17 @@ pub fn parse_nl(query: &str) -> Result<NLQuery<'_>, ParseError> {
                 let _ = langs.insert(item);
             }
             Rule::unquoted_literal | Rule::quoted_literal | Rule::single_quoted_literal => {
+                let quoted = pair.as_rule() == Rule::quoted_literal;
                 let rhs = Literal::from(pair);
+                match &rhs {
+                    Literal::Plain(phrase)
+                        if quoted && !phrase.trim().is_empty() && !phrases.contains(phrase) =>
+                    {
+                        phrases.push(phrase.clone());
+                    }
+                    _ => {}
+                }
+
                 if let Some(t) = target {
                     target = t.join_as_plain(rhs);
                 } else {
@@ -460,6 +539,7 @@ pub fn parse_nl(query: &str) -> Result<NLQuery<'_>, ParseError> {
         langs,
         branch,
         target,
+        phrases,
     })
 }
 
@@ -1004,7 +1084,8 @@ mod tests {
                 langs: ["tsx".into()].into(),
                 repos: [Literal::Plain("bloop".into())].into(),
                 paths: [].into(),
-                branch: [].into()
+                branch: [].into(),
+                phrases: vec![],
             },
         );
     }
@@ -1036,6 +1117,7 @@ mod tests {
                 ]
                 .into(),
                 paths: [Literal::Plain("server/bleep".into())].into(),
+                phrases: vec![],
             },
         );
     }
@@ -1053,6 +1135,7 @@ mod tests {
                 repos: [Literal::Plain("bloop".into())].into(),
                 paths: [].into(),
                 branch: [].into(),
+                phrases: vec![],
             },
         );
 
@@ -1068,6 +1151,35 @@ mod tests {
         );
     }
 
+    #[test]
+    fn nl_parse_phrases() {
+        let query = parse_nl(r#"where is "fn main" called "fn main" lang:rust"#).unwrap();
+        assert_eq!(query.phrases, vec![Cow::from("fn main")]);
+        assert_eq!(query.target().unwrap(), "where is fn main called fn main");
+        assert!(parse_nl(r#""" bloop"#).unwrap().phrases.is_empty());
+
+        // single quotes only group words
+        let query = parse_nl("'fn main' \"parse_args\"").unwrap();
+        assert_eq!(query.phrases, vec![Cow::from("parse_args")]);
+        assert_eq!(query.target().unwrap(), "fn main parse_args");
+    }
+
+    #[test]
+    fn nl_parse_cached() {
+        let cache = BoundedCache::new(8);
+        let query = "what is background color? lang:tsx repo:bloop";
+
+        let first = parse_nl_with_cache(&cache, query, CachePolicy::Reuse).unwrap();
+        let second = cache
+            .get_or_try_insert_with(query.to_owned(), || -> Result<_, ParseError> {
+                panic!("identical query was parsed twice")
+            })
+            .unwrap();
+
+        assert!(Arc::ptr_eq(&first, &second));
+        assert_eq!(*first, parse_nl(query).unwrap());
+    }
+
     #[test]
     fn escape_characters() {
         assert_eq!(
@@ -199,11 +199,25 @@ pub(crate) fn gather_repo_roots(
                         .expect("repo root is both a dir and exists"),
                     ))
                 }
+                Some(ft) if ft.is_dir() && is_bare_repo(de.path()) => Some(RepoRef::from(
+                    &crate::canonicalize(de.path()).expect("repo root is both a dir and exists"),
+                )),
                 _ => None,
             })
         })
 }
 
+/// Whether `path` looks like a bare repository, such as a `--mirror` clone.
+///
+/// The git directories of working trees, and the ones of submodules nested within them, look
+/// the same, so anything under a `.git` directory is left to the check for those.
+fn is_bare_repo(path: &Path) -> bool {
+    !path.components().any(|c| c.as_os_str() == ".git")
+        && path.join("HEAD").is_file()
+        && path.join("objects").is_dir()
+        && path.join("refs").is_dir()
+}
+
 struct BackendEntry {
     inner: BackendCredential,
     updated: flume::Receiver<()>,
@@ -324,7 +338,13 @@ impl BackendCredential {
                     .read_async(&repo_ref, |_k, repo| repo.clone())
                     .await
                     .expect("repo exists & locked, this shouldn't happen");
-                gh.auth.pull_repo(repo).await
+
+                // the clones of evicted repositories are deleted, and cloned again on restore
+                if repo.disk_path.exists() {
+                    gh.auth.pull_repo(repo).await
+                } else {
+                    gh.auth.clone_repo(repo).await
+                }
             }
             None => {
                 create_repository(&app, &repo_ref).await;
@@ -372,6 +392,10 @@ async fn create_repository<'a>(app: &'a Application, reporef: &RepoRef) {
             sync_status: SyncStatus::Syncing,
             last_index_unix_secs: 0,
             last_commit_unix_secs: 0,
+            indexed_commit: None,
             most_common_lang: None,
+            lang_stats: Default::default(),
+            pinned: false,
+            usage: Default::default(),
         });
 }
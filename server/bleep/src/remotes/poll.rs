@@ -234,7 +234,7 @@ impl Poller {
         if app.config.disable_fsevents.not() && reporef.backend() == Backend::Local {
             let git_path = app
                 .repo_pool
-                .read(reporef, |_, v| v.disk_path.join(".git"))?;
+                .read(reporef, |_, v| v.git_dir())?;
 
             let mut debouncer = debounced_events(tx);
             debouncer
@@ -1,6 +1,9 @@
 use anyhow::Context;
+use once_cell::sync::OnceCell;
 use serde::{de::Error, Deserialize, Deserializer, Serialize, Serializer};
 use std::{
+    borrow::Cow,
+    collections::HashMap,
     fmt::{self, Display},
     path::{Path, PathBuf},
     str::FromStr,
@@ -18,7 +21,13 @@ use crate::{
 pub(crate) mod iterator;
 use iterator::language;
 
-pub(crate) type FileCache = Arc<scc::HashMap<PathBuf, FreshValue<String>>>;
+pub(crate) type FileCache = Arc<scc::HashMap<PathBuf, FreshValue<CacheEntry>>>;
+
+/// Language bucket for files where no language was detected
+pub const OTHER_LANG: &str = "other";
+
+/// Separator of indexed `relative_path` values, on every platform
+pub const PATH_SEPARATOR: char = '/';
 
 #[derive(Serialize, Deserialize)]
 pub(crate) struct FreshValue<T> {
@@ -34,6 +43,69 @@ impl<T> From<T> for FreshValue<T> {
     }
 }
 
+#[derive(Serialize, Deserialize)]
+pub(crate) struct CacheEntry {
+    pub(crate) content_hash: String,
+
+    /// Statistics of the indexed file; `None` for directories and skipped files
+    #[serde(default)]
+    pub(crate) stats: Option<FileStats>,
+}
+
+impl CacheEntry {
+    pub(crate) fn new(content_hash: String) -> Self {
+        Self {
+            content_hash,
+            stats: None,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub(crate) struct FileStats {
+    pub(crate) lang: String,
+    pub(crate) bytes: usize,
+    pub(crate) chunks: usize,
+}
+
+/// Indexed content of a single language in a repository
+#[derive(Serialize, Deserialize, ToSchema, Default, Clone, Copy, Debug, PartialEq, Eq)]
+pub struct LanguageCount {
+    pub files: usize,
+    pub bytes: usize,
+    pub chunks: usize,
+}
+
+impl LanguageCount {
+    pub(crate) fn add(&mut self, stats: &FileStats) {
+        self.files += 1;
+        self.bytes += stats.bytes;
+        self.chunks += stats.chunks;
+    }
+}
+
+/// Aggregate per-file statistics in the cache into per-language counters.
+///
+/// The file cache tracks exactly the set of currently indexed files, so
+/// this stays correct across incremental reindexing.
+pub(crate) fn language_stats(cache: &FileCache) -> HashMap<String, LanguageCount> {
+    let mut langs = HashMap::<String, LanguageCount>::new();
+
+    cache.scan(|_, entry| {
+        if let Some(stats) = &entry.value.stats {
+            let lang = if stats.lang.is_empty() {
+                OTHER_LANG.to_owned()
+            } else {
+                stats.lang.to_ascii_lowercase()
+            };
+
+            langs.entry(lang).or_default().add(stats);
+        }
+    });
+
+    langs
+}
+
 // Types of repo
 #[derive(Serialize, Deserialize, ToSchema, Hash, PartialEq, Eq, Clone, Debug)]
 #[serde(rename_all = "snake_case")]
@@ -210,8 +282,46 @@ pub struct Repository {
     pub remote: RepoRemote,
     pub sync_status: SyncStatus,
     pub last_commit_unix_secs: u64,
+    /// The `HEAD` commit when the repository was last indexed, which the lines of its chunks
+    /// refer to
+    #[serde(default)]
+    pub indexed_commit: Option<String>,
     pub last_index_unix_secs: u64,
     pub most_common_lang: Option<String>,
+    #[serde(default)]
+    pub lang_stats: HashMap<String, LanguageCount>,
+    /// Pinned repositories are never evicted, see [`crate::eviction`]
+    #[serde(default)]
+    pub pinned: bool,
+    #[serde(default)]
+    pub usage: RepoUsage,
+}
+
+/// When a repository was last searched, for evicting the least recently used ones.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct RepoUsage {
+    /// The last search returning results from the repository, in unix seconds
+    pub last_search_unix_secs: u64,
+    /// The last answer drawing on snippets of the repository, in unix seconds
+    pub last_answer_unix_secs: u64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UsageKind {
+    Search,
+    Answer,
+}
+
+impl RepoUsage {
+    /// Record a use at `now`, returning when the repository was last used that way.
+    pub(crate) fn record(&mut self, kind: UsageKind, now: u64) -> u64 {
+        let last = match kind {
+            UsageKind::Search => &mut self.last_search_unix_secs,
+            UsageKind::Answer => &mut self.last_answer_unix_secs,
+        };
+
+        std::mem::replace(last, now.max(*last))
+    }
 }
 
 impl Repository {
@@ -239,9 +349,13 @@ impl Repository {
             sync_status: SyncStatus::Queued,
             last_index_unix_secs: 0,
             last_commit_unix_secs: 0,
+            indexed_commit: None,
             disk_path,
             remote,
             most_common_lang: None,
+            lang_stats: HashMap::new(),
+            pinned: false,
+            usage: RepoUsage::default(),
         }
     }
 
@@ -263,19 +377,56 @@ impl Repository {
         Ok(metadata)
     }
 
+    /// Preview indexing this repository, see [`indexes::Indexes::dry_run`].
+    pub(crate) async fn dry_run(
+        &self,
+        reporef: &RepoRef,
+        indexes: &indexes::Indexes,
+    ) -> Result<indexes::DryRunReport, RepoError> {
+        let metadata = self.get_repo_metadata().await?;
+
+        Ok(tokio::task::block_in_place(|| {
+            indexes.dry_run(reporef, self, &metadata)
+        })?)
+    }
+
+    /// Whether the repository is bare, like a `--mirror` clone, and so has no working tree to
+    /// walk. Bare repositories are indexed from the tree of their `HEAD` instead.
+    pub(crate) fn is_bare(&self) -> bool {
+        git2::Repository::open(&self.disk_path)
+            .map(|git| git.is_bare())
+            .unwrap_or_default()
+    }
+
+    /// The directory holding the git data of the repository, which is `disk_path` itself if it
+    /// is bare.
+    pub(crate) fn git_dir(&self) -> PathBuf {
+        if self.is_bare() {
+            self.disk_path.clone()
+        } else {
+            self.disk_path.join(".git")
+        }
+    }
+
     /// Pre-scan the repository to provide supporting metadata for a
     /// new indexing operation
     async fn get_repo_metadata(&self) -> Result<Arc<RepoMetadata>, RepoError> {
-        let last_commit_unix_secs = gix::open(&self.disk_path)
+        let head = gix::open(&self.disk_path)
             .context("failed to open git repo")
-            .and_then(|repo| Ok(repo.head()?.peel_to_commit_in_place()?.time()?.seconds()))
-            .unwrap_or(0) as u64;
+            .and_then(|repo| {
+                let commit = repo.head()?.peel_to_commit_in_place()?;
+                Ok((commit.time()?.seconds() as u64, commit.id.to_string()))
+            })
+            .ok();
+        let last_commit_unix_secs = head.as_ref().map_or(0, |(time, _)| *time);
 
         let langs = Default::default();
 
         Ok(RepoMetadata {
             last_commit_unix_secs,
+            last_commit: head.map(|(_, id)| id),
             langs,
+            lang_stats: OnceCell::new(),
         }
         .into())
     }
@@ -286,6 +437,22 @@ impl Repository {
         self.sync_status = SyncStatus::Removed;
     }
 
+    /// Marks the repository as evicted, once its clone and search index are deleted.
+    pub(crate) fn mark_evicted(&mut self) {
+        self.sync_status = SyncStatus::Evicted;
+    }
+
+    /// When the repository was last searched, answered from, or indexed.
+    ///
+    /// Indexing counts as a use, so repositories that were just added aren't evicted before
+    /// anyone had a chance to search them.
+    pub(crate) fn last_used_unix_secs(&self) -> u64 {
+        self.usage
+            .last_search_unix_secs
+            .max(self.usage.last_answer_unix_secs)
+            .max(self.last_index_unix_secs)
+    }
+
     /// Marks the repository for indexing on the next sync
     /// Does not initiate a new sync.
     pub(crate) fn mark_queued(&mut self) {
@@ -295,8 +462,13 @@ impl Repository {
     pub(crate) fn sync_done_with(&mut self, metadata: Arc<RepoMetadata>) {
         self.last_index_unix_secs = get_unix_time(SystemTime::now());
         self.last_commit_unix_secs = metadata.last_commit_unix_secs;
+        self.indexed_commit = metadata.last_commit.clone();
         self.sync_status = SyncStatus::Done;
         self.most_common_lang = metadata.langs.most_common_lang().map(|l| l.to_string());
+
+        if let Some(stats) = metadata.lang_stats.get() {
+            self.lang_stats = stats.clone();
+        }
     }
 
     fn file_cache_path(&self, index_dir: &Path) -> PathBuf {
@@ -326,6 +498,41 @@ impl Repository {
     }
 }
 
+/// Render a path relative to a repository root the way it's indexed, with `/` separators.
+pub fn relative_path_str(path: &Path) -> String {
+    path.components()
+        .map(|c| c.as_os_str().to_string_lossy())
+        .collect::<Vec<_>>()
+        .join("/")
+}
+
+/// Normalize a relative path from a request or an existing index point to `/` separators.
+///
+/// Paths used to be indexed with the platform separator, so points created on Windows before
+/// normalization carry `\` separators. Files with a `\` in their name can't be addressed by
+/// path as a result.
+pub fn normalize_relative_path(path: &str) -> Cow<'_, str> {
+    if path.contains('\\') {
+        Cow::Owned(path.replace('\\', "/"))
+    } else {
+        Cow::Borrowed(path)
+    }
+}
+
+/// The forms a relative path may be stored in: normalized, and with the `\` separators of
+/// points indexed on Windows before normalization. This is for matching stored paths exactly,
+/// e.g. when deleting points.
+pub fn relative_path_variants(path: &str) -> Vec<String> {
+    let normalized = normalize_relative_path(path).into_owned();
+    let legacy = normalized.replace(PATH_SEPARATOR, "\\");
+
+    if legacy == normalized {
+        vec![normalized]
+    } else {
+        vec![normalized, legacy]
+    }
+}
+
 fn get_unix_time(time: SystemTime) -> u64 {
     time.duration_since(SystemTime::UNIX_EPOCH)
         .expect("system time error")
@@ -335,13 +542,20 @@ fn get_unix_time(time: SystemTime) -> u64 {
 #[derive(Debug)]
 pub struct RepoMetadata {
     pub last_commit_unix_secs: u64,
+    /// The `HEAD` commit being indexed, `None` if the repository has no git history
+    pub last_commit: Option<String>,
     pub langs: language::LanguageInfo,
+
+    /// Per-language counters, populated by the file indexer
+    pub lang_stats: OnceCell<HashMap<String, LanguageCount>>,
 }
 
 #[derive(Serialize, Deserialize, ToSchema, PartialEq, Eq, Clone, Debug, Hash)]
 #[serde(rename_all = "snake_case")]
 pub enum SyncStatus {
-    Error { message: String },
+    Error {
+        message: String,
+    },
     Uninitialized,
     Removed,
     Syncing,
@@ -349,6 +563,9 @@ pub enum SyncStatus {
     Indexing,
     RemoteRemoved,
     Done,
+    /// The clone and search index of the repository were deleted to save disk space. It is
+    /// restored by indexing it again
+    Evicted,
 }
 
 impl SyncStatus {
@@ -469,6 +686,10 @@ pub enum RepoError {
         #[from]
         error: serde_json::Error,
     },
+    #[error("state file can't be decrypted")]
+    Decrypt,
+    #[error("state file is encrypted, but no store key is configured")]
+    MissingStoreKey,
     #[error("indexing error")]
     Anyhow {
         #[from]
@@ -534,4 +755,69 @@ mod test {
         assert_eq!(ssh, "git@github.com:org/repo.git/".parse().unwrap());
         assert_eq!(ssh, "git@github.com:/org/repo.git/".parse().unwrap());
     }
+
+    #[test]
+    fn windows_relative_paths() {
+        assert_eq!(
+            normalize_relative_path(r"src\webserver\query.rs"),
+            "src/webserver/query.rs"
+        );
+        assert_eq!(normalize_relative_path("src/lib.rs"), "src/lib.rs");
+
+        assert_eq!(
+            relative_path_variants(r"src\lib.rs"),
+            vec!["src/lib.rs", r"src\lib.rs"]
+        );
+        assert_eq!(relative_path_variants("Cargo.toml"), vec!["Cargo.toml"]);
+
+        assert_eq!(
+            relative_path_str(&Path::new("src").join("webserver").join("query.rs")),
+            "src/webserver/query.rs"
+        );
+    }
+    #[tokio::test]
+    async fn the_indexed_commit_is_kept_until_the_next_index() {
+        let dir = tempdir::TempDir::new("repo").unwrap();
+        let git = git2::Repository::init(dir.path()).unwrap();
+        let commit = |contents: &str| {
+            std::fs::write(dir.path().join("main.rs"), contents).unwrap();
+            let mut index = git.index().unwrap();
+            index.add_path(Path::new("main.rs")).unwrap();
+            let tree = git.find_tree(index.write_tree().unwrap()).unwrap();
+            let signature = git2::Signature::now("dev", "dev@bloop.ai").unwrap();
+            let parent = git.head().ok().map(|head| head.peel_to_commit().unwrap());
+            git.commit(
+                Some("HEAD"),
+                &signature,
+                &signature,
+                "change",
+                &tree,
+                parent.as_ref().into_iter().collect::<Vec<_>>().as_slice(),
+            )
+            .unwrap()
+        };
+
+        let indexed = commit("fn main() {}\n");
+        let mut repo = Repository {
+            disk_path: dir.path().to_owned(),
+            remote: RepoRemote::None,
+            sync_status: SyncStatus::Indexing,
+            last_commit_unix_secs: 0,
+            indexed_commit: None,
+            last_index_unix_secs: 0,
+            most_common_lang: None,
+            lang_stats: Default::default(),
+            pinned: false,
+            usage: Default::default(),
+        };
+        repo.sync_done_with(repo.get_repo_metadata().await.unwrap());
+
+        // pulling moves HEAD, but the index still refers to the commit it was built from
+        let head = commit("fn main() {}\nfn helper() {}\n");
+        assert_ne!(head, indexed);
+        assert_eq!(repo.indexed_commit, Some(indexed.to_string()));
+
+        repo.sync_done_with(repo.get_repo_metadata().await.unwrap());
+        assert_eq!(repo.indexed_commit, Some(head.to_string()));
+    }
 }
@@ -2,8 +2,10 @@ use std::{collections::HashMap, path::Path};
 
 use once_cell::sync::Lazy;
 use regex::Regex;
+use serde::Serialize;
 use smallvec::SmallVec;
 use tracing::warn;
+use utoipa::ToSchema;
 
 mod fs;
 mod git;
@@ -21,6 +23,29 @@ pub const MAX_FILE_LEN: u64 = AVG_LINE_LEN * MAX_LINE_COUNT;
 pub trait FileSource {
     fn len(&self) -> usize;
     fn for_each(self, iterator: impl Fn(RepoFile) + Sync + Send);
+
+    /// Number of files left out during discovery, by reason
+    fn skipped(&self) -> &HashMap<SkipReason, usize>;
+}
+
+/// Why a file is not indexed.
+#[derive(Serialize, ToSchema, Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord)]
+#[serde(rename_all = "snake_case")]
+pub enum SkipReason {
+    /// The extension is of a binary or media format
+    ExcludedExtension,
+    /// The file is vendored, e.g. under `node_modules`
+    Vendored,
+    /// The file is larger than `MAX_FILE_LEN`
+    TooLarge,
+    /// The file has more than `MAX_LINE_COUNT` lines
+    TooManyLines,
+    /// The file couldn't be read, or its blob exceeded `MAX_FILE_LEN`
+    Unreadable,
+    /// The notebook couldn't be parsed
+    MalformedNotebook,
+    /// The file is indexed already, with the same contents
+    Unchanged,
 }
 
 pub struct RepoFile {
@@ -47,7 +72,13 @@ impl FileType {
     }
 }
 
+#[cfg(test)]
 fn should_index<P: AsRef<Path>>(p: &P) -> bool {
+    skip_reason(p).is_none()
+}
+
+/// Why the file at `p` is left out of the index, if it is.
+fn skip_reason<P: AsRef<Path>>(p: &P) -> Option<SkipReason> {
     let path = p.as_ref();
 
     #[rustfmt::skip]
@@ -72,12 +103,12 @@ fn should_index<P: AsRef<Path>>(p: &P) -> bool {
     ];
 
     let Some(ext) = path.extension() else {
-        return true;
+        return None;
     };
 
     let ext = ext.to_string_lossy();
     if EXT_BLACKLIST.contains(&&*ext) {
-        return false;
+        return Some(SkipReason::ExcludedExtension);
     }
 
     static VENDOR_PATTERNS: Lazy<HashMap<&'static str, SmallVec<[Regex; 1]>>> = Lazy::new(|| {
@@ -113,8 +144,10 @@ fn should_index<P: AsRef<Path>>(p: &P) -> bool {
     });
 
     match VENDOR_PATTERNS.get(&*ext) {
-        None => true,
-        Some(rxs) => !rxs.iter().any(|r| r.is_match(&path.to_string_lossy())),
+        Some(rxs) if rxs.iter().any(|r| r.is_match(&path.to_string_lossy())) => {
+            Some(SkipReason::Vendored)
+        }
+        _ => None,
     }
 }
 
@@ -155,4 +188,17 @@ mod test {
             assert_eq!(should_index(&Path::new(path)), index);
         }
     }
+
+    #[test]
+    fn test_skip_reason() {
+        assert_eq!(
+            skip_reason(&Path::new("logo.png")),
+            Some(SkipReason::ExcludedExtension)
+        );
+        assert_eq!(
+            skip_reason(&Path::new("node_modules/react/index.js")),
+            Some(SkipReason::Vendored)
+        );
+        assert_eq!(skip_reason(&Path::new("src/main.rs")), None);
+    }
 }
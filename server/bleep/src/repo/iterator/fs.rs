@@ -6,31 +6,50 @@ use std::path::{Path, PathBuf};
 
 pub struct FileWalker {
     file_list: Vec<PathBuf>,
+    skipped: HashMap<SkipReason, usize>,
 }
 
 impl FileWalker {
     pub fn index_directory(dir: impl AsRef<Path>) -> impl FileSource {
+        let mut file_list = vec![];
+        let mut skipped = HashMap::new();
+
         // note: this WILL observe .gitignore files for the respective repos.
-        let file_list = ignore::Walk::new(&dir)
-            .filter_map(|de| match de {
-                Ok(de) => Some(de),
+        for de in ignore::Walk::new(&dir) {
+            let de = match de {
+                Ok(de) => de,
                 Err(err) => {
                     warn!(%err, "access failure; skipping");
-                    None
+                    continue;
                 }
-            })
+            };
+
             // Preliminarily ignore files that are very large, without reading the contents.
-            .filter(|de| matches!(de.metadata(), Ok(meta) if meta.len() < MAX_FILE_LEN))
-            .filter_map(|de| crate::canonicalize(de.into_path()).ok())
-            .filter(|p| {
-                p.strip_prefix(&dir)
-                    .as_ref()
-                    .map(should_index)
-                    .unwrap_or_default()
-            })
-            .collect();
+            let reason = match de.metadata() {
+                Ok(meta) if meta.len() < MAX_FILE_LEN => {
+                    let Ok(path) = crate::canonicalize(de.into_path()) else {
+                        continue;
+                    };
+                    let Ok(relative) = path.strip_prefix(&dir) else {
+                        continue;
+                    };
 
-        Self { file_list }
+                    match skip_reason(&relative) {
+                        Some(reason) => reason,
+                        None => {
+                            file_list.push(path);
+                            continue;
+                        }
+                    }
+                }
+                Ok(_) => SkipReason::TooLarge,
+                Err(_) => continue,
+            };
+
+            *skipped.entry(reason).or_default() += 1;
+        }
+
+        Self { file_list, skipped }
     }
 }
 
@@ -39,6 +58,10 @@ impl FileSource for FileWalker {
         self.file_list.len()
     }
 
+    fn skipped(&self) -> &HashMap<SkipReason, usize> {
+        &self.skipped
+    }
+
     fn for_each(self, iterator: impl Fn(RepoFile) + Sync + Send) {
         use rayon::prelude::*;
         self.file_list
@@ -37,6 +37,7 @@ fn human_readable_branch_name(r: &gix::Reference<'_>) -> String {
 pub struct GitWalker {
     git: ThreadSafeRepository,
     entries: HashMap<(String, FileType, gix::ObjectId), Vec<String>>,
+    skipped: HashMap<SkipReason, usize>,
 }
 
 impl GitWalker {
@@ -57,7 +58,7 @@ impl GitWalker {
             .map(|r| r.name().to_owned());
 
         let refs = local_git.references()?;
-        let entries = refs
+        let (entries, skipped) = refs
             .all()?
             .filter_map(Result::ok)
             .map(|r| {
@@ -81,26 +82,27 @@ impl GitWalker {
 
                 let files = tree.traverse().breadthfirst.files().unwrap().into_iter();
 
-                Some(
-                    files
-                        .map(move |entry| {
-                            let strpath = String::from_utf8_lossy(entry.filepath.as_ref());
-                            let full_path = root_dir.join(strpath.as_ref());
-                            (
-                                is_head,
-                                branch.clone(),
-                                full_path.to_string_lossy().to_string(),
-                                entry.mode,
-                                entry.oid,
-                            )
-                        })
-                        .filter(|(_, _, path, _, _)| should_index(path)),
-                )
+                Some(files.map(move |entry| {
+                    let strpath = String::from_utf8_lossy(entry.filepath.as_ref());
+                    let full_path = root_dir.join(strpath.as_ref());
+                    (
+                        is_head,
+                        branch.clone(),
+                        full_path.to_string_lossy().to_string(),
+                        entry.mode,
+                        entry.oid,
+                    )
+                }))
             })
             .flatten()
             .fold(
-                HashMap::new(),
-                |mut acc, (is_head, branch, file, mode, oid)| {
+                (HashMap::new(), HashMap::new()),
+                |(mut acc, mut skipped), (is_head, branch, file, mode, oid)| {
+                    if let Some(reason) = skip_reason(&file) {
+                        *skipped.entry(reason).or_default() += 1;
+                        return (acc, skipped);
+                    }
+
                     let kind = if mode.is_tree() {
                         FileType::Dir
                     } else if mode.is_blob() {
@@ -115,11 +117,15 @@ impl GitWalker {
                     }
 
                     branches.push(branch);
-                    acc
+                    (acc, skipped)
                 },
             );
 
-        Ok(Self { git, entries })
+        Ok(Self {
+            git,
+            entries,
+            skipped,
+        })
     }
 }
 
@@ -128,6 +134,10 @@ impl FileSource for GitWalker {
         self.entries.len()
     }
 
+    fn skipped(&self) -> &HashMap<SkipReason, usize> {
+        &self.skipped
+    }
+
     fn for_each(self, iterator: impl Fn(RepoFile) + Sync + Send) {
         use rayon::prelude::*;
         self.entries
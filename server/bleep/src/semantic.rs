@@ -1,29 +1,87 @@
-use std::{collections::HashMap, ops::Not, path::Path, sync::Arc};
+use std::{
+    collections::HashSet,
+    ops::Not,
+    path::Path,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
 
-use crate::{query::parser::NLQuery, Configuration};
+use crate::{
+    cache::CachePolicy,
+    query::parser::NLQuery,
+    repo::{normalize_relative_path, relative_path_variants},
+    symbol::SymbolLocations,
+    Configuration,
+};
 
-use ndarray::Axis;
+use ndarray::{Axis, Slice};
 use ort::{
     tensor::{FromArray, InputTensor, OrtOwnedTensor},
     Environment, ExecutionProvider, GraphOptimizationLevel, LoggingLevel, SessionBuilder,
 };
-use qdrant_client::{
-    prelude::{QdrantClient, QdrantClientConfig},
-    qdrant::{
-        r#match::MatchValue, vectors_config, with_payload_selector, with_vectors_selector,
-        CollectionOperationResponse, CreateCollection, Distance, FieldCondition, Filter, Match,
-        PointId, PointStruct, ScoredPoint, SearchPoints, Value, VectorParams, VectorsConfig,
-        WithPayloadSelector, WithVectorsSelector,
-    },
+use qdrant_client::qdrant::{
+    condition::ConditionOneOf, points_selector::PointsSelectorOneOf, value::Kind,
+    vectors::VectorsOptions, vectors_config, with_payload_selector, with_vectors_selector,
+    Condition, CreateCollection, Distance, Filter, HasIdCondition, PointId, PointStruct,
+    PointsIdsList, PointsSelector, RetrievedPoint, ScoredPoint, ScrollPoints, ScrollResponse,
+    SearchParams, SearchPoints, Vector, VectorParams, Vectors, VectorsConfig, WithPayloadSelector,
+    WithVectorsSelector,
 };
 
 use rayon::prelude::*;
 use thiserror::Error;
 use tracing::{debug, info, trace, warn};
 
+mod batch;
+pub mod breaker;
 pub mod chunk;
+pub mod filter;
+pub mod kind;
+pub mod limit;
+mod local;
+pub mod notebook;
+pub mod payload;
+pub mod post_process;
+mod query_cache;
+pub mod retry;
+pub mod similarity;
+pub mod store;
+pub mod trace;
+pub mod weights;
+
+use batch::EmbedQueue;
+use breaker::CircuitBreaker;
+use filter::{build_filter, make_kv_keyword_filter, FilterArgs, Folding};
+use kind::FileSymbols;
+use limit::EmbedLimit;
+use notebook::{CellKind, Notebook};
+use payload::{PayloadFields, PayloadSchema, TIE_BREAK_FIELDS};
+use post_process::{AsSearchResult, ResultPostProcessor, Unprocessed};
+use query_cache::{Preprocessing, QueryEmbeddings};
+use retry::{ChunkFailures, ChunkPayload, RetryReport};
+use similarity::Similarity;
+use store::VectorStore;
+use trace::{elapsed_ms, SearchTrace};
+use weights::{VectorWeights, BODY_VECTOR, DOC_VECTOR};
+
+/// The collection every instance shared before collection names were configurable
+pub const LEGACY_COLLECTION: &str = "documents";
+
+/// The distance metric of the `documents` collection. This determines the scale of the scores
+/// returned for a search, see [`normalize_scores`].
+pub const DISTANCE: Distance = Distance::Cosine;
+
+/// Dimension of the embeddings produced by the model
+pub const EMBEDDING_DIM: u64 = 384;
 
-const COLLECTION_NAME: &str = "documents";
+/// How long a fetched points count is reused
+const STATS_TTL: Duration = Duration::from_secs(30);
+
+/// Points fetched from qdrant at a time when browsing or migrating chunks
+const BROWSE_PAGE_SIZE: u32 = 256;
 
 #[derive(Error, Debug)]
 pub enum SemanticError {
@@ -44,22 +102,53 @@ pub enum SemanticError {
     },
 }
 
+/// Statistics of the `documents` collection, for telling whether a search hit a stale index.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct CollectionStats {
+    pub points: u64,
+
+    /// Unix timestamp of the last write this instance made to the collection, 0 if none
+    pub last_write: u64,
+}
+
 #[derive(Clone)]
 pub struct Semantic {
-    qdrant: Arc<QdrantClient>,
+    store: Arc<dyn VectorStore>,
     tokenizer: Arc<tokenizers::Tokenizer>,
     gpt2_tokenizer: Arc<tokenizers::Tokenizer>,
     session: Arc<ort::Session>,
+    embed_queue: Arc<EmbedQueue>,
+    embed_limit: EmbedLimit,
+    breaker: Arc<CircuitBreaker>,
+    query_embeddings: Arc<QueryEmbeddings>,
+    payload_schema: Arc<PayloadSchema>,
+    folding: Folding,
+    post_processor: Arc<dyn ResultPostProcessor>,
     config: Arc<Configuration>,
+
+    /// Whether the collection stores separate `body` and `doc` vectors per point
+    named_vectors: bool,
+
+    /// The shared collection of older versions, if this instance has its own collection and the
+    /// shared one still exists
+    legacy_collection: Option<&'static str>,
+
+    /// The last fetched points count, and when it was fetched
+    points_count: Arc<Mutex<Option<(Instant, u64)>>>,
+    last_write: Arc<AtomicU64>,
+    /// Bumped by every write this instance makes to the collection
+    generation: Arc<AtomicU64>,
+
+    failures: ChunkFailures,
 }
 
-fn collection_config() -> CreateCollection {
+fn collection_config(name: &str) -> CreateCollection {
     CreateCollection {
-        collection_name: COLLECTION_NAME.to_string(),
+        collection_name: name.to_owned(),
         vectors_config: Some(VectorsConfig {
             config: Some(vectors_config::Config::Params(VectorParams {
-                size: 384,
-                distance: Distance::Cosine.into(),
+                size: EMBEDDING_DIM,
+                distance: DISTANCE.into(),
             })),
         }),
         ..Default::default()
@@ -69,33 +158,47 @@ fn collection_config() -> CreateCollection {
 impl Semantic {
     pub async fn initialize(
         model_dir: &Path,
-        qdrant_url: &str,
+        store: Arc<dyn VectorStore>,
         config: Arc<Configuration>,
     ) -> Result<Self, SemanticError> {
-        let qdrant = QdrantClient::new(Some(QdrantClientConfig::from_url(qdrant_url)))
-            .await
-            .unwrap();
+        let collection = config.qdrant_collection.as_str();
 
-        match qdrant.has_collection(COLLECTION_NAME).await {
+        match store.has_collection(collection).await {
             Ok(has_collection) => {
                 if has_collection.not() {
-                    let CollectionOperationResponse { result, time } = qdrant
-                        .create_collection(&collection_config())
+                    store
+                        .create_collection(&collection_config(collection))
                         .await
                         .unwrap();
+                }
+            }
+            Err(_) => return Err(SemanticError::QdrantInitializationError),
+        }
 
-                    debug!(
-                        time,
-                        created = result,
-                        name = COLLECTION_NAME,
-                        "created qdrant collection"
+        // points indexed before this instance had its own collection are left behind in the
+        // shared one, until they are migrated
+        let legacy_collection = if collection == LEGACY_COLLECTION {
+            None
+        } else {
+            match store.has_collection(LEGACY_COLLECTION).await {
+                Ok(true) => {
+                    warn!(
+                        collection,
+                        legacy = LEGACY_COLLECTION,
+                        "found a legacy qdrant collection, move this instance's points out of it \
+                         with `POST /admin/semantic/migrate`"
                     );
-
-                    assert!(result);
+                    Some(LEGACY_COLLECTION)
                 }
+                Ok(false) => None,
+                Err(_) => return Err(SemanticError::QdrantInitializationError),
             }
+        };
+
+        let named_vectors = match store.named_vectors(collection).await {
+            Ok(named_vectors) => named_vectors,
             Err(_) => return Err(SemanticError::QdrantInitializationError),
-        }
+        };
 
         let environment = Arc::new(
             Environment::builder()
@@ -111,164 +214,487 @@ impl Semantic {
             1
         };
 
-        Ok(Self {
-            qdrant: qdrant.into(),
-            tokenizer: tokenizers::Tokenizer::from_file(model_dir.join("tokenizer.json"))
+        let tokenizer: Arc<tokenizers::Tokenizer> =
+            tokenizers::Tokenizer::from_file(model_dir.join("tokenizer.json"))
                 .unwrap()
-                .into(),
+                .into();
+        let session: Arc<ort::Session> = SessionBuilder::new(&environment)?
+            .with_optimization_level(GraphOptimizationLevel::Level3)?
+            .with_intra_threads(threads)?
+            .with_model_from_file(model_dir.join("model.onnx"))?
+            .into();
+
+        let embed_limit = EmbedLimit::new(
+            config.embedding_concurrency,
+            Duration::from_secs(config.embedding_wait_timeout_secs),
+        );
+        let embed_queue = {
+            let tokenizer = Arc::clone(&tokenizer);
+            let session = Arc::clone(&session);
+
+            EmbedQueue::new(
+                Duration::from_millis(config.embedding_batch_window_ms),
+                config.embedding_batch_size,
+                Arc::new(move |sequences: &[String]| {
+                    let sequences = sequences.iter().map(String::as_str).collect::<Vec<_>>();
+                    embed_batch(&tokenizer, &session, &sequences)
+                }),
+                embed_limit.clone(),
+            )
+        };
+
+        let query_embeddings = QueryEmbeddings::new(
+            query_cache::model_id(model_dir),
+            Preprocessing::new(&config),
+            config.query_embedding_cache_entries,
+        );
+        let payload_schema = PayloadSchema::parse(&config.payload_keys)?;
+        let breaker = CircuitBreaker::new(
+            config.qdrant_breaker_failures,
+            Duration::from_secs(config.qdrant_breaker_cooldown_secs),
+        );
+        let failures = ChunkFailures::load(&config.source)?;
+
+        Ok(Self {
+            store,
+            tokenizer,
             gpt2_tokenizer: tokenizers::Tokenizer::from_file(model_dir.join("gpt-2").join("tokenizer.json"))
                 .expect("unable to open gpt2-tokenizer, try `git lfs pull` and pass `--model-dir bloop/model` at the CLI")
                 .into(),
-            session: SessionBuilder::new(&environment)?
-                .with_optimization_level(GraphOptimizationLevel::Level3)?
-                .with_intra_threads(threads)?
-                .with_model_from_file(model_dir.join("model.onnx"))?
-                .into(),
+            session,
+            embed_queue: embed_queue.into(),
+            embed_limit,
+            breaker: breaker.into(),
+            query_embeddings: query_embeddings.into(),
+            payload_schema: payload_schema.into(),
+            folding: Folding::new(&config),
+            post_processor: Arc::new(Unprocessed),
             config,
+            named_vectors,
+            legacy_collection,
+            points_count: Arc::default(),
+            last_write: Arc::default(),
+            generation: Arc::default(),
+            failures,
         })
     }
 
+    /// Process search results with `processor`, rather than returning them as they are.
+    pub fn with_post_processor(mut self, processor: impl ResultPostProcessor + 'static) -> Self {
+        self.post_processor = Arc::new(processor);
+        self
+    }
+
+    /// Apply the [`ResultPostProcessor`] to the `results` found for `query`.
+    pub fn post_process<T: AsSearchResult>(&self, query: &str, results: Vec<T>) -> Vec<T> {
+        let views = results
+            .iter()
+            .map(|result| result.as_search_result(&self.payload_schema))
+            .collect::<Vec<_>>();
+        let order = self.post_processor.process(query, &views);
+        post_process::reorder(results, order)
+    }
+
+    /// The payload keys chunks are stored under, see `Configuration::payload_keys`.
+    pub fn payload_schema(&self) -> &PayloadSchema {
+        &self.payload_schema
+    }
+
+    /// The point of a chunk, keyed by the [`PayloadSchema`], along with the folded text its
+    /// filters match, see [`Folding`].
+    fn point(&self, payload: ChunkPayload, embedding: Vec<f32>) -> PointStruct {
+        let mut point = point(payload, embedding);
+        point.payload = self.payload_schema.store(point.payload);
+        self.folding.index(&self.payload_schema, &mut point.payload);
+        point
+    }
+
+    /// The Qdrant filter for `filters`, folded and keyed as chunks are indexed.
+    fn filter(&self, filters: FilterArgs) -> Option<Filter> {
+        build_filter(&filters.folded(self.folding).keyed(&self.payload_schema))
+    }
+
     pub async fn health_check(&self) -> anyhow::Result<()> {
-        self.qdrant.health_check().await?;
-        Ok(())
+        self.store.health_check().await
+    }
+
+    /// The collection this instance keeps its points in, see
+    /// `Configuration::qdrant_collection`.
+    pub fn collection(&self) -> &str {
+        &self.config.qdrant_collection
+    }
+
+    /// The collections searches run against, see `Configuration::qdrant_search_collections`.
+    /// Writes only ever go to [`Semantic::collection`].
+    pub fn search_collections(&self) -> Vec<&str> {
+        if self.config.qdrant_search_collections.is_empty() {
+            vec![self.collection()]
+        } else {
+            self.config
+                .qdrant_search_collections
+                .iter()
+                .map(String::as_str)
+                .collect()
+        }
     }
 
-    pub fn embed(&self, sequence: &str) -> anyhow::Result<Vec<f32>> {
-        let tokenizer_output = self.tokenizer.encode(sequence, true).unwrap();
+    /// The shared collection of older versions, if it existed alongside this instance's own
+    /// collection at startup.
+    pub fn legacy_collection(&self) -> Option<&str> {
+        self.legacy_collection
+    }
 
-        let input_ids = tokenizer_output.get_ids();
-        let attention_mask = tokenizer_output.get_attention_mask();
-        let token_type_ids = tokenizer_output.get_type_ids();
-        let length = input_ids.len();
-        trace!("embedding {} tokens {:?}", length, sequence);
+    /// Embed a single sequence.
+    ///
+    /// Concurrent calls are queued and run through the model together, see
+    /// `Configuration::embedding_batch_window_ms`.
+    pub async fn embed(&self, sequence: &str) -> anyhow::Result<Vec<f32>> {
+        self.embed_queue.embed(sequence).await
+    }
 
-        let inputs_ids_array = ndarray::Array::from_shape_vec(
-            (1, length),
-            input_ids.iter().map(|&x| x as i64).collect(),
-        )?;
+    /// Embed a search query, reusing the embedding of an earlier search for the same query.
+    ///
+    /// Queries are preprocessed before they are embedded, see `Configuration::strip_stopwords`.
+    /// Returns whether the embedding came from the cache.
+    pub async fn embed_query(&self, query: &str) -> anyhow::Result<(Vec<f32>, bool)> {
+        self.embed_query_with(query, CachePolicy::Reuse).await
+    }
 
-        let attention_mask_array = ndarray::Array::from_shape_vec(
-            (1, length),
-            attention_mask.iter().map(|&x| x as i64).collect(),
-        )?;
+    /// [`Semantic::embed_query`], only using the cache as `policy` allows.
+    pub async fn embed_query_with(
+        &self,
+        query: &str,
+        policy: CachePolicy,
+    ) -> anyhow::Result<(Vec<f32>, bool)> {
+        let key = self.query_embeddings.key(query);
+        if let Some(vector) = self.query_embeddings.get(&key).filter(|_| policy.reads()) {
+            return Ok((vector, true));
+        }
 
-        let token_type_ids_array = ndarray::Array::from_shape_vec(
-            (1, length),
-            token_type_ids.iter().map(|&x| x as i64).collect(),
-        )?;
+        let vector = self.embed(&key.text).await?;
+        if policy.writes() {
+            self.query_embeddings.insert(key, &vector);
+        }
+        Ok((vector, false))
+    }
 
-        let outputs = self.session.run([
-            InputTensor::from_array(inputs_ids_array.into_dyn()),
-            InputTensor::from_array(attention_mask_array.into_dyn()),
-            InputTensor::from_array(token_type_ids_array.into_dyn()),
-        ])?;
+    /// Embed a single sequence on the current thread, bypassing the batching queue.
+    ///
+    /// This is used while indexing, where chunks are already embedded in parallel.
+    pub fn embed_blocking(&self, sequence: &str) -> anyhow::Result<Vec<f32>> {
+        let mut embeddings = self.embed_batch(&[sequence])?;
+        Ok(embeddings.swap_remove(0))
+    }
 
-        let output_tensor: OrtOwnedTensor<f32, _> = outputs[0].try_extract().unwrap();
-        let sequence_embedding = &*output_tensor.view();
-        let pooled = sequence_embedding.mean_axis(Axis(1)).unwrap();
-        Ok(pooled.to_owned().as_slice().unwrap().to_vec())
+    /// Embed a single sequence on a blocking thread, bypassing the batching queue.
+    ///
+    /// Padding within a batch can shift an embedding slightly, so this always yields the same
+    /// vector for the same sequence.
+    async fn embed_unbatched(&self, sequence: &str) -> anyhow::Result<Vec<f32>> {
+        let tokenizer = Arc::clone(&self.tokenizer);
+        let session = Arc::clone(&self.session);
+        let sequence = sequence.to_owned();
+
+        let mut embeddings = self
+            .embed_limit
+            .run(move || embed_batch(&tokenizer, &session, &[&sequence]))
+            .await?;
+        Ok(embeddings.swap_remove(0))
+    }
+
+    /// Embed `sequences` in a single forward pass of the model.
+    ///
+    /// This blocks until fewer than `Configuration::embedding_concurrency` embeddings are
+    /// running, for up to `Configuration::embedding_wait_timeout_secs`, so it must not be called
+    /// from within an async context.
+    pub fn embed_batch(&self, sequences: &[&str]) -> anyhow::Result<Vec<Vec<f32>>> {
+        self.embed_limit
+            .run_blocking(|| embed_batch(&self.tokenizer, &self.session, sequences))
     }
 
+    /// Search for the chunks closest to the target of `parsed_query`.
+    ///
+    /// A `deterministic` search returns the same points in the same order for as long as the
+    /// collection is unchanged: the query is embedded on its own rather than in a batch, every
+    /// point is scanned instead of walking the HNSW graph, and score ties are broken with
+    /// [`sort_deterministically`]. Exact search grows linearly with the number of points
+    /// matching the filters, so this is meant for evaluation, not for interactive use.
+    ///
+    /// Only the payload `fields` are fetched with each point.
     pub async fn search<'a>(
         &self,
         parsed_query: &NLQuery<'a>,
+        filters: FilterArgs,
+        weights: VectorWeights,
+        fields: PayloadFields,
         limit: u64,
+        deterministic: bool,
     ) -> anyhow::Result<Vec<ScoredPoint>> {
+        self.search_traced(
+            parsed_query,
+            filters,
+            weights,
+            fields,
+            limit,
+            deterministic,
+            CachePolicy::Reuse,
+        )
+        .await
+        .map(|(points, _)| points)
+    }
+
+    /// [`Semantic::search`], along with a trace of how it ran.
+    ///
+    /// The query embedding is only cached as `cache` allows.
+    #[allow(clippy::too_many_arguments)]
+    pub async fn search_traced<'a>(
+        &self,
+        parsed_query: &NLQuery<'a>,
+        filters: FilterArgs,
+        weights: VectorWeights,
+        fields: PayloadFields,
+        limit: u64,
+        deterministic: bool,
+        cache: CachePolicy,
+    ) -> anyhow::Result<(Vec<ScoredPoint>, SearchTrace)> {
         let Some(query) = parsed_query.target() else {
             anyhow::bail!("no search target for query");
         };
 
-        let repo_filter = {
-            let conditions = parsed_query
-                .repos()
-                .map(|r| {
-                    if r.contains('/') && !r.starts_with("github.com/") {
-                        format!("github.com/{r}")
-                    } else {
-                        r.to_string()
-                    }
-                })
-                .map(|r| make_kv_keyword_filter("repo_name", r.as_str()).into())
-                .collect::<Vec<_>>();
-            // one of the above repos should match
-            if conditions.is_empty() {
-                None
-            } else {
-                Some(Filter {
-                    should: conditions,
-                    ..Default::default()
-                })
-            }
+        // deterministic searches embed the query on its own, so they don't share cached
+        // embeddings computed in a batch
+        let start = Instant::now();
+        let (vector, embed_cached) = if deterministic {
+            let text = self.query_embeddings.key(query).text;
+            (self.embed_unbatched(&text).await?, false)
+        } else {
+            self.embed_query_with(query, cache).await?
         };
+        let embed_ms = elapsed_ms(start);
 
-        let path_filter = {
-            let conditions = parsed_query
-                .paths()
-                .map(|r| make_kv_text_filter("relative_path", r).into())
-                .collect::<Vec<_>>();
-            if conditions.is_empty() {
-                None
-            } else {
-                Some(Filter {
-                    should: conditions,
-                    ..Default::default()
-                })
-            }
+        let (points, trace) = self
+            .search_with_vector(vector, filters, weights, fields, limit, deterministic)
+            .await?;
+
+        let trace = SearchTrace {
+            embed_ms,
+            embed_batched: !deterministic && !embed_cached,
+            embed_cached,
+            ..trace
         };
+        Ok((points, trace))
+    }
 
-        let lang_filter = {
-            let conditions = parsed_query
-                .langs()
-                .map(|l| make_kv_keyword_filter("lang", l).into())
-                .collect::<Vec<_>>();
-            // one of the above langs should match
-            if conditions.is_empty() {
-                None
-            } else {
-                Some(Filter {
-                    should: conditions,
-                    ..Default::default()
-                })
-            }
+    /// Blocking version of [`Semantic::search`], for callers without an async runtime.
+    ///
+    /// This must not be called from within an async context: it runs the search on a dedicated
+    /// runtime, and returns an error if one is already running on this thread.
+    pub fn search_blocking(
+        &self,
+        parsed_query: &NLQuery<'_>,
+        filters: FilterArgs,
+        weights: VectorWeights,
+        fields: PayloadFields,
+        limit: u64,
+        deterministic: bool,
+    ) -> anyhow::Result<Vec<ScoredPoint>> {
+        let Some(query) = parsed_query.target() else {
+            anyhow::bail!("no search target for query");
         };
 
-        let branch_filter = {
-            let conditions = parsed_query
-                .branch()
-                .map(|l| make_kv_keyword_filter("branches", l).into())
-                .collect::<Vec<_>>();
-
-            if conditions.is_empty() {
-                None
-            } else {
-                Some(Filter {
-                    should: conditions,
-                    ..Default::default()
-                })
+        // the batching queue lives on the runtime `Semantic` was initialized on, which may not
+        // be polled while we block
+        let key = self.query_embeddings.key(query);
+        let vector = match self.query_embeddings.get(&key) {
+            Some(vector) => vector,
+            None => {
+                let vector = self.embed_blocking(&key.text)?;
+                self.query_embeddings.insert(key, &vector);
+                vector
             }
         };
+        block_on(self.search_with_vector(vector, filters, weights, fields, limit, deterministic))?
+            .map(|(points, _)| points)
+    }
 
-        let filters = [repo_filter, path_filter, lang_filter, branch_filter]
-            .into_iter()
-            .flatten()
-            .map(Into::into)
+    /// [`Semantic::search`] for an already embedded query.
+    pub async fn search_with_vector(
+        &self,
+        vector: Vec<f32>,
+        filters: FilterArgs,
+        weights: VectorWeights,
+        fields: PayloadFields,
+        limit: u64,
+        deterministic: bool,
+    ) -> anyhow::Result<(Vec<ScoredPoint>, SearchTrace)> {
+        let mut trace = SearchTrace::default();
+        if filters.matches_nothing() {
+            trace.skipped = true;
+            return Ok((vec![], trace));
+        }
+
+        let filter = self.filter(filters);
+        let params = deterministic.then(|| SearchParams {
+            exact: Some(true),
+            ..Default::default()
+        });
+        let fields = if deterministic {
+            fields.with(TIE_BREAK_FIELDS.iter().copied())
+        } else {
+            fields
+        };
+        let fields = self.payload_schema.keys_of(fields);
+
+        let start = Instant::now();
+        let collections = self.search_collections();
+        let searches = collections.iter().map(|collection| {
+            self.search_collection(
+                collection,
+                vector.clone(),
+                filter.clone(),
+                params.clone(),
+                weights,
+                &fields,
+                limit,
+            )
+        });
+        let results = futures::future::join_all(searches).await;
+
+        let results = collections
+            .iter()
+            .zip(results)
+            .map(|(collection, result)| {
+                let result = result.map(|(points, candidates)| {
+                    for (vector, count) in candidates {
+                        *trace.qdrant_candidates.entry(vector.into()).or_default() += count;
+                    }
+                    points
+                });
+                (collection.to_string(), result)
+            })
             .collect();
+        let (mut points, unavailable) = merge_collections(results, limit as usize)?;
+        trace.qdrant_ms = elapsed_ms(start);
+        trace.candidates = points.len();
+        trace.unavailable_collections = unavailable;
+
+        if deterministic {
+            sort_deterministically(&mut points, &self.payload_schema);
+        }
+
+        Ok((points, trace))
+    }
+
+    /// Search a single `collection`, merging the results of each of its vectors. Points
+    /// returned for each vector are counted by vector name, `default` for unnamed vectors.
+    #[allow(clippy::too_many_arguments)]
+    async fn search_collection(
+        &self,
+        collection: &str,
+        vector: Vec<f32>,
+        filter: Option<Filter>,
+        params: Option<SearchParams>,
+        weights: VectorWeights,
+        fields: &PayloadFields,
+        limit: u64,
+    ) -> anyhow::Result<(Vec<ScoredPoint>, Vec<(&'static str, usize)>)> {
+        if !self.named_vectors {
+            let points = self
+                .search_vector(search_points(
+                    collection, None, vector, filter, params, fields, limit,
+                ))
+                .await?;
+            let candidates = vec![("default", points.len())];
+            Ok((points, candidates))
+        } else if !weights.uses_doc() {
+            let points = self
+                .search_vector(search_points(
+                    collection,
+                    Some(BODY_VECTOR),
+                    vector,
+                    filter,
+                    params,
+                    fields,
+                    limit,
+                ))
+                .await?;
+            let candidates = vec![(BODY_VECTOR, points.len())];
+            Ok((points, candidates))
+        } else {
+            let (body, doc) = futures::try_join!(
+                self.search_vector(search_points(
+                    collection,
+                    Some(BODY_VECTOR),
+                    vector.clone(),
+                    filter.clone(),
+                    params.clone(),
+                    fields,
+                    limit
+                )),
+                self.search_vector(search_points(
+                    collection,
+                    Some(DOC_VECTOR),
+                    vector,
+                    filter,
+                    params,
+                    fields,
+                    limit
+                )),
+            )?;
+            let candidates = vec![(BODY_VECTOR, body.len()), (DOC_VECTOR, doc.len())];
+
+            Ok((weights.combine(body, doc, limit as usize), candidates))
+        }
+    }
+
+    /// Search through the [`CircuitBreaker`], which fails with [`BreakerOpen`] while the
+    /// store keeps failing.
+    async fn search_vector(&self, request: SearchPoints) -> anyhow::Result<Vec<ScoredPoint>> {
+        self.breaker.call(self.store.search(&request)).await
+    }
+
+    /// Scroll through the [`CircuitBreaker`], like [`Semantic::search_vector`].
+    async fn scroll_points(&self, request: ScrollPoints) -> anyhow::Result<ScrollResponse> {
+        self.breaker.call(self.store.scroll(&request)).await
+    }
+
+    /// Chunks whose text contains any of `keywords`, verbatim or as folded by [`Folding`].
+    ///
+    /// This is a fallback for literal matches that vector search misses, so points are returned
+    /// in storage order and without a score.
+    pub async fn keyword_search(
+        &self,
+        filters: FilterArgs,
+        keywords: &[String],
+        limit: u32,
+    ) -> anyhow::Result<Vec<RetrievedPoint>> {
+        if keywords.is_empty() || filters.matches_nothing() {
+            return Ok(vec![]);
+        }
+
+        let schema = &self.payload_schema;
+        let mut filter = self.filter(filters).unwrap_or_default();
+        filter.must.push(
+            Filter {
+                should: keywords
+                    .iter()
+                    .map(|k| self.folding.text_condition(schema, "snippet", k))
+                    .collect(),
+                ..Default::default()
+            }
+            .into(),
+        );
 
         let response = self
-            .qdrant
-            .search_points(&SearchPoints {
-                collection_name: COLLECTION_NAME.to_string(),
-                limit,
-                vector: self.embed(query)?,
+            .scroll_points(ScrollPoints {
+                collection_name: self.collection().to_owned(),
+                filter: Some(filter),
+                limit: Some(limit),
                 with_payload: Some(WithPayloadSelector {
                     selector_options: Some(with_payload_selector::SelectorOptions::Enable(true)),
                 }),
-                filter: Some(Filter {
-                    must: filters,
-                    ..Default::default()
-                }),
                 with_vectors: Some(WithVectorsSelector {
-                    selector_options: Some(with_vectors_selector::SelectorOptions::Enable(true)),
+                    selector_options: Some(with_vectors_selector::SelectorOptions::Enable(false)),
                 }),
                 ..Default::default()
             })
@@ -277,7 +703,63 @@ impl Semantic {
         Ok(response.result)
     }
 
-    #[tracing::instrument(skip(self, repo_ref, relative_path, buffer))]
+    /// Every chunk matching `filters`, with the payload of a `Snippet`, in storage order and
+    /// without a score.
+    ///
+    /// Returns `None` once more than `max_points` chunks match, without fetching the rest.
+    pub async fn browse(
+        &self,
+        filters: FilterArgs,
+        max_points: usize,
+    ) -> anyhow::Result<Option<Vec<RetrievedPoint>>> {
+        if filters.matches_nothing() {
+            return Ok(Some(vec![]));
+        }
+
+        let filter = self.filter(filters);
+        let mut points = vec![];
+        let mut offset = None;
+
+        loop {
+            let response = self
+                .scroll_points(ScrollPoints {
+                    collection_name: self.collection().to_owned(),
+                    filter: filter.clone(),
+                    offset,
+                    limit: Some(BROWSE_PAGE_SIZE),
+                    with_payload: Some(
+                        self.payload_schema
+                            .keys_of(PayloadFields::snippet())
+                            .selector(),
+                    ),
+                    with_vectors: Some(WithVectorsSelector {
+                        selector_options: Some(with_vectors_selector::SelectorOptions::Enable(
+                            false,
+                        )),
+                    }),
+                    ..Default::default()
+                })
+                .await?;
+
+            points.extend(response.result);
+            if points.len() > max_points {
+                return Ok(None);
+            }
+
+            match response.next_page_offset {
+                Some(next) => offset = Some(next),
+                None => return Ok(Some(points)),
+            }
+        }
+    }
+
+    /// Chunk and embed `buffer`, replacing all existing points for the same path.
+    ///
+    /// Chunks are classified with the `symbols` of the file, see [`FileSymbols::classify`].
+    ///
+    /// Returns the number of points written.
+    #[allow(clippy::too_many_arguments)]
+    #[tracing::instrument(skip(self, repo_ref, relative_path, buffer, symbols))]
     pub async fn insert_points_for_buffer(
         &self,
         repo_name: &str,
@@ -286,91 +768,526 @@ impl Semantic {
         buffer: &str,
         lang_str: &str,
         branches: &[String],
-    ) {
+        symbols: &SymbolLocations,
+    ) -> usize {
         // Delete all points corresponding to the same path
         self.delete_points_by_path(repo_ref, std::iter::once(relative_path))
             .await;
 
-        let chunks = chunk::by_tokens(
+        let Ok(notebook) = parse_notebook(relative_path, buffer, &self.config) else {
+            return 0;
+        };
+
+        let lang = lang_str.to_ascii_lowercase();
+        let chunks = self.chunk_buffer(
             repo_name,
             relative_path,
             buffer,
-            &self.tokenizer,
-            50..self.config.max_chunk_tokens,
-            15,
+            notebook.as_ref(),
+            &lang,
             self.overlap_strategy(),
         );
         debug!(chunk_count = chunks.len(), "found chunks");
 
-        // Prepend all chunks with `repo_name   relative_path`
-        let chunk_prefix = format!("{repo_name}\t{relative_path}\n");
-
-        let datapoints = chunks
-            .par_iter()
-            .filter_map(
-                |chunk| match self.embed(&(chunk_prefix.clone() + chunk.data)) {
-                    Ok(ok) => Some(PointStruct {
-                        id: Some(PointId::from(uuid::Uuid::new_v4().to_string())),
-                        vectors: Some(ok.into()),
-                        payload: HashMap::from([
-                            ("lang".into(), lang_str.to_ascii_lowercase().into()),
-                            ("repo_name".into(), repo_name.into()),
-                            ("repo_ref".into(), repo_ref.into()),
-                            ("branches".into(), Value::from(branches.to_owned())),
-                            ("relative_path".into(), relative_path.into()),
-                            ("snippet".into(), chunk.data.into()),
-                            (
-                                "start_line".into(),
-                                chunk.range.start.line.to_string().into(),
-                            ),
-                            ("end_line".into(), chunk.range.end.line.to_string().into()),
-                            (
-                                "start_byte".into(),
-                                chunk.range.start.byte.to_string().into(),
-                            ),
-                            ("end_byte".into(), chunk.range.end.byte.to_string().into()),
-                        ]),
-                    }),
+        // symbol ranges refer to the raw buffer, not to notebook cells
+        let symbols = match notebook {
+            Some(_) => FileSymbols::default(),
+            None => FileSymbols::new(buffer, symbols),
+        };
+
+        let (datapoints, failed): (Vec<_>, Vec<_>) = chunks
+            .into_par_iter()
+            .map(|(chunk, lang, cell_index)| {
+                let (kind, definitions) = symbols.classify(&chunk.range);
+                let payload = ChunkPayload {
+                    repo_name: repo_name.to_owned(),
+                    repo_ref: repo_ref.to_owned(),
+                    relative_path: relative_path.to_owned(),
+                    lang: lang.to_owned(),
+                    branches: branches.to_owned(),
+                    snippet: chunk.data.to_owned(),
+                    start_line: chunk.range.start.line,
+                    end_line: chunk.range.end.line,
+                    start_byte: chunk.range.start.byte,
+                    end_byte: chunk.range.end.byte,
+                    cell_index,
+                    kind,
+                    definitions,
+                };
+
+                match self.embed_blocking(&payload.embedding_input()) {
+                    Ok(embedding) => Ok((payload, embedding)),
                     Err(err) => {
-                        warn!(?err, %chunk_prefix, "embedding failed");
-                        None
+                        warn!(?err, relative_path, "embedding failed");
+                        Err((payload, err.to_string()))
                     }
-                },
-            )
-            .collect::<Vec<_>>();
+                }
+            })
+            .partition_map(|result| match result {
+                Ok(point) => rayon::iter::Either::Left(point),
+                Err(failure) => rayon::iter::Either::Right(failure),
+            });
 
-        if !datapoints.is_empty() {
-            let num_datapoints = datapoints.len();
+        let written = if !datapoints.is_empty() {
+            let (payloads, points) = datapoints
+                .into_iter()
+                .map(|(payload, embedding)| (payload.clone(), self.point(payload, embedding)))
+                .unzip::<_, _, Vec<_>, Vec<_>>();
+
+            let num_datapoints = points.len();
             debug!(point_count = num_datapoints, "updating docs");
-            let upserted = self.qdrant.upsert_points(COLLECTION_NAME, datapoints).await;
-            if upserted.is_ok() {
-                info!(
-                    ?chunk_prefix,
-                    "Successfully upserted {:?} vectors", num_datapoints
-                );
-            } else {
-                warn!(
-                    ?chunk_prefix,
-                    "Failed to upsert {:?} vectors", num_datapoints
-                );
+            match self.upsert(points).await {
+                Ok(()) => {
+                    info!(
+                        relative_path,
+                        "Successfully upserted {num_datapoints} vectors"
+                    );
+                    num_datapoints
+                }
+                Err(err) => {
+                    warn!(
+                        ?err,
+                        relative_path, "Failed to upsert {num_datapoints} vectors"
+                    );
+                    self.record_failures(
+                        payloads
+                            .into_iter()
+                            .map(|payload| (payload, err.to_string()))
+                            .collect(),
+                    );
+                    0
+                }
             }
         } else {
-            warn!(?chunk_prefix, "No vectors to insert");
+            warn!(relative_path, "No vectors to insert");
+            0
+        };
+
+        self.record_failures(failed);
+        written
+    }
+
+    /// Retry the chunks of `repo_ref` that failed to make it into the index, see
+    /// [`ChunkFailures`]. With `force`, every failure is retried, regardless of backoff or
+    /// attempts.
+    pub async fn retry_failures(&self, repo_ref: &str, force: bool) -> anyhow::Result<RetryReport> {
+        let due = self.failures.due(repo_ref, force);
+        if due.is_empty() {
+            return Ok(RetryReport::default());
+        }
+
+        let embeddings = futures::future::join_all(
+            due.iter()
+                .map(|payload| async move { self.embed(&payload.embedding_input()).await }),
+        )
+        .await;
+
+        let mut embedded = vec![];
+        let mut failed = vec![];
+        for (payload, embedding) in due.into_iter().zip(embeddings) {
+            match embedding {
+                Ok(embedding) => embedded.push((payload, embedding)),
+                Err(err) => failed.push((payload, err.to_string())),
+            }
+        }
+
+        let mut report = RetryReport {
+            retried: embedded.len() + failed.len(),
+            ..Default::default()
+        };
+
+        if !embedded.is_empty() {
+            let payloads = embedded.iter().map(|(p, _)| p.clone()).collect::<Vec<_>>();
+            let points = embedded
+                .into_iter()
+                .map(|(payload, embedding)| self.point(payload, embedding))
+                .collect();
+
+            match self.upsert(points).await {
+                Ok(()) => {
+                    report.succeeded = payloads.len();
+                    self.failures.resolve(&payloads)?;
+                }
+                Err(err) => failed.extend(payloads.into_iter().map(|p| (p, err.to_string()))),
+            }
+        }
+
+        report.failed = failed.len();
+        self.failures.record(failed)?;
+
+        Ok(report)
+    }
+
+    /// Re-embed the chunk `id` with `text` as its snippet, keeping the rest of its payload.
+    ///
+    /// Returns the updated point, or `None` if there is no such point.
+    pub async fn update_chunk(
+        &self,
+        id: PointId,
+        text: &str,
+    ) -> anyhow::Result<Option<PointStruct>> {
+        let response = self
+            .scroll_points(ScrollPoints {
+                collection_name: self.collection().to_owned(),
+                filter: Some(Filter {
+                    must: vec![Condition {
+                        condition_one_of: Some(ConditionOneOf::HasId(HasIdCondition {
+                            has_id: vec![id],
+                        })),
+                    }],
+                    ..Default::default()
+                }),
+                limit: Some(1),
+                with_payload: Some(WithPayloadSelector {
+                    selector_options: Some(with_payload_selector::SelectorOptions::Enable(true)),
+                }),
+                with_vectors: Some(WithVectorsSelector {
+                    selector_options: Some(with_vectors_selector::SelectorOptions::Enable(true)),
+                }),
+                ..Default::default()
+            })
+            .await?;
+
+        // chunks hidden from searches can't be updated either
+        let Some(point) = self.post_process("", response.result).into_iter().next() else {
+            return Ok(None);
+        };
+
+        let schema = &self.payload_schema;
+        let payload_str = |field| {
+            let value = point.payload.get(schema.key(field));
+            match value.and_then(|v| v.kind.as_ref()) {
+                Some(Kind::StringValue(s)) => s.as_str(),
+                _ => "",
+            }
+        };
+        let input = format!(
+            "{}\t{}\n{text}",
+            payload_str("repo_name"),
+            payload_str("relative_path")
+        );
+
+        let embedding = self.embed(&input).await?;
+        let mut point = updated_point(schema, point, text, embedding)?;
+        self.folding.index(schema, &mut point.payload);
+        self.breaker.call(self.upsert(vec![point.clone()])).await?;
+
+        Ok(Some(point))
+    }
+
+    /// Chunks that failed to make it into the index.
+    pub fn failures(&self) -> &ChunkFailures {
+        &self.failures
+    }
+
+    fn record_failures(&self, failed: Vec<(ChunkPayload, String)>) {
+        if let Err(err) = self.failures.record(failed) {
+            warn!(?err, "failed to persist chunk failures");
+        }
+    }
+
+    async fn upsert(&self, points: Vec<PointStruct>) -> anyhow::Result<()> {
+        self.store.upsert(self.collection(), points).await?;
+        self.record_write();
+        Ok(())
+    }
+
+    /// Number of chunks `buffer` is split into with `strategy`, without embedding them.
+    pub fn count_chunks(
+        &self,
+        repo_name: &str,
+        relative_path: &str,
+        buffer: &str,
+        strategy: chunk::OverlapStrategy,
+    ) -> usize {
+        let Ok(notebook) = parse_notebook(relative_path, buffer, &self.config) else {
+            return 0;
+        };
+
+        self.chunk_buffer(
+            repo_name,
+            relative_path,
+            buffer,
+            notebook.as_ref(),
+            "",
+            strategy,
+        )
+        .len()
+    }
+
+    /// Split `buffer` into chunks, each with its language and notebook cell index.
+    fn chunk_buffer<'a>(
+        &self,
+        repo_name: &str,
+        relative_path: &str,
+        buffer: &'a str,
+        notebook: Option<&'a Notebook>,
+        lang: &'a str,
+        strategy: chunk::OverlapStrategy,
+    ) -> Vec<(chunk::Chunk<'a>, &'a str, Option<usize>)> {
+        let split = |src| {
+            chunk::by_tokens(
+                repo_name,
+                relative_path,
+                src,
+                &self.tokenizer,
+                50..self.config.max_chunk_tokens,
+                15,
+                strategy,
+            )
+        };
+
+        match notebook {
+            Some(notebook) => {
+                let code_lang = notebook.lang.as_deref().unwrap_or(lang);
+                notebook
+                    .cells
+                    .iter()
+                    .flat_map(|cell| {
+                        let cell_lang = match cell.kind {
+                            CellKind::Code => code_lang,
+                            CellKind::Markdown => "markdown",
+                        };
+                        split(&cell.source)
+                            .into_iter()
+                            .map(move |c| (c, cell_lang, Some(cell.index)))
+                    })
+                    .collect()
+            }
+            None => split(buffer).into_iter().map(|c| (c, lang, None)).collect(),
         }
     }
 
     pub async fn delete_points_by_path(&self, repo_ref: &str, paths: impl Iterator<Item = &str>) {
-        let repo_filter = make_kv_keyword_filter("repo_ref", repo_ref).into();
-        let file_filter = paths
-            .map(|p| make_kv_keyword_filter("relative_path", p).into())
-            .collect::<Vec<_>>();
-        let selector = Filter {
-            must: vec![repo_filter],
-            should: file_filter,
+        let paths = paths.collect::<Vec<_>>();
+        if let Err(err) = self.failures.forget(repo_ref, &paths) {
+            warn!(?err, "failed to persist chunk failures");
+        }
+
+        let selector = paths_filter(&self.payload_schema, repo_ref, paths.into_iter()).into();
+        let _ = self.store.delete(self.collection(), &selector).await;
+        self.record_write();
+    }
+
+    /// Page through the points of a repository, returning the id and relative path of each
+    /// point, and the offset of the next page if there is one.
+    ///
+    /// Paths are normalized to `/` separators, see [`normalize_relative_path`].
+    pub async fn scroll_paths(
+        &self,
+        repo_ref: &str,
+        offset: Option<PointId>,
+        limit: u32,
+    ) -> anyhow::Result<(Vec<(PointId, String)>, Option<PointId>)> {
+        let response = self
+            .scroll(repo_ref, offset, limit, &["relative_path"], false)
+            .await?;
+
+        let schema = &self.payload_schema;
+        let points = response
+            .result
+            .into_iter()
+            .filter_map(|mut point| {
+                let id = point.id?;
+                let path = schema.take(&mut point.payload, "relative_path")?;
+                match path.kind? {
+                    Kind::StringValue(path) => Some((id, normalize_relative_path(&path).into())),
+                    _ => None,
+                }
+            })
+            .collect();
+
+        Ok((points, response.next_page_offset))
+    }
+
+    /// Page through the points of a repository with their embeddings and location payload,
+    /// keyed by the [`PayloadSchema`].
+    pub async fn scroll_chunks(
+        &self,
+        repo_ref: &str,
+        offset: Option<PointId>,
+        limit: u32,
+    ) -> anyhow::Result<(Vec<RetrievedPoint>, Option<PointId>)> {
+        let response = self
+            .scroll(
+                repo_ref,
+                offset,
+                limit,
+                &["relative_path", "start_line", "end_line"],
+                true,
+            )
+            .await?;
+
+        Ok((response.result, response.next_page_offset))
+    }
+
+    async fn scroll(
+        &self,
+        repo_ref: &str,
+        offset: Option<PointId>,
+        limit: u32,
+        fields: &[&str],
+        with_vectors: bool,
+    ) -> anyhow::Result<ScrollResponse> {
+        let response = self
+            .scroll_points(ScrollPoints {
+                collection_name: self.collection().to_owned(),
+                filter: Some(self.repo_filter(repo_ref)),
+                offset,
+                limit: Some(limit),
+                with_payload: Some(
+                    self.payload_schema
+                        .keys_of(PayloadFields::only(fields.iter().copied()))
+                        .selector(),
+                ),
+                with_vectors: Some(WithVectorsSelector {
+                    selector_options: Some(with_vectors_selector::SelectorOptions::Enable(
+                        with_vectors,
+                    )),
+                }),
+                ..Default::default()
+            })
+            .await?;
+
+        Ok(response)
+    }
+
+    /// The points of `repo_ref`.
+    fn repo_filter(&self, repo_ref: &str) -> Filter {
+        let key = self.payload_schema.key("repo_ref");
+        Filter {
+            must: vec![make_kv_keyword_filter(key, repo_ref).into()],
             ..Default::default()
         }
-        .into();
-        let _ = self.qdrant.delete_points(COLLECTION_NAME, &selector).await;
+    }
+
+    pub async fn delete_points(&self, ids: Vec<PointId>) -> anyhow::Result<()> {
+        let selector = PointsSelector {
+            points_selector_one_of: Some(PointsSelectorOneOf::Points(PointsIdsList { ids })),
+        };
+
+        self.store.delete(self.collection(), &selector).await?;
+        self.record_write();
+        Ok(())
+    }
+
+    /// Move the points of `repo_refs` out of the legacy collection, into this instance's own
+    /// collection, keeping their ids, payloads and vectors.
+    ///
+    /// Points of other repositories are left behind for the instances they belong to. Returns
+    /// the number of points moved for each repository.
+    pub async fn migrate_legacy(
+        &self,
+        repo_refs: &[String],
+    ) -> anyhow::Result<Vec<(String, usize)>> {
+        let Some(legacy) = self.legacy_collection else {
+            anyhow::bail!("there is no legacy collection to migrate from");
+        };
+
+        let mut migrated = vec![];
+        for repo_ref in repo_refs {
+            let mut moved = 0;
+
+            // moved points leave the legacy collection, so every page is read from the start
+            loop {
+                let response = self
+                    .store
+                    .scroll(&ScrollPoints {
+                        collection_name: legacy.to_owned(),
+                        filter: Some(self.repo_filter(repo_ref)),
+                        limit: Some(BROWSE_PAGE_SIZE),
+                        with_payload: Some(PayloadFields::all().selector()),
+                        with_vectors: Some(WithVectorsSelector {
+                            selector_options: Some(with_vectors_selector::SelectorOptions::Enable(
+                                true,
+                            )),
+                        }),
+                        ..Default::default()
+                    })
+                    .await?;
+
+                if response.result.is_empty() {
+                    break;
+                }
+
+                let ids = response
+                    .result
+                    .iter()
+                    .filter_map(|p| p.id.clone())
+                    .collect();
+                let points = response
+                    .result
+                    .into_iter()
+                    .map(|point| PointStruct {
+                        id: point.id,
+                        payload: point.payload,
+                        vectors: point.vectors,
+                    })
+                    .collect::<Vec<_>>();
+
+                moved += points.len();
+                self.upsert(points).await?;
+
+                let selector = PointsSelector {
+                    points_selector_one_of: Some(PointsSelectorOneOf::Points(PointsIdsList {
+                        ids,
+                    })),
+                };
+                self.store.delete(legacy, &selector).await?;
+            }
+
+            info!(
+                repo_ref,
+                moved, legacy, "migrated points out of the legacy collection"
+            );
+            migrated.push((repo_ref.clone(), moved));
+        }
+
+        Ok(migrated)
+    }
+
+    /// [`Semantic::collection_stats`], fetching the points count regardless of when it was last
+    /// fetched.
+    pub async fn fresh_collection_stats(&self) -> anyhow::Result<CollectionStats> {
+        *self.points_count.lock().unwrap() = None;
+        self.collection_stats().await
+    }
+
+    /// Statistics of the collection. The points count is fetched at most once every
+    /// [`STATS_TTL`], unless this instance writes to the collection in the meantime.
+    pub async fn collection_stats(&self) -> anyhow::Result<CollectionStats> {
+        let cached = *self.points_count.lock().unwrap();
+        let points = match cached {
+            Some((fetched, points)) if fetched.elapsed() < STATS_TTL => points,
+            _ => {
+                let points = self.store.points_count(self.collection()).await?;
+
+                *self.points_count.lock().unwrap() = Some((Instant::now(), points));
+                points
+            }
+        };
+
+        Ok(CollectionStats {
+            points,
+            last_write: self.last_write.load(Ordering::Relaxed),
+        })
+    }
+
+    /// Changes whenever this instance writes to the collection, including the upserts of every
+    /// index run and every delete, so results computed at the same generation are still current.
+    pub fn generation(&self) -> u64 {
+        self.generation.load(Ordering::Acquire)
+    }
+
+    fn record_write(&self) {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or_default();
+
+        self.last_write.store(now, Ordering::Relaxed);
+        self.generation.fetch_add(1, Ordering::AcqRel);
+        *self.points_count.lock().unwrap() = None;
     }
 
     pub fn gpt2_token_count(&self, input: &str) -> usize {
@@ -385,59 +1302,86 @@ impl Semantic {
     }
 }
 
-// Exact match filter
-fn make_kv_keyword_filter(key: &str, value: &str) -> FieldCondition {
-    let key = key.to_owned();
-    let value = value.to_owned();
-    FieldCondition {
-        key,
-        r#match: Some(Match {
-            match_value: MatchValue::Keyword(value).into(),
-        }),
-        ..Default::default()
+fn embed_batch(
+    tokenizer: &tokenizers::Tokenizer,
+    session: &ort::Session,
+    sequences: &[&str],
+) -> anyhow::Result<Vec<Vec<f32>>> {
+    if sequences.is_empty() {
+        return Ok(vec![]);
     }
-}
 
-// Substring match filter
-fn make_kv_text_filter(key: &str, value: &str) -> FieldCondition {
-    let key = key.to_owned();
-    let value = value.to_owned();
-    FieldCondition {
-        key,
-        r#match: Some(Match {
-            match_value: MatchValue::Text(value).into(),
-        }),
-        ..Default::default()
-    }
-}
+    let encodings = sequences
+        .iter()
+        .map(|&sequence| tokenizer.encode(sequence, true))
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|e| anyhow::anyhow!(e))?;
 
-fn dot(a: &[f32], b: &[f32]) -> f32 {
-    a.iter().zip(b.iter()).map(|(ai, bi)| ai * bi).sum()
-}
+    // shorter sequences are padded up to the longest one in the batch
+    let batch_size = encodings.len();
+    let length = encodings
+        .iter()
+        .map(|e| e.get_ids().len())
+        .max()
+        .unwrap_or_default();
+    trace!(batch_size, length, "embedding batch");
 
-fn norm(a: &[f32]) -> f32 {
-    dot(a, a)
-}
+    let padded = |values: fn(&tokenizers::Encoding) -> &[u32]| {
+        let data = encodings
+            .iter()
+            .flat_map(|e| {
+                values(e)
+                    .iter()
+                    .map(|&x| x as i64)
+                    .chain(std::iter::repeat(0))
+                    .take(length)
+            })
+            .collect();
 
-fn cosine_similarity(a: &[f32], b: &[f32]) -> f32 {
-    dot(a, b) / (norm(a) * norm(b))
+        ndarray::Array::from_shape_vec((batch_size, length), data)
+    };
+
+    let outputs = session.run([
+        InputTensor::from_array(padded(|e| e.get_ids())?.into_dyn()),
+        InputTensor::from_array(padded(|e| e.get_attention_mask())?.into_dyn()),
+        InputTensor::from_array(padded(|e| e.get_type_ids())?.into_dyn()),
+    ])?;
+
+    let output_tensor: OrtOwnedTensor<f32, _> = outputs[0].try_extract().unwrap();
+    let sequence_embeddings = &*output_tensor.view();
+
+    // mean-pool over the tokens of each sequence, excluding padding
+    Ok(encodings
+        .iter()
+        .enumerate()
+        .map(|(i, encoding)| {
+            let tokens = sequence_embeddings.index_axis(Axis(0), i);
+            let tokens = tokens.slice_axis(Axis(0), Slice::from(0..encoding.get_ids().len()));
+            tokens.mean_axis(Axis(0)).unwrap().iter().copied().collect()
+        })
+        .collect())
 }
 
 // returns a list of indices to preserve from `snippets`
 //
 // query_embedding: the embedding of the query terms
 // embeddings: the list of embeddings to select from
+// boosts: added to the relevance of the embedding at the same index, or empty for no boosts
 // lambda: MMR is a weighted selection of two opposing factors:
 //    - relevance to the query
 //    - "novelty" or, the measure of how minimal the similarity is
 //      to existing documents in the selection
 //      The value of lambda skews the weightage in favor of either relevance or novelty.
 //  k: the number of embeddings to select
+//  similarity: how alike embeddings are, which should follow the distance metric of the
+//    collection they were ranked in
 pub fn deduplicate_with_mmr(
     query_embedding: &[f32],
     embeddings: &[&[f32]],
+    boosts: &[f32],
     lambda: f32,
     k: usize,
+    similarity: Similarity,
 ) -> Vec<usize> {
     let mut idxs = vec![];
 
@@ -453,12 +1397,13 @@ pub fn deduplicate_with_mmr(
             if idxs.contains(&i) {
                 continue;
             }
-            let first_part = cosine_similarity(query_embedding, emb);
+            let first_part =
+                similarity.of(query_embedding, emb) + boosts.get(i).copied().unwrap_or_default();
             let mut second_part = 0.;
             for j in idxs.iter() {
-                let cos_sim = cosine_similarity(emb, embeddings[*j]);
-                if cos_sim > second_part {
-                    second_part = cos_sim;
+                let sim = similarity.of(emb, embeddings[*j]);
+                if sim > second_part {
+                    second_part = sim;
                 }
             }
             let equation_score = lambda * first_part - (1. - lambda) * second_part;
@@ -473,3 +1418,576 @@ pub fn deduplicate_with_mmr(
     }
     idxs
 }
+
+/// Merge the points found in each collection of a sharded index into the `limit` best ones,
+/// returning them with the collections that could not be searched.
+///
+/// Every collection is embedded with the same model and scored with [`DISTANCE`], so raw scores
+/// are comparable across collections, and are normalized over the merged set by callers. A
+/// point found in several collections is only kept with its best score. This fails only if no
+/// collection could be searched.
+fn merge_collections(
+    results: Vec<(String, anyhow::Result<Vec<ScoredPoint>>)>,
+    limit: usize,
+) -> anyhow::Result<(Vec<ScoredPoint>, Vec<String>)> {
+    let searched = results.len();
+    let mut points = vec![];
+    let mut unavailable = vec![];
+    let mut last_error = None;
+
+    for (collection, result) in results {
+        match result {
+            Ok(found) => points.extend(found),
+            Err(err) => {
+                warn!(
+                    ?err,
+                    collection, "qdrant collection unavailable, results are partial"
+                );
+                unavailable.push(collection);
+                last_error = Some(err);
+            }
+        }
+    }
+
+    if let Some(err) = last_error.filter(|_| unavailable.len() == searched) {
+        return Err(err);
+    }
+
+    points.sort_by(|a, b| b.score.total_cmp(&a.score));
+
+    let mut seen = HashSet::new();
+    points.retain(
+        |point| match point.id.as_ref().and_then(weights::point_key) {
+            Some(key) => seen.insert(key),
+            None => true,
+        },
+    );
+    points.truncate(limit);
+
+    Ok((points, unavailable))
+}
+
+/// Order `points` by descending score, breaking ties by the repository, path and position of
+/// each chunk, then by point id, so equally scored points always come out in the same order.
+///
+/// Those are read at the keys `schema` stores them under.
+pub fn sort_deterministically(points: &mut [ScoredPoint], schema: &PayloadSchema) {
+    fn tie_break<'a>(
+        point: &'a ScoredPoint,
+        schema: &PayloadSchema,
+    ) -> (
+        Option<&'a str>,
+        Option<&'a str>,
+        Option<usize>,
+        Option<usize>,
+        Option<String>,
+    ) {
+        let text = |field| match point.payload.get(schema.key(field))?.kind.as_ref()? {
+            Kind::StringValue(s) => Some(s.as_str()),
+            _ => None,
+        };
+        let number = |field| match point.payload.get(schema.key(field))?.kind.as_ref()? {
+            Kind::StringValue(s) => s.parse().ok(),
+            Kind::IntegerValue(i) => usize::try_from(*i).ok(),
+            _ => None,
+        };
+
+        (
+            text("repo_ref"),
+            text("relative_path"),
+            number("cell_index"),
+            number("start_byte"),
+            point.id.as_ref().and_then(weights::point_key),
+        )
+    }
+
+    points.sort_by(|a, b| {
+        b.score
+            .total_cmp(&a.score)
+            .then_with(|| tie_break(a, schema).cmp(&tie_break(b, schema)))
+    });
+}
+
+// Map raw Qdrant scores onto `[0, 1]`, preserving their ranking order. The raw scale depends
+// on the distance metric of the collection:
+//
+//  Cosine: similarity in `[-1, 1]`, higher is better. Normalized as `(score + 1) / 2`.
+//  Dot:    unbounded similarity, higher is better. Min-max scaled over the result set.
+//  Euclid: unbounded distance, lower is better. Min-max scaled over the result set, and
+//          inverted so that the closest result scores highest.
+//
+// When min-max scaling a result set where all scores are equal, every result scores `1.0`.
+pub fn normalize_scores(distance: Distance, scores: &[f32]) -> Vec<f32> {
+    let min = scores.iter().copied().fold(f32::INFINITY, f32::min);
+    let max = scores.iter().copied().fold(f32::NEG_INFINITY, f32::max);
+    let range = max - min;
+
+    let min_max = |score: f32| {
+        if range > 0.0 {
+            (score - min) / range
+        } else {
+            1.0
+        }
+    };
+
+    scores
+        .iter()
+        .map(|&score| match distance {
+            Distance::Cosine => ((score + 1.0) / 2.0).clamp(0.0, 1.0),
+            Distance::Euclid => 1.0 - min_max(score),
+            _ => min_max(score),
+        })
+        .collect()
+}
+
+/// `point` with a new `snippet`, stored at its key in `schema`, and its `embedding`, which must
+/// have [`EMBEDDING_DIM`] dimensions. In collections with named vectors, only the `body` vector
+/// is replaced.
+pub(crate) fn updated_point(
+    schema: &PayloadSchema,
+    point: RetrievedPoint,
+    snippet: &str,
+    embedding: Vec<f32>,
+) -> anyhow::Result<PointStruct> {
+    if embedding.len() as u64 != EMBEDDING_DIM {
+        anyhow::bail!(
+            "expected an embedding of {EMBEDDING_DIM} dimensions, got {}",
+            embedding.len()
+        );
+    }
+
+    let RetrievedPoint {
+        id,
+        mut payload,
+        vectors,
+    } = point;
+    payload.insert(schema.key("snippet").into(), snippet.into());
+
+    let vectors = match vectors.and_then(|v| v.vectors_options) {
+        Some(VectorsOptions::Vectors(mut named)) => {
+            named
+                .vectors
+                .insert(BODY_VECTOR.into(), Vector { data: embedding });
+            Vectors {
+                vectors_options: Some(VectorsOptions::Vectors(named)),
+            }
+        }
+        _ => embedding.into(),
+    };
+
+    Ok(PointStruct {
+        id,
+        vectors: Some(vectors),
+        payload,
+    })
+}
+
+fn point(payload: ChunkPayload, embedding: Vec<f32>) -> PointStruct {
+    PointStruct {
+        id: Some(PointId::from(uuid::Uuid::new_v4().to_string())),
+        vectors: Some(embedding.into()),
+        payload: payload.into_qdrant(),
+    }
+}
+
+/// The request for the `limit` points closest to `vector`, with their payload `fields`.
+fn search_points(
+    collection: &str,
+    vector_name: Option<&str>,
+    vector: Vec<f32>,
+    filter: Option<Filter>,
+    params: Option<SearchParams>,
+    fields: &PayloadFields,
+    limit: u64,
+) -> SearchPoints {
+    SearchPoints {
+        collection_name: collection.to_owned(),
+        limit,
+        vector,
+        vector_name: vector_name.map(ToOwned::to_owned),
+        with_payload: Some(fields.selector()),
+        filter,
+        with_vectors: Some(WithVectorsSelector {
+            selector_options: Some(with_vectors_selector::SelectorOptions::Enable(true)),
+        }),
+        params,
+        ..Default::default()
+    }
+}
+
+/// Run `future` to completion on a dedicated single-threaded runtime.
+///
+/// Blocking inside an async context would stall its executor, so this fails if a runtime is
+/// already running on the current thread.
+fn block_on<F: std::future::Future>(future: F) -> anyhow::Result<F::Output> {
+    if tokio::runtime::Handle::try_current().is_ok() {
+        anyhow::bail!("blocking call made from within an async context");
+    }
+
+    let runtime = tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()?;
+
+    Ok(runtime.block_on(future))
+}
+
+/// Parse `buffer` as a notebook, if `relative_path` is one.
+///
+/// Notebooks are embedded cell by cell, with their outputs dropped.
+fn parse_notebook(
+    relative_path: &str,
+    buffer: &str,
+    config: &Configuration,
+) -> Result<Option<Notebook>, notebook::NotebookError> {
+    if !relative_path.ends_with(".ipynb") {
+        return Ok(None);
+    }
+
+    match Notebook::parse(buffer, config.index_notebook_markdown) {
+        Ok(notebook) => Ok(Some(notebook)),
+        Err(err) => {
+            warn!(%err, %relative_path, "skipping malformed notebook");
+            Err(err)
+        }
+    }
+}
+
+/// Points of the files at `paths` in `repo_ref`, including any indexed with `\` separators.
+fn paths_filter<'a>(
+    schema: &PayloadSchema,
+    repo_ref: &str,
+    paths: impl Iterator<Item = &'a str>,
+) -> Filter {
+    let repo_filter = make_kv_keyword_filter(schema.key("repo_ref"), repo_ref).into();
+    let file_filter = paths
+        .flat_map(relative_path_variants)
+        .map(|p| make_kv_keyword_filter(schema.key("relative_path"), &p).into())
+        .collect::<Vec<_>>();
+
+    Filter {
+        must: vec![repo_filter],
+        should: file_filter,
+        ..Default::default()
+    }
+}
+
+/// A [`Semantic`] over an in-process store in `dir`, along with the store, for tests.
+///
+/// This loads the model at the root of the repository, which is checked out with `git lfs`.
+#[cfg(test)]
+pub(crate) async fn local_for_tests(dir: &Path) -> (Semantic, Arc<local::LocalStore>) {
+    let mut config = serde_json::from_value::<Configuration>(serde_json::json!({
+        "index_dir": dir,
+    }))
+    .unwrap();
+    config.source.set_default_dir(dir);
+
+    let model_dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("../../model");
+    let store = Arc::new(local::LocalStore::open(&dir.join("vectors")).unwrap());
+    let semantic = Semantic::initialize(&model_dir, store.clone(), Arc::new(config))
+        .await
+        .unwrap();
+
+    (semantic, store)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use qdrant_client::qdrant::NamedVectors;
+    use std::collections::HashMap;
+
+    fn assert_normalized(distance: Distance, scores: &[f32]) {
+        let normalized = normalize_scores(distance, scores);
+        assert!(normalized.iter().all(|s| (0.0..=1.0).contains(s)));
+
+        let order = |scores: &[f32]| {
+            let mut idxs = (0..scores.len()).collect::<Vec<_>>();
+            idxs.sort_by(|&a, &b| scores[b].total_cmp(&scores[a]));
+            idxs
+        };
+
+        match distance {
+            // lower distances rank higher
+            Distance::Euclid => {
+                let negated = scores.iter().map(|s| -s).collect::<Vec<_>>();
+                assert_eq!(order(&negated), order(&normalized));
+            }
+            _ => assert_eq!(order(scores), order(&normalized)),
+        }
+    }
+
+    #[test]
+    fn updated_points_keep_their_payload() {
+        let embedding = |value| Vector {
+            data: vec![value; EMBEDDING_DIM as usize],
+        };
+        let named = |body: f32, doc: f32| Vectors {
+            vectors_options: Some(VectorsOptions::Vectors(NamedVectors {
+                vectors: HashMap::from([
+                    (BODY_VECTOR.to_owned(), embedding(body)),
+                    (DOC_VECTOR.to_owned(), embedding(doc)),
+                ]),
+            })),
+        };
+        let point = || RetrievedPoint {
+            id: Some(PointId::from(42)),
+            payload: HashMap::from([
+                ("relative_path".to_owned(), "src/main.rs".into()),
+                ("snippet".to_owned(), "fn main() {}".into()),
+            ]),
+            vectors: Some(named(0.0, 1.0)),
+        };
+
+        let schema = PayloadSchema::default();
+        let updated =
+            updated_point(&schema, point(), "fn main() { run() }", embedding(0.5).data).unwrap();
+        assert_eq!(updated.id, Some(PointId::from(42)));
+        assert_eq!(updated.payload["relative_path"], "src/main.rs".into());
+        assert_eq!(updated.payload["snippet"], "fn main() { run() }".into());
+        assert_eq!(updated.vectors, Some(named(0.5, 1.0)));
+
+        assert!(updated_point(&schema, point(), "fn main() {}", vec![0.5; 3]).is_err());
+
+        let schema = PayloadSchema::parse(&["snippet=content".into()]).unwrap();
+        let updated = updated_point(&schema, point(), "fn run() {}", embedding(0.5).data).unwrap();
+        assert_eq!(updated.payload["content"], "fn run() {}".into());
+        assert_eq!(updated.payload["snippet"], "fn main() {}".into());
+    }
+
+    #[test]
+    fn windows_paths_are_deleted_in_both_forms() {
+        let filter = paths_filter(
+            &PayloadSchema::default(),
+            "local//bloop",
+            [r"src\webserver\query.rs", "Cargo.toml"].into_iter(),
+        );
+
+        assert_eq!(
+            filter.should,
+            vec![
+                make_kv_keyword_filter("relative_path", "src/webserver/query.rs").into(),
+                make_kv_keyword_filter("relative_path", r"src\webserver\query.rs").into(),
+                make_kv_keyword_filter("relative_path", "Cargo.toml").into(),
+            ]
+        );
+        assert_eq!(
+            filter.must,
+            vec![make_kv_keyword_filter("repo_ref", "local//bloop").into()]
+        );
+    }
+
+    #[test]
+    fn block_on_without_runtime() {
+        assert_eq!(block_on(async { 42 }).unwrap(), 42);
+    }
+
+    #[tokio::test]
+    async fn block_on_rejects_async_context() {
+        assert!(block_on(async { 42 }).is_err());
+    }
+
+    #[test]
+    fn blocking_searches_return_results() {
+        let dir = tempdir::TempDir::new("semantic").unwrap();
+        let (semantic, _) = block_on(local_for_tests(dir.path())).unwrap();
+
+        let snippet = "fn parse_query(query: &str) -> Query {}";
+        let chunk = ChunkPayload {
+            repo_name: "bloop".into(),
+            repo_ref: "local//bloop".into(),
+            relative_path: "src/query.rs".into(),
+            lang: "rust".into(),
+            branches: vec!["main".into()],
+            snippet: snippet.into(),
+            start_line: 0,
+            end_line: 1,
+            start_byte: 0,
+            end_byte: snippet.len(),
+            cell_index: None,
+            kind: Default::default(),
+            definitions: vec![],
+        };
+        let point = semantic.point(chunk, semantic.embed_blocking(snippet).unwrap());
+        block_on(semantic.upsert(vec![point])).unwrap().unwrap();
+
+        // no runtime is running on this thread
+        let query = crate::query::parser::parse_nl("how are queries parsed").unwrap();
+        let points = semantic
+            .search_blocking(
+                &query,
+                FilterArgs::new(filter::FilterLogic::And),
+                VectorWeights::default(),
+                PayloadFields::snippet(),
+                10,
+                false,
+            )
+            .unwrap();
+
+        assert_eq!(points.len(), 1);
+        assert_eq!(
+            points[0].payload["snippet"].kind,
+            Some(Kind::StringValue(snippet.into()))
+        );
+    }
+
+    #[tokio::test]
+    async fn open_breakers_also_stop_keyword_matches_browsing_and_updates() {
+        let dir = tempdir::TempDir::new("semantic").unwrap();
+        let (mut semantic, _) = local_for_tests(dir.path()).await;
+        semantic.breaker = CircuitBreaker::new(1, Duration::from_secs(30)).into();
+        let down = async { Err::<(), _>(anyhow::anyhow!("down")) };
+        semantic.breaker.call(down).await.unwrap_err();
+
+        let filters = || FilterArgs::new(filter::FilterLogic::And);
+        let paused = |err: anyhow::Error| err.is::<breaker::BreakerOpen>();
+
+        let keywords = ["parse".to_owned()];
+        let keyword_search = semantic.keyword_search(filters(), &keywords, 10).await;
+        assert!(paused(keyword_search.unwrap_err()));
+        assert!(paused(semantic.browse(filters(), 10).await.unwrap_err()));
+        let scrolled = semantic.scroll_paths("local//bloop", None, 10).await;
+        assert!(paused(scrolled.unwrap_err()));
+        let updated = semantic.update_chunk(PointId::from(1u64), "main").await;
+        assert!(paused(updated.unwrap_err()));
+    }
+
+    #[test]
+    fn normalized_scores_are_bounded_and_ordered() {
+        assert_normalized(Distance::Cosine, &[0.93, 0.71, 0.2, -0.4, -1.0]);
+        assert_normalized(Distance::Dot, &[42.0, 12.5, 3.0, -7.25]);
+        assert_normalized(Distance::Euclid, &[0.1, 0.8, 2.5, 11.0]);
+
+        assert_eq!(
+            normalize_scores(Distance::Cosine, &[1.0, 0.0]),
+            vec![1.0, 0.5]
+        );
+        assert_eq!(normalize_scores(Distance::Dot, &[3.0, 3.0]), vec![1.0, 1.0]);
+        assert!(normalize_scores(Distance::Dot, &[]).is_empty());
+    }
+
+    #[test]
+    fn mmr_duplicates_follow_the_similarity() {
+        let query = [1.0, 0.0];
+        let (across, short, long) = ([0.0, 0.5], [0.5, 0.0], [2.0, 0.0]);
+        let embeddings: [&[f32]; 3] = [&across, &short, &long];
+        let select =
+            |similarity| deduplicate_with_mmr(&query, &embeddings, &[], 0.5, 2, similarity);
+
+        // `short` and `long` point the same way, so they are duplicates by cosine only
+        assert_eq!(select(Similarity::Cosine), vec![1, 0]);
+        // the longest embedding is the most relevant by dot product
+        assert_eq!(select(Similarity::Dot), vec![2, 0]);
+        // `long` is far from `short`, but `across` is farther from the query
+        assert_eq!(select(Similarity::Euclidean), vec![1, 2]);
+    }
+
+    #[test]
+    fn instances_only_touch_their_own_collection() {
+        assert_eq!(collection_config("staging").collection_name, "staging");
+
+        let search = search_points(
+            "staging",
+            None,
+            vec![],
+            None,
+            None,
+            &PayloadFields::all(),
+            10,
+        );
+        assert_eq!(search.collection_name, "staging");
+    }
+
+    #[test]
+    fn sharded_results_are_merged_by_score() {
+        let point = |id: u64, score| ScoredPoint {
+            id: Some(PointId::from(id)),
+            score,
+            ..Default::default()
+        };
+        let ids = |points: &[ScoredPoint]| {
+            points
+                .iter()
+                .map(|p| weights::point_key(p.id.as_ref().unwrap()).unwrap())
+                .collect::<Vec<_>>()
+        };
+        let rust = || ("rust".to_owned(), Ok(vec![point(1, 0.9), point(2, 0.4)]));
+        let go = || ("go".to_owned(), Ok(vec![point(3, 0.7), point(1, 0.5)]));
+
+        let (merged, unavailable) = merge_collections(vec![rust(), go()], 10).unwrap();
+        assert_eq!(ids(&merged), ["1", "3", "2"]);
+        assert_eq!(merged[0].score, 0.9);
+        assert!(unavailable.is_empty());
+
+        let (merged, _) = merge_collections(vec![rust(), go()], 2).unwrap();
+        assert_eq!(ids(&merged), ["1", "3"]);
+
+        // an unavailable collection only makes the results partial
+        let down = || ("go".to_owned(), Err(anyhow::anyhow!("connection refused")));
+        let (merged, unavailable) = merge_collections(vec![rust(), down()], 10).unwrap();
+        assert_eq!(ids(&merged), ["1", "2"]);
+        assert_eq!(unavailable, ["go"]);
+
+        assert!(merge_collections(vec![down()], 10).is_err());
+    }
+
+    #[test]
+    fn searches_only_fetch_the_requested_fields() {
+        let included = |fields: &PayloadFields| match search_points(
+            LEGACY_COLLECTION,
+            None,
+            vec![],
+            None,
+            None,
+            fields,
+            10,
+        )
+        .with_payload
+        .and_then(|p| p.selector_options)
+        {
+            Some(with_payload_selector::SelectorOptions::Include(include)) => Some(include.fields),
+            Some(with_payload_selector::SelectorOptions::Enable(true)) => None,
+            other => panic!("unexpected payload selector {other:?}"),
+        };
+
+        assert_eq!(
+            included(&PayloadFields::snippet()),
+            Some(
+                [
+                    "cell_index",
+                    "definitions",
+                    "end_byte",
+                    "end_line",
+                    "kind",
+                    "lang",
+                    "relative_path",
+                    "repo_name",
+                    "repo_ref",
+                    "snippet",
+                    "start_byte",
+                    "start_line",
+                    "symbol",
+                ]
+                .map(String::from)
+                .to_vec()
+            )
+        );
+
+        assert_eq!(
+            included(&PayloadFields::only(["snippet"]).with(TIE_BREAK_FIELDS.iter().copied())),
+            Some(
+                [
+                    "cell_index",
+                    "relative_path",
+                    "repo_ref",
+                    "snippet",
+                    "start_byte"
+                ]
+                .map(String::from)
+                .to_vec()
+            )
+        );
+
+        assert_eq!(included(&PayloadFields::all().with(["kind"])), None);
+    }
+}
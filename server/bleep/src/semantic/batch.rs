@@ -0,0 +1,160 @@
+use std::{sync::Arc, time::Duration};
+
+use anyhow::anyhow;
+use tokio::{
+    sync::{mpsc, oneshot},
+    time::Instant,
+};
+use tracing::{trace, warn};
+
+use super::limit::EmbedLimit;
+
+type Embedding = Vec<f32>;
+type Request = (String, oneshot::Sender<anyhow::Result<Embedding>>);
+
+/// Embeds a batch of sequences, returning one embedding per sequence in the same order.
+pub(super) type BatchFn = dyn Fn(&[String]) -> anyhow::Result<Vec<Embedding>> + Send + Sync;
+
+/// Queues concurrent embedding requests and runs them through the model together.
+///
+/// A batch is flushed once `window` has elapsed since its first request, or once it holds
+/// `max_batch` requests, whichever comes first.
+pub(super) struct EmbedQueue {
+    sender: mpsc::UnboundedSender<Request>,
+}
+
+impl EmbedQueue {
+    /// Spawn the batching task. It runs until the queue is dropped.
+    ///
+    /// Each batch takes a permit from `limit` while it runs through the model.
+    pub(super) fn new(
+        window: Duration,
+        max_batch: usize,
+        embed_batch: Arc<BatchFn>,
+        limit: EmbedLimit,
+    ) -> Self {
+        let (sender, receiver) = mpsc::unbounded_channel();
+        tokio::spawn(run(receiver, window, max_batch.max(1), embed_batch, limit));
+
+        Self { sender }
+    }
+
+    pub(super) async fn embed(&self, sequence: &str) -> anyhow::Result<Embedding> {
+        let (tx, rx) = oneshot::channel();
+        self.sender
+            .send((sequence.to_owned(), tx))
+            .map_err(|_| anyhow!("embedding queue is closed"))?;
+
+        rx.await.map_err(|_| anyhow!("embedding request dropped"))?
+    }
+}
+
+async fn run(
+    mut receiver: mpsc::UnboundedReceiver<Request>,
+    window: Duration,
+    max_batch: usize,
+    embed_batch: Arc<BatchFn>,
+    limit: EmbedLimit,
+) {
+    while let Some(first) = receiver.recv().await {
+        let deadline = Instant::now() + window;
+        let mut batch = vec![first];
+
+        while batch.len() < max_batch {
+            match tokio::time::timeout_at(deadline, receiver.recv()).await {
+                Ok(Some(request)) => batch.push(request),
+                // either the window elapsed, or all senders are gone
+                Ok(None) | Err(_) => break,
+            }
+        }
+
+        let (sequences, senders): (Vec<_>, Vec<_>) = batch.into_iter().unzip();
+        trace!(size = sequences.len(), "flushing embedding batch");
+
+        let embed_batch = Arc::clone(&embed_batch);
+        let result = limit.run(move || embed_batch(&sequences)).await;
+
+        match result {
+            Ok(embeddings) if embeddings.len() == senders.len() => {
+                for (tx, embedding) in senders.into_iter().zip(embeddings) {
+                    _ = tx.send(Ok(embedding));
+                }
+            }
+            Ok(embeddings) => {
+                warn!(
+                    expected = senders.len(),
+                    got = embeddings.len(),
+                    "embedding batch size mismatch"
+                );
+                for tx in senders {
+                    _ = tx.send(Err(anyhow!("embedding batch size mismatch")));
+                }
+            }
+            Err(err) => {
+                warn!(?err, "embedding batch failed");
+                for tx in senders {
+                    _ = tx.send(Err(anyhow!("embedding batch failed: {err}")));
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn concurrent_requests_are_batched() {
+        let calls = Arc::new(AtomicUsize::new(0));
+        let embed_batch = {
+            let calls = Arc::clone(&calls);
+            Arc::new(
+                move |sequences: &[String]| -> anyhow::Result<Vec<Embedding>> {
+                    calls.fetch_add(1, Ordering::SeqCst);
+                    Ok(sequences.iter().map(|s| vec![s.len() as f32]).collect())
+                },
+            )
+        };
+
+        let queue = Arc::new(EmbedQueue::new(
+            Duration::from_millis(50),
+            16,
+            embed_batch,
+            EmbedLimit::new(1, Duration::from_secs(10)),
+        ));
+
+        let requests = (0..64)
+            .map(|i| {
+                let queue = Arc::clone(&queue);
+                tokio::spawn(async move { queue.embed(&"x".repeat(i)).await.unwrap() })
+            })
+            .collect::<Vec<_>>();
+
+        for (i, request) in requests.into_iter().enumerate() {
+            assert_eq!(request.await.unwrap(), vec![i as f32]);
+        }
+
+        let calls = calls.load(Ordering::SeqCst);
+        assert!(calls >= 4, "batches should hold at most 16 requests");
+        assert!(
+            calls < 64,
+            "requests should share batches, got {calls} batches"
+        );
+    }
+
+    #[tokio::test]
+    async fn failed_batches_fail_every_request() {
+        let queue = EmbedQueue::new(
+            Duration::from_millis(5),
+            16,
+            Arc::new(|_: &[String]| -> anyhow::Result<Vec<Embedding>> {
+                Err(anyhow!("model failure"))
+            }),
+            EmbedLimit::new(1, Duration::from_secs(10)),
+        );
+
+        assert!(queue.embed("foo").await.is_err());
+    }
+}
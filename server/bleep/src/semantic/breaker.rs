@@ -0,0 +1,179 @@
+use std::{
+    future::Future,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use thiserror::Error;
+
+/// Returned instead of searching while qdrant keeps failing, see [`CircuitBreaker`].
+#[derive(Error, Debug)]
+#[error("the semantic index is failing, searches resume in {}s", retry_in.as_secs().max(1))]
+pub struct BreakerOpen {
+    pub retry_in: Duration,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+enum State {
+    Closed {
+        failures: u32,
+    },
+    Open {
+        until: Instant,
+    },
+    /// A single call was let through to test whether qdrant recovered
+    Probing {
+        since: Instant,
+    },
+}
+
+/// Stops sending searches to qdrant once it keeps failing, see
+/// `Configuration::qdrant_breaker_failures`. Keyword matches, browsing and chunk updates go
+/// through it too.
+///
+/// After `threshold` consecutive failures, calls fail fast with [`BreakerOpen`] for `cooldown`.
+/// The first call after that is a probe: the breaker closes again if it succeeds, and stays open
+/// for another `cooldown` otherwise. A probe that never finishes, because its caller went
+/// away, is replaced by another one after `cooldown`.
+pub(super) struct CircuitBreaker {
+    threshold: u32,
+    cooldown: Duration,
+    state: Mutex<State>,
+}
+
+impl CircuitBreaker {
+    /// A breaker opening after `threshold` consecutive failures, or never if it is 0.
+    pub(super) fn new(threshold: u32, cooldown: Duration) -> Self {
+        Self {
+            threshold,
+            cooldown,
+            state: Mutex::new(State::Closed { failures: 0 }),
+        }
+    }
+
+    /// Run `call`, unless the breaker is open.
+    pub(super) async fn call<T>(
+        &self,
+        call: impl Future<Output = anyhow::Result<T>>,
+    ) -> anyhow::Result<T> {
+        self.admit(Instant::now())?;
+        let result = call.await;
+        self.record(result.is_ok(), Instant::now());
+        result
+    }
+
+    fn admit(&self, now: Instant) -> Result<(), BreakerOpen> {
+        let mut state = self.state.lock().unwrap();
+        let until = match *state {
+            State::Closed { .. } => return Ok(()),
+            State::Open { until } => until,
+            State::Probing { since } => since + self.cooldown,
+        };
+
+        if now < until {
+            return Err(BreakerOpen {
+                retry_in: until - now,
+            });
+        }
+
+        *state = State::Probing { since: now };
+        Ok(())
+    }
+
+    fn record(&self, succeeded: bool, now: Instant) {
+        if self.threshold == 0 {
+            return;
+        }
+
+        let mut state = self.state.lock().unwrap();
+        *state = match *state {
+            _ if succeeded => State::Closed { failures: 0 },
+            State::Closed { failures } if failures + 1 < self.threshold => State::Closed {
+                failures: failures + 1,
+            },
+            // calls let through before the breaker opened can still fail afterwards
+            State::Open { until } => State::Open { until },
+            _ => State::Open {
+                until: now + self.cooldown,
+            },
+        };
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const COOLDOWN: Duration = Duration::from_secs(30);
+
+    #[test]
+    fn repeated_failures_open_the_breaker_until_a_probe_succeeds() {
+        let breaker = CircuitBreaker::new(3, COOLDOWN);
+        let start = Instant::now();
+
+        for _ in 0..3 {
+            breaker.admit(start).unwrap();
+            breaker.record(false, start);
+        }
+
+        // further calls fail fast for the cooldown
+        let open = breaker.admit(start + Duration::from_secs(10)).unwrap_err();
+        assert_eq!(open.retry_in, Duration::from_secs(20));
+
+        // a failed probe opens the breaker for another cooldown, and only one is let through
+        let probe = start + COOLDOWN;
+        breaker.admit(probe).unwrap();
+        assert!(breaker.admit(probe).is_err());
+        breaker.record(false, probe);
+        assert!(breaker.admit(probe + Duration::from_secs(29)).is_err());
+
+        // a successful probe closes it again
+        let probe = probe + COOLDOWN;
+        breaker.admit(probe).unwrap();
+        breaker.record(true, probe);
+        assert_eq!(
+            *breaker.state.lock().unwrap(),
+            State::Closed { failures: 0 }
+        );
+        breaker.admit(probe).unwrap();
+    }
+
+    #[test]
+    fn successes_reset_the_failure_count() {
+        let breaker = CircuitBreaker::new(2, COOLDOWN);
+        let now = Instant::now();
+
+        for succeeded in [false, true, false, true] {
+            breaker.admit(now).unwrap();
+            breaker.record(succeeded, now);
+        }
+        breaker.admit(now).unwrap();
+
+        let disabled = CircuitBreaker::new(0, COOLDOWN);
+        for _ in 0..10 {
+            disabled.record(false, now);
+        }
+        disabled.admit(now).unwrap();
+    }
+
+    #[tokio::test]
+    async fn open_breakers_skip_the_call() {
+        let breaker = CircuitBreaker::new(1, COOLDOWN);
+        let failed = breaker
+            .call(async { Err::<(), _>(anyhow::anyhow!("connection refused")) })
+            .await;
+        assert!(failed.is_err());
+
+        let mut called = false;
+        let skipped = breaker
+            .call(async {
+                called = true;
+                Ok(())
+            })
+            .await
+            .unwrap_err();
+
+        assert!(!called);
+        assert!(skipped.downcast_ref::<BreakerOpen>().is_some());
+    }
+}
@@ -3,7 +3,7 @@ use std::{
     ops::Range,
 };
 
-use crate::text_range::{Point, TextRange};
+use crate::text_range::{ceil_char_boundary, floor_char_boundary, Point, TextRange};
 
 use clap::{builder::PossibleValue, ValueEnum};
 use serde::{Deserialize, Serialize};
@@ -63,7 +63,8 @@ pub fn point(src: &str, byte: usize, last_line: usize, last_byte: usize) -> Poin
         .filter(|&&b| b == b'\n')
         .count()
         + last_line;
-    let column = if let Some(last_nl) = src[..byte].rfind('\n') {
+    // `byte` may fall inside a character, so the line is searched byte by byte
+    let column = if let Some(last_nl) = src.as_bytes()[..byte].iter().rposition(|&b| b == b'\n') {
         byte - last_nl
     } else {
         byte
@@ -86,7 +87,7 @@ impl Display for OverlapStrategy {
         match self {
             Self::ByLines(n) => n.fmt(f),
             Self::Partial(p) => {
-                (*p / 100.0).fmt(f)?;
+                (*p * 100.0).fmt(f)?;
                 f.write_char('%')
             }
         }
@@ -167,7 +168,8 @@ fn add_token_range<'s>(
     last_byte: &mut usize,
     go_back_to_line_start: bool,
 ) {
-    let start_byte = offsets[o.start].0;
+    // token offsets can fall inside a multibyte character, which must not be cut in half
+    let start_byte = floor_char_boundary(src, offsets[o.start].0);
     let line_start_byte = if go_back_to_line_start {
         src[..start_byte]
             .char_indices()
@@ -177,7 +179,7 @@ fn add_token_range<'s>(
     } else {
         start_byte
     };
-    let end_byte = offsets.get(o.end).map_or(src.len(), |&(s, _)| s);
+    let end_byte = ceil_char_boundary(src, offsets.get(o.end).map_or(src.len(), |&(s, _)| s));
     let Some(trimmed_end_byte) = src[..end_byte]
         .char_indices()
         .rev()
@@ -422,4 +424,15 @@ mod tests {
             "Average chunk size should be more than {min_avg_size}, was {avg_size}",
         );
     }
+
+    #[test]
+    fn overlap_strategy_round_trips() {
+        for (input, strategy) in [
+            ("1", OverlapStrategy::ByLines(1)),
+            ("50%", OverlapStrategy::Partial(0.5)),
+        ] {
+            assert_eq!(OverlapStrategy::try_from(input), Ok(strategy));
+            assert_eq!(strategy.to_string(), input);
+        }
+    }
 }
@@ -0,0 +1,743 @@
+use std::collections::{BTreeMap, HashMap};
+
+use qdrant_client::qdrant::{
+    r#match::MatchValue, value::Kind, Condition, FieldCondition, Filter, Match, Value,
+};
+use serde::{Deserialize, Serialize};
+
+use super::payload::PayloadSchema;
+use crate::{query::parser::NLQuery, repo::relative_path_variants, Configuration};
+
+/// Payload fields that are stored folded as well, for [`Folding`]
+const FOLDED_FIELDS: &[&str] = &["relative_path", "snippet"];
+
+/// Letters with diacritics of the Latin-1 Supplement and Latin Extended-A blocks, along with
+/// the letter they fold to
+const ACCENTED: &[(&str, char)] = &[
+    ("ÀÁÂÃÄÅĀĂĄ", 'A'),
+    ("àáâãäåāăą", 'a'),
+    ("ÇĆĈĊČ", 'C'),
+    ("çćĉċč", 'c'),
+    ("ĎĐ", 'D'),
+    ("ďđ", 'd'),
+    ("ÈÉÊËĒĔĖĘĚ", 'E'),
+    ("èéêëēĕėęě", 'e'),
+    ("ĜĞĠĢ", 'G'),
+    ("ĝğġģ", 'g'),
+    ("ĤĦ", 'H'),
+    ("ĥħ", 'h'),
+    ("ÌÍÎÏĨĪĬĮİ", 'I'),
+    ("ìíîïĩīĭįı", 'i'),
+    ("Ĵ", 'J'),
+    ("ĵ", 'j'),
+    ("Ķ", 'K'),
+    ("ķ", 'k'),
+    ("ĹĻĽĿŁ", 'L'),
+    ("ĺļľŀł", 'l'),
+    ("ÑŃŅŇ", 'N'),
+    ("ñńņň", 'n'),
+    ("ÒÓÔÕÖØŌŎŐ", 'O'),
+    ("òóôõöøōŏő", 'o'),
+    ("ŔŖŘ", 'R'),
+    ("ŕŗř", 'r'),
+    ("ŚŜŞŠ", 'S'),
+    ("śŝşš", 's'),
+    ("ŢŤŦ", 'T'),
+    ("ţťŧ", 't'),
+    ("ÙÚÛÜŨŪŬŮŰŲ", 'U'),
+    ("ùúûüũūŭůűų", 'u'),
+    ("Ŵ", 'W'),
+    ("ŵ", 'w'),
+    ("ÝŶŸ", 'Y'),
+    ("ýÿŷ", 'y'),
+    ("ŹŻŽ", 'Z'),
+    ("źżž", 'z'),
+];
+
+/// How text filters, such as `path:` and keyword matches, compare to the stored text, see
+/// `Configuration::fold_filter_case`.
+///
+/// The folded text of [`FOLDED_FIELDS`] is stored next to the original when chunks are indexed,
+/// and filter values are folded the same way. Filters match either, so chunks indexed without
+/// folding still match verbatim. Quoted phrases are always matched verbatim, and this never
+/// affects vector similarity.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Folding {
+    /// Match regardless of case, so `error` matches `Error`
+    pub case: bool,
+    /// Match regardless of diacritics, so `cafe` matches `café`
+    pub accents: bool,
+}
+
+impl Folding {
+    pub fn new(config: &Configuration) -> Self {
+        Self {
+            case: config.fold_filter_case,
+            accents: config.fold_filter_accents,
+        }
+    }
+
+    fn is_enabled(self) -> bool {
+        self.case || self.accents
+    }
+
+    /// `text`, folded.
+    pub fn apply(self, text: &str) -> String {
+        let text = if self.case {
+            text.to_lowercase()
+        } else {
+            text.to_owned()
+        };
+
+        if !self.accents {
+            return text;
+        }
+
+        text.chars()
+            .map(|c| {
+                ACCENTED
+                    .iter()
+                    .find(|(accented, _)| accented.contains(c))
+                    .map_or(c, |&(_, base)| base)
+            })
+            .collect()
+    }
+
+    /// Store the folded text of the [`FOLDED_FIELDS`] of `payload`, keyed by `schema`, next to
+    /// the original.
+    pub(super) fn index(self, schema: &PayloadSchema, payload: &mut HashMap<String, Value>) {
+        if !self.is_enabled() {
+            return;
+        }
+
+        for field in FOLDED_FIELDS {
+            let key = schema.key(field);
+            if let Some(Kind::StringValue(text)) = payload.get(key).and_then(|v| v.kind.as_ref()) {
+                let folded = self.apply(text);
+                payload.insert(folded_key(key), folded.into());
+            }
+        }
+    }
+
+    /// Match `value` as a substring of the payload field `field`, or of its folded text, at the
+    /// keys `schema` stores them under.
+    pub(super) fn text_condition(
+        self,
+        schema: &PayloadSchema,
+        field: &str,
+        value: &str,
+    ) -> Condition {
+        let key = schema.key(field);
+        if !self.is_enabled() || !FOLDED_FIELDS.contains(&field) {
+            return make_kv_text_filter(key, value).into();
+        }
+
+        Filter {
+            should: vec![
+                make_kv_text_filter(key, value).into(),
+                make_kv_text_filter(&folded_key(key), &self.apply(value)).into(),
+            ],
+            ..Default::default()
+        }
+        .into()
+    }
+}
+
+/// The payload key the folded text of the payload key `key` is stored under.
+fn folded_key(key: &str) -> String {
+    format!("{key}_folded")
+}
+
+/// How filters on different payload fields are combined.
+///
+/// Multiple values for the same field are always OR'd: `lang:rust lang:go` matches chunks in
+/// either language.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum FilterLogic {
+    /// A chunk must match every field, e.g. `repo:bloop lang:rust` matches Rust chunks in bloop
+    #[default]
+    And,
+    /// A chunk must match any field, e.g. `repo:bloop lang:rust` matches all chunks in bloop,
+    /// and Rust chunks in any repository
+    Or,
+}
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+enum MatchKind {
+    Keyword,
+    Text,
+}
+
+/// Payload filters for a semantic search, see [`build_filter`].
+///
+/// Fields are named as in [`CHUNK_FIELDS`](super::payload::CHUNK_FIELDS), and matched at the
+/// keys of the schema set with [`FilterArgs::keyed`].
+#[derive(Debug, Default)]
+pub struct FilterArgs {
+    fields: Vec<(&'static str, MatchKind, Vec<String>)>,
+    logic: FilterLogic,
+    folding: Folding,
+    schema: PayloadSchema,
+
+    /// Repositories every chunk must belong to, regardless of `logic`
+    repo_refs: Option<Vec<String>>,
+    /// Phrases the snippet of every chunk must contain, regardless of `logic`
+    phrases: Vec<String>,
+}
+
+impl FilterArgs {
+    /// Construct the filters of a semantic query, combined with `logic`.
+    pub fn from_query(query: &NLQuery<'_>, logic: FilterLogic) -> Self {
+        let repos = query.repos().map(|r| {
+            if r.contains('/') && !r.starts_with("github.com/") {
+                format!("github.com/{r}")
+            } else {
+                r.to_string()
+            }
+        });
+
+        Self::new(logic)
+            .keyword("repo_name", repos)
+            .text(
+                "relative_path",
+                query.paths().flat_map(|p| relative_path_variants(&p)),
+            )
+            .keyword("lang", query.langs())
+            .keyword("branches", query.branch())
+            .containing(query.phrases())
+    }
+
+    pub fn new(logic: FilterLogic) -> Self {
+        Self {
+            fields: vec![],
+            logic,
+            folding: Folding::default(),
+            schema: PayloadSchema::default(),
+            repo_refs: None,
+            phrases: vec![],
+        }
+    }
+
+    /// Only match chunks of the repositories `repo_refs`.
+    ///
+    /// Unlike other fields, this is never combined with [`FilterLogic::Or`].
+    pub fn within_repos(mut self, repo_refs: impl IntoIterator<Item = impl ToString>) -> Self {
+        self.repo_refs = Some(repo_refs.into_iter().map(|r| r.to_string()).collect());
+        self
+    }
+
+    /// Only match chunks whose snippet contains every one of `phrases`, verbatim even when
+    /// [folded](FilterArgs::folded).
+    ///
+    /// Like the repository scope, this is never combined with [`FilterLogic::Or`].
+    pub fn containing(mut self, phrases: impl IntoIterator<Item = impl ToString>) -> Self {
+        self.phrases
+            .extend(phrases.into_iter().map(|p| p.to_string()));
+        self
+    }
+
+    /// Match text fields according to `folding`, rather than verbatim.
+    pub fn folded(mut self, folding: Folding) -> Self {
+        self.folding = folding;
+        self
+    }
+
+    /// Match fields at the keys `schema` stores them under, rather than at keys of their name.
+    pub fn keyed(mut self, schema: &PayloadSchema) -> Self {
+        self.schema = schema.clone();
+        self
+    }
+
+    /// Whether these filters exclude every chunk, because they are scoped to no repositories.
+    pub fn matches_nothing(&self) -> bool {
+        matches!(&self.repo_refs, Some(refs) if refs.is_empty())
+    }
+
+    /// The values matched on each payload field, by field name rather than payload key, not
+    /// including the repository scope. Required phrases are reported under `snippet`.
+    pub fn fields(&self) -> BTreeMap<&'static str, Vec<String>> {
+        let mut fields = BTreeMap::<_, Vec<_>>::new();
+        for (field, _, values) in &self.fields {
+            fields
+                .entry(*field)
+                .or_default()
+                .extend(values.iter().cloned());
+        }
+        if !self.phrases.is_empty() {
+            fields
+                .entry("snippet")
+                .or_default()
+                .extend(self.phrases.iter().cloned());
+        }
+        fields
+    }
+
+    /// The repositories every chunk must belong to, if the search is scoped.
+    pub fn repos(&self) -> Option<&[String]> {
+        self.repo_refs.as_deref()
+    }
+
+    /// Match any of `values` exactly on the payload field `field`.
+    pub fn keyword(
+        self,
+        field: &'static str,
+        values: impl IntoIterator<Item = impl ToString>,
+    ) -> Self {
+        self.field(field, MatchKind::Keyword, values)
+    }
+
+    /// Match any of `values` as a substring of the payload field `field`.
+    pub fn text(
+        self,
+        field: &'static str,
+        values: impl IntoIterator<Item = impl ToString>,
+    ) -> Self {
+        self.field(field, MatchKind::Text, values)
+    }
+
+    fn field(
+        mut self,
+        field: &'static str,
+        kind: MatchKind,
+        values: impl IntoIterator<Item = impl ToString>,
+    ) -> Self {
+        let values = values
+            .into_iter()
+            .map(|v| v.to_string())
+            .collect::<Vec<_>>();
+
+        if !values.is_empty() {
+            self.fields.push((field, kind, values));
+        }
+
+        self
+    }
+}
+
+/// Build the Qdrant filter for `args`.
+///
+/// Each field becomes a `should` filter over its values, and the fields are then combined in a
+/// `must` (for [`FilterLogic::And`]) or `should` (for [`FilterLogic::Or`]) filter. A repository
+/// scope and each required phrase are required on top of those. Returns `None` if there are no
+/// filters at all.
+pub fn build_filter(args: &FilterArgs) -> Option<Filter> {
+    let schema = &args.schema;
+    let fields = args
+        .fields
+        .iter()
+        .map(|(field, kind, values)| {
+            let should = values
+                .iter()
+                .map(|value| match kind {
+                    MatchKind::Keyword => make_kv_keyword_filter(schema.key(field), value).into(),
+                    MatchKind::Text => args.folding.text_condition(schema, field, value),
+                })
+                .collect();
+
+            Filter {
+                should,
+                ..Default::default()
+            }
+            .into()
+        })
+        .collect::<Vec<Condition>>();
+
+    let filter = (!fields.is_empty()).then(|| match args.logic {
+        FilterLogic::And => Filter {
+            must: fields,
+            ..Default::default()
+        },
+        FilterLogic::Or => Filter {
+            should: fields,
+            ..Default::default()
+        },
+    });
+
+    let mut required = args
+        .phrases
+        .iter()
+        .map(|phrase| make_kv_text_filter(schema.key("snippet"), phrase).into())
+        .collect::<Vec<_>>();
+
+    if let Some(repo_refs) = &args.repo_refs {
+        let scope = Filter {
+            should: repo_refs
+                .iter()
+                .map(|r| make_kv_keyword_filter(schema.key("repo_ref"), r).into())
+                .collect(),
+            ..Default::default()
+        };
+        required.push(scope.into());
+    }
+
+    if required.is_empty() {
+        return filter;
+    }
+
+    Some(match filter {
+        Some(mut filter) if args.logic == FilterLogic::And => {
+            filter.must.extend(required);
+            filter
+        }
+        Some(filter) => Filter {
+            must: std::iter::once(filter.into()).chain(required).collect(),
+            ..Default::default()
+        },
+        None => Filter {
+            must: required,
+            ..Default::default()
+        },
+    })
+}
+
+// Exact match filter
+pub(super) fn make_kv_keyword_filter(key: &str, value: &str) -> FieldCondition {
+    let key = key.to_owned();
+    let value = value.to_owned();
+    FieldCondition {
+        key,
+        r#match: Some(Match {
+            match_value: MatchValue::Keyword(value).into(),
+        }),
+        ..Default::default()
+    }
+}
+
+// Substring match filter
+pub(super) fn make_kv_text_filter(key: &str, value: &str) -> FieldCondition {
+    let key = key.to_owned();
+    let value = value.to_owned();
+    FieldCondition {
+        key,
+        r#match: Some(Match {
+            match_value: MatchValue::Text(value).into(),
+        }),
+        ..Default::default()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::query::parser;
+
+    fn any_of(conditions: Vec<FieldCondition>) -> Condition {
+        Filter {
+            should: conditions.into_iter().map(Into::into).collect(),
+            ..Default::default()
+        }
+        .into()
+    }
+
+    #[test]
+    fn empty_filter() {
+        let query = parser::parse_nl("what is bloop?").unwrap();
+
+        assert_eq!(
+            build_filter(&FilterArgs::from_query(&query, FilterLogic::And)),
+            None
+        );
+        assert_eq!(build_filter(&FilterArgs::new(FilterLogic::Or)), None);
+        assert_eq!(
+            build_filter(&FilterArgs::default().keyword("lang", Vec::<String>::new())),
+            None
+        );
+    }
+
+    #[test]
+    fn single_field_values_are_ored() {
+        let args = FilterArgs::default().keyword("lang", ["rust", "go"]);
+
+        assert_eq!(
+            build_filter(&args),
+            Some(Filter {
+                must: vec![any_of(vec![
+                    make_kv_keyword_filter("lang", "rust"),
+                    make_kv_keyword_filter("lang", "go"),
+                ])],
+                ..Default::default()
+            })
+        );
+    }
+
+    #[test]
+    fn multiple_fields() {
+        let fields = || {
+            vec![
+                any_of(vec![make_kv_keyword_filter(
+                    "repo_name",
+                    "github.com/bloop",
+                )]),
+                any_of(vec![make_kv_text_filter("relative_path", "src/")]),
+            ]
+        };
+        let args = |logic| {
+            FilterArgs::new(logic)
+                .keyword("repo_name", ["github.com/bloop"])
+                .text("relative_path", ["src/"])
+        };
+
+        assert_eq!(
+            build_filter(&args(FilterLogic::And)),
+            Some(Filter {
+                must: fields(),
+                ..Default::default()
+            })
+        );
+        assert_eq!(
+            build_filter(&args(FilterLogic::Or)),
+            Some(Filter {
+                should: fields(),
+                ..Default::default()
+            })
+        );
+    }
+
+    #[test]
+    fn windows_path_filters_match_both_separators() {
+        let query = parser::parse_nl(r"path:src\webserver what is bloop?").unwrap();
+
+        assert_eq!(
+            build_filter(&FilterArgs::from_query(&query, FilterLogic::And)),
+            Some(Filter {
+                must: vec![any_of(vec![
+                    make_kv_text_filter("relative_path", "src/webserver"),
+                    make_kv_text_filter("relative_path", r"src\webserver"),
+                ])],
+                ..Default::default()
+            })
+        );
+    }
+
+    #[test]
+    fn repo_scope_is_always_required() {
+        let scope = || {
+            any_of(vec![
+                make_kv_keyword_filter("repo_ref", "github.com/org/bloop"),
+                make_kv_keyword_filter("repo_ref", "local//bleep"),
+            ])
+        };
+        let lang = || any_of(vec![make_kv_keyword_filter("lang", "rust")]);
+        let args = |logic| {
+            FilterArgs::new(logic)
+                .keyword("lang", ["rust"])
+                .within_repos(["github.com/org/bloop", "local//bleep"])
+        };
+
+        assert_eq!(
+            build_filter(&args(FilterLogic::And)),
+            Some(Filter {
+                must: vec![lang(), scope()],
+                ..Default::default()
+            })
+        );
+        assert_eq!(
+            build_filter(&args(FilterLogic::Or)),
+            Some(Filter {
+                must: vec![
+                    Filter {
+                        should: vec![lang()],
+                        ..Default::default()
+                    }
+                    .into(),
+                    scope()
+                ],
+                ..Default::default()
+            })
+        );
+
+        let empty = FilterArgs::default().within_repos(Vec::<String>::new());
+        assert!(empty.matches_nothing());
+        assert!(!args(FilterLogic::And).matches_nothing());
+    }
+
+    #[test]
+    fn filters_from_query() {
+        let query = parser::parse_nl("what is this? repo:org/bloop").unwrap();
+
+        assert_eq!(
+            build_filter(&FilterArgs::from_query(&query, FilterLogic::And)),
+            Some(Filter {
+                must: vec![any_of(vec![make_kv_keyword_filter(
+                    "repo_name",
+                    "github.com/org/bloop"
+                )])],
+                ..Default::default()
+            })
+        );
+    }
+
+    #[test]
+    fn folded_filters_also_match_the_folded_text() {
+        let folding = Folding {
+            case: true,
+            accents: true,
+        };
+        let args = FilterArgs::default()
+            .text("relative_path", ["Src/Café"])
+            .keyword("lang", ["Rust"])
+            .folded(folding);
+
+        let path = Filter {
+            should: vec![
+                make_kv_text_filter("relative_path", "Src/Café").into(),
+                make_kv_text_filter("relative_path_folded", "src/cafe").into(),
+            ],
+            ..Default::default()
+        };
+        assert_eq!(
+            build_filter(&args),
+            Some(Filter {
+                must: vec![
+                    Filter {
+                        should: vec![path.into()],
+                        ..Default::default()
+                    }
+                    .into(),
+                    // keywords, such as languages or repositories, are always matched verbatim
+                    any_of(vec![make_kv_keyword_filter("lang", "Rust")]),
+                ],
+                ..Default::default()
+            })
+        );
+
+        let case_only = Folding {
+            case: true,
+            accents: false,
+        };
+        assert_eq!(case_only.apply("ParseÉtat"), "parseétat");
+        assert_eq!(
+            Folding {
+                case: false,
+                accents: true
+            }
+            .apply("ParseÉtat"),
+            "ParseEtat"
+        );
+    }
+
+    #[test]
+    fn quoted_phrases_are_required() {
+        let query = parser::parse_nl(r#"lang:rust where is "fn main" defined"#).unwrap();
+        let logic = FilterLogic::Or;
+        let args = FilterArgs::from_query(&query, logic);
+
+        // words outside the quotes are only embedded
+        assert_eq!(
+            build_filter(&args),
+            Some(Filter {
+                must: vec![
+                    Filter {
+                        should: vec![any_of(vec![make_kv_keyword_filter("lang", "rust")])],
+                        ..Default::default()
+                    }
+                    .into(),
+                    make_kv_text_filter("snippet", "fn main").into(),
+                ],
+                ..Default::default()
+            })
+        );
+        assert_eq!(args.fields()["snippet"], vec!["fn main"]);
+    }
+
+    #[test]
+    fn quoted_phrases_are_matched_verbatim_when_folded() {
+        let query = parser::parse_nl(r#"path:Src "fn Main" defined"#).unwrap();
+        let args = FilterArgs::from_query(&query, FilterLogic::And).folded(Folding {
+            case: true,
+            accents: true,
+        });
+
+        let path = Filter {
+            should: vec![
+                make_kv_text_filter("relative_path", "Src").into(),
+                make_kv_text_filter("relative_path_folded", "src").into(),
+            ],
+            ..Default::default()
+        };
+        assert_eq!(
+            build_filter(&args),
+            Some(Filter {
+                must: vec![
+                    Filter {
+                        should: vec![path.into()],
+                        ..Default::default()
+                    }
+                    .into(),
+                    make_kv_text_filter("snippet", "fn Main").into(),
+                ],
+                ..Default::default()
+            })
+        );
+    }
+
+    #[test]
+    fn fields_are_matched_at_their_aliased_keys() {
+        let schema = PayloadSchema::parse(&[
+            "repo_ref=repository".into(),
+            "relative_path=path".into(),
+            "lang=language".into(),
+            "snippet=content".into(),
+        ])
+        .unwrap();
+        let query = parser::parse_nl(r#"lang:rust path:src "fn main" what is bloop?"#).unwrap();
+        let args = FilterArgs::from_query(&query, FilterLogic::And)
+            .within_repos(["local//bloop"])
+            .folded(Folding {
+                case: true,
+                accents: false,
+            })
+            .keyed(&schema);
+
+        let path = Filter {
+            should: vec![
+                make_kv_text_filter("path", "src").into(),
+                make_kv_text_filter("path_folded", "src").into(),
+            ],
+            ..Default::default()
+        };
+        assert_eq!(
+            build_filter(&args),
+            Some(Filter {
+                must: vec![
+                    Filter {
+                        should: vec![path.into()],
+                        ..Default::default()
+                    }
+                    .into(),
+                    any_of(vec![make_kv_keyword_filter("language", "rust")]),
+                    make_kv_text_filter("content", "fn main").into(),
+                    any_of(vec![make_kv_keyword_filter("repository", "local//bloop")]),
+                ],
+                ..Default::default()
+            })
+        );
+
+        // fields are still reported by name
+        assert_eq!(
+            args.fields().keys().copied().collect::<Vec<_>>(),
+            vec!["lang", "relative_path", "snippet"]
+        );
+    }
+
+    #[test]
+    fn fields_are_reported_by_key() {
+        let query = parser::parse_nl("lang:rust path:src lang:go what is bloop?").unwrap();
+        let args = FilterArgs::from_query(&query, FilterLogic::And)
+            .keyword("kind", ["definition"])
+            .within_repos(["github.com/bloopai/bloop"]);
+
+        let fields = args.fields();
+        assert_eq!(
+            fields.keys().copied().collect::<Vec<_>>(),
+            vec!["kind", "lang", "relative_path"]
+        );
+        assert_eq!(fields["kind"], vec!["definition"]);
+        assert_eq!(
+            args.repos(),
+            Some(&["github.com/bloopai/bloop".to_owned()][..])
+        );
+        assert_eq!(FilterArgs::default().repos(), None);
+    }
+}
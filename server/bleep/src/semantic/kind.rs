@@ -0,0 +1,145 @@
+use serde::{Deserialize, Serialize};
+use utoipa::ToSchema;
+
+use crate::{symbol::SymbolLocations, text_range::TextRange};
+
+/// What a chunk contains, recorded in its `kind` payload field.
+#[derive(Serialize, Deserialize, ToSchema, Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+#[serde(rename_all = "snake_case")]
+pub enum ChunkKind {
+    /// The chunk defines at least one symbol
+    Definition,
+    /// The chunk is inside a nested scope, such as a function body, without defining a symbol
+    Body,
+    /// Anything else, including chunks of files without symbol information
+    #[default]
+    Other,
+}
+
+impl ChunkKind {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::Definition => "definition",
+            Self::Body => "body",
+            Self::Other => "other",
+        }
+    }
+
+    pub fn from_payload(value: &str) -> Self {
+        match value {
+            "definition" => Self::Definition,
+            "body" => Self::Body,
+            _ => Self::Other,
+        }
+    }
+}
+
+/// The symbols of a file, for classifying its chunks.
+#[derive(Debug, Default)]
+pub struct FileSymbols {
+    /// Names of the symbols defined in the file, with the range of each name
+    definitions: Vec<(String, TextRange)>,
+    /// Ranges of the scopes nested in the file scope
+    scopes: Vec<TextRange>,
+}
+
+impl FileSymbols {
+    pub fn new(buffer: &str, locations: &SymbolLocations) -> Self {
+        let definitions = locations
+            .list()
+            .into_iter()
+            .filter_map(|sym| {
+                let name = buffer.get(sym.range.start.byte..sym.range.end.byte)?;
+                Some((name.to_owned(), sym.range))
+            })
+            .collect();
+
+        Self {
+            definitions,
+            scopes: locations.nested_scopes(),
+        }
+    }
+
+    /// The kind of the chunk spanning `range`, and the names of the symbols it defines, in
+    /// order of appearance.
+    ///
+    /// A chunk defines a symbol if the name of the definition starts within the chunk.
+    pub fn classify(&self, range: &TextRange) -> (ChunkKind, Vec<String>) {
+        let (start, end) = (range.start.byte, range.end.byte);
+
+        let mut definitions = self
+            .definitions
+            .iter()
+            .filter(|(_, name)| (start..end).contains(&name.start.byte))
+            .collect::<Vec<_>>();
+        definitions.sort_by_key(|(_, name)| name.start.byte);
+
+        let mut names: Vec<String> = vec![];
+        for (name, _) in definitions {
+            if !names.contains(name) {
+                names.push(name.clone());
+            }
+        }
+
+        let kind = if !names.is_empty() {
+            ChunkKind::Definition
+        } else if self
+            .scopes
+            .iter()
+            .any(|scope| scope.start.byte <= start && start < scope.end.byte)
+        {
+            ChunkKind::Body
+        } else {
+            ChunkKind::Other
+        };
+
+        (kind, names)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::text_range::Point;
+
+    fn range(start: usize, end: usize) -> TextRange {
+        TextRange {
+            start: Point::new(start, 0, start),
+            end: Point::new(end, 0, end),
+        }
+    }
+
+    #[test]
+    fn chunks_are_classified_by_their_symbols() {
+        let symbols = FileSymbols {
+            definitions: vec![
+                ("parse".into(), range(33, 38)),
+                ("Query".into(), range(7, 12)),
+                ("Query".into(), range(20, 25)),
+            ],
+            scopes: vec![range(40, 100)],
+        };
+
+        assert_eq!(
+            symbols.classify(&range(0, 39)),
+            (ChunkKind::Definition, vec!["Query".into(), "parse".into()])
+        );
+        assert_eq!(symbols.classify(&range(50, 80)), (ChunkKind::Body, vec![]));
+        assert_eq!(
+            symbols.classify(&range(100, 120)),
+            (ChunkKind::Other, vec![])
+        );
+        assert_eq!(
+            FileSymbols::default().classify(&range(0, 10)),
+            (ChunkKind::Other, vec![])
+        );
+    }
+
+    #[test]
+    fn kinds_round_trip_through_payloads() {
+        for kind in [ChunkKind::Definition, ChunkKind::Body, ChunkKind::Other] {
+            assert_eq!(ChunkKind::from_payload(kind.as_str()), kind);
+        }
+        assert_eq!(ChunkKind::from_payload(""), ChunkKind::Other);
+    }
+}
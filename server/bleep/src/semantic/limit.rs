@@ -0,0 +1,167 @@
+use std::{sync::Arc, time::Duration};
+
+use thiserror::Error;
+use tokio::sync::Semaphore;
+
+/// Returned when an embedding waited longer than `Configuration::embedding_wait_timeout_secs` for
+/// a permit, see [`EmbedLimit`].
+#[derive(Error, Debug)]
+#[error("the embedding model is busy, gave up after waiting {}s", waited.as_secs())]
+pub struct EmbedTimeout {
+    pub waited: Duration,
+}
+
+/// Bounds how many embedding operations run through the model at once, see
+/// `Configuration::embedding_concurrency`.
+///
+/// Operations over the limit wait for a permit instead of oversubscribing the model, and fail
+/// with [`EmbedTimeout`] once they waited for `timeout`.
+#[derive(Clone)]
+pub(super) struct EmbedLimit {
+    permits: Arc<Semaphore>,
+    timeout: Duration,
+}
+
+impl EmbedLimit {
+    pub(super) fn new(concurrency: usize, timeout: Duration) -> Self {
+        Self {
+            permits: Arc::new(Semaphore::new(concurrency.max(1))),
+            timeout,
+        }
+    }
+
+    /// Run `embed` on a blocking thread once a permit is free.
+    ///
+    /// Waiting for a permit is cancellable, so a search whose client goes away gives up its
+    /// place in the queue when its future is dropped.
+    pub(super) async fn run<T: Send + 'static>(
+        &self,
+        embed: impl FnOnce() -> anyhow::Result<T> + Send + 'static,
+    ) -> anyhow::Result<T> {
+        let permit = tokio::time::timeout(self.timeout, Arc::clone(&self.permits).acquire_owned())
+            .await
+            .map_err(|_| EmbedTimeout {
+                waited: self.timeout,
+            })??;
+
+        tokio::task::spawn_blocking(move || {
+            let _permit = permit;
+            embed()
+        })
+        .await?
+    }
+
+    /// Run `embed` on the current thread, blocking it until a permit is free.
+    ///
+    /// This waits in the same queue as [`EmbedLimit::run`], for as long, and fails if it is
+    /// called from within an async context.
+    pub(super) fn run_blocking<T>(
+        &self,
+        embed: impl FnOnce() -> anyhow::Result<T>,
+    ) -> anyhow::Result<T> {
+        let acquire = tokio::time::timeout(self.timeout, self.permits.acquire());
+        let _permit = super::block_on(acquire)?.map_err(|_| EmbedTimeout {
+            waited: self.timeout,
+        })??;
+        embed()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{
+        sync::atomic::{AtomicUsize, Ordering},
+        thread,
+        time::Duration,
+    };
+
+    /// Counts the operations running at once, and the most that ever did.
+    #[derive(Default)]
+    struct Occupancy {
+        running: AtomicUsize,
+        peak: AtomicUsize,
+    }
+
+    impl Occupancy {
+        fn enter(&self) {
+            let running = self.running.fetch_add(1, Ordering::SeqCst) + 1;
+            self.peak.fetch_max(running, Ordering::SeqCst);
+            thread::sleep(Duration::from_millis(10));
+            self.running.fetch_sub(1, Ordering::SeqCst);
+        }
+    }
+
+    const WAIT: Duration = Duration::from_secs(10);
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn a_limit_of_one_serializes_embeds() {
+        let limit = EmbedLimit::new(1, WAIT);
+        let occupancy = Arc::new(Occupancy::default());
+
+        let embeds = (0..8)
+            .map(|i| {
+                let limit = limit.clone();
+                let occupancy = Arc::clone(&occupancy);
+                tokio::spawn(async move {
+                    limit
+                        .run(move || {
+                            occupancy.enter();
+                            Ok(i)
+                        })
+                        .await
+                })
+            })
+            .collect::<Vec<_>>();
+
+        for (i, embed) in embeds.into_iter().enumerate() {
+            assert_eq!(embed.await.unwrap().unwrap(), i);
+        }
+
+        assert_eq!(occupancy.peak.load(Ordering::SeqCst), 1);
+    }
+
+    #[test]
+    fn blocking_embeds_share_the_limit() {
+        let limit = EmbedLimit::new(2, WAIT);
+        let occupancy = Occupancy::default();
+
+        thread::scope(|scope| {
+            for _ in 0..8 {
+                scope.spawn(|| {
+                    limit
+                        .run_blocking(|| {
+                            occupancy.enter();
+                            Ok(())
+                        })
+                        .unwrap()
+                });
+            }
+        });
+
+        assert!(occupancy.peak.load(Ordering::SeqCst) <= 2);
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn embeds_give_up_waiting_after_the_timeout() {
+        let limit = EmbedLimit::new(1, Duration::from_millis(20));
+        let held = Arc::clone(&limit.permits).acquire_owned().await.unwrap();
+
+        let err = limit.run(|| Ok(())).await.unwrap_err();
+        assert_eq!(
+            err.downcast_ref::<EmbedTimeout>().unwrap().waited,
+            Duration::from_millis(20)
+        );
+
+        let blocking = limit.clone();
+        // blocking embeds run outside of the runtime
+        let err = thread::spawn(move || blocking.run_blocking(|| Ok(())))
+            .join()
+            .unwrap()
+            .unwrap_err();
+        assert!(err.is::<EmbedTimeout>());
+
+        drop(held);
+        limit.run(|| Ok(())).await.unwrap();
+    }
+}
@@ -0,0 +1,1217 @@
+//! An in-process [`VectorStore`], for running without a qdrant server.
+//!
+//! Each collection is a directory of shards, one per repository. A shard is two append-only
+//! files: a vectors file, with the little-endian vector of every write in its own slot, and
+//! `points.jsonl`, a log of the upserts and deletes made to its points. Shards are compacted when
+//! most of their slots hold overwritten or deleted points.
+//!
+//! The ids and payloads of points are kept in memory, while vectors files are memory-mapped, so
+//! the vectors of a shard are paged in from disk as searches scan it rather than held in memory.
+//!
+//! Searches are exact, scanning every point matching the filters, which keeps up with
+//! interactive use to a few hundred thousand points.
+
+use std::{
+    collections::HashMap,
+    fs::{self, File, OpenOptions},
+    io::{BufRead, BufReader, BufWriter, Write},
+    path::{Path, PathBuf},
+    sync::{Arc, RwLock},
+};
+
+use anyhow::{bail, Context};
+use async_trait::async_trait;
+use memmap2::Mmap;
+use qdrant_client::qdrant::{
+    condition::ConditionOneOf, point_id::PointIdOptions, points_selector::PointsSelectorOneOf,
+    r#match::MatchValue, value::Kind, vectors::VectorsOptions, vectors_config,
+    with_payload_selector, with_vectors_selector, Condition, CreateCollection, Distance, Filter,
+    ListValue, PointId, PointStruct, PointsSelector, RetrievedPoint, ScoredPoint, ScrollPoints,
+    ScrollResponse, SearchPoints, Struct, Value, WithPayloadSelector, WithVectorsSelector,
+};
+use serde::{Deserialize, Serialize};
+use tracing::{debug, warn};
+
+use super::store::VectorStore;
+
+/// Points returned by a scroll without a `limit`, as in qdrant
+const DEFAULT_SCROLL_LIMIT: u32 = 10;
+
+/// Dead slots a shard may hold before it is compacted, if they outnumber its live points
+const MIN_COMPACTED_SLOTS: u64 = 4096;
+
+#[derive(Serialize, Deserialize)]
+struct CollectionMeta {
+    name: String,
+    dim: usize,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+#[serde(rename_all = "snake_case")]
+enum StoredId {
+    Num(u64),
+    Uuid(String),
+}
+
+impl StoredId {
+    fn from_point(id: Option<&PointId>) -> anyhow::Result<Self> {
+        match id.and_then(|id| id.point_id_options.as_ref()) {
+            Some(PointIdOptions::Num(num)) => Ok(Self::Num(*num)),
+            Some(PointIdOptions::Uuid(uuid)) => Ok(Self::Uuid(uuid.clone())),
+            None => bail!("point has no id"),
+        }
+    }
+
+    fn to_point(&self) -> PointId {
+        match self {
+            Self::Num(num) => PointId::from(*num),
+            Self::Uuid(uuid) => PointId::from(uuid.clone()),
+        }
+    }
+}
+
+/// A line of `points.jsonl`.
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum Record {
+    /// The first line, naming the vectors file the slots of the log are in
+    Vectors {
+        file: String,
+    },
+    Upsert {
+        id: StoredId,
+        slot: u64,
+        payload: serde_json::Map<String, serde_json::Value>,
+    },
+    Delete {
+        id: StoredId,
+    },
+}
+
+/// The points of a single repository.
+struct Shard {
+    dir: PathBuf,
+    dim: usize,
+    /// Bumped by every compaction, which writes to a new vectors file
+    generation: u64,
+
+    ids: Vec<StoredId>,
+    positions: HashMap<StoredId, usize>,
+    /// The slot of the vector of each point, in the order of `ids`
+    slots: Vec<u64>,
+    payloads: Vec<HashMap<String, Value>>,
+
+    /// The vectors file, normalized to unit length, or `None` while it is empty
+    vectors: Option<Mmap>,
+    /// The slot the next vector is written to
+    next_slot: u64,
+}
+
+struct Collection {
+    dir: PathBuf,
+    dim: usize,
+    /// Shards by repository, `""` for points without a `repo_ref`
+    shards: HashMap<String, Shard>,
+}
+
+pub struct LocalStore {
+    root: PathBuf,
+    /// The payload key points are sharded by
+    repo_key: String,
+    collections: Arc<RwLock<HashMap<String, Collection>>>,
+}
+
+impl LocalStore {
+    /// Open the store in `root`, loading every collection in it.
+    pub fn open(root: &Path) -> anyhow::Result<Self> {
+        fs::create_dir_all(root)?;
+
+        let mut collections = HashMap::new();
+        for entry in fs::read_dir(root)? {
+            let dir = entry?.path();
+            let Ok(meta) = fs::read(dir.join("collection.json")) else {
+                continue;
+            };
+
+            let meta = serde_json::from_slice::<CollectionMeta>(&meta)?;
+            let collection = Collection::load(dir, meta.dim)
+                .with_context(|| format!("failed to load collection `{}`", meta.name))?;
+            collections.insert(meta.name, collection);
+        }
+
+        Ok(Self {
+            root: root.to_owned(),
+            repo_key: "repo_ref".to_owned(),
+            collections: Arc::new(RwLock::new(collections)),
+        })
+    }
+
+    /// Shard points by the payload key `key`, for chunks whose `repo_ref` is stored under
+    /// another key, see [`PayloadSchema`](super::payload::PayloadSchema).
+    pub fn sharded_by(mut self, key: &str) -> Self {
+        self.repo_key = key.to_owned();
+        self
+    }
+
+    /// Run `f` on the collections on the blocking thread pool, as searches scan every point and
+    /// writes go to disk.
+    async fn with_collections<T, F>(&self, f: F) -> anyhow::Result<T>
+    where
+        T: Send + 'static,
+        F: FnOnce(&mut HashMap<String, Collection>) -> anyhow::Result<T> + Send + 'static,
+    {
+        let collections = Arc::clone(&self.collections);
+        tokio::task::spawn_blocking(move || f(&mut collections.write().unwrap())).await?
+    }
+
+    /// Run `f` on the collections on the blocking thread pool, sharing them with other reads.
+    ///
+    /// Every access to the collections goes through the blocking pool, as waiting for the lock
+    /// blocks for as long as a write holds it.
+    async fn read_collections<T, F>(&self, f: F) -> anyhow::Result<T>
+    where
+        T: Send + 'static,
+        F: FnOnce(&HashMap<String, Collection>) -> anyhow::Result<T> + Send + 'static,
+    {
+        let collections = Arc::clone(&self.collections);
+        tokio::task::spawn_blocking(move || f(&collections.read().unwrap())).await?
+    }
+
+    async fn read_collection<T, F>(&self, name: &str, f: F) -> anyhow::Result<T>
+    where
+        T: Send + 'static,
+        F: FnOnce(&Collection) -> anyhow::Result<T> + Send + 'static,
+    {
+        let name = name.to_owned();
+        self.read_collections(move |collections| match collections.get(&name) {
+            Some(collection) => f(collection),
+            None => bail!("collection `{name}` does not exist"),
+        })
+        .await
+    }
+}
+
+#[async_trait]
+impl VectorStore for LocalStore {
+    async fn health_check(&self) -> anyhow::Result<()> {
+        Ok(())
+    }
+
+    async fn has_collection(&self, collection: &str) -> anyhow::Result<bool> {
+        let name = collection.to_owned();
+        self.read_collections(move |collections| Ok(collections.contains_key(&name)))
+            .await
+    }
+
+    async fn create_collection(&self, config: &CreateCollection) -> anyhow::Result<()> {
+        let dim = match config
+            .vectors_config
+            .as_ref()
+            .and_then(|v| v.config.as_ref())
+        {
+            Some(vectors_config::Config::Params(params)) => {
+                if params.distance != Distance::Cosine as i32 {
+                    bail!("the local vector store only supports cosine distance");
+                }
+                params.size as usize
+            }
+            _ => bail!("the local vector store only supports a single unnamed vector"),
+        };
+
+        let name = config.collection_name.clone();
+        let dir = self.root.join(hashed(&name));
+        self.with_collections(move |collections| {
+            if collections.contains_key(&name) {
+                bail!("collection `{name}` already exists");
+            }
+
+            fs::create_dir_all(&dir)?;
+            let meta = CollectionMeta {
+                name: name.clone(),
+                dim,
+            };
+            fs::write(dir.join("collection.json"), serde_json::to_vec(&meta)?)?;
+
+            collections.insert(
+                name,
+                Collection {
+                    dir,
+                    dim,
+                    shards: HashMap::new(),
+                },
+            );
+            Ok(())
+        })
+        .await
+    }
+
+    async fn named_vectors(&self, _collection: &str) -> anyhow::Result<bool> {
+        Ok(false)
+    }
+
+    async fn points_count(&self, collection: &str) -> anyhow::Result<u64> {
+        self.read_collection(collection, |collection| {
+            Ok(collection.shards.values().map(|s| s.ids.len() as u64).sum())
+        })
+        .await
+    }
+
+    async fn search(&self, request: &SearchPoints) -> anyhow::Result<Vec<ScoredPoint>> {
+        let request = request.clone();
+        self.read_collection(&request.collection_name.clone(), move |collection| {
+            collection.search(request)
+        })
+        .await
+    }
+
+    async fn scroll(&self, request: &ScrollPoints) -> anyhow::Result<ScrollResponse> {
+        let request = request.clone();
+        self.read_collection(&request.collection_name.clone(), move |collection| {
+            Ok(collection.scroll(request))
+        })
+        .await
+    }
+
+    async fn upsert(&self, collection: &str, points: Vec<PointStruct>) -> anyhow::Result<()> {
+        let name = collection.to_owned();
+        let repo_key = self.repo_key.clone();
+        self.with_collections(move |collections| {
+            let Some(collection) = collections.get_mut(&name) else {
+                bail!("collection `{name}` does not exist");
+            };
+            collection.upsert(points, &repo_key)
+        })
+        .await
+    }
+
+    async fn delete(&self, collection: &str, selector: &PointsSelector) -> anyhow::Result<()> {
+        let name = collection.to_owned();
+        let selector = selector.clone();
+        self.with_collections(move |collections| {
+            let Some(collection) = collections.get_mut(&name) else {
+                bail!("collection `{name}` does not exist");
+            };
+            collection.delete(selector)
+        })
+        .await
+    }
+}
+
+impl Collection {
+    fn load(dir: PathBuf, dim: usize) -> anyhow::Result<Self> {
+        let mut shards = HashMap::new();
+        for entry in fs::read_dir(&dir)? {
+            let path = entry?.path();
+            let Ok(repo_ref) = fs::read_to_string(path.join("repo_ref")) else {
+                continue;
+            };
+
+            shards.insert(repo_ref, Shard::load(path, dim)?);
+        }
+
+        Ok(Self { dir, dim, shards })
+    }
+
+    fn shard(&mut self, repo_ref: &str) -> anyhow::Result<&mut Shard> {
+        if !self.shards.contains_key(repo_ref) {
+            let dir = self.dir.join(hashed(repo_ref));
+            fs::create_dir_all(&dir)?;
+            let shard = Shard::create(dir.clone(), self.dim)?;
+            // written last, as collections skip the directories of shards without it
+            fs::write(dir.join("repo_ref"), repo_ref)?;
+            self.shards.insert(repo_ref.to_owned(), shard);
+        }
+
+        Ok(self
+            .shards
+            .get_mut(repo_ref)
+            .expect("the shard was just created"))
+    }
+
+    /// Write `points` to the shards of the repository at their payload key `repo_key`.
+    fn upsert(&mut self, points: Vec<PointStruct>, repo_key: &str) -> anyhow::Result<()> {
+        let mut by_shard = HashMap::<String, Vec<_>>::new();
+        for point in points {
+            let id = StoredId::from_point(point.id.as_ref())?;
+            let vector = match point.vectors.and_then(|v| v.vectors_options) {
+                Some(VectorsOptions::Vector(vector)) => vector.data,
+                _ => bail!("the local vector store only supports a single unnamed vector"),
+            };
+            if vector.len() != self.dim {
+                bail!(
+                    "expected a vector of {} dimensions, got {}",
+                    self.dim,
+                    vector.len()
+                );
+            }
+
+            let repo_ref = match point.payload.get(repo_key).and_then(|v| v.kind.as_ref()) {
+                Some(Kind::StringValue(repo_ref)) => repo_ref.clone(),
+                _ => String::new(),
+            };
+            by_shard
+                .entry(repo_ref)
+                .or_default()
+                .push((id, normalized(vector), point.payload));
+        }
+
+        for (repo_ref, points) in by_shard {
+            // a point moved to another repository must not be left behind in its old shard
+            for (other, shard) in self.shards.iter_mut() {
+                if *other != repo_ref {
+                    shard.delete_ids(points.iter().map(|(id, ..)| id))?;
+                }
+            }
+
+            self.shard(&repo_ref)?.upsert(points)?;
+        }
+
+        Ok(())
+    }
+
+    fn delete(&mut self, selector: PointsSelector) -> anyhow::Result<()> {
+        match selector.points_selector_one_of {
+            Some(PointsSelectorOneOf::Points(list)) => {
+                let ids = list
+                    .ids
+                    .iter()
+                    .map(|id| StoredId::from_point(Some(id)))
+                    .collect::<anyhow::Result<Vec<_>>>()?;
+                for shard in self.shards.values_mut() {
+                    shard.delete_ids(ids.iter())?;
+                }
+            }
+            Some(PointsSelectorOneOf::Filter(filter)) => {
+                for shard in self.shards.values_mut() {
+                    let deleted = (0..shard.ids.len())
+                        .filter(|&i| matches(&filter, &shard.ids[i], &shard.payloads[i]))
+                        .collect();
+                    shard.delete(deleted)?;
+                }
+            }
+            None => {}
+        }
+
+        Ok(())
+    }
+
+    fn search(&self, request: SearchPoints) -> anyhow::Result<Vec<ScoredPoint>> {
+        if request.vector_name.is_some() {
+            bail!("the local vector store only supports a single unnamed vector");
+        }
+        if request.vector.len() != self.dim {
+            bail!(
+                "expected a vector of {} dimensions, got {}",
+                self.dim,
+                request.vector.len()
+            );
+        }
+
+        let query = normalized(request.vector);
+        let mut scored = self
+            .shards
+            .values()
+            .flat_map(|shard| (0..shard.ids.len()).map(move |i| (shard, i)))
+            .filter(|(shard, i)| {
+                let filter = request.filter.as_ref();
+                filter.map_or(true, |f| matches(f, &shard.ids[*i], &shard.payloads[*i]))
+            })
+            .map(|(shard, i)| (shard.score(&query, i), shard, i))
+            .filter(|(score, ..)| request.score_threshold.map_or(true, |min| *score >= min))
+            .collect::<Vec<_>>();
+
+        scored.sort_by(|a, b| b.0.total_cmp(&a.0));
+
+        let offset = request.offset.unwrap_or_default() as usize;
+        Ok(scored
+            .into_iter()
+            .skip(offset)
+            .take(request.limit as usize)
+            .map(|(score, shard, i)| ScoredPoint {
+                id: Some(shard.ids[i].to_point()),
+                payload: selected(&shard.payloads[i], request.with_payload.as_ref()),
+                score,
+                version: 0,
+                vectors: with_vectors(request.with_vectors.as_ref())
+                    .then(|| shard.vector(i).into()),
+            })
+            .collect())
+    }
+
+    /// Points in id order, like qdrant, starting at the `offset` id.
+    fn scroll(&self, request: ScrollPoints) -> ScrollResponse {
+        let offset = request
+            .offset
+            .as_ref()
+            .and_then(|id| StoredId::from_point(Some(id)).ok());
+        let limit = request.limit.unwrap_or(DEFAULT_SCROLL_LIMIT) as usize;
+
+        let mut found = self
+            .shards
+            .values()
+            .flat_map(|shard| (0..shard.ids.len()).map(move |i| (shard, i)))
+            .filter(|(shard, i)| offset.as_ref().map_or(true, |o| &shard.ids[*i] >= o))
+            .filter(|(shard, i)| {
+                let filter = request.filter.as_ref();
+                filter.map_or(true, |f| matches(f, &shard.ids[*i], &shard.payloads[*i]))
+            })
+            .collect::<Vec<_>>();
+
+        found.sort_by(|(a, i), (b, j)| a.ids[*i].cmp(&b.ids[*j]));
+
+        let next_page_offset = found.get(limit).map(|(shard, i)| shard.ids[*i].to_point());
+        let result = found
+            .into_iter()
+            .take(limit)
+            .map(|(shard, i)| RetrievedPoint {
+                id: Some(shard.ids[i].to_point()),
+                payload: selected(&shard.payloads[i], request.with_payload.as_ref()),
+                vectors: with_vectors(request.with_vectors.as_ref())
+                    .then(|| shard.vector(i).into()),
+            })
+            .collect();
+
+        ScrollResponse {
+            next_page_offset,
+            result,
+            time: 0.0,
+        }
+    }
+}
+
+impl Shard {
+    fn empty(dir: PathBuf, dim: usize, generation: u64) -> Self {
+        Self {
+            dir,
+            dim,
+            generation,
+            ids: vec![],
+            positions: HashMap::new(),
+            slots: vec![],
+            payloads: vec![],
+            vectors: None,
+            next_slot: 0,
+        }
+    }
+
+    fn create(dir: PathBuf, dim: usize) -> anyhow::Result<Self> {
+        let mut shard = Self::empty(dir, dim, 0);
+        shard.compact()?;
+        Ok(shard)
+    }
+
+    /// Replay the log of the shard in `dir`, compacting it if any of its slots are dead.
+    ///
+    /// A write interrupted halfway leaves a truncated last line, which is skipped along with the
+    /// slot it was written to.
+    fn load(dir: PathBuf, dim: usize) -> anyhow::Result<Self> {
+        let log = BufReader::new(File::open(dir.join("points.jsonl"))?);
+        let mut vectors_file = None;
+        let mut live = HashMap::<StoredId, (u64, HashMap<String, Value>)>::new();
+
+        for line in log.lines() {
+            let Ok(record) = serde_json::from_str::<Record>(&line?) else {
+                warn!(?dir, "skipping truncated vector store record");
+                continue;
+            };
+
+            match record {
+                Record::Vectors { file } => vectors_file = Some(file),
+                Record::Upsert { id, slot, payload } => {
+                    let payload = payload.into_iter().map(|(k, v)| (k, from_json(v)));
+                    live.insert(id, (slot, payload.collect()));
+                }
+                Record::Delete { id } => {
+                    live.remove(&id);
+                }
+            }
+        }
+
+        let Some(generation) = vectors_file.as_deref().and_then(generation_of) else {
+            bail!("vector store shard in {dir:?} has no vectors file");
+        };
+        let mut shard = Self::empty(dir, dim, generation);
+        shard.remap()?;
+        let len = shard.vectors.as_ref().map_or(0, |vectors| vectors.len());
+        let slots = (len / (dim * 4)) as u64;
+
+        let mut live = live
+            .into_iter()
+            .filter(|(_, (slot, _))| *slot < slots)
+            .collect::<Vec<_>>();
+        live.sort_by_key(|(_, (slot, _))| *slot);
+
+        for (id, (slot, payload)) in live {
+            shard.push(id, slot, payload);
+        }
+        shard.next_slot = slots;
+
+        let aligned = len % (dim * 4) == 0;
+        if !aligned || slots != shard.ids.len() as u64 {
+            shard.compact()?;
+        }
+
+        Ok(shard)
+    }
+
+    /// Map the current vectors file, after it was written to.
+    fn remap(&mut self) -> anyhow::Result<()> {
+        let file = File::open(self.dir.join(vectors_file_name(self.generation)))?;
+        self.vectors = if file.metadata()?.len() == 0 {
+            None
+        } else {
+            // SAFETY: vectors files are only ever appended to past the slots in use, and
+            // compaction writes a new file rather than rewriting the mapped one
+            Some(unsafe { Mmap::map(&file)? })
+        };
+        Ok(())
+    }
+
+    /// The little-endian bytes of the vector of the point at `i`.
+    fn vector_bytes(&self, i: usize) -> &[u8] {
+        let vectors = self
+            .vectors
+            .as_ref()
+            .expect("the vectors of live points are mapped");
+        let start = self.slots[i] as usize * self.dim * 4;
+        &vectors[start..start + self.dim * 4]
+    }
+
+    fn vector(&self, i: usize) -> Vec<f32> {
+        self.vector_bytes(i)
+            .chunks_exact(4)
+            .map(|b| f32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+            .collect()
+    }
+
+    /// The cosine similarity of the point at `i` to the normalized `query`.
+    fn score(&self, query: &[f32], i: usize) -> f32 {
+        self.vector_bytes(i)
+            .chunks_exact(4)
+            .zip(query)
+            .map(|(b, q)| f32::from_le_bytes([b[0], b[1], b[2], b[3]]) * q)
+            .sum()
+    }
+
+    fn push(&mut self, id: StoredId, slot: u64, payload: HashMap<String, Value>) {
+        match self.positions.get(&id) {
+            Some(&i) => {
+                self.slots[i] = slot;
+                self.payloads[i] = payload;
+            }
+            None => {
+                self.positions.insert(id.clone(), self.ids.len());
+                self.ids.push(id);
+                self.slots.push(slot);
+                self.payloads.push(payload);
+            }
+        }
+    }
+
+    fn upsert(
+        &mut self,
+        points: Vec<(StoredId, Vec<f32>, HashMap<String, Value>)>,
+    ) -> anyhow::Result<()> {
+        let mut vectors = BufWriter::new(self.append(&vectors_file_name(self.generation))?);
+        let mut log = BufWriter::new(self.append("points.jsonl")?);
+
+        for (id, vector, payload) in points {
+            for value in &vector {
+                vectors.write_all(&value.to_le_bytes())?;
+            }
+
+            let record = Record::Upsert {
+                id: id.clone(),
+                slot: self.next_slot,
+                payload: payload
+                    .iter()
+                    .map(|(k, v)| (k.clone(), to_json(v)))
+                    .collect(),
+            };
+            serde_json::to_writer(&mut log, &record)?;
+            log.write_all(b"\n")?;
+
+            self.push(id, self.next_slot, payload);
+            self.next_slot += 1;
+        }
+
+        // vectors go first, so every logged slot is on disk
+        vectors.flush()?;
+        log.flush()?;
+        drop((vectors, log));
+
+        self.remap()?;
+        self.compact_if_sparse()
+    }
+
+    fn delete_ids<'a>(&mut self, ids: impl Iterator<Item = &'a StoredId>) -> anyhow::Result<()> {
+        let deleted = ids
+            .filter_map(|id| self.positions.get(id).copied())
+            .collect();
+        self.delete(deleted)
+    }
+
+    /// Delete the points at the positions `deleted`.
+    fn delete(&mut self, mut deleted: Vec<usize>) -> anyhow::Result<()> {
+        if deleted.is_empty() {
+            return Ok(());
+        }
+
+        deleted.sort_unstable();
+        deleted.dedup();
+
+        let mut log = BufWriter::new(self.append("points.jsonl")?);
+        for &i in &deleted {
+            let record = Record::Delete {
+                id: self.ids[i].clone(),
+            };
+            serde_json::to_writer(&mut log, &record)?;
+            log.write_all(b"\n")?;
+        }
+        log.flush()?;
+        drop(log);
+
+        // removing from the back keeps the positions of the points still to remove
+        for &i in deleted.iter().rev() {
+            self.swap_remove(i);
+        }
+
+        self.compact_if_sparse()
+    }
+
+    fn swap_remove(&mut self, i: usize) {
+        let last = self.ids.len() - 1;
+        let id = self.ids.swap_remove(i);
+        self.positions.remove(&id);
+        self.slots.swap_remove(i);
+        self.payloads.swap_remove(i);
+
+        if i != last {
+            self.positions.insert(self.ids[i].clone(), i);
+        }
+    }
+
+    fn compact_if_sparse(&mut self) -> anyhow::Result<()> {
+        let live = self.ids.len() as u64;
+        let dead = self.next_slot - live;
+        if dead >= MIN_COMPACTED_SLOTS && dead > live {
+            self.compact()?;
+        }
+
+        Ok(())
+    }
+
+    /// Rewrite the shard with only its live points, into a new vectors file and log.
+    ///
+    /// The new log replaces the old one at once, and names the new vectors file, so an
+    /// interrupted compaction leaves the shard as it was.
+    fn compact(&mut self) -> anyhow::Result<()> {
+        let generation = self.generation + 1;
+        let vectors_file = vectors_file_name(generation);
+
+        let mut vectors = BufWriter::new(File::create(self.dir.join(&vectors_file))?);
+        for i in 0..self.ids.len() {
+            vectors.write_all(self.vector_bytes(i))?;
+        }
+        vectors.flush()?;
+
+        let log_path = self.dir.join("points.jsonl.tmp");
+        let mut log = BufWriter::new(File::create(&log_path)?);
+        serde_json::to_writer(&mut log, &Record::Vectors { file: vectors_file })?;
+        log.write_all(b"\n")?;
+        for (slot, (id, payload)) in self.ids.iter().zip(&self.payloads).enumerate() {
+            let record = Record::Upsert {
+                id: id.clone(),
+                slot: slot as u64,
+                payload: payload
+                    .iter()
+                    .map(|(k, v)| (k.clone(), to_json(v)))
+                    .collect(),
+            };
+            serde_json::to_writer(&mut log, &record)?;
+            log.write_all(b"\n")?;
+        }
+        log.flush()?;
+
+        drop((vectors, log));
+        fs::rename(log_path, self.dir.join("points.jsonl"))?;
+
+        // the stale file is unmapped before it is removed, which some platforms require
+        let stale = self.dir.join(vectors_file_name(self.generation));
+        self.generation = generation;
+        self.slots = (0..self.ids.len() as u64).collect();
+        self.next_slot = self.ids.len() as u64;
+        self.remap()?;
+
+        if let Err(err) = fs::remove_file(stale) {
+            if err.kind() != std::io::ErrorKind::NotFound {
+                warn!(?err, dir = ?self.dir, "failed to remove compacted vectors");
+            }
+        }
+
+        debug!(dir = ?self.dir, points = self.ids.len(), "compacted vector store shard");
+        Ok(())
+    }
+
+    fn append(&self, name: &str) -> std::io::Result<File> {
+        OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(self.dir.join(name))
+    }
+}
+
+fn vectors_file_name(generation: u64) -> String {
+    format!("vectors.{generation}.f32")
+}
+
+fn generation_of(vectors_file: &str) -> Option<u64> {
+    vectors_file
+        .strip_prefix("vectors.")?
+        .strip_suffix(".f32")?
+        .parse()
+        .ok()
+}
+
+/// Evaluate `filter` like qdrant does: every `must` condition holds, at least one `should`
+/// condition holds if there are any, and no `must_not` condition holds.
+///
+/// Only the conditions the semantic index filters with are supported, others never hold.
+fn matches(filter: &Filter, id: &StoredId, payload: &HashMap<String, Value>) -> bool {
+    let holds = |condition: &Condition| match condition.condition_one_of.as_ref() {
+        Some(ConditionOneOf::Field(field)) => {
+            let value = payload.get(&field.key);
+            match field.r#match.as_ref().and_then(|m| m.match_value.as_ref()) {
+                Some(expected) => value.map_or(false, |v| value_matches(v, expected)),
+                None => false,
+            }
+        }
+        Some(ConditionOneOf::HasId(has_id)) => has_id
+            .has_id
+            .iter()
+            .any(|other| StoredId::from_point(Some(other)).map_or(false, |other| other == *id)),
+        Some(ConditionOneOf::Filter(nested)) => matches(nested, id, payload),
+        _ => false,
+    };
+
+    filter.must.iter().all(holds)
+        && (filter.should.is_empty() || filter.should.iter().any(holds))
+        && !filter.must_not.iter().any(holds)
+}
+
+/// Whether a payload `value` matches, like qdrant lists match if any of their items does.
+fn value_matches(value: &Value, expected: &MatchValue) -> bool {
+    match (value.kind.as_ref(), expected) {
+        (Some(Kind::ListValue(list)), _) => list.values.iter().any(|v| value_matches(v, expected)),
+        (Some(Kind::StringValue(s)), MatchValue::Keyword(keyword)) => s == keyword,
+        (Some(Kind::StringValue(s)), MatchValue::Text(text)) => s.contains(text.as_str()),
+        (Some(Kind::IntegerValue(i)), MatchValue::Integer(expected)) => i == expected,
+        (Some(Kind::BoolValue(b)), MatchValue::Boolean(expected)) => b == expected,
+        _ => false,
+    }
+}
+
+/// The fields of `payload` selected by `selector`, none if it is unset, like in qdrant.
+fn selected(
+    payload: &HashMap<String, Value>,
+    selector: Option<&WithPayloadSelector>,
+) -> HashMap<String, Value> {
+    use with_payload_selector::SelectorOptions;
+
+    match selector.and_then(|s| s.selector_options.as_ref()) {
+        Some(SelectorOptions::Enable(true)) => payload.clone(),
+        Some(SelectorOptions::Include(include)) => include
+            .fields
+            .iter()
+            .filter_map(|f| Some((f.clone(), payload.get(f)?.clone())))
+            .collect(),
+        Some(SelectorOptions::Exclude(exclude)) => payload
+            .iter()
+            .filter(|(k, _)| !exclude.fields.contains(k))
+            .map(|(k, v)| (k.clone(), v.clone()))
+            .collect(),
+        _ => HashMap::new(),
+    }
+}
+
+fn with_vectors(selector: Option<&WithVectorsSelector>) -> bool {
+    matches!(
+        selector.and_then(|s| s.selector_options.as_ref()),
+        Some(with_vectors_selector::SelectorOptions::Enable(true))
+    )
+}
+
+/// `vector` scaled to unit length, so cosine similarity is a dot product. This is what qdrant
+/// does with the vectors of cosine collections too.
+fn normalized(mut vector: Vec<f32>) -> Vec<f32> {
+    let norm = super::dot(&vector, &vector).sqrt();
+    if norm > 0.0 {
+        vector.iter_mut().for_each(|v| *v /= norm);
+    }
+    vector
+}
+
+/// A directory name for `name`, which may not be a valid path itself.
+fn hashed(name: &str) -> String {
+    blake3::hash(name.as_bytes()).to_hex()[..32].to_owned()
+}
+
+fn to_json(value: &Value) -> serde_json::Value {
+    match value.kind.as_ref() {
+        None | Some(Kind::NullValue(_)) => serde_json::Value::Null,
+        Some(Kind::DoubleValue(d)) => (*d).into(),
+        Some(Kind::IntegerValue(i)) => (*i).into(),
+        Some(Kind::StringValue(s)) => s.clone().into(),
+        Some(Kind::BoolValue(b)) => (*b).into(),
+        Some(Kind::StructValue(s)) => serde_json::Value::Object(
+            s.fields
+                .iter()
+                .map(|(k, v)| (k.clone(), to_json(v)))
+                .collect(),
+        ),
+        Some(Kind::ListValue(l)) => l.values.iter().map(to_json).collect(),
+    }
+}
+
+fn from_json(value: serde_json::Value) -> Value {
+    let kind = match value {
+        serde_json::Value::Null => Kind::NullValue(0),
+        serde_json::Value::Bool(b) => Kind::BoolValue(b),
+        serde_json::Value::Number(n) => match n.as_i64() {
+            Some(i) => Kind::IntegerValue(i),
+            None => Kind::DoubleValue(n.as_f64().unwrap_or_default()),
+        },
+        serde_json::Value::String(s) => Kind::StringValue(s),
+        serde_json::Value::Array(values) => Kind::ListValue(ListValue {
+            values: values.into_iter().map(from_json).collect(),
+        }),
+        serde_json::Value::Object(fields) => Kind::StructValue(Struct {
+            fields: fields.into_iter().map(|(k, v)| (k, from_json(v))).collect(),
+        }),
+    };
+
+    Value { kind: Some(kind) }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::query::parser;
+    use crate::semantic::{
+        collection_config,
+        filter::{build_filter, FilterArgs, FilterLogic, Folding},
+        kind::ChunkKind,
+        paths_filter,
+        payload::{PayloadFields, PayloadSchema},
+        point,
+        retry::ChunkPayload,
+        search_points, EMBEDDING_DIM,
+    };
+
+    fn chunk(repo_ref: &str, relative_path: &str, snippet: &str) -> ChunkPayload {
+        ChunkPayload {
+            repo_name: repo_ref.to_owned(),
+            repo_ref: repo_ref.to_owned(),
+            relative_path: relative_path.to_owned(),
+            lang: "rust".to_owned(),
+            branches: vec!["main".to_owned()],
+            snippet: snippet.to_owned(),
+            start_line: 0,
+            end_line: 1,
+            start_byte: 0,
+            end_byte: snippet.len(),
+            cell_index: None,
+            kind: ChunkKind::default(),
+            definitions: vec![],
+        }
+    }
+
+    /// A vector along the first two axes, at `angle` radians from the first.
+    fn vector(angle: f32) -> Vec<f32> {
+        let mut vector = vec![0.0; EMBEDDING_DIM as usize];
+        vector[0] = angle.cos();
+        vector[1] = angle.sin();
+        vector
+    }
+
+    async fn store(root: &Path) -> LocalStore {
+        let store = LocalStore::open(root).unwrap();
+        store
+            .create_collection(&collection_config("test"))
+            .await
+            .unwrap();
+        store
+    }
+
+    fn snippets(points: &[ScoredPoint]) -> Vec<&str> {
+        points
+            .iter()
+            .map(|p| match p.payload["snippet"].kind.as_ref() {
+                Some(Kind::StringValue(s)) => s.as_str(),
+                _ => panic!("snippet is not a string"),
+            })
+            .collect()
+    }
+
+    async fn search(store: &LocalStore, filter: Option<Filter>) -> Vec<ScoredPoint> {
+        let request = search_points(
+            "test",
+            None,
+            vector(0.0),
+            filter,
+            None,
+            &PayloadFields::snippet(),
+            10,
+        );
+        store.search(&request).await.unwrap()
+    }
+
+    #[tokio::test]
+    async fn search_ranks_by_cosine_within_filters() {
+        let dir = tempdir::TempDir::new("local-store").unwrap();
+        let store = store(dir.path()).await;
+
+        store
+            .upsert(
+                "test",
+                vec![
+                    point(chunk("a", "src/far.rs", "far"), vector(1.0)),
+                    point(chunk("a", "src/near.rs", "near"), vector(0.1)),
+                    point(chunk("b", "src/nearest.rs", "nearest"), vector(0.0)),
+                ],
+            )
+            .await
+            .unwrap();
+
+        let all = search(&store, None).await;
+        assert_eq!(snippets(&all), ["nearest", "near", "far"]);
+        assert!((all[0].score - 1.0).abs() < 1e-6);
+        assert!((all[2].score - 1.0f32.cos()).abs() < 1e-6);
+        assert!(!all[0].payload.contains_key("branches"));
+
+        let filter = build_filter(&FilterArgs::new(FilterLogic::And).within_repos(["a"]));
+        assert_eq!(snippets(&search(&store, filter).await), ["near", "far"]);
+
+        let filter =
+            build_filter(&FilterArgs::new(FilterLogic::And).text("relative_path", ["far"]));
+        assert_eq!(snippets(&search(&store, filter).await), ["far"]);
+    }
+
+    #[tokio::test]
+    async fn quoted_phrases_filter_and_the_rest_ranks() {
+        let dir = tempdir::TempDir::new("local-store").unwrap();
+        let store = store(dir.path()).await;
+
+        store
+            .upsert(
+                "test",
+                vec![
+                    point(chunk("a", "src/main.rs", "fn main() {}"), vector(0.5)),
+                    point(
+                        chunk("a", "src/cli.rs", "parse args in fn main"),
+                        vector(0.2),
+                    ),
+                    // closest to the query, but only has the words of the phrase
+                    point(chunk("a", "src/lib.rs", "fn parse_main() {}"), vector(0.0)),
+                ],
+            )
+            .await
+            .unwrap();
+
+        let query = parser::parse_nl(r#"parse args "fn main""#).unwrap();
+        let filter = build_filter(&FilterArgs::from_query(&query, FilterLogic::And));
+        assert_eq!(
+            snippets(&search(&store, filter).await),
+            ["parse args in fn main", "fn main() {}"]
+        );
+    }
+
+    #[tokio::test]
+    async fn folded_filters_match_regardless_of_case_and_accents() {
+        let dir = tempdir::TempDir::new("local-store").unwrap();
+        let store = store(dir.path()).await;
+        let folding = Folding {
+            case: true,
+            accents: true,
+        };
+        let folded = |chunk| {
+            let mut point = point(chunk, vector(0.0));
+            folding.index(&PayloadSchema::default(), &mut point.payload);
+            point
+        };
+
+        store
+            .upsert(
+                "test",
+                vec![
+                    folded(chunk("a", "src/Résumé.rs", "fn handle_Error() {}")),
+                    folded(chunk("a", "src/lib.rs", "fn parse() {}")),
+                    // indexed before folding was enabled
+                    point(chunk("a", "src/Legacy.rs", "ERROR"), vector(0.0)),
+                ],
+            )
+            .await
+            .unwrap();
+
+        let path = |value: &str| {
+            build_filter(
+                &FilterArgs::new(FilterLogic::And)
+                    .text("relative_path", [value])
+                    .folded(folding),
+            )
+        };
+        let text = |value: &str| {
+            Some(Filter {
+                must: vec![folding.text_condition(&PayloadSchema::default(), "snippet", value)],
+                ..Default::default()
+            })
+        };
+
+        for filter in [path("src/resume"), path("SRC/RÉSUMÉ.rs"), text("error()")] {
+            assert_eq!(
+                snippets(&search(&store, filter).await),
+                ["fn handle_Error() {}"]
+            );
+        }
+
+        // chunks without folded text are still matched verbatim
+        assert_eq!(snippets(&search(&store, path("Legacy")).await), ["ERROR"]);
+        assert!(search(&store, path("legacy")).await.is_empty());
+
+        // quoted phrases are matched verbatim
+        let phrase = |value: &str| {
+            build_filter(
+                &FilterArgs::new(FilterLogic::And)
+                    .containing([value])
+                    .folded(folding),
+            )
+        };
+        assert_eq!(
+            snippets(&search(&store, phrase("handle_Error")).await),
+            ["fn handle_Error() {}"]
+        );
+        assert!(search(&store, phrase("handle_error")).await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn points_persist_across_reopening() {
+        let dir = tempdir::TempDir::new("local-store").unwrap();
+        {
+            let store = store(dir.path()).await;
+            store
+                .upsert(
+                    "test",
+                    vec![
+                        point(chunk("a", "src/kept.rs", "kept"), vector(0.2)),
+                        point(chunk("a", "src/deleted.rs", "deleted"), vector(0.0)),
+                    ],
+                )
+                .await
+                .unwrap();
+
+            let selector = paths_filter(
+                &PayloadSchema::default(),
+                "a",
+                ["src/deleted.rs"].into_iter(),
+            )
+            .into();
+            store.delete("test", &selector).await.unwrap();
+        }
+
+        let store = LocalStore::open(dir.path()).unwrap();
+        assert!(store.has_collection("test").await.unwrap());
+        assert_eq!(store.points_count("test").await.unwrap(), 1);
+        assert_eq!(snippets(&search(&store, None).await), ["kept"]);
+    }
+
+    #[tokio::test]
+    async fn upserting_an_id_again_replaces_the_point() {
+        let dir = tempdir::TempDir::new("local-store").unwrap();
+        let store = store(dir.path()).await;
+
+        let first = point(chunk("a", "src/lib.rs", "old"), vector(1.0));
+        let mut second = point(chunk("b", "src/lib.rs", "new"), vector(0.0));
+        second.id = first.id.clone();
+
+        store.upsert("test", vec![first]).await.unwrap();
+        store.upsert("test", vec![second]).await.unwrap();
+
+        assert_eq!(store.points_count("test").await.unwrap(), 1);
+        let found = search(&store, None).await;
+        assert_eq!(snippets(&found), ["new"]);
+        assert!((found[0].score - 1.0).abs() < 1e-6);
+    }
+
+    #[test]
+    fn compacted_shards_keep_mapped_vectors() {
+        let dir = tempdir::TempDir::new("local-store").unwrap();
+        let mut shard = Shard::create(dir.path().to_owned(), 2).unwrap();
+
+        let ids = (0..3).map(StoredId::Num).collect::<Vec<_>>();
+        let points = ids
+            .iter()
+            .zip([[1.0, 0.0], [0.0, 1.0], [0.6, 0.8]])
+            .map(|(id, vector)| (id.clone(), vector.to_vec(), HashMap::new()))
+            .collect();
+        shard.upsert(points).unwrap();
+        shard.delete_ids(ids[..1].iter()).unwrap();
+        shard.compact().unwrap();
+
+        let vectors = |shard: &Shard| {
+            let mut vectors = (0..shard.ids.len())
+                .map(|i| (shard.ids[i].clone(), shard.vector(i)))
+                .collect::<Vec<_>>();
+            vectors.sort_by(|a, b| a.0.cmp(&b.0));
+            vectors
+        };
+        let expected = vec![
+            (StoredId::Num(1), vec![0.0, 1.0]),
+            (StoredId::Num(2), vec![0.6, 0.8]),
+        ];
+
+        assert_eq!(vectors(&shard), expected);
+        assert!(!dir.path().join(vectors_file_name(1)).exists());
+
+        let reloaded = Shard::load(dir.path().to_owned(), 2).unwrap();
+        assert_eq!(vectors(&reloaded), expected);
+        assert!((reloaded.score(&[0.0, 1.0], reloaded.positions[&ids[2]]) - 0.8).abs() < 1e-6);
+    }
+
+    #[tokio::test]
+    async fn scroll_pages_through_points_in_id_order() {
+        let dir = tempdir::TempDir::new("local-store").unwrap();
+        let store = store(dir.path()).await;
+
+        let points = (0..5)
+            .map(|i| {
+                let mut point = point(chunk("a", &format!("src/{i}.rs"), "code"), vector(0.0));
+                point.id = Some(PointId::from(i as u64));
+                point
+            })
+            .collect();
+        store.upsert("test", points).await.unwrap();
+
+        let mut offset = None;
+        let mut seen = vec![];
+        loop {
+            let response = store
+                .scroll(&ScrollPoints {
+                    collection_name: "test".to_owned(),
+                    offset,
+                    limit: Some(2),
+                    ..Default::default()
+                })
+                .await
+                .unwrap();
+
+            seen.extend(response.result.into_iter().map(|p| p.id.unwrap()));
+            match response.next_page_offset {
+                Some(next) => offset = Some(next),
+                None => break,
+            }
+        }
+
+        let expected = (0..5).map(|i| PointId::from(i as u64)).collect::<Vec<_>>();
+        assert_eq!(seen, expected);
+    }
+}
@@ -0,0 +1,201 @@
+use serde::Deserialize;
+use thiserror::Error;
+
+#[derive(Error, Debug)]
+pub enum NotebookError {
+    #[error("invalid notebook: {0}")]
+    Json(#[from] serde_json::Error),
+
+    #[error("unsupported nbformat version {0}")]
+    UnsupportedVersion(u32),
+}
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum CellKind {
+    Code,
+    Markdown,
+}
+
+/// A single cell of a notebook, without its outputs.
+#[derive(Debug, PartialEq, Eq)]
+pub struct Cell {
+    /// Position of this cell in the notebook, counting cells of every kind
+    pub index: usize,
+    pub kind: CellKind,
+    pub source: String,
+}
+
+/// The indexable contents of a Jupyter notebook.
+#[derive(Debug)]
+pub struct Notebook {
+    /// Lowercase kernel language, e.g. `python`
+    pub lang: Option<String>,
+    pub cells: Vec<Cell>,
+}
+
+impl Notebook {
+    /// Parse the JSON source of an `.ipynb` file.
+    ///
+    /// Code cells are always extracted, markdown cells only if `markdown` is set. Raw cells and
+    /// all cell outputs are dropped.
+    pub fn parse(src: &str, markdown: bool) -> Result<Self, NotebookError> {
+        let raw = serde_json::from_str::<RawNotebook>(src)?;
+
+        // nbformat 3 and earlier nest cells under `worksheets`
+        if raw.nbformat < 4 {
+            return Err(NotebookError::UnsupportedVersion(raw.nbformat));
+        }
+
+        let lang = raw
+            .metadata
+            .kernelspec
+            .and_then(|k| k.language)
+            .or_else(|| raw.metadata.language_info.and_then(|l| l.name))
+            .map(|l| l.to_lowercase());
+
+        let cells = raw
+            .cells
+            .into_iter()
+            .enumerate()
+            .filter_map(|(index, cell)| {
+                let kind = match cell.cell_type.as_str() {
+                    "code" => CellKind::Code,
+                    "markdown" if markdown => CellKind::Markdown,
+                    _ => return None,
+                };
+
+                let source = match cell.source {
+                    Source::Text(text) => text,
+                    Source::Lines(lines) => lines.concat(),
+                };
+
+                Some(Cell {
+                    index,
+                    kind,
+                    source,
+                })
+            })
+            .collect();
+
+        Ok(Self { lang, cells })
+    }
+}
+
+#[derive(Deserialize)]
+struct RawNotebook {
+    nbformat: u32,
+    #[serde(default)]
+    metadata: RawMetadata,
+    #[serde(default)]
+    cells: Vec<RawCell>,
+}
+
+#[derive(Deserialize, Default)]
+struct RawMetadata {
+    kernelspec: Option<KernelSpec>,
+    language_info: Option<LanguageInfo>,
+}
+
+#[derive(Deserialize)]
+struct KernelSpec {
+    language: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct LanguageInfo {
+    name: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct RawCell {
+    cell_type: String,
+    #[serde(default)]
+    source: Source,
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum Source {
+    Text(String),
+    Lines(Vec<String>),
+}
+
+impl Default for Source {
+    fn default() -> Self {
+        Self::Text(String::new())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const NOTEBOOK: &str = r##"{
+        "nbformat": 4,
+        "nbformat_minor": 5,
+        "metadata": {
+            "kernelspec": { "display_name": "Python 3", "language": "Python", "name": "python3" }
+        },
+        "cells": [
+            { "cell_type": "markdown", "metadata": {}, "source": ["# Title\n", "Some text"] },
+            {
+                "cell_type": "code",
+                "metadata": {},
+                "execution_count": 1,
+                "source": ["import numpy as np\n", "np.zeros(3)"],
+                "outputs": [{ "output_type": "display_data", "data": { "image/png": "iVBORw0KGgo=" } }]
+            },
+            { "cell_type": "raw", "metadata": {}, "source": "raw text" },
+            { "cell_type": "code", "metadata": {}, "source": "print('hi')", "outputs": [] }
+        ]
+    }"##;
+
+    #[test]
+    fn extracts_code_cells() {
+        let notebook = Notebook::parse(NOTEBOOK, false).unwrap();
+
+        assert_eq!(notebook.lang.as_deref(), Some("python"));
+        assert_eq!(
+            notebook.cells,
+            vec![
+                Cell {
+                    index: 1,
+                    kind: CellKind::Code,
+                    source: "import numpy as np\nnp.zeros(3)".into(),
+                },
+                Cell {
+                    index: 3,
+                    kind: CellKind::Code,
+                    source: "print('hi')".into(),
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn extracts_markdown_cells() {
+        let notebook = Notebook::parse(NOTEBOOK, true).unwrap();
+
+        assert_eq!(notebook.cells.len(), 3);
+        assert_eq!(
+            notebook.cells[0],
+            Cell {
+                index: 0,
+                kind: CellKind::Markdown,
+                source: "# Title\nSome text".into(),
+            }
+        );
+    }
+
+    #[test]
+    fn rejects_malformed_notebooks() {
+        assert!(matches!(
+            Notebook::parse("{ not json", false),
+            Err(NotebookError::Json(_))
+        ));
+        assert!(matches!(
+            Notebook::parse(r#"{ "nbformat": 3, "worksheets": [] }"#, false),
+            Err(NotebookError::UnsupportedVersion(3))
+        ));
+    }
+}
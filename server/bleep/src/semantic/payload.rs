@@ -0,0 +1,216 @@
+use std::collections::{BTreeSet, HashMap};
+
+use anyhow::bail;
+use qdrant_client::qdrant::{with_payload_selector, PayloadIncludeSelector, WithPayloadSelector};
+use serde::Serialize;
+
+/// Fields of every chunk, locating it and holding its text
+pub const CHUNK_FIELDS: &[&str] = &[
+    "repo_name",
+    "repo_ref",
+    "relative_path",
+    "lang",
+    "snippet",
+    "start_line",
+    "end_line",
+    "start_byte",
+    "end_byte",
+    "cell_index",
+];
+
+/// Fields read into an answer `Snippet` besides [`CHUNK_FIELDS`]
+pub const SNIPPET_FIELDS: &[&str] = &["symbol", "kind", "definitions"];
+
+/// Fields that break score ties, see [`sort_deterministically`](super::sort_deterministically)
+pub(super) const TIE_BREAK_FIELDS: &[&str] =
+    &["repo_ref", "relative_path", "cell_index", "start_byte"];
+
+/// The payload fields fetched with each point of a search.
+///
+/// Fields missing from a point's payload are skipped, so projections can list fields that
+/// only some chunks have.
+#[derive(Serialize, Clone, Debug, PartialEq, Eq)]
+#[serde(transparent)]
+pub struct PayloadFields {
+    /// `None` fetches the whole payload
+    fields: Option<BTreeSet<String>>,
+}
+
+impl PayloadFields {
+    pub fn all() -> Self {
+        Self { fields: None }
+    }
+
+    pub fn only<S: Into<String>>(fields: impl IntoIterator<Item = S>) -> Self {
+        Self {
+            fields: Some(fields.into_iter().map(Into::into).collect()),
+        }
+    }
+
+    /// The fields of an answer `Snippet`.
+    pub fn snippet() -> Self {
+        Self::only(CHUNK_FIELDS.iter().chain(SNIPPET_FIELDS).copied())
+    }
+
+    /// Fetch `fields` as well, if the payload is not fetched whole already.
+    pub fn with<S: Into<String>>(mut self, fields: impl IntoIterator<Item = S>) -> Self {
+        if let Some(ref mut own) = self.fields {
+            own.extend(fields.into_iter().map(Into::into));
+        }
+        self
+    }
+
+    pub(super) fn selector(&self) -> WithPayloadSelector {
+        let options = match &self.fields {
+            None => with_payload_selector::SelectorOptions::Enable(true),
+            Some(fields) => {
+                with_payload_selector::SelectorOptions::Include(PayloadIncludeSelector {
+                    fields: fields.iter().cloned().collect(),
+                })
+            }
+        };
+
+        WithPayloadSelector {
+            selector_options: Some(options),
+        }
+    }
+}
+
+impl Default for PayloadFields {
+    fn default() -> Self {
+        Self::all()
+    }
+}
+
+/// The payload keys the fields of a chunk are stored under, see `Configuration::payload_keys`.
+///
+/// Fields are named after the keys this instance indexes with, and stored under keys of their
+/// own name unless mapped to another one. Filters, orderings and the chunks this instance
+/// writes all go through the mapping.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct PayloadSchema {
+    keys: HashMap<&'static str, String>,
+}
+
+impl PayloadSchema {
+    /// Parse `FIELD=KEY` mappings, whose fields are [`CHUNK_FIELDS`] or [`SNIPPET_FIELDS`].
+    pub fn parse(mappings: &[String]) -> anyhow::Result<Self> {
+        let mut keys = HashMap::new();
+        for mapping in mappings {
+            let Some((field, key)) = mapping.split_once('=') else {
+                bail!("payload key `{mapping}` should be given as `FIELD=KEY`");
+            };
+
+            let Some(field) = CHUNK_FIELDS
+                .iter()
+                .chain(SNIPPET_FIELDS)
+                .find(|known| **known == field.trim())
+            else {
+                bail!(
+                    "unknown chunk field `{field}`, expected one of: {}",
+                    CHUNK_FIELDS
+                        .iter()
+                        .chain(SNIPPET_FIELDS)
+                        .copied()
+                        .collect::<Vec<_>>()
+                        .join(", ")
+                );
+            };
+
+            keys.insert(*field, key.trim().to_owned());
+        }
+
+        Ok(Self { keys })
+    }
+
+    /// The payload key `field` is stored under.
+    pub fn key<'a>(&'a self, field: &'a str) -> &'a str {
+        self.keys.get(field).map_or(field, String::as_str)
+    }
+
+    /// `payload`, whose keys are named by field, with each field moved to the key it is stored
+    /// under.
+    pub(super) fn store<V>(&self, payload: HashMap<String, V>) -> HashMap<String, V> {
+        if self.keys.is_empty() {
+            return payload;
+        }
+
+        payload
+            .into_iter()
+            .map(|(field, value)| (self.key(&field).to_owned(), value))
+            .collect()
+    }
+
+    /// `payload`, keyed as it is stored, with each key renamed to the field stored under it.
+    ///
+    /// Keys no field is mapped to are kept as they are.
+    pub fn load<V>(&self, payload: HashMap<String, V>) -> HashMap<String, V> {
+        if self.keys.is_empty() {
+            return payload;
+        }
+
+        payload
+            .into_iter()
+            .map(|(key, value)| {
+                let field = self.keys.iter().find(|(_, stored)| **stored == key);
+                (field.map_or(key, |(field, _)| (*field).to_owned()), value)
+            })
+            .collect()
+    }
+
+    /// Take `field` out of `payload`.
+    pub fn take<V>(&self, payload: &mut HashMap<String, V>, field: &str) -> Option<V> {
+        payload.remove(self.key(field))
+    }
+
+    /// Name `fields` by the keys they are stored under, for fetching them.
+    pub(super) fn keys_of(&self, fields: PayloadFields) -> PayloadFields {
+        match fields.fields {
+            Some(fields) if !self.keys.is_empty() => {
+                PayloadFields::only(fields.iter().map(|field| self.key(field)))
+            }
+            _ => fields,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn mapped_fields_are_fetched_by_their_key() {
+        let schema =
+            PayloadSchema::parse(&["snippet=content".into(), " relative_path = path".into()])
+                .unwrap();
+
+        assert_eq!(schema.key("snippet"), "content");
+        assert_eq!(schema.key("relative_path"), "path");
+        assert_eq!(schema.key("lang"), "lang");
+        assert_eq!(
+            schema.keys_of(PayloadFields::only(["snippet", "lang"])),
+            PayloadFields::only(["content", "lang"])
+        );
+        assert_eq!(schema.keys_of(PayloadFields::all()), PayloadFields::all());
+
+        let stored = schema.store(HashMap::from([
+            ("snippet".to_owned(), 1),
+            ("lang".into(), 2),
+        ]));
+        assert_eq!(
+            stored,
+            HashMap::from([("content".to_owned(), 1), ("lang".into(), 2)])
+        );
+        assert_eq!(
+            schema.load(stored),
+            HashMap::from([("snippet".to_owned(), 1), ("lang".into(), 2)])
+        );
+    }
+
+    #[test]
+    fn mappings_name_known_fields() {
+        assert!(PayloadSchema::parse(&["snippet".into()]).is_err());
+        assert!(PayloadSchema::parse(&["body=content".into()]).is_err());
+        assert_eq!(PayloadSchema::parse(&[]).unwrap(), PayloadSchema::default());
+    }
+}
@@ -0,0 +1,100 @@
+use std::collections::HashMap;
+
+use qdrant_client::qdrant::{value::Kind, RetrievedPoint, ScoredPoint, Value};
+
+use super::payload::PayloadSchema;
+
+/// What a [`ResultPostProcessor`] sees of a search result.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct SearchResult<'a> {
+    pub repo_ref: &'a str,
+    pub relative_path: &'a str,
+    /// The text of the chunk, empty if the search didn't fetch it
+    pub text: &'a str,
+    /// The raw score of the result, `0.0` for browsed chunks
+    pub score: f32,
+}
+
+/// Filtering and reordering of search results, for applications embedding `bleep` with rules of
+/// their own, such as hiding files a user may not access.
+///
+/// Set one with [`Semantic::with_post_processor`](super::Semantic::with_post_processor). It
+/// processes the results of every search, the chunks browsed, and the chunks that are updated.
+pub trait ResultPostProcessor: Send + Sync {
+    /// The positions in `results` of those to return for `query`, in the order to return them.
+    ///
+    /// Results come ranked by relevance, after answers collapsed the duplicates of their `dedup`
+    /// strategy. They are cut down to the limit of the search afterwards, so the ones dropped
+    /// here make room for others. Answers and tiered searches drop near duplicates while doing
+    /// so. The `query` is empty for chunks that weren't searched for, and browsed chunks keep
+    /// their browsing order.
+    fn process(&self, query: &str, results: &[SearchResult<'_>]) -> Vec<usize>;
+}
+
+/// Leaves results as they are, which is the default.
+pub struct Unprocessed;
+
+impl ResultPostProcessor for Unprocessed {
+    fn process(&self, _query: &str, results: &[SearchResult<'_>]) -> Vec<usize> {
+        (0..results.len()).collect()
+    }
+}
+
+/// Results [`Semantic::post_process`](super::Semantic::post_process) can be applied to.
+pub trait AsSearchResult {
+    /// This result, with its fields read from their keys in `schema`.
+    fn as_search_result<'a>(&'a self, schema: &PayloadSchema) -> SearchResult<'a>;
+}
+
+impl AsSearchResult for ScoredPoint {
+    fn as_search_result<'a>(&'a self, schema: &PayloadSchema) -> SearchResult<'a> {
+        SearchResult {
+            score: self.score,
+            ..payload_result(&self.payload, schema)
+        }
+    }
+}
+
+impl AsSearchResult for RetrievedPoint {
+    fn as_search_result<'a>(&'a self, schema: &PayloadSchema) -> SearchResult<'a> {
+        payload_result(&self.payload, schema)
+    }
+}
+
+fn payload_result<'a>(
+    payload: &'a HashMap<String, Value>,
+    schema: &PayloadSchema,
+) -> SearchResult<'a> {
+    let field = |field| match payload.get(schema.key(field)).and_then(|v| v.kind.as_ref()) {
+        Some(Kind::StringValue(value)) => value.as_str(),
+        _ => "",
+    };
+
+    SearchResult {
+        repo_ref: field("repo_ref"),
+        relative_path: field("relative_path"),
+        text: field("snippet"),
+        score: 0.0,
+    }
+}
+
+/// The `results` at `order`, skipping positions out of bounds or repeated.
+pub(super) fn reorder<T>(results: Vec<T>, order: Vec<usize>) -> Vec<T> {
+    let mut results = results.into_iter().map(Some).collect::<Vec<_>>();
+    order
+        .into_iter()
+        .filter_map(|i| results.get_mut(i)?.take())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn results_are_reordered_once_each() {
+        let results = vec!["a", "b", "c", "d"];
+        assert_eq!(reorder(results.clone(), vec![2, 0, 2, 9]), ["c", "a"]);
+        assert_eq!(reorder(results, vec![]), Vec::<&str>::new());
+    }
+}
@@ -0,0 +1,162 @@
+use std::{path::Path, sync::Arc};
+
+use crate::{cache::BoundedCache, Configuration};
+
+/// Words dropped from queries with `Configuration::strip_stopwords`
+const STOPWORDS: &[&str] = &[
+    "a", "an", "and", "are", "as", "at", "be", "by", "can", "do", "does", "for", "from", "how",
+    "i", "in", "is", "it", "of", "on", "or", "that", "the", "this", "to", "was", "what", "where",
+    "which", "who", "why", "with",
+];
+
+/// How queries are turned into the text that is embedded.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub(super) struct Preprocessing {
+    strip_stopwords: bool,
+}
+
+impl Preprocessing {
+    pub(super) fn new(config: &Configuration) -> Self {
+        Self {
+            strip_stopwords: config.strip_stopwords,
+        }
+    }
+
+    /// The text embedded for `query`.
+    ///
+    /// Whitespace is collapsed, which the tokenizer splits on anyway. Queries made up of
+    /// stopwords only are embedded as they are, rather than as an empty string.
+    pub(super) fn apply(self, query: &str) -> String {
+        let words = query.split_whitespace();
+        let kept = words
+            .clone()
+            .filter(|word| !(self.strip_stopwords && is_stopword(word)))
+            .collect::<Vec<_>>();
+
+        if kept.is_empty() {
+            words.collect::<Vec<_>>().join(" ")
+        } else {
+            kept.join(" ")
+        }
+    }
+}
+
+fn is_stopword(word: &str) -> bool {
+    STOPWORDS.contains(&word.to_lowercase().as_str())
+}
+
+/// Identifies the model embeddings are computed with, by the path and size of its weights.
+pub(super) fn model_id(model_dir: &Path) -> String {
+    let model = model_dir.join("model.onnx");
+    let model = model.canonicalize().unwrap_or(model);
+    let size = std::fs::metadata(&model)
+        .map(|m| m.len())
+        .unwrap_or_default();
+
+    format!("{}:{size}", model.display())
+}
+
+/// Everything an embedding of a query depends on.
+///
+/// Keying on the preprocessed text lets queries that only differ in whitespace, or in the
+/// stopwords that are dropped, share an entry, while changing the model or the preprocessing
+/// never hits vectors computed before.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub(super) struct QueryKey {
+    pub(super) text: String,
+    model_id: Arc<str>,
+    preprocessing: Preprocessing,
+}
+
+/// Embeddings of recent search queries, see `Configuration::query_embedding_cache_entries`.
+pub(super) struct QueryEmbeddings {
+    model_id: Arc<str>,
+    preprocessing: Preprocessing,
+    entries: Option<BoundedCache<QueryKey, Arc<Vec<f32>>>>,
+}
+
+impl QueryEmbeddings {
+    pub(super) fn new(model_id: String, preprocessing: Preprocessing, capacity: usize) -> Self {
+        Self {
+            model_id: model_id.into(),
+            preprocessing,
+            entries: (capacity > 0).then(|| BoundedCache::new(capacity)),
+        }
+    }
+
+    /// The key of `query`, whose `text` is what gets embedded.
+    pub(super) fn key(&self, query: &str) -> QueryKey {
+        QueryKey {
+            text: self.preprocessing.apply(query),
+            model_id: Arc::clone(&self.model_id),
+            preprocessing: self.preprocessing,
+        }
+    }
+
+    pub(super) fn get(&self, key: &QueryKey) -> Option<Vec<f32>> {
+        let vector = self.entries.as_ref()?.get(key)?;
+        Some(vector.as_ref().clone())
+    }
+
+    pub(super) fn insert(&self, key: QueryKey, vector: &[f32]) {
+        if let Some(entries) = self.entries.as_ref() {
+            entries.insert(key, Arc::new(vector.to_vec()));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PLAIN: Preprocessing = Preprocessing {
+        strip_stopwords: false,
+    };
+
+    const STRIPPED: Preprocessing = Preprocessing {
+        strip_stopwords: true,
+    };
+
+    #[test]
+    fn changing_the_model_misses_the_cache() {
+        let before = QueryEmbeddings::new("/models/a/model.onnx:90".into(), PLAIN, 8);
+        before.insert(before.key("parse a query"), &[1.0, 0.0]);
+        assert_eq!(
+            before.get(&before.key("parse  a query ")),
+            Some(vec![1.0, 0.0])
+        );
+
+        let after = QueryEmbeddings::new("/models/b/model.onnx:90".into(), PLAIN, 8);
+        assert_ne!(before.key("parse a query"), after.key("parse a query"));
+
+        // entries computed with the old model are never handed out for the new one
+        assert_eq!(before.get(&after.key("parse a query")), None);
+    }
+
+    #[test]
+    fn changing_the_preprocessing_misses_the_cache() {
+        let plain = QueryEmbeddings::new("model".into(), PLAIN, 8);
+        let stripped = QueryEmbeddings::new("model".into(), STRIPPED, 8);
+        plain.insert(plain.key("how to parse the query"), &[1.0]);
+
+        assert_eq!(stripped.key("how to parse the query").text, "parse query");
+        assert_eq!(plain.get(&stripped.key("how to parse the query")), None);
+        assert_eq!(
+            stripped.key("parse THE query"),
+            stripped.key("how to parse query")
+        );
+    }
+
+    #[test]
+    fn stopword_queries_are_kept_whole() {
+        assert_eq!(STRIPPED.apply("what is  this"), "what is this");
+        assert_eq!(PLAIN.apply("  where is the\tlexer "), "where is the lexer");
+    }
+
+    #[test]
+    fn empty_caches_store_nothing() {
+        let cache = QueryEmbeddings::new("model".into(), PLAIN, 0);
+        cache.insert(cache.key("parse"), &[1.0]);
+        assert_eq!(cache.get(&cache.key("parse")), None);
+    }
+}
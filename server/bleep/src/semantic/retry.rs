@@ -0,0 +1,394 @@
+use std::{
+    collections::HashMap,
+    sync::RwLock,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::Result;
+use qdrant_client::qdrant::Value;
+use serde::{Deserialize, Serialize};
+use utoipa::ToSchema;
+
+use super::kind::ChunkKind;
+use crate::state::{PersistedState, StateSource};
+
+/// Failed attempts after which a chunk is no longer retried automatically
+pub const MAX_ATTEMPTS: u32 = 5;
+
+/// Wait before the second retry of a chunk, doubled for every further one. The first retry
+/// happens on the next pass.
+const BASE_BACKOFF_SECS: u64 = 60;
+
+/// A chunk as written to the semantic index, without its embedding.
+#[derive(Serialize, Deserialize, ToSchema, Clone, Debug, PartialEq, Eq)]
+pub struct ChunkPayload {
+    pub repo_name: String,
+    pub repo_ref: String,
+    pub relative_path: String,
+    pub lang: String,
+    pub branches: Vec<String>,
+    pub snippet: String,
+    pub start_line: usize,
+    pub end_line: usize,
+    pub start_byte: usize,
+    pub end_byte: usize,
+    /// For notebook cells, lines and bytes are relative to the cell source
+    pub cell_index: Option<usize>,
+    #[serde(default)]
+    pub kind: ChunkKind,
+    /// Names of the symbols defined in the chunk
+    #[serde(default)]
+    pub definitions: Vec<String>,
+}
+
+impl ChunkPayload {
+    /// The sequence embedded for this chunk, prefixed with the repository name and path.
+    pub fn embedding_input(&self) -> String {
+        format!(
+            "{}\t{}\n{}",
+            self.repo_name, self.relative_path, self.snippet
+        )
+    }
+
+    pub fn into_qdrant(self) -> HashMap<String, Value> {
+        let mut payload = HashMap::from([
+            ("lang".into(), self.lang.into()),
+            ("repo_name".into(), self.repo_name.into()),
+            ("repo_ref".into(), self.repo_ref.into()),
+            ("branches".into(), Value::from(self.branches)),
+            ("relative_path".into(), self.relative_path.into()),
+            ("snippet".into(), self.snippet.into()),
+            ("start_line".into(), self.start_line.to_string().into()),
+            ("end_line".into(), self.end_line.to_string().into()),
+            ("start_byte".into(), self.start_byte.to_string().into()),
+            ("end_byte".into(), self.end_byte.to_string().into()),
+            ("kind".into(), self.kind.as_str().into()),
+            ("definitions".into(), Value::from(self.definitions)),
+        ]);
+
+        if let Some(index) = self.cell_index {
+            payload.insert("cell_index".into(), index.to_string().into());
+        }
+
+        payload
+    }
+
+    fn same_chunk(&self, other: &Self) -> bool {
+        self.relative_path == other.relative_path
+            && self.cell_index == other.cell_index
+            && self.start_byte == other.start_byte
+            && self.end_byte == other.end_byte
+    }
+}
+
+/// A chunk that failed to be embedded or written to the semantic index.
+#[derive(Serialize, Deserialize, ToSchema, Clone, Debug, PartialEq, Eq)]
+pub struct ChunkFailure {
+    pub chunk: ChunkPayload,
+    /// The error of the last attempt
+    pub error: String,
+    /// Failed attempts so far, including the one during indexing
+    pub attempts: u32,
+    /// Time of the last attempt, in seconds since the Unix epoch
+    pub last_attempt: u64,
+    /// Whether the chunk failed `MAX_ATTEMPTS` times, and is only retried on request
+    pub permanent: bool,
+}
+
+impl ChunkFailure {
+    fn is_due(&self, now: u64) -> bool {
+        let backoff = match self.attempts {
+            0 | 1 => 0,
+            n => BASE_BACKOFF_SECS << (n - 2).min(16),
+        };
+
+        !self.permanent && now >= self.last_attempt + backoff
+    }
+}
+
+#[derive(Serialize, ToSchema, Default, Clone, Copy, Debug, PartialEq, Eq)]
+pub struct FailureCounts {
+    /// Chunks that will be retried
+    pub pending: usize,
+    /// Chunks that failed `MAX_ATTEMPTS` times
+    pub permanent: usize,
+}
+
+#[derive(Serialize, ToSchema, Default, Debug, PartialEq, Eq)]
+pub struct RetryReport {
+    pub retried: usize,
+    pub succeeded: usize,
+    pub failed: usize,
+}
+
+/// Chunks that failed to make it into the semantic index, by repository.
+///
+/// Failures are retried with exponential backoff at the end of every index run, until they
+/// succeed, their file is reindexed or removed, or they fail `MAX_ATTEMPTS` times.
+#[derive(Clone)]
+pub struct ChunkFailures {
+    failures: PersistedState<RwLock<HashMap<String, Vec<ChunkFailure>>>>,
+}
+
+impl ChunkFailures {
+    pub fn load(source: &StateSource) -> Result<Self> {
+        Ok(Self {
+            failures: source.load_or_default("chunk_failures")?,
+        })
+    }
+
+    /// Record a failed attempt for each chunk, along with its error.
+    pub fn record(&self, failed: Vec<(ChunkPayload, String)>) -> Result<()> {
+        if failed.is_empty() {
+            return Ok(());
+        }
+
+        let now = unix_now();
+        {
+            let mut failures = self.failures.write().unwrap();
+            for (chunk, error) in failed {
+                let repo = failures.entry(chunk.repo_ref.clone()).or_default();
+                let failure = match repo.iter_mut().find(|f| f.chunk.same_chunk(&chunk)) {
+                    Some(failure) => failure,
+                    None => {
+                        repo.push(ChunkFailure {
+                            chunk,
+                            error: String::new(),
+                            attempts: 0,
+                            last_attempt: 0,
+                            permanent: false,
+                        });
+                        repo.last_mut().unwrap()
+                    }
+                };
+
+                failure.error = error;
+                failure.attempts += 1;
+                failure.last_attempt = now;
+                failure.permanent = failure.attempts >= MAX_ATTEMPTS;
+            }
+        }
+
+        self.failures.store()
+    }
+
+    /// Remove chunks that made it into the index.
+    pub fn resolve(&self, chunks: &[ChunkPayload]) -> Result<()> {
+        if chunks.is_empty() {
+            return Ok(());
+        }
+
+        {
+            let mut failures = self.failures.write().unwrap();
+            for chunk in chunks {
+                if let Some(repo) = failures.get_mut(&chunk.repo_ref) {
+                    repo.retain(|f| !f.chunk.same_chunk(chunk));
+                }
+            }
+            failures.retain(|_, repo| !repo.is_empty());
+        }
+
+        self.failures.store()
+    }
+
+    /// Forget the failures of `paths` in `repo_ref`, or of the whole repository if `paths` is
+    /// empty, as their points are being replaced or deleted.
+    pub fn forget(&self, repo_ref: &str, paths: &[&str]) -> Result<()> {
+        let changed = {
+            let mut failures = self.failures.write().unwrap();
+            match failures.get_mut(repo_ref) {
+                None => false,
+                Some(_) if paths.is_empty() => failures.remove(repo_ref).is_some(),
+                Some(repo) => {
+                    let len = repo.len();
+                    repo.retain(|f| !paths.contains(&f.chunk.relative_path.as_str()));
+                    let changed = repo.len() != len;
+
+                    if repo.is_empty() {
+                        failures.remove(repo_ref);
+                    }
+                    changed
+                }
+            }
+        };
+
+        if changed {
+            self.failures.store()?;
+        }
+
+        Ok(())
+    }
+
+    /// Chunks of `repo_ref` that are due for a retry, or all of them if `force` is set.
+    pub fn due(&self, repo_ref: &str, force: bool) -> Vec<ChunkPayload> {
+        let now = unix_now();
+        self.failures
+            .read()
+            .unwrap()
+            .get(repo_ref)
+            .into_iter()
+            .flatten()
+            .filter(|f| force || f.is_due(now))
+            .map(|f| f.chunk.clone())
+            .collect()
+    }
+
+    pub fn list(&self, repo_ref: &str) -> Vec<ChunkFailure> {
+        self.failures
+            .read()
+            .unwrap()
+            .get(repo_ref)
+            .cloned()
+            .unwrap_or_default()
+    }
+
+    pub fn counts(&self, repo_ref: &str) -> FailureCounts {
+        let mut counts = FailureCounts::default();
+        for failure in self
+            .failures
+            .read()
+            .unwrap()
+            .get(repo_ref)
+            .into_iter()
+            .flatten()
+        {
+            if failure.permanent {
+                counts.permanent += 1;
+            } else {
+                counts.pending += 1;
+            }
+        }
+
+        counts
+    }
+}
+
+fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempdir::TempDir;
+
+    fn failures(dir: &TempDir) -> ChunkFailures {
+        let mut source = StateSource::default();
+        source.set_default_dir(dir.path());
+        ChunkFailures::load(&source).unwrap()
+    }
+
+    fn chunk(relative_path: &str, start_byte: usize) -> ChunkPayload {
+        ChunkPayload {
+            repo_name: "bloop".into(),
+            repo_ref: "github.com/bloopai/bloop".into(),
+            relative_path: relative_path.into(),
+            lang: "rust".into(),
+            branches: vec!["head".into()],
+            snippet: "fn main() {}".into(),
+            start_line: 0,
+            end_line: 1,
+            start_byte,
+            end_byte: start_byte + 12,
+            cell_index: None,
+            kind: ChunkKind::Other,
+            definitions: vec![],
+        }
+    }
+
+    const REPO: &str = "github.com/bloopai/bloop";
+
+    #[test]
+    fn failures_are_persisted_until_resolved() {
+        let dir = TempDir::new("chunk-failures").unwrap();
+        let store = failures(&dir);
+
+        store
+            .record(vec![
+                (chunk("src/main.rs", 0), "timeout".into()),
+                (chunk("src/main.rs", 100), "timeout".into()),
+            ])
+            .unwrap();
+
+        let store = failures(&dir);
+        assert_eq!(
+            store.counts(REPO),
+            FailureCounts {
+                pending: 2,
+                permanent: 0
+            }
+        );
+        assert_eq!(store.due(REPO, false).len(), 2);
+
+        store.resolve(&[chunk("src/main.rs", 0)]).unwrap();
+        assert_eq!(
+            failures(&dir)
+                .list(REPO)
+                .iter()
+                .map(|f| f.chunk.start_byte)
+                .collect::<Vec<_>>(),
+            vec![100]
+        );
+    }
+
+    #[test]
+    fn retries_back_off_until_permanent() {
+        let dir = TempDir::new("chunk-failures").unwrap();
+        let store = failures(&dir);
+
+        for attempt in 1..=MAX_ATTEMPTS {
+            store
+                .record(vec![(
+                    chunk("src/main.rs", 0),
+                    format!("attempt {attempt}"),
+                )])
+                .unwrap();
+        }
+
+        let failure = store.list(REPO).pop().unwrap();
+        assert_eq!(failure.attempts, MAX_ATTEMPTS);
+        assert_eq!(failure.error, format!("attempt {MAX_ATTEMPTS}"));
+        assert!(failure.permanent);
+        assert_eq!(
+            store.counts(REPO),
+            FailureCounts {
+                pending: 0,
+                permanent: 1
+            }
+        );
+
+        // permanent failures are only retried on request
+        assert!(store.due(REPO, false).is_empty());
+        assert_eq!(store.due(REPO, true), vec![chunk("src/main.rs", 0)]);
+
+        let retried = ChunkFailure {
+            attempts: 2,
+            permanent: false,
+            ..failure
+        };
+        assert!(!retried.is_due(retried.last_attempt));
+        assert!(retried.is_due(retried.last_attempt + BASE_BACKOFF_SECS));
+    }
+
+    #[test]
+    fn reindexed_paths_are_forgotten() {
+        let dir = TempDir::new("chunk-failures").unwrap();
+        let store = failures(&dir);
+
+        store
+            .record(vec![
+                (chunk("src/main.rs", 0), "oom".into()),
+                (chunk("src/lib.rs", 0), "oom".into()),
+            ])
+            .unwrap();
+
+        store.forget(REPO, &["src/main.rs"]).unwrap();
+        assert_eq!(failures(&dir).counts(REPO).pending, 1);
+
+        store.forget(REPO, &[]).unwrap();
+        assert_eq!(failures(&dir).counts(REPO), FailureCounts::default());
+    }
+}
@@ -0,0 +1,84 @@
+use qdrant_client::qdrant::Distance;
+
+/// How alike two embeddings are when deduplicating results in memory, higher meaning closer.
+///
+/// This follows the distance metric the collection ranks points by, see
+/// [`DISTANCE`](super::DISTANCE), so that deduplication agrees with the ranking.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Similarity {
+    Cosine,
+    Dot,
+    /// `1 / (1 + d)` for the euclidean distance `d`, which is 1 for identical embeddings
+    Euclidean,
+}
+
+impl From<Distance> for Similarity {
+    fn from(distance: Distance) -> Self {
+        match distance {
+            Distance::Dot => Self::Dot,
+            Distance::Euclid => Self::Euclidean,
+            Distance::Cosine | Distance::UnknownDistance => Self::Cosine,
+        }
+    }
+}
+
+impl Similarity {
+    /// The similarity of `a` and `b`. Zero vectors have a cosine similarity of 0 to anything,
+    /// rather than `NaN`.
+    pub fn of(self, a: &[f32], b: &[f32]) -> f32 {
+        match self {
+            Self::Cosine => {
+                let norms = norm(a) * norm(b);
+                if norms == 0.0 {
+                    0.0
+                } else {
+                    dot(a, b) / norms
+                }
+            }
+            Self::Dot => dot(a, b),
+            Self::Euclidean => {
+                let distance = a
+                    .iter()
+                    .zip(b)
+                    .map(|(ai, bi)| (ai - bi).powi(2))
+                    .sum::<f32>()
+                    .sqrt();
+                1.0 / (1.0 + distance)
+            }
+        }
+    }
+}
+
+fn dot(a: &[f32], b: &[f32]) -> f32 {
+    a.iter().zip(b.iter()).map(|(ai, bi)| ai * bi).sum()
+}
+
+fn norm(a: &[f32]) -> f32 {
+    dot(a, a).sqrt()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn zero_vectors_are_unlike_anything() {
+        assert_eq!(Similarity::Cosine.of(&[0.0, 0.0], &[1.0, 0.0]), 0.0);
+        assert_eq!(Similarity::Cosine.of(&[0.0, 0.0], &[0.0, 0.0]), 0.0);
+        assert_eq!(Similarity::Dot.of(&[0.0, 0.0], &[1.0, 0.0]), 0.0);
+        assert_eq!(Similarity::Euclidean.of(&[0.0, 0.0], &[0.0, 0.0]), 1.0);
+    }
+
+    #[test]
+    fn similarities_follow_the_distance_metric() {
+        assert_eq!(Similarity::from(Distance::Cosine), Similarity::Cosine);
+        assert_eq!(Similarity::from(Distance::Dot), Similarity::Dot);
+        assert_eq!(Similarity::from(Distance::Euclid), Similarity::Euclidean);
+
+        // vectors of the same direction are identical by cosine only
+        let (short, long) = ([0.5, 0.0], [2.0, 0.0]);
+        assert_eq!(Similarity::Cosine.of(&short, &long), 1.0);
+        assert_eq!(Similarity::Dot.of(&short, &long), 1.0);
+        assert_eq!(Similarity::Euclidean.of(&short, &long), 0.4);
+    }
+}
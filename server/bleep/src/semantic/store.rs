@@ -0,0 +1,142 @@
+use std::sync::Arc;
+
+use async_trait::async_trait;
+use qdrant_client::{
+    prelude::{QdrantClient, QdrantClientConfig},
+    qdrant::{
+        vectors_config, CreateCollection, PointStruct, PointsSelector, ScoredPoint, ScrollPoints,
+        ScrollResponse, SearchPoints,
+    },
+};
+use serde::{Deserialize, Serialize};
+use tracing::debug;
+
+use super::{local::LocalStore, payload::PayloadSchema};
+use crate::Configuration;
+
+/// Where the semantic index is kept, see `Configuration::vector_store`.
+#[derive(Serialize, Deserialize, clap::ValueEnum, Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum VectorStoreKind {
+    /// A qdrant server, at `qdrant_url`
+    #[default]
+    Qdrant,
+    /// An in-process store in the index directory, for running without a qdrant server
+    Local,
+}
+
+/// The operations [`super::Semantic`] needs from the store its points are kept in.
+///
+/// Requests and points are qdrant's, so every store behaves like a qdrant server as far as the
+/// rest of the code can tell.
+#[async_trait]
+pub trait VectorStore: Send + Sync {
+    async fn health_check(&self) -> anyhow::Result<()>;
+
+    async fn has_collection(&self, collection: &str) -> anyhow::Result<bool>;
+
+    async fn create_collection(&self, config: &CreateCollection) -> anyhow::Result<()>;
+
+    /// Whether the collection stores separate `body` and `doc` vectors per point.
+    async fn named_vectors(&self, collection: &str) -> anyhow::Result<bool>;
+
+    async fn points_count(&self, collection: &str) -> anyhow::Result<u64>;
+
+    async fn search(&self, request: &SearchPoints) -> anyhow::Result<Vec<ScoredPoint>>;
+
+    async fn scroll(&self, request: &ScrollPoints) -> anyhow::Result<ScrollResponse>;
+
+    async fn upsert(&self, collection: &str, points: Vec<PointStruct>) -> anyhow::Result<()>;
+
+    async fn delete(&self, collection: &str, selector: &PointsSelector) -> anyhow::Result<()>;
+}
+
+/// The store selected in `config`, or `None` if that is qdrant and no `qdrant_url` is set.
+pub async fn open(config: &Configuration) -> anyhow::Result<Option<Arc<dyn VectorStore>>> {
+    Ok(match (config.vector_store, &config.qdrant_url) {
+        (VectorStoreKind::Local, _) => {
+            let root = config.index_path("vectors");
+            let schema = PayloadSchema::parse(&config.payload_keys)?;
+            let store = LocalStore::open(root.as_ref())?.sharded_by(schema.key("repo_ref"));
+            Some(Arc::new(store))
+        }
+        (VectorStoreKind::Qdrant, Some(url)) => Some(Arc::new(QdrantStore::connect(url).await?)),
+        (VectorStoreKind::Qdrant, None) => None,
+    })
+}
+
+pub struct QdrantStore {
+    client: QdrantClient,
+}
+
+impl QdrantStore {
+    pub async fn connect(url: &str) -> anyhow::Result<Self> {
+        let client = QdrantClient::new(Some(QdrantClientConfig::from_url(url))).await?;
+        Ok(Self { client })
+    }
+}
+
+#[async_trait]
+impl VectorStore for QdrantStore {
+    async fn health_check(&self) -> anyhow::Result<()> {
+        self.client.health_check().await?;
+        Ok(())
+    }
+
+    async fn has_collection(&self, collection: &str) -> anyhow::Result<bool> {
+        self.client.has_collection(collection).await
+    }
+
+    async fn create_collection(&self, config: &CreateCollection) -> anyhow::Result<()> {
+        let response = self.client.create_collection(config).await?;
+        debug!(
+            time = response.time,
+            created = response.result,
+            name = %config.collection_name,
+            "created qdrant collection"
+        );
+
+        anyhow::ensure!(response.result, "qdrant did not create the collection");
+        Ok(())
+    }
+
+    async fn named_vectors(&self, collection: &str) -> anyhow::Result<bool> {
+        let info = self.client.collection_info(collection).await?;
+        Ok(matches!(
+            info.result
+                .and_then(|i| i.config)
+                .and_then(|c| c.params)
+                .and_then(|p| p.vectors_config)
+                .and_then(|v| v.config),
+            Some(vectors_config::Config::ParamsMap(_))
+        ))
+    }
+
+    async fn points_count(&self, collection: &str) -> anyhow::Result<u64> {
+        Ok(self
+            .client
+            .collection_info(collection)
+            .await?
+            .result
+            .map(|info| info.points_count)
+            .unwrap_or_default())
+    }
+
+    async fn search(&self, request: &SearchPoints) -> anyhow::Result<Vec<ScoredPoint>> {
+        Ok(self.client.search_points(request).await?.result)
+    }
+
+    async fn scroll(&self, request: &ScrollPoints) -> anyhow::Result<ScrollResponse> {
+        self.client.scroll(request).await
+    }
+
+    async fn upsert(&self, collection: &str, points: Vec<PointStruct>) -> anyhow::Result<()> {
+        self.client.upsert_points(collection, points).await?;
+        Ok(())
+    }
+
+    async fn delete(&self, collection: &str, selector: &PointsSelector) -> anyhow::Result<()> {
+        self.client.delete_points(collection, selector).await?;
+        Ok(())
+    }
+}
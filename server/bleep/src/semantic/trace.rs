@@ -0,0 +1,34 @@
+use std::{collections::BTreeMap, time::Instant};
+
+use serde::Serialize;
+
+/// Where the time of a semantic search went, and how many candidates each step returned.
+///
+/// Traces are collected on every search, as they only take a few clock reads.
+#[derive(Serialize, Debug, Default, Clone, PartialEq)]
+pub struct SearchTrace {
+    /// Time spent embedding the query, in milliseconds
+    pub embed_ms: f64,
+    /// Whether the query went through the batching queue, sharing a forward pass with
+    /// concurrent queries
+    pub embed_batched: bool,
+    /// Whether the query embedding was reused from an earlier search for the same query, see
+    /// `Configuration::query_embedding_cache_entries`
+    pub embed_cached: bool,
+    /// Time spent waiting for Qdrant, in milliseconds. Each vector of a multi-vector search is
+    /// searched concurrently.
+    pub qdrant_ms: f64,
+    /// Points returned by Qdrant for each searched vector, `default` for unnamed vectors
+    pub qdrant_candidates: BTreeMap<String, usize>,
+    /// Candidates left after merging the results of each vector
+    pub candidates: usize,
+    /// Whether Qdrant was not queried at all, because the filters exclude every chunk
+    pub skipped: bool,
+    /// Collections of a sharded index that failed to respond, whose chunks are missing
+    pub unavailable_collections: Vec<String>,
+}
+
+/// Milliseconds since `start`.
+pub fn elapsed_ms(start: Instant) -> f64 {
+    start.elapsed().as_secs_f64() * 1000.0
+}
@@ -0,0 +1,173 @@
+use std::collections::HashMap;
+
+use qdrant_client::qdrant::{point_id::PointIdOptions, PointId, ScoredPoint};
+
+/// Name of the code body vector in multi-vector collections
+pub const BODY_VECTOR: &str = "body";
+
+/// Name of the docstring vector in multi-vector collections
+pub const DOC_VECTOR: &str = "doc";
+
+/// How much the code body and docstring similarities contribute to a chunk's score.
+///
+/// This only applies to collections with named `body` and `doc` vectors. Collections with a
+/// single vector are always ranked by that vector alone.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct VectorWeights {
+    pub body_weight: f32,
+    pub doc_weight: f32,
+}
+
+impl Default for VectorWeights {
+    /// Rank by the code body alone
+    fn default() -> Self {
+        Self {
+            body_weight: Self::default_body_weight(),
+            doc_weight: 0.0,
+        }
+    }
+}
+
+impl VectorWeights {
+    pub fn default_body_weight() -> f32 {
+        1.0
+    }
+
+    /// Whether the docstring vector has to be searched at all.
+    pub fn uses_doc(&self) -> bool {
+        self.doc_weight != 0.0
+    }
+
+    /// Merge the results of searching the body and docstring vectors into a single ranking by
+    /// the weighted sum of both scores, returning at most `limit` points.
+    ///
+    /// A point missing from one of the result lists scored at most as well as the last result
+    /// of that list, so it is assigned that score.
+    pub fn combine(
+        &self,
+        body: Vec<ScoredPoint>,
+        doc: Vec<ScoredPoint>,
+        limit: usize,
+    ) -> Vec<ScoredPoint> {
+        let floor = |points: &[ScoredPoint]| {
+            points
+                .iter()
+                .map(|p| p.score)
+                .reduce(f32::min)
+                .unwrap_or(0.0)
+        };
+        let (body_floor, doc_floor) = (floor(&body), floor(&doc));
+
+        let doc_scores = doc
+            .iter()
+            .filter_map(|p| Some((point_key(p.id.as_ref()?)?, p.score)))
+            .collect::<HashMap<_, _>>();
+
+        let mut combined = HashMap::new();
+        for point in body {
+            let Some(key) = point.id.as_ref().and_then(point_key) else {
+                continue;
+            };
+
+            let doc_score = doc_scores.get(&key).copied().unwrap_or(doc_floor);
+            combined.insert(key, (point, doc_score));
+        }
+
+        for point in doc {
+            let Some(key) = point.id.as_ref().and_then(point_key) else {
+                continue;
+            };
+
+            combined.entry(key).or_insert_with(|| {
+                let doc_score = point.score;
+                let point = ScoredPoint {
+                    score: body_floor,
+                    ..point
+                };
+                (point, doc_score)
+            });
+        }
+
+        let mut ranked = combined
+            .into_iter()
+            .map(|(key, (point, doc_score))| {
+                let point = ScoredPoint {
+                    score: self.body_weight * point.score + self.doc_weight * doc_score,
+                    ..point
+                };
+                (key, point)
+            })
+            .collect::<Vec<_>>();
+
+        // ties are broken by point id, as the map yields points in arbitrary order
+        ranked.sort_by(|(a_key, a), (b_key, b)| {
+            b.score.total_cmp(&a.score).then_with(|| a_key.cmp(b_key))
+        });
+        ranked.truncate(limit);
+        ranked.into_iter().map(|(_, point)| point).collect()
+    }
+}
+
+pub(super) fn point_key(id: &PointId) -> Option<String> {
+    match id.point_id_options.as_ref()? {
+        PointIdOptions::Uuid(uuid) => Some(uuid.clone()),
+        PointIdOptions::Num(num) => Some(num.to_string()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn point(id: u64, score: f32) -> ScoredPoint {
+        ScoredPoint {
+            id: Some(PointId::from(id)),
+            score,
+            ..Default::default()
+        }
+    }
+
+    fn first(points: &[ScoredPoint]) -> Option<String> {
+        point_key(points.first()?.id.as_ref()?)
+    }
+
+    #[test]
+    fn weights_change_ranking() {
+        // chunk 1 has the closer code body, chunk 2 the closer docstring
+        let body = || vec![point(1, 0.9), point(2, 0.5)];
+        let doc = || vec![point(2, 0.9), point(1, 0.2)];
+
+        let body_only = VectorWeights::default().combine(body(), doc(), 10);
+        assert_eq!(first(&body_only).as_deref(), Some("1"));
+        assert_eq!(body_only[0].score, 0.9);
+
+        let docs_heavy = VectorWeights {
+            body_weight: 0.3,
+            doc_weight: 0.7,
+        };
+        let ranked = docs_heavy.combine(body(), doc(), 10);
+        assert_eq!(first(&ranked).as_deref(), Some("2"));
+        assert_eq!(ranked.len(), 2);
+    }
+
+    #[test]
+    fn missing_scores_use_the_lowest_score_of_the_list() {
+        let weights = VectorWeights {
+            body_weight: 1.0,
+            doc_weight: 1.0,
+        };
+        let ranked = weights.combine(
+            vec![point(1, 0.75), point(2, 0.5)],
+            vec![point(3, 1.0), point(2, 0.25)],
+            2,
+        );
+
+        let scores = ranked
+            .iter()
+            .map(|p| (first(std::slice::from_ref(p)).unwrap(), p.score))
+            .collect::<Vec<_>>();
+
+        // 3: 0.5 + 1.0, 1: 0.75 + 0.25, and 2: 0.5 + 0.25 is cut by the limit
+        assert_eq!(scores, vec![("3".to_owned(), 1.5), ("1".to_owned(), 1.0)]);
+    }
+}
@@ -4,7 +4,7 @@ use serde::Serialize;
 use smallvec::{smallvec, SmallVec};
 use utoipa::ToSchema;
 
-use crate::{indexes, symbol::Symbol};
+use crate::{indexes, symbol::Symbol, text_range::slice_chars};
 use std::ops::Range;
 
 #[derive(Serialize, ToSchema, Debug, PartialEq, Eq)]
@@ -262,6 +262,7 @@ impl Snipper {
         text: &'a str,
         line_ends: &'a [u32],
     ) -> Location {
+        let (_, highlight) = slice_chars(text, highlight);
         let start = text[..highlight.start]
             .rmatch_indices('\n')
             .nth(self.context_before)
@@ -2,21 +2,38 @@ use crate::{
     remotes::{gather_repo_roots, BackendCredential},
     repo::{Backend, RepoError, RepoRef, Repository, SyncStatus},
 };
-use anyhow::Result;
+use anyhow::{bail, Context, Result};
 use clap::Args;
 use rand::Rng;
 use relative_path::RelativePath;
+use secrecy::SecretString;
 use serde::{de::DeserializeOwned, Deserialize, Serialize};
 use std::{
     collections::HashSet,
+    io::Write,
     ops::Deref,
     path::{Path, PathBuf},
     sync::Arc,
+    time::{SystemTime, UNIX_EPOCH},
 };
 use tracing::debug;
 
+mod encryption;
+
+use encryption::StoreCipher;
+
 include!(concat!(env!("OUT_DIR"), "/schema_version.rs"));
 
+/// Names of the models stored with [`PersistedState`]
+const STATE_NAMES: &[&str] = &[
+    "search_history",
+    "workspaces",
+    "maintenance",
+    "user_tracking",
+    "device_id",
+    "chunk_failures",
+];
+
 pub(crate) type RepositoryPool = Arc<scc::HashMap<RepoRef, Repository>>;
 
 #[derive(Serialize, Deserialize, Args, Debug, Clone, Default, PartialEq)]
@@ -46,34 +63,47 @@ pub struct StateSource {
     #[clap(long)]
     #[serde(default)]
     cookie_key: Option<PathBuf>,
+
+    /// Encrypts the store, if a store key is configured
+    #[clap(skip)]
+    #[serde(skip)]
+    cipher: Option<StoreCipher>,
 }
 
 /// Unified wrapper to persist state in the central state-store.
-/// Every model is stored in its own file as a pretty-printed json.
+/// Every model is stored in its own file as a pretty-printed json, or encrypted if a store key
+/// is configured.
 pub struct PersistedState<T> {
     path: PathBuf,
     state: Arc<T>,
+    cipher: Option<StoreCipher>,
 }
 
 impl<T: Serialize + DeserializeOwned + Default + Send + Sync> PersistedState<T> {
     fn load_or_default(name: &'static str, source: &StateSource) -> Result<Self> {
-        let path = source.directory().join(name).with_extension("json");
+        let path = source.state_path(name);
         Ok(Self {
-            state: Arc::new(read_file_or_default(&path)?),
+            state: Arc::new(read_state_or_default(&path, source.cipher.as_ref())?),
+            cipher: source.cipher.clone(),
             path,
         })
     }
 
     fn load_or(name: &'static str, source: &StateSource, val: T) -> Self {
-        let path = source.directory().join(name).with_extension("json");
+        let path = source.state_path(name);
         Self {
-            state: Arc::new(read_file(&path).unwrap_or(val)),
+            state: Arc::new(read_state(&path, source.cipher.as_ref()).unwrap_or(val)),
+            cipher: source.cipher.clone(),
             path,
         }
     }
 
     pub fn store(&self) -> Result<()> {
-        Ok(pretty_write_file(&self.path, self.state.as_ref())?)
+        Ok(write_state(
+            &self.path,
+            self.state.as_ref(),
+            self.cipher.as_ref(),
+        )?)
     }
 }
 
@@ -90,6 +120,7 @@ impl<T> Clone for PersistedState<T> {
         Self {
             path: self.path.clone(),
             state: self.state.clone(),
+            cipher: self.cipher.clone(),
         }
     }
 }
@@ -135,6 +166,107 @@ impl StateSource {
         Ok(val)
     }
 
+    fn state_path(&self, name: &'static str) -> PathBuf {
+        debug_assert!(
+            STATE_NAMES.contains(&name),
+            "`{name}` must be listed in `STATE_NAMES` to be encrypted"
+        );
+
+        self.directory().join(name).with_extension("json")
+    }
+
+    /// Existing files of the state store.
+    ///
+    /// The version file and file caches are left out, as they only describe the search indexes.
+    fn store_files(&self) -> Vec<PathBuf> {
+        [&self.state_file, &self.credentials, &self.cookie_key]
+            .into_iter()
+            .flatten()
+            .cloned()
+            .chain(STATE_NAMES.iter().map(|name| self.state_path(name)))
+            .filter(|path| path.exists())
+            .collect()
+    }
+
+    /// Encrypt and decrypt the store with `key`, or store plaintext if there's no key.
+    ///
+    /// This fails if the store on disk doesn't match: when it's encrypted with a different key,
+    /// encrypted while no key is given, or not yet encrypted while a key is given.
+    pub(crate) fn set_store_key(&mut self, key: Option<&SecretString>) -> Result<()> {
+        let cipher = key.map(StoreCipher::new);
+
+        for path in self.store_files() {
+            let data = std::fs::read(&path)?;
+            match (&cipher, encryption::is_encrypted(&data)) {
+                (Some(cipher), true) => {
+                    cipher.decrypt(&data).with_context(|| {
+                        format!(
+                            "failed to decrypt `{}`, is the store key correct?",
+                            path.display()
+                        )
+                    })?;
+                }
+                (Some(_), false) => bail!(
+                    "`{}` is not encrypted, run with `--encrypt-store` to encrypt the existing store",
+                    path.display()
+                ),
+                (None, true) => bail!(
+                    "`{}` is encrypted, but no store key is configured",
+                    path.display()
+                ),
+                (None, false) => {}
+            }
+        }
+
+        self.cipher = cipher;
+        Ok(())
+    }
+
+    /// Encrypt the plaintext files of the store in place with `key`.
+    ///
+    /// The plaintext files are copied to a backup directory first, which is returned. Files
+    /// already encrypted with `key` are left as they are, so an interrupted run can be resumed.
+    pub(crate) fn encrypt_store(&self, key: &SecretString) -> Result<PathBuf> {
+        let cipher = StoreCipher::new(key);
+
+        let mut plaintext = vec![];
+        for path in self.store_files() {
+            let data = std::fs::read(&path)?;
+            if encryption::is_encrypted(&data) {
+                cipher.decrypt(&data).with_context(|| {
+                    format!("`{}` is encrypted with a different key", path.display())
+                })?;
+            } else {
+                plaintext.push((path, data));
+            }
+        }
+
+        let now = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs();
+        let backup = self
+            .state_file
+            .as_deref()
+            .and_then(Path::parent)
+            .map(Path::to_owned)
+            .unwrap_or_else(|| self.directory())
+            .join(format!("store_backup_{now}"));
+
+        std::fs::create_dir_all(&backup)?;
+        for (path, _) in &plaintext {
+            let name = path.file_name().context("store files have names")?;
+            std::fs::copy(path, backup.join(name))?;
+        }
+
+        for (path, data) in plaintext {
+            debug!(path = %path.display(), "encrypting store file");
+            write_atomically(
+                &path,
+                |mut file| Ok(file.write_all(&cipher.encrypt(&data))?),
+            )?;
+        }
+
+        Ok(backup)
+    }
+
     pub(crate) fn repo_dir(&self) -> Option<PathBuf> {
         self.directory.clone()
     }
@@ -156,7 +288,7 @@ impl StateSource {
 
         match (self.directory.as_ref(), self.state_file.as_ref()) {
             // Load RepositoryPool from path
-            (None, Some(path)) => read_file_or_default(path).map(Arc::new),
+            (None, Some(path)) => read_state_or_default(path, self.cipher.as_ref()).map(Arc::new),
 
             // Initialize RepositoryPool from repos under `root`
             (Some(root), None) => {
@@ -173,7 +305,8 @@ impl StateSource {
             // Update RepositoryPool with repos under `root`
             (Some(root), Some(path)) => {
                 // Load RepositoryPool from path
-                let state: RepositoryPool = Arc::new(read_file_or_default(path)?);
+                let state: RepositoryPool =
+                    Arc::new(read_state_or_default(path, self.cipher.as_ref())?);
 
                 let current_repos = gather_repo_roots(root, None).collect::<HashSet<_>>();
                 let root = canonicalize(root)?;
@@ -223,20 +356,20 @@ impl StateSource {
     pub fn save_pool(&self, pool: RepositoryPool) -> Result<(), RepoError> {
         match self.state_file {
             None => Err(RepoError::NoSourceGiven),
-            Some(ref path) => pretty_write_file(path, pool.as_ref()),
+            Some(ref path) => write_state(path, pool.as_ref(), self.cipher.as_ref()),
         }
     }
 
     pub(crate) fn initialize_credentials(
         &self,
     ) -> Result<std::collections::HashMap<Backend, BackendCredential>, RepoError> {
-        read_file_or_default(self.credentials.as_ref().unwrap())
+        read_state_or_default(self.credentials.as_ref().unwrap(), self.cipher.as_ref())
     }
 
     pub(crate) fn save_credentials(&self, creds: impl Serialize) -> Result<(), RepoError> {
         match self.credentials {
             None => Err(RepoError::NoSourceGiven),
-            Some(ref path) => pretty_write_file(path, &creds),
+            Some(ref path) => write_state(path, &creds, self.cipher.as_ref()),
         }
     }
 
@@ -254,11 +387,19 @@ impl StateSource {
         let path = self.cookie_key.as_ref().unwrap();
 
         if path.exists() {
-            let master_key = std::fs::read(path)?;
+            let mut master_key = std::fs::read(path)?;
+            if let Some(ref cipher) = self.cipher {
+                master_key = cipher.decrypt(&master_key)?;
+            }
+
             Ok(axum_extra::extract::cookie::Key::from(&master_key))
         } else {
             let master_key = axum_extra::extract::cookie::Key::generate();
-            std::fs::write(path, master_key.master())?;
+            match self.cipher {
+                Some(ref cipher) => std::fs::write(path, cipher.encrypt(master_key.master()))?,
+                None => std::fs::write(path, master_key.master())?,
+            }
+
             Ok(master_key)
         }
     }
@@ -267,6 +408,30 @@ impl StateSource {
 pub fn pretty_write_file<T: Serialize + ?Sized>(
     path: impl AsRef<Path>,
     val: &T,
+) -> Result<(), RepoError> {
+    write_atomically(path.as_ref(), |file| {
+        Ok(serde_json::to_writer_pretty(file, val)?)
+    })
+}
+
+fn write_state<T: Serialize + ?Sized>(
+    path: &Path,
+    val: &T,
+    cipher: Option<&StoreCipher>,
+) -> Result<(), RepoError> {
+    match cipher {
+        Some(cipher) => {
+            let data = cipher.encrypt(&serde_json::to_vec(val)?);
+            write_atomically(path, |mut file| Ok(file.write_all(&data)?))
+        }
+        None => pretty_write_file(path, val),
+    }
+}
+
+/// Write a file through a temporary file, so readers never see it half-written.
+fn write_atomically(
+    path: &Path,
+    write: impl FnOnce(std::fs::File) -> Result<(), RepoError>,
 ) -> Result<(), RepoError> {
     let tmpfile = path
         .as_ref()
@@ -291,24 +456,37 @@ pub fn pretty_write_file<T: Serialize + ?Sized>(
         }
     }?;
 
-    serde_json::to_writer_pretty(file, val)?;
+    write(file)?;
     std::fs::rename(tmpfile, path)?;
 
     Ok(())
 }
 
-pub fn read_file<T: Default + DeserializeOwned>(path: &Path) -> Result<T, RepoError> {
-    let file = std::fs::File::open(path)?;
-    Ok(serde_json::from_reader::<_, T>(file)?)
+pub fn read_file_or_default<T: Default + DeserializeOwned>(path: &Path) -> Result<T, RepoError> {
+    read_state_or_default(path, None)
 }
 
-pub fn read_file_or_default<T: Default + DeserializeOwned>(path: &Path) -> Result<T, RepoError> {
+fn read_state<T: DeserializeOwned>(
+    path: &Path,
+    cipher: Option<&StoreCipher>,
+) -> Result<T, RepoError> {
+    let data = std::fs::read(path)?;
+    match cipher {
+        Some(cipher) => Ok(serde_json::from_slice(&cipher.decrypt(&data)?)?),
+        None if encryption::is_encrypted(&data) => Err(RepoError::MissingStoreKey),
+        None => Ok(serde_json::from_slice(&data)?),
+    }
+}
+
+fn read_state_or_default<T: Default + DeserializeOwned>(
+    path: &Path,
+    cipher: Option<&StoreCipher>,
+) -> Result<T, RepoError> {
     if !path.exists() {
         return Ok(Default::default());
     }
 
-    let file = std::fs::File::open(path)?;
-    Ok(serde_json::from_reader::<_, T>(file)?)
+    read_state(path, cipher)
 }
 
 pub fn get_relative_path<P>(path: &Path, base: P) -> PathBuf
@@ -360,6 +538,7 @@ mod test {
             credentials: None,
             version_file: None,
             cookie_key: None,
+            cipher: None,
         }
         .initialize_pool()
         .unwrap();
@@ -385,4 +564,81 @@ mod test {
 
         assert_eq!(found_repos, expected_repos);
     }
+
+    fn source(dir: &TempDir) -> StateSource {
+        let mut source = StateSource::default();
+        source.set_default_dir(dir.path());
+        source
+    }
+
+    fn key(key: &str) -> SecretString {
+        SecretString::new(key.to_owned())
+    }
+
+    #[test]
+    fn encrypted_store_round_trip() {
+        let tmpdir = TempDir::new("test-encrypted-store").unwrap();
+
+        let mut source = source(&tmpdir);
+        source.set_store_key(Some(&key("secret"))).unwrap();
+
+        let state = source
+            .load_or_default::<std::sync::RwLock<Vec<String>>>("workspaces")
+            .unwrap();
+        state
+            .write()
+            .unwrap()
+            .push("github.com/bloopai/bloop".into());
+        state.store().unwrap();
+
+        let data = std::fs::read(source.state_path("workspaces")).unwrap();
+        assert!(encryption::is_encrypted(&data));
+        assert!(!String::from_utf8_lossy(&data).contains("bloopai"));
+
+        let state = source
+            .load_or_default::<std::sync::RwLock<Vec<String>>>("workspaces")
+            .unwrap();
+        assert_eq!(*state.read().unwrap(), vec!["github.com/bloopai/bloop"]);
+
+        // the store doesn't open without the right key
+        let mut other = self::source(&tmpdir);
+        assert!(other.set_store_key(Some(&key("wrong"))).is_err());
+        assert!(other.set_store_key(None).is_err());
+    }
+
+    #[test]
+    fn plaintext_store_is_encrypted_in_place() {
+        let tmpdir = TempDir::new("test-store-migration").unwrap();
+
+        let source = source(&tmpdir);
+        let state = source
+            .load_or_default::<std::sync::RwLock<Vec<String>>>("search_history")
+            .unwrap();
+        state.write().unwrap().push("fn main".into());
+        state.store().unwrap();
+        source.save_credentials(["token"]).unwrap();
+
+        let plaintext = std::fs::read(source.state_path("search_history")).unwrap();
+
+        // a key can't be used until the store is encrypted
+        let mut keyed = self::source(&tmpdir);
+        assert!(keyed.set_store_key(Some(&key("secret"))).is_err());
+
+        let backup = source.encrypt_store(&key("secret")).unwrap();
+        assert_eq!(
+            std::fs::read(backup.join("search_history.json")).unwrap(),
+            plaintext
+        );
+        assert!(backup.join("credentials.json").exists());
+
+        // running the migration again is a no-op
+        source.encrypt_store(&key("secret")).unwrap();
+        assert!(source.encrypt_store(&key("wrong")).is_err());
+
+        keyed.set_store_key(Some(&key("secret"))).unwrap();
+        let state = keyed
+            .load_or_default::<std::sync::RwLock<Vec<String>>>("search_history")
+            .unwrap();
+        assert_eq!(*state.read().unwrap(), vec!["fn main"]);
+    }
 }
@@ -0,0 +1,84 @@
+use aes_gcm::{
+    aead::{Aead, KeyInit},
+    Aes256Gcm, Key, Nonce,
+};
+use secrecy::{ExposeSecret, SecretString};
+
+use crate::repo::RepoError;
+
+/// Leading bytes of an encrypted state file
+const MAGIC: &[u8; 4] = b"BLPS";
+const VERSION: u8 = 1;
+const NONCE_LEN: usize = 12;
+const HEADER_LEN: usize = MAGIC.len() + 1 + NONCE_LEN;
+
+/// Context string for deriving the AES key from the store key
+const KEY_CONTEXT: &str = "bloop 2023-04-01 state store encryption v1";
+
+/// Encrypts and decrypts state files with AES-256-GCM.
+///
+/// An encrypted file is the magic bytes `BLPS`, a version byte and a random 96-bit nonce,
+/// followed by the ciphertext of the file. The AES key is derived from the configured store key
+/// with BLAKE3, which is fast, so the store key should be a random secret rather than a
+/// password.
+#[derive(Clone)]
+pub(crate) struct StoreCipher {
+    cipher: Aes256Gcm,
+
+    /// Identifies the key, without revealing it
+    key_id: blake3::Hash,
+}
+
+impl StoreCipher {
+    pub(crate) fn new(key: &SecretString) -> Self {
+        let key = blake3::derive_key(KEY_CONTEXT, key.expose_secret().as_bytes());
+
+        Self {
+            cipher: Aes256Gcm::new(Key::<Aes256Gcm>::from_slice(&key)),
+            key_id: blake3::hash(&key),
+        }
+    }
+
+    pub(crate) fn encrypt(&self, plaintext: &[u8]) -> Vec<u8> {
+        let nonce = rand::random::<[u8; NONCE_LEN]>();
+        let ciphertext = self
+            .cipher
+            .encrypt(Nonce::from_slice(&nonce), plaintext)
+            .expect("encrypting an in-memory buffer can't fail");
+
+        let mut out = Vec::with_capacity(HEADER_LEN + ciphertext.len());
+        out.extend_from_slice(MAGIC);
+        out.push(VERSION);
+        out.extend_from_slice(&nonce);
+        out.extend(ciphertext);
+        out
+    }
+
+    pub(crate) fn decrypt(&self, data: &[u8]) -> Result<Vec<u8>, RepoError> {
+        if !is_encrypted(data) {
+            return Err(RepoError::Decrypt);
+        }
+
+        let (nonce, ciphertext) = data[MAGIC.len() + 1..].split_at(NONCE_LEN);
+        self.cipher
+            .decrypt(Nonce::from_slice(nonce), ciphertext)
+            .map_err(|_| RepoError::Decrypt)
+    }
+}
+
+impl PartialEq for StoreCipher {
+    fn eq(&self, other: &Self) -> bool {
+        self.key_id == other.key_id
+    }
+}
+
+impl std::fmt::Debug for StoreCipher {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("StoreCipher(..)")
+    }
+}
+
+/// Whether `data` is the contents of an encrypted state file.
+pub(crate) fn is_encrypted(data: &[u8]) -> bool {
+    data.len() >= HEADER_LEN && data.starts_with(MAGIC) && data[MAGIC.len()] == VERSION
+}
@@ -28,4 +28,12 @@ impl SymbolLocations {
             Self::Empty => Vec::new(),
         }
     }
+
+    /// Ranges of the scopes nested in the file scope.
+    pub fn nested_scopes(&self) -> Vec<TextRange> {
+        match self {
+            Self::TreeSitter(graph) => graph.nested_scopes(),
+            Self::Empty => Vec::new(),
+        }
+    }
 }
@@ -1,4 +1,7 @@
-use std::cmp::{Ord, Ordering};
+use std::{
+    cmp::{Ord, Ordering},
+    ops::Range,
+};
 use utoipa::ToSchema;
 
 use serde::{Deserialize, Serialize};
@@ -94,8 +97,62 @@ impl From<tree_sitter::Range> for TextRange {
     }
 }
 
-impl From<TextRange> for std::ops::Range<usize> {
-    fn from(r: TextRange) -> std::ops::Range<usize> {
+impl From<TextRange> for Range<usize> {
+    fn from(r: TextRange) -> Range<usize> {
         r.start.byte..r.end.byte
     }
 }
+
+/// The closest char boundary of `text` at or before the byte `index`.
+///
+/// Indices past the end of `text` are clamped to its length.
+pub fn floor_char_boundary(text: &str, index: usize) -> usize {
+    if index >= text.len() {
+        return text.len();
+    }
+
+    (0..=index)
+        .rev()
+        .find(|&i| text.is_char_boundary(i))
+        .unwrap_or(0)
+}
+
+/// The closest char boundary of `text` at or after the byte `index`.
+///
+/// Indices past the end of `text` are clamped to its length.
+pub fn ceil_char_boundary(text: &str, index: usize) -> usize {
+    (index..text.len())
+        .find(|&i| text.is_char_boundary(i))
+        .unwrap_or(text.len())
+}
+
+/// Slice `text` by the byte `range`, widened to the closest char boundaries so that it can't
+/// cut a character in half, along with the range that was actually sliced.
+pub fn slice_chars(text: &str, range: Range<usize>) -> (&str, Range<usize>) {
+    let start = floor_char_boundary(text, range.start);
+    let end = ceil_char_boundary(text, range.end).max(start);
+    (&text[start..end], start..end)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn slices_never_cut_characters() {
+        // `🦀` takes up bytes 3 to 7, and `é` bytes 11 and 12
+        let text = "// 🦀 café";
+
+        assert_eq!(floor_char_boundary(text, 5), 3);
+        assert_eq!(ceil_char_boundary(text, 5), 7);
+        assert_eq!(floor_char_boundary(text, 3), 3);
+        assert_eq!(ceil_char_boundary(text, 12), 13);
+        assert_eq!(floor_char_boundary(text, 100), text.len());
+        assert_eq!(ceil_char_boundary(text, 100), text.len());
+
+        assert_eq!(slice_chars(text, 4..12), ("🦀 café", 3..13));
+        assert_eq!(slice_chars(text, 0..3), ("// ", 0..3));
+        assert_eq!(slice_chars(text, 6..5), ("🦀", 3..7));
+        assert_eq!(slice_chars(text, 20..30), ("", 13..13));
+    }
+}
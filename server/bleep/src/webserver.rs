@@ -1,6 +1,15 @@
-use crate::{env::Feature, snippet, Application};
-
-use axum::{http::StatusCode, response::IntoResponse, routing::get, Extension, Json};
+use crate::{
+    env::Feature,
+    semantic::{breaker::BreakerOpen, limit::EmbedTimeout},
+    snippet, Application,
+};
+
+use axum::{
+    http::StatusCode,
+    response::IntoResponse,
+    routing::{get, post, put},
+    Extension, Json,
+};
 use std::sync::Arc;
 use std::{borrow::Cow, net::SocketAddr};
 use tower::Service;
@@ -13,22 +22,35 @@ use utoipa::ToSchema;
 mod aaa;
 pub mod answer;
 mod autocomplete;
+mod blame;
+mod changes;
 mod config;
+mod embeddings;
 mod file;
 mod github;
 mod hoverable;
 mod index;
 mod intelligence;
+mod maintenance;
 pub mod middleware;
+mod notifications;
 mod query;
+mod replay;
 mod repos;
+mod searches;
 mod semantic;
+mod snippets;
+mod tiered;
+mod validate;
+mod workspaces;
 
 pub type Router<S = Application> = axum::Router<S>;
 
 #[allow(unused)]
 pub(in crate::webserver) mod prelude {
-    pub(in crate::webserver) use super::{json, EndpointError, Error, ErrorKind, Result, Router};
+    pub(in crate::webserver) use super::{
+        json, EndpointError, Error, ErrorCode, ErrorKind, Result, Router,
+    };
     pub(in crate::webserver) use crate::indexes::Indexes;
     pub(in crate::webserver) use axum::{
         extract::Query, http::StatusCode, response::IntoResponse, Extension,
@@ -51,7 +73,10 @@ pub async fn start(app: Application) -> anyhow::Result<()> {
         .route("/index", get(index::handle))
         // repo management
         .route("/repos", get(repos::available))
-        .route("/repos/index-status", get(repos::index_status))
+        .route(
+            "/repos/index-status",
+            get(repos::index_status).with_state(Arc::new(repos::IndexStatus::start(&app))),
+        )
         .route(
             "/repos/indexed",
             get(repos::indexed).put(repos::set_indexed),
@@ -61,17 +86,59 @@ pub async fn start(app: Application) -> anyhow::Result<()> {
             get(repos::get_by_id).delete(repos::delete_by_id),
         )
         .route("/repos/sync/*path", get(repos::sync))
+        .route("/repos/languages/*path", get(repos::languages))
+        .route("/repos/index-run/*path", get(repos::index_run))
+        .route("/repos/embeddings/*path", get(repos::export_embeddings))
+        .route(
+            "/repos/failures/*path",
+            get(repos::failures).post(repos::retry_failures),
+        )
+        .route("/repos/restore/*path", post(repos::restore_by_id))
+        .route("/repos/pin/*path", post(repos::pin_by_id))
+        .route("/repos/unpin/*path", post(repos::unpin_by_id))
+        // workspaces
+        .route("/workspaces", get(workspaces::list))
+        .route(
+            "/workspaces/:name",
+            get(workspaces::get)
+                .put(workspaces::set)
+                .delete(workspaces::delete),
+        )
         // intelligence
         .route("/hoverable", get(hoverable::handle))
         .route("/token-info", get(intelligence::handle))
         // misc
         .route("/file/*ref", get(file::handle))
-        .route("/semantic/chunks", get(semantic::raw_chunks))
+        .route(
+            "/semantic/chunks",
+            get(semantic::raw_chunks).with_state(Arc::new(semantic::ChunksState::new(&app.config))),
+        )
+        .route("/semantic/chunk/:id", put(semantic::update_chunk))
+        .route("/semantic/browse", get(semantic::browse))
+        .route("/semantic/changes", get(changes::search))
+        .route("/semantic/validate", post(validate::handle))
+        .route("/search/tiered", get(tiered::handle))
+        .route("/snippets/reanchor", post(snippets::reanchor))
+        .route("/searches/recent", get(searches::recent))
         .route(
             "/answer",
-            get(answer::handle).with_state(Arc::new(answer::AnswerState::default())),
+            get(answer::handle).with_state(Arc::new(answer::AnswerState::new(&app.config))),
         );
 
+    api = api.merge(middleware::admin_only(
+        Router::new()
+            .route("/admin/searches/top", get(searches::top))
+            .route("/admin/maintenance/run", post(maintenance::run))
+            .route("/admin/eviction/run", post(maintenance::evict))
+            .route(
+                "/admin/semantic/migrate",
+                post(maintenance::migrate_semantic),
+            )
+            .route("/admin/events", get(notifications::events))
+            .route("/admin/notifications/test", post(notifications::test)),
+        app.clone(),
+    ));
+
     if app.env.allow(Feature::AnyPathScan) {
         api = api.route("/repos/scan", get(repos::scan_local));
     }
@@ -157,6 +224,7 @@ impl Error {
         };
 
         let body = Json(Response::from(EndpointError {
+            code: ErrorCode::of(&kind),
             kind,
             message: message.into(),
         }));
@@ -169,32 +237,77 @@ impl Error {
         self
     }
 
+    /// Replace the generic code of the error's kind with a more specific one.
+    fn with_code(mut self, code: ErrorCode) -> Self {
+        if let Json(Response::Error(error)) = &mut self.body {
+            error.code = code;
+        }
+        self
+    }
+
     fn internal<S: std::fmt::Display>(message: S) -> Self {
         Error {
             status: StatusCode::INTERNAL_SERVER_ERROR,
             body: Json(Response::from(EndpointError {
                 kind: ErrorKind::Internal,
+                code: ErrorCode::Internal,
                 message: message.to_string().into(),
             })),
         }
     }
 
+    /// The error of a failed semantic search, which is a 503 while searches fail fast because
+    /// the vector store keeps failing, or when the query waited too long to be embedded.
+    fn search(err: anyhow::Error) -> Self {
+        if let Some(open) = err.downcast_ref::<BreakerOpen>() {
+            return Error::new(ErrorKind::UpstreamService, open.to_string())
+                .with_status(StatusCode::SERVICE_UNAVAILABLE)
+                .with_code(ErrorCode::SearchesPaused);
+        }
+
+        match err.downcast_ref::<EmbedTimeout>() {
+            Some(timeout) => Error::new(ErrorKind::UpstreamService, timeout.to_string())
+                .with_status(StatusCode::SERVICE_UNAVAILABLE)
+                .with_code(ErrorCode::EmbeddingTimedOut),
+            None => Error::internal(err),
+        }
+    }
+
     fn user<S: std::fmt::Display>(message: S) -> Self {
         Error {
             status: StatusCode::BAD_REQUEST,
             body: Json(Response::from(EndpointError {
                 kind: ErrorKind::User,
+                code: ErrorCode::BadRequest,
                 message: message.to_string().into(),
             })),
         }
     }
 
+    /// An error responding with `body` instead of an [`EndpointError`], for errors clients act
+    /// on beyond showing their message.
+    fn with_body<T: ApiResponse + Send + Sync + 'static>(status: StatusCode, body: T) -> Self {
+        Error {
+            status,
+            body: json(body),
+        }
+    }
+
     fn message(&self) -> &str {
         match &self.body {
             Json(Response::Error(EndpointError { message, .. })) => message.as_ref(),
             _ => "",
         }
     }
+
+    /// The code of the error, unless it responds with a custom body.
+    #[cfg(test)]
+    fn code(&self) -> Option<ErrorCode> {
+        match &self.body {
+            Json(Response::Error(EndpointError { code, .. })) => Some(*code),
+            _ => None,
+        }
+    }
 }
 
 impl From<anyhow::Error> for Error {
@@ -215,6 +328,9 @@ struct EndpointError<'a> {
     /// The kind of this error
     kind: ErrorKind,
 
+    /// A stable identifier of this error, for clients to branch on
+    code: ErrorCode,
+
     /// A context aware message describing the error
     message: Cow<'a, str>,
 }
@@ -237,6 +353,60 @@ enum ErrorKind {
     Custom,
 }
 
+/// A stable identifier of an error, for clients to branch on, which unlike the message of an
+/// error never changes.
+///
+/// The HTTP status is the coarse signal of what went wrong, and the code the fine-grained one.
+/// Errors without a more specific code carry the generic code of their [`ErrorKind`]:
+/// `bad_request`, `not_found`, `not_configured`, `upstream_unavailable` or `internal`.
+#[derive(serde::Serialize, Clone, Copy, PartialEq, Eq, ToSchema, Debug)]
+#[serde(rename_all = "snake_case")]
+enum ErrorCode {
+    /// A `user` error without a more specific code
+    BadRequest,
+    NotFound,
+    /// A service the endpoint relies on, such as Qdrant, is not configured
+    NotConfigured,
+    /// A service the endpoint relies on, such as Qdrant, failed to respond
+    UpstreamUnavailable,
+    Internal,
+
+    /// The query has no search target, only filters
+    EmptyQuery,
+    /// The search target is shorter than `min_query_chars`
+    QueryTooShort,
+    /// The query could not be parsed
+    InvalidQuery,
+    /// Parameters that can't be combined, such as `fields=summary` with `context`
+    IncompatibleParams,
+    /// The requested workspace does not exist
+    UnknownWorkspace,
+    /// A repository of the `repo:` filters was evicted to save disk space, and has to be
+    /// restored before it can be searched
+    RepoEvicted,
+    /// Semantic search is not configured on this instance
+    SemanticSearchUnavailable,
+    /// The vector store failed to run the search
+    SearchFailed,
+    /// The vector store kept failing, so searches fail fast for `qdrant_breaker_cooldown_secs`
+    SearchesPaused,
+    /// The query waited longer than `embedding_wait_timeout_secs` for the embedding model
+    EmbeddingTimedOut,
+}
+
+impl ErrorCode {
+    /// The generic code of errors of `kind`.
+    fn of(kind: &ErrorKind) -> Self {
+        match kind {
+            ErrorKind::User => Self::BadRequest,
+            ErrorKind::NotFound => Self::NotFound,
+            ErrorKind::Configuration => Self::NotConfigured,
+            ErrorKind::UpstreamService => Self::UpstreamUnavailable,
+            ErrorKind::Unknown | ErrorKind::Internal | ErrorKind::Custom => Self::Internal,
+        }
+    }
+}
+
 trait ApiResponse: erased_serde::Serialize {}
 erased_serde::serialize_trait_object!(ApiResponse);
 
@@ -270,6 +440,7 @@ impl<'a> From<EndpointError<'a>> for Response<'a> {
         crate::text_range::Point,
         EndpointError<'_>,
         ErrorKind,
+        ErrorCode,
         autocomplete::AutocompleteResponse,
         query::QueryResponse,
         query::QueryResult,
@@ -315,3 +486,66 @@ async fn health(Extension(app): Extension<Application>) {
         semantic.health_check().await.unwrap()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn errors_default_to_the_code_of_their_kind() {
+        let codes = [
+            (Error::user("bad"), ErrorCode::BadRequest),
+            (Error::internal("oops"), ErrorCode::Internal),
+            (
+                Error::new(ErrorKind::NotFound, "missing"),
+                ErrorCode::NotFound,
+            ),
+            (
+                Error::new(ErrorKind::Configuration, "unset"),
+                ErrorCode::NotConfigured,
+            ),
+            (
+                Error::new(ErrorKind::UpstreamService, "down"),
+                ErrorCode::UpstreamUnavailable,
+            ),
+        ];
+
+        for (err, code) in codes {
+            assert_eq!(err.code(), Some(code));
+        }
+    }
+
+    #[test]
+    fn paused_searches_are_unavailable() {
+        let open = BreakerOpen {
+            retry_in: std::time::Duration::from_secs(12),
+        };
+        let err = Error::search(open.into());
+        assert_eq!(err.status, StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(err.code(), Some(ErrorCode::SearchesPaused));
+
+        let timeout = EmbedTimeout {
+            waited: std::time::Duration::from_secs(30),
+        };
+        let err = Error::search(timeout.into());
+        assert_eq!(err.status, StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(err.code(), Some(ErrorCode::EmbeddingTimedOut));
+
+        let err = Error::search(anyhow::anyhow!("connection refused"));
+        assert_eq!(err.status, StatusCode::INTERNAL_SERVER_ERROR);
+        assert_eq!(err.code(), Some(ErrorCode::Internal));
+    }
+
+    #[test]
+    fn codes_are_serialized_next_to_the_message() {
+        let err = Error::user("empty search").with_code(ErrorCode::EmptyQuery);
+        assert_eq!(
+            serde_json::to_value(&*err.body).unwrap(),
+            serde_json::json!({
+                "kind": "user",
+                "code": "empty_query",
+                "message": "empty search",
+            })
+        );
+    }
+}
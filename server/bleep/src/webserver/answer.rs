@@ -1,16 +1,18 @@
 use std::{
-    sync::Arc,
+    collections::HashMap,
+    ops::Range,
+    sync::{Arc, Mutex},
     time::{Duration, Instant},
 };
 
 use axum::{
     extract::{Query, State},
-    http::StatusCode,
+    http::{HeaderMap, StatusCode},
     response::{sse::Event, IntoResponse, Sse},
     Extension,
 };
 use futures::{stream, Stream, StreamExt, TryStreamExt};
-use qdrant_client::qdrant::{vectors, ScoredPoint};
+use qdrant_client::qdrant::{value::Kind, vectors, PointId, ScoredPoint, Value};
 use secrecy::ExposeSecret;
 use thiserror::Error;
 use tokio::sync::RwLock;
@@ -19,16 +21,38 @@ use utoipa::ToSchema;
 
 use crate::{
     analytics::{QueryEvent, Stage},
+    cache::BoundedCache,
     env::Feature,
+    history::SearchEntry,
     indexes::reader::ContentDocument,
     query::parser,
     remotes,
-    repo::RepoRef,
-    semantic::{self, Semantic},
-    Application,
+    repo::{normalize_relative_path, RepoRef},
+    semantic::{
+        self,
+        filter::{FilterArgs, FilterLogic},
+        kind::ChunkKind,
+        payload::{PayloadFields, PayloadSchema},
+        post_process::{AsSearchResult, SearchResult},
+        similarity::Similarity,
+        weights::VectorWeights,
+        Semantic,
+    },
+    text_range::slice_chars,
+    Application, Configuration,
 };
 
-use super::{middleware::User, prelude::*};
+use super::{
+    middleware::User,
+    prelude::*,
+    replay::{self, EventId, Followed, ReplayBuffer, SharedBuffer},
+    semantic::check_query_length,
+    workspaces,
+};
+
+mod patch;
+
+use patch::{CitedFile, PatchRejection, PatchSuggestion};
 
 /// Mirrored from `answer_api/lib.rs` to avoid private dependency.
 pub mod api {
@@ -90,7 +114,28 @@ pub struct Snippet {
     pub end_line: usize,
     pub start_byte: usize,
     pub end_byte: usize,
+    /// the notebook cell this snippet was taken from, for `.ipynb` files.
+    ///
+    /// lines and bytes of such snippets are relative to the cell source
+    pub cell_index: Option<usize>,
+    /// the symbol enclosing this snippet, if one was recorded at index time
+    pub symbol: Option<String>,
+    /// what this snippet contains, `other` if it was indexed without a `kind`
+    #[serde(default)]
+    pub kind: ChunkKind,
+    /// names of the symbols defined in this snippet
+    #[serde(default)]
+    pub definitions: Vec<String>,
+    /// the raw score returned by qdrant, whose scale depends on the distance metric
     pub score: f32,
+    /// `score` mapped onto `[0, 1]`, see semantic::normalize_scores
+    pub normalized_score: f32,
+    /// how this snippet was found
+    #[serde(default)]
+    pub source: SnippetSource,
+    /// why this snippet was returned, only set for `debug` requests
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub explanation: Option<Explanation>,
 
     /// the vector embeddings for each chunk.
     ///
@@ -99,8 +144,29 @@ pub struct Snippet {
     pub embedding: Vec<f32>,
 }
 
-fn default_limit() -> u64 {
-    20
+impl AsSearchResult for Snippet {
+    fn as_search_result<'a>(&'a self, _schema: &PayloadSchema) -> SearchResult<'a> {
+        SearchResult {
+            repo_ref: &self.repo_ref,
+            relative_path: &self.relative_path,
+            text: &self.text,
+            score: self.score,
+        }
+    }
+}
+
+#[derive(Debug, serde::Serialize, serde::Deserialize, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum SnippetSource {
+    #[default]
+    Semantic,
+    /// A literal match on the query, added because semantic search found too few snippets
+    Keyword,
+}
+
+/// Results returned by the searches that don't ask for a `limit`
+pub(super) const fn default_limit() -> u64 {
+    SNIPPET_COUNT as u64
 }
 
 #[derive(Clone, Debug, serde::Deserialize)]
@@ -109,6 +175,147 @@ pub struct Params {
     pub thread_id: String,
     #[serde(default = "default_limit")]
     pub limit: u64,
+    /// Whether `limit` caps the returned snippets or the fetched candidates, `results` by
+    /// default
+    pub limit_kind: Option<LimitKind>,
+    /// How filters on different fields are combined, `and` by default
+    #[serde(default)]
+    pub filter_logic: FilterLogic,
+    /// How duplicate snippets are removed, `mmr` by default
+    #[serde(default)]
+    pub dedup: DedupStrategy,
+    /// Weight of the code body vector, for multi-vector collections
+    #[serde(default = "VectorWeights::default_body_weight")]
+    pub body_weight: f32,
+    /// Weight of the docstring vector, for multi-vector collections
+    #[serde(default)]
+    pub doc_weight: f32,
+    /// Only search the repositories of this workspace
+    pub workspace: Option<String>,
+    /// Fill in keyword matches when semantic search finds fewer than
+    /// `keyword_fallback_min_results` snippets, on by default
+    pub fallback: Option<bool>,
+    /// Fail the request when a candidate can't be read as a snippet, instead of skipping it and
+    /// counting it in `skipped`, off by default
+    #[serde(default)]
+    pub strict: bool,
+    /// Rank snippets defining a symbol named in the query above snippets that only use it, on
+    /// by default
+    pub prefer_definitions: Option<bool>,
+    /// Explain why each snippet was returned in its `explanation`, off by default
+    #[serde(default)]
+    pub debug: bool,
+    /// What to answer with, `explain` by default
+    #[serde(default)]
+    pub mode: AnswerMode,
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AnswerMode {
+    /// Explain the most relevant snippet in prose
+    #[default]
+    Explain,
+    /// Answer with a unified diff changing the files of the retrieved snippets.
+    ///
+    /// The diff is checked against the indexed files, and refused with a `422` if it changes
+    /// other files or doesn't apply. It is never applied on the server.
+    Patch,
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum DedupStrategy {
+    /// Drop snippets that are too similar to higher ranked ones, see
+    /// semantic::deduplicate_with_mmr
+    #[default]
+    Mmr,
+    /// Keep only the highest scoring snippet of each symbol, then deduplicate with MMR.
+    ///
+    /// Snippets without a `symbol` payload are left alone.
+    Symbol,
+}
+
+/// Why a snippet was returned, see [`explain`].
+#[derive(Debug, serde::Serialize, serde::Deserialize, Clone, PartialEq, Eq)]
+#[serde(tag = "mode", rename_all = "snake_case")]
+pub enum Explanation {
+    /// Found by vector similarity, which can't be attributed to parts of the query
+    Semantic { note: String },
+    /// Found by matching query terms verbatim
+    Keyword { terms: Vec<TermMatch> },
+}
+
+#[derive(Debug, serde::Serialize, serde::Deserialize, Clone, PartialEq, Eq)]
+pub struct TermMatch {
+    pub term: String,
+    /// byte ranges of the term in the snippet text, matched ignoring ASCII case
+    pub positions: Vec<Range<usize>>,
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum LimitKind {
+    /// Return `limit` snippets if the index has enough matches.
+    ///
+    /// `4 * limit` candidates are fetched, and deduplicated down to `limit` snippets.
+    #[default]
+    Results,
+    /// Fetch at most `limit` candidates, for a predictable search cost.
+    ///
+    /// Only these candidates are deduplicated, so fewer than `limit` snippets are returned when
+    /// some of them are duplicates.
+    Candidates,
+}
+
+impl LimitKind {
+    /// Number of candidates to fetch for a search returning at most `limit` snippets.
+    fn candidates(self, limit: usize) -> usize {
+        match self {
+            Self::Results => candidate_count(limit as u64, [Widening::Dedup]) as usize,
+            Self::Candidates => limit,
+        }
+    }
+}
+
+/// Why a search fetches more candidates than the results it returns, see [`candidate_count`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(super) enum Widening {
+    /// Near duplicates are dropped, or candidates away from the searched changes
+    Dedup,
+    /// Facets count the languages of a wider set than the chunks returned
+    Facets,
+    /// Boosted chunks may overtake slightly closer ones
+    Boost,
+    /// Files are ranked by their candidate chunks
+    Files,
+    /// Each repository returns up to `max_per_repo` chunks, and the best candidates may be
+    /// concentrated in a few repositories
+    Repos { max_per_repo: usize },
+}
+
+impl Widening {
+    /// Candidates fetched per requested result.
+    fn per_result(self) -> u64 {
+        match self {
+            Self::Dedup | Self::Facets | Self::Files => 4,
+            Self::Boost => 2,
+            Self::Repos { max_per_repo } => (max_per_repo as u64).saturating_mul(2),
+        }
+    }
+}
+
+/// How many candidates to fetch for `limit` results.
+///
+/// The results of a search widened for several reasons are all picked from one candidate set,
+/// so the widest set any of them asks for is fetched.
+pub(super) fn candidate_count(limit: u64, widenings: impl IntoIterator<Item = Widening>) -> u64 {
+    let per_result = widenings
+        .into_iter()
+        .map(Widening::per_result)
+        .max()
+        .unwrap_or(1);
+    limit.saturating_mul(per_result)
 }
 
 #[derive(serde::Serialize, ToSchema, Debug)]
@@ -116,24 +323,32 @@ pub struct AnswerResponse {
     pub session_id: String,
     pub query_id: uuid::Uuid,
     pub snippets: Option<AnswerSnippets>,
+    /// The suggested patch and how each of its hunks applies, for `mode=patch`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub patch: Option<PatchSuggestion>,
 }
 
 #[derive(serde::Serialize, ToSchema, Debug)]
 pub struct AnswerSnippets {
     pub matches: Vec<Snippet>,
     pub answer_path: String,
+    /// Candidates left out because their payload couldn't be read as a snippet
+    pub skipped: usize,
 }
 
 impl super::ApiResponse for AnswerResponse {}
+impl super::ApiResponse for PatchRejection {}
 
 const SNIPPET_COUNT: usize = 20;
 
 pub(super) struct AnswerState {
     client: reqwest::Client,
+    /// Recent answer streams by query id, for clients resuming them with `Last-Event-ID`
+    transcripts: BoundedCache<uuid::Uuid, Transcript>,
 }
 
-impl Default for AnswerState {
-    fn default() -> Self {
+impl AnswerState {
+    pub(super) fn new(config: &Configuration) -> Self {
         Self {
             client: reqwest::Client::builder()
                 .cookie_store(true)
@@ -141,8 +356,35 @@ impl Default for AnswerState {
                 // This should never fail, the only default properties we change are enabling
                 // cookies.
                 .unwrap(),
+            transcripts: BoundedCache::new(config.answer_replay_streams),
         }
     }
+
+    /// The transcript of the answer stream `last` belongs to, if it is still kept.
+    fn transcript(&self, thread_id: &str, last: &EventId) -> Option<SharedBuffer<Frame>> {
+        let query_id: uuid::Uuid = last.stream.parse().ok()?;
+        self.transcripts
+            .get(&query_id)
+            .filter(|transcript| transcript.thread_id == thread_id)
+            .map(|transcript| transcript.events)
+    }
+}
+
+/// The events of an answer stream, identified by its query id.
+#[derive(Clone)]
+struct Transcript {
+    thread_id: String,
+    events: SharedBuffer<Frame>,
+}
+
+#[derive(Clone, Debug)]
+enum Frame {
+    /// The JSON data of an event
+    Event(String),
+    /// Generation failed, ending the stream with an error
+    Failed(String),
+    /// Generation finished
+    Done,
 }
 
 pub(super) async fn handle(
@@ -150,7 +392,14 @@ pub(super) async fn handle(
     State(state): State<Arc<AnswerState>>,
     Extension(app): Extension<Application>,
     Extension(user): Extension<User>,
+    headers: HeaderMap,
 ) -> Result<impl IntoResponse> {
+    if let Some(last) = EventId::last(&headers) {
+        // a reconnecting client resumes the stream, without running the query again
+        let events = state.transcript(&params.thread_id, &last);
+        return Ok(stream_transcript(events, Some(last), &app.config));
+    }
+
     // create a new analytics event for this query
     let event = Arc::new(RwLock::new(QueryEvent::default()));
 
@@ -178,17 +427,64 @@ pub(super) async fn handle(
         // send to rudderstack
         app.track_query(&user, &ev);
     } else {
-        // the analytics event is fired when the stream is consumed
+        // the analytics event is fired when the answer is complete
     }
-    response
+
+    response.map(|events| stream_transcript(Some(events), None, &app.config))
 }
 
-fn parse_query(query: &str) -> Result<String, Error> {
-    Ok(parser::parse_nl(query)
-        .map_err(Error::user)?
-        .target()
-        .ok_or_else(|| Error::user("empty search"))?
-        .to_string())
+/// Stream the events of a transcript after `last`, or all of them, until the answer is
+/// complete.
+///
+/// A missing transcript produces a single `restart` event.
+fn stream_transcript(
+    events: Option<SharedBuffer<Frame>>,
+    last: Option<EventId>,
+    config: &Configuration,
+) -> Sse<impl Stream<Item = anyhow::Result<Event>>> {
+    let stream = async_stream::stream! {
+        let Some(events) = events else {
+            yield Ok(replay::restart());
+            return;
+        };
+
+        let last = last.unwrap_or_else(|| EventId {
+            stream: events.lock().unwrap().stream().to_owned(),
+            seq: 0,
+        });
+
+        let followed = replay::follow(events, Some(last));
+        futures::pin_mut!(followed);
+
+        while let Some(followed) = followed.next().await {
+            match followed {
+                Followed::Event(id, Frame::Event(data)) => {
+                    yield Ok(Event::default().id(id.to_string()).data(data));
+                }
+                Followed::Event(id, Frame::Done) => {
+                    yield Ok(Event::default().id(id.to_string()).data("[DONE]"));
+                    break;
+                }
+                Followed::Event(_, Frame::Failed(message)) => {
+                    yield Err(anyhow::anyhow!(message));
+                    break;
+                }
+                Followed::Restart => {
+                    yield Ok(replay::restart());
+                    break;
+                }
+            }
+        }
+    };
+
+    Sse::new(stream).keep_alive(replay::keep_alive(config))
+}
+
+fn parse_query(query: &str, min_chars: usize) -> Result<String, Error> {
+    let parsed = parser::parse_nl_cached(query).map_err(Error::user)?;
+    let target = parsed.target().ok_or_else(|| Error::user("empty search"))?;
+    check_query_length(target, min_chars)?;
+    Ok(target.to_string())
 }
 
 const MAX_HISTORY: usize = 3;
@@ -201,82 +497,440 @@ enum AnswerProgress {
     Search(String),
     // Explain the results
     Explain(String),
+    // Suggest a patch to the files of the results
+    Patch(String),
 }
 
+/// The snippets of a semantic search, along with how many candidates were skipped, see
+/// [`snippets_from_points`].
+#[allow(clippy::too_many_arguments)]
 async fn search_snippets(
     semantic: &Semantic,
     raw_query: &str,
     rephrased_query: &str,
-) -> Result<Vec<Snippet>, Error> {
-    let mut parsed_query = &mut parser::parse_nl(raw_query).map_err(Error::user)?;
+    filter_logic: FilterLogic,
+    repo_refs: Option<Vec<RepoRef>>,
+    weights: VectorWeights,
+    candidates: usize,
+    strict: bool,
+) -> Result<(Vec<Snippet>, usize), Error> {
+    let mut parsed_query = parser::parse_nl_cached(raw_query)
+        .map_err(Error::user)?
+        .as_ref()
+        .clone();
+
+    parsed_query.target = Some(parser::Literal::Plain(rephrased_query.to_owned().into()));
 
-    parsed_query.target = Some(parser::Literal::Plain(rephrased_query.into()));
+    let mut filters = FilterArgs::from_query(&parsed_query, filter_logic);
+    if let Some(repo_refs) = repo_refs {
+        filters = filters.within_repos(repo_refs);
+    }
 
-    let all_snippets: Vec<Snippet> = semantic
-        .search(parsed_query, 4 * SNIPPET_COUNT as u64) // heuristic
+    let points = semantic
+        .search(
+            &parsed_query,
+            filters,
+            weights,
+            PayloadFields::snippet(),
+            candidates as u64,
+            false,
+        )
         .await
-        .map_err(Error::internal)?
-        .into_iter()
-        .map(|r| {
-            use qdrant_client::qdrant::{value::Kind, Value};
-
-            // TODO: Can we merge with webserver/semantic.rs:L63?
-            fn value_to_string(value: Value) -> String {
-                match value.kind.unwrap() {
-                    Kind::StringValue(s) => s,
-                    _ => panic!("got non-string value"),
-                }
-            }
+        .map_err(Error::search)?;
 
-            fn extract_vector(point: &ScoredPoint) -> Vec<f32> {
-                if let Some(vectors) = &point.vectors {
-                    if let Some(vectors::VectorsOptions::Vector(v)) = &vectors.vectors_options {
-                        return v.data.clone();
+    snippets_from_points(points, semantic.payload_schema(), strict)
+}
+
+/// Semantic snippets of the `points` of a search, with their scores normalized, along with how
+/// many points were skipped.
+///
+/// Points whose payload can't be read as a snippet are logged and skipped, or fail the whole
+/// conversion if `strict`.
+pub(super) fn snippets_from_points(
+    points: Vec<ScoredPoint>,
+    schema: &PayloadSchema,
+    strict: bool,
+) -> Result<(Vec<Snippet>, usize), Error> {
+    fn extract_vector(point: &ScoredPoint) -> Result<Vec<f32>, PayloadError> {
+        if let Some(vectors) = &point.vectors {
+            match &vectors.vectors_options {
+                Some(vectors::VectorsOptions::Vector(v)) => return Ok(v.data.clone()),
+                Some(vectors::VectorsOptions::Vectors(named)) => {
+                    if let Some(v) = named.vectors.get(semantic::weights::BODY_VECTOR) {
+                        return Ok(v.data.clone());
                     }
                 }
-                panic!("got non-vector value");
+                None => {}
             }
+        }
+        Err(PayloadError::MissingVector)
+    }
 
-            let embedding = extract_vector(&r);
+    let converted = points.into_iter().map(|r| {
+        let snippet = extract_vector(&r).and_then(|embedding| {
+            snippet_from_payload(
+                r.payload,
+                schema,
+                r.score,
+                embedding,
+                SnippetSource::Semantic,
+            )
+        });
+        (r.id, snippet)
+    });
+    let (mut all_snippets, skipped) = readable_snippets(converted, strict)?;
+
+    let scores = all_snippets.iter().map(|s| s.score).collect::<Vec<_>>();
+    let normalized = semantic::normalize_scores(semantic::DISTANCE, &scores);
+    for (snippet, normalized_score) in all_snippets.iter_mut().zip(normalized) {
+        snippet.normalized_score = normalized_score;
+    }
 
-            let mut s = r.payload;
+    Ok((all_snippets, skipped))
+}
 
-            Snippet {
-                lang: value_to_string(s.remove("lang").unwrap()),
-                repo_name: value_to_string(s.remove("repo_name").unwrap()),
-                repo_ref: value_to_string(s.remove("repo_ref").unwrap()),
-                relative_path: value_to_string(s.remove("relative_path").unwrap()),
-                text: value_to_string(s.remove("snippet").unwrap()),
-
-                start_line: value_to_string(s.remove("start_line").unwrap())
-                    .parse::<usize>()
-                    .unwrap(),
-                end_line: value_to_string(s.remove("end_line").unwrap())
-                    .parse::<usize>()
-                    .unwrap(),
-                start_byte: value_to_string(s.remove("start_byte").unwrap())
-                    .parse::<usize>()
-                    .unwrap(),
-                end_byte: value_to_string(s.remove("end_byte").unwrap())
-                    .parse::<usize>()
-                    .unwrap(),
-                score: r.score,
-                embedding,
+/// The snippets of the `converted` candidates that could be read, along with how many couldn't.
+///
+/// Those are logged and skipped, or fail the whole conversion if `strict`.
+pub(super) fn readable_snippets(
+    converted: impl IntoIterator<Item = (Option<PointId>, Result<Snippet, PayloadError>)>,
+    strict: bool,
+) -> Result<(Vec<Snippet>, usize), Error> {
+    let mut skipped = 0;
+    let mut snippets = vec![];
+    for (id, snippet) in converted {
+        match snippet {
+            Ok(snippet) => snippets.push(snippet),
+            Err(err) if strict => return Err(Error::internal(err)),
+            Err(err) => {
+                warn!(?id, %err, "skipping malformed search candidate");
+                skipped += 1;
             }
+        }
+    }
+
+    Ok((snippets, skipped))
+}
+
+/// The snippet of a chunk `payload`, whose fields are read from their keys in `schema`.
+pub(super) fn snippet_from_payload(
+    mut payload: HashMap<String, Value>,
+    schema: &PayloadSchema,
+    score: f32,
+    embedding: Vec<f32>,
+    source: SnippetSource,
+) -> Result<Snippet, PayloadError> {
+    let mut s = |field: &str| schema.take(&mut payload, field);
+    let mut required = |field: &'static str| s(field).ok_or(PayloadError::Missing(field));
+    let lang = value_to_str(required("lang")?)?;
+    let repo_name = value_to_str(required("repo_name")?)?;
+    let repo_ref = value_to_str(required("repo_ref")?)?;
+    let relative_path = value_to_str(required("relative_path")?)?;
+    let text = value_to_str(required("snippet")?)?;
+    let start_line = value_to_usize(required("start_line")?)?;
+    let end_line = value_to_usize(required("end_line")?)?;
+    let start_byte = value_to_usize(required("start_byte")?)?;
+    let end_byte = value_to_usize(required("end_byte")?)?;
+
+    Ok(Snippet {
+        lang,
+        repo_name,
+        repo_ref,
+        relative_path: normalize_relative_path(&relative_path).into_owned(),
+        text,
+
+        start_line,
+        end_line,
+        start_byte,
+        end_byte,
+        cell_index: s("cell_index").map(value_to_usize).transpose()?,
+        symbol: s("symbol").map(value_to_str).transpose()?,
+        kind: s("kind")
+            .map(value_to_str)
+            .transpose()?
+            .map(|kind| ChunkKind::from_payload(&kind))
+            .unwrap_or_default(),
+        definitions: s("definitions")
+            .map(value_to_strs)
+            .transpose()?
+            .unwrap_or_default(),
+        score,
+        normalized_score: 0.0,
+        source,
+        explanation: None,
+        embedding,
+    })
+}
+
+/// Terms of the keyword fallback: the words of `query`, without surrounding punctuation.
+///
+/// Words shorter than 3 characters are dropped, as they match nearly every chunk.
+pub(super) fn query_keywords(query: &str) -> Vec<String> {
+    let mut keywords = vec![];
+    for word in query.split_whitespace() {
+        let word = word.trim_matches(|c: char| !c.is_alphanumeric() && c != '_');
+        if word.chars().count() >= 3 && !keywords.iter().any(|k| k == word) {
+            keywords.push(word.to_owned());
+        }
+    }
+
+    keywords
+}
+
+/// Chunks that literally contain one of `keywords`, for queries semantic search misses, along
+/// with how many were skipped, see [`readable_snippets`].
+async fn keyword_snippets(
+    semantic: &Semantic,
+    raw_query: &str,
+    keywords: &[String],
+    filter_logic: FilterLogic,
+    repo_refs: Option<Vec<RepoRef>>,
+    limit: usize,
+    strict: bool,
+) -> Result<(Vec<Snippet>, usize), Error> {
+    let parsed_query = parser::parse_nl_cached(raw_query).map_err(Error::user)?;
+
+    let mut filters = FilterArgs::from_query(&parsed_query, filter_logic);
+    if let Some(repo_refs) = repo_refs {
+        filters = filters.within_repos(repo_refs);
+    }
+
+    let points = semantic
+        .keyword_search(filters, keywords, limit as u32)
+        .await
+        .map_err(Error::search)?;
+
+    let converted = points.into_iter().map(|p| {
+        let snippet = snippet_from_payload(
+            p.payload,
+            semantic.payload_schema(),
+            0.0,
+            vec![],
+            SnippetSource::Keyword,
+        );
+        (p.id, snippet)
+    });
+    readable_snippets(converted, strict)
+}
+
+/// Append keyword `matches` to `snippets` until there are `limit` of them.
+///
+/// Matches are ranked by the fraction of `keywords` they contain, which also becomes their
+/// score. Chunks that are already in `snippets` are skipped.
+fn merge_keyword_matches(
+    mut snippets: Vec<Snippet>,
+    matches: Vec<Snippet>,
+    keywords: &[String],
+    limit: usize,
+) -> Vec<Snippet> {
+    let mut matches = matches
+        .into_iter()
+        .filter(|m| {
+            !snippets.iter().any(|s| {
+                s.repo_ref == m.repo_ref
+                    && s.relative_path == m.relative_path
+                    && s.start_byte == m.start_byte
+            })
         })
-        .collect();
+        .map(|mut m| {
+            let text = m.text.to_lowercase();
+            let found = keywords
+                .iter()
+                .filter(|k| text.contains(&k.to_lowercase()))
+                .count();
+
+            m.score = found as f32 / keywords.len().max(1) as f32;
+            m.normalized_score = m.score;
+            m
+        })
+        .collect::<Vec<_>>();
+
+    matches.sort_by(|a, b| b.score.total_cmp(&a.score));
+
+    let missing = limit.saturating_sub(snippets.len());
+    snippets.extend(matches.into_iter().take(missing));
+    snippets
+}
+
+/// Explain why `snippet` was returned for a query with the keyword fallback terms `keywords`.
+///
+/// Keyword matches list the terms found in their text, semantic matches only say that their
+/// score comes from vector similarity.
+fn explain(snippet: &Snippet, keywords: &[String]) -> Explanation {
+    match snippet.source {
+        SnippetSource::Semantic => Explanation::Semantic {
+            note: "ranked by the similarity of its embedding to the query's; no single term \
+                   drives the score"
+                .into(),
+        },
+        SnippetSource::Keyword => {
+            let text = snippet.text.to_ascii_lowercase();
+            let terms = keywords
+                .iter()
+                .filter_map(|term| {
+                    let needle = term.to_ascii_lowercase();
+                    let positions = text
+                        .match_indices(&needle)
+                        .map(|(start, m)| start..start + m.len())
+                        .collect::<Vec<_>>();
+
+                    (!positions.is_empty()).then(|| TermMatch {
+                        term: term.clone(),
+                        positions,
+                    })
+                })
+                .collect();
+
+            Explanation::Keyword { terms }
+        }
+    }
+}
+
+#[derive(Error, Debug, PartialEq)]
+pub(super) enum PayloadError {
+    #[error("expected a non-negative integer, got {0:?}")]
+    NotAnInteger(Option<Kind>),
+    #[error("expected a string, got {0:?}")]
+    NotAString(Option<Kind>),
+    #[error("expected a list, got {0:?}")]
+    NotAList(Option<Kind>),
+    #[error("missing the `{0}` field")]
+    Missing(&'static str),
+    #[error("missing the code body vector")]
+    MissingVector,
+}
+
+/// Read a string payload field, failing rather than panicking on other values.
+fn value_to_str(value: Value) -> Result<String, PayloadError> {
+    match value.kind {
+        Some(Kind::StringValue(s)) => Ok(s),
+        kind => Err(PayloadError::NotAString(kind)),
+    }
+}
 
-    Ok(all_snippets)
+/// Read a list of strings payload field, failing rather than panicking on other values.
+fn value_to_strs(value: Value) -> Result<Vec<String>, PayloadError> {
+    match value.kind {
+        Some(Kind::ListValue(list)) => list.values.into_iter().map(value_to_str).collect(),
+        kind => Err(PayloadError::NotAList(kind)),
+    }
+}
+
+/// Read a numeric payload field, stored either as an integer or as a string of digits.
+pub(super) fn value_to_usize(value: Value) -> Result<usize, PayloadError> {
+    let parsed = match &value.kind {
+        Some(Kind::IntegerValue(i)) => usize::try_from(*i).ok(),
+        Some(Kind::StringValue(s)) => s.parse::<usize>().ok(),
+        _ => None,
+    };
+
+    parsed.ok_or(PayloadError::NotAnInteger(value.kind))
+}
+
+/// Added to the query similarity of snippets defining a symbol named in the query, so that MMR
+/// selects them over snippets that only use the symbol.
+const DEFINITION_BOOST: f32 = 0.1;
+
+/// Whether `snippet` defines one of the symbols named by `keywords`.
+fn defines_any(snippet: &Snippet, keywords: &[String]) -> bool {
+    snippet.kind == ChunkKind::Definition
+        && snippet
+            .definitions
+            .iter()
+            .any(|d| keywords.iter().any(|k| d.eq_ignore_ascii_case(k)))
+}
+
+/// Whether selecting `limit` of the `candidates` takes MMR, which has nothing to choose between
+/// unless there are more candidates than that.
+pub(super) fn needs_mmr(candidates: &[Snippet], limit: usize) -> bool {
+    candidates.len() > limit
+}
+
+/// Collapse the duplicates of `strategy` among `snippets`, before [`select_snippets`].
+pub(super) fn collapse_duplicates(snippets: Vec<Snippet>, strategy: DedupStrategy) -> Vec<Snippet> {
+    match strategy {
+        DedupStrategy::Mmr => snippets,
+        DedupStrategy::Symbol => collapse_by_symbol(snippets),
+    }
+}
+
+/// The `max` best scoring of `candidates`, in their original order, before [`select_snippets`].
+///
+/// Selecting snippets compares candidates pairwise, so this bounds its cost however many
+/// candidates a search returned. Fewer than `limit` snippets are selected if `max` is lower.
+pub(super) fn cap_candidates(candidates: Vec<Snippet>, max: usize) -> Vec<Snippet> {
+    if candidates.len() <= max {
+        return candidates;
+    }
+
+    let mut ranked = (0..candidates.len()).collect::<Vec<_>>();
+    ranked.sort_by(|&a, &b| candidates[b].score.total_cmp(&candidates[a].score));
+    let mut kept = vec![false; candidates.len()];
+    for i in ranked.into_iter().take(max) {
+        kept[i] = true;
+    }
+
+    candidates
+        .into_iter()
+        .zip(kept)
+        .filter_map(|(candidate, kept)| kept.then_some(candidate))
+        .collect()
+}
+
+/// The candidates of an answer that [`select_snippets`] compares, with the duplicates of
+/// `strategy` collapsed and cut down to [`Configuration::dedup_max_candidates`].
+pub(super) fn answer_candidates(
+    snippets: Vec<Snippet>,
+    strategy: DedupStrategy,
+    config: &Configuration,
+) -> Vec<Snippet> {
+    cap_candidates(
+        collapse_duplicates(snippets, strategy),
+        config.dedup_max_candidates,
+    )
 }
 
-fn deduplicate_snippets(all_snippets: Vec<Snippet>, query_embedding: Vec<f32>) -> Vec<Snippet> {
+/// Select `limit` snippets, preferring the ones that define a symbol named by `definitions`.
+/// Pass no `definitions` to rank by query similarity alone.
+///
+/// The `query_embedding` is only used if [`needs_mmr`], and may be left empty otherwise. Without
+/// MMR, only exact duplicates are collapsed, see [`collapse_exact`].
+pub(super) fn select_snippets(
+    mut all_snippets: Vec<Snippet>,
+    query_embedding: Vec<f32>,
+    definitions: &[String],
+    limit: usize,
+) -> Vec<Snippet> {
+    all_snippets.sort_by_key(|s| !defines_any(s, definitions));
+    if !needs_mmr(&all_snippets, limit) {
+        return collapse_exact(all_snippets);
+    }
+
+    let boosts = all_snippets
+        .iter()
+        .map(|s| {
+            if defines_any(s, definitions) {
+                DEFINITION_BOOST
+            } else {
+                0.0
+            }
+        })
+        .collect::<Vec<_>>();
+
     let lambda = 0.5;
-    let k = SNIPPET_COUNT; // number of snippets
+    let k = limit; // number of snippets
     let embeddings = all_snippets
         .iter()
         .map(|s| s.embedding.as_slice())
         .collect::<Vec<_>>();
-    let idxs = semantic::deduplicate_with_mmr(&query_embedding, &embeddings, lambda, k);
+    let idxs = semantic::deduplicate_with_mmr(
+        &query_embedding,
+        &embeddings,
+        &boosts,
+        lambda,
+        k,
+        Similarity::from(semantic::DISTANCE),
+    );
     let mut snippets = vec![];
     info!("preserved idxs after MMR are {:?}", idxs);
     for i in idxs {
@@ -286,6 +940,73 @@ fn deduplicate_snippets(all_snippets: Vec<Snippet>, query_embedding: Vec<f32>) -
     snippets
 }
 
+/// Collapse snippets sharing a `(repo_ref, relative_path, symbol)` into the highest scoring one,
+/// preserving the order of the remaining snippets.
+fn collapse_by_symbol(snippets: Vec<Snippet>) -> Vec<Snippet> {
+    let mut by_symbol = HashMap::new();
+    let mut collapsed: Vec<Snippet> = Vec::with_capacity(snippets.len());
+
+    for snippet in snippets {
+        let Some(symbol) = snippet.symbol.clone() else {
+            collapsed.push(snippet);
+            continue;
+        };
+
+        let key = (
+            snippet.repo_ref.clone(),
+            snippet.relative_path.clone(),
+            symbol,
+        );
+        match by_symbol.get(&key) {
+            Some(&i) if collapsed[i].score >= snippet.score => {}
+            Some(&i) => collapsed[i] = snippet,
+            None => {
+                by_symbol.insert(key, collapsed.len());
+                collapsed.push(snippet);
+            }
+        }
+    }
+
+    collapsed
+}
+
+/// Collapse snippets of the same chunk, by `(repo_ref, relative_path, start_byte, end_byte)`, or
+/// with the same non-empty text into the highest scoring one, preserving the order of the
+/// remaining snippets.
+fn collapse_exact(snippets: Vec<Snippet>) -> Vec<Snippet> {
+    let mut by_range = HashMap::new();
+    let mut by_text = HashMap::new();
+    let mut collapsed: Vec<Snippet> = Vec::with_capacity(snippets.len());
+
+    for snippet in snippets {
+        let range = (
+            snippet.repo_ref.clone(),
+            snippet.relative_path.clone(),
+            snippet.start_byte,
+            snippet.end_byte,
+        );
+        let text = (!snippet.text.is_empty()).then(|| snippet.text.clone());
+        let existing = by_range
+            .get(&range)
+            .or_else(|| text.as_ref().and_then(|t| by_text.get(t)))
+            .copied();
+
+        match existing {
+            Some(i) if collapsed[i].score >= snippet.score => {}
+            Some(i) => collapsed[i] = snippet,
+            None => {
+                by_range.insert(range, collapsed.len());
+                if let Some(text) = text {
+                    by_text.insert(text, collapsed.len());
+                }
+                collapsed.push(snippet);
+            }
+        }
+    }
+
+    collapsed
+}
+
 // we use this internally to check whether the first token (skipping whitespace) is a
 // number or something else
 enum FirstToken {
@@ -299,6 +1020,11 @@ async fn grow_snippet(
     semantic: &Semantic,
     app: &Application,
 ) -> Result<Snippet, Error> {
+    // notebook snippets can't be grown from the raw file, as their ranges are cell-relative
+    if relevant_snippet.cell_index.is_some() {
+        return Ok(relevant_snippet.clone());
+    }
+
     // grow the snippet by 60 lines above and below, we have sufficient space
     // to grow this snippet by 10 times its original size (15 to 150)
     let repo_ref = &relevant_snippet
@@ -338,11 +1064,20 @@ async fn grow_snippet(
         end_line: relevant_snippet.end_line,
         start_byte: relevant_snippet.start_byte,
         end_byte: relevant_snippet.end_byte,
+        cell_index: relevant_snippet.cell_index,
+        symbol: relevant_snippet.symbol.clone(),
+        kind: relevant_snippet.kind,
+        definitions: relevant_snippet.definitions.clone(),
         score: relevant_snippet.score,
+        normalized_score: relevant_snippet.normalized_score,
+        source: relevant_snippet.source,
+        explanation: relevant_snippet.explanation.clone(),
         embedding: relevant_snippet.embedding.clone(),
     })
 }
 
+type AnswerStream = std::pin::Pin<Box<dyn Stream<Item = Result<String, AnswerAPIError>> + Send>>;
+
 async fn handle_inner(
     query: &str,
     thread_id: &str,
@@ -350,11 +1085,14 @@ async fn handle_inner(
     params: Arc<Params>,
     app: Arc<Application>,
     event: Arc<RwLock<QueryEvent>>,
+    user: &User,
     mut stop_watch: StopWatch,
 ) -> Result<(
     Option<Vec<Snippet>>,
+    usize,
+    Option<PatchSuggestion>,
     StopWatch,
-    std::pin::Pin<Box<dyn Stream<Item = Result<String, AnswerAPIError>> + Send>>,
+    AnswerStream,
 )> {
     let query = query.to_string(); // TODO: Sort out query handling
 
@@ -365,6 +1103,9 @@ async fn handle_inner(
         .push(Stage::new("parsed_query", &query).with_time(stop_watch.lap()));
 
     let mut snippets = None;
+    let mut skipped = 0;
+    // files the patch prompt quoted from, by the path it showed them at, for `mode=patch`
+    let mut cited_files = vec![];
 
     let answer_bearer = if app.env.allow(Feature::GithubDeviceFlow) {
         let Some(cred) = app.credentials.github() else {
@@ -410,6 +1151,17 @@ async fn handle_inner(
         answer_bearer.clone(),
     );
 
+    // resolve the workspace up front, so an unknown one fails before any model is queried
+    let repo_refs = params
+        .workspace
+        .as_deref()
+        .map(|workspace| workspaces::resolve(&app, workspace))
+        .transpose()?;
+
+    if let Ok(parsed) = parser::parse_nl_cached(&params.q) {
+        super::repos::reject_evicted(&app, parsed.repos().map(|r| r.as_ref())).await?;
+    }
+
     let mut progress = app
         .with_prior_conversation(thread_id, |history| {
             if history.is_empty() {
@@ -431,19 +1183,100 @@ async fn handle_inner(
                 (prompt, 20, 0.0, vec![])
             }
             AnswerProgress::Search(rephrased_query) => {
+                let limit = params.limit as usize;
+                let limit_kind = params.limit_kind.unwrap_or_default();
+
                 // TODO: Clean up this query handling logic
-                let all_snippets = search_snippets(&semantic, &params.q, rephrased_query).await?;
+                let (all_snippets, search_skipped) = search_snippets(
+                    &semantic,
+                    &params.q,
+                    rephrased_query,
+                    params.filter_logic,
+                    repo_refs.clone(),
+                    VectorWeights {
+                        body_weight: params.body_weight,
+                        doc_weight: params.doc_weight,
+                    },
+                    limit_kind.candidates(limit),
+                    params.strict,
+                )
+                .await?;
+                skipped = search_skipped;
                 info!("Retrieved {} snippets", all_snippets.len());
 
+                if let Ok(parsed) = parser::parse_nl_cached(&params.q) {
+                    let repos = all_snippets.iter().map(|s| s.repo_ref.clone());
+                    app.record_search(
+                        SearchEntry::semantic(
+                            &params.q,
+                            &parsed,
+                            all_snippets.len(),
+                            all_snippets.first().map(|s| s.score),
+                            user.0.clone(),
+                        )
+                        .with_repos(repos),
+                    );
+                }
+
                 event.write().await.stages.push(
                     Stage::new("semantic_results", &all_snippets).with_time(stop_watch.lap()),
                 );
 
-                let query_embedding = semantic.embed(rephrased_query).map_err(|e| {
-                    error!("failed to embed query: {}", e);
-                    Error::internal(e)
-                })?;
-                let filtered_snippets = deduplicate_snippets(all_snippets, query_embedding);
+                let candidates = semantic.post_process(
+                    &params.q,
+                    answer_candidates(all_snippets, params.dedup, &app.config),
+                );
+
+                // usually cached by the search for the snippets above
+                let query_embedding = if needs_mmr(&candidates, limit) {
+                    let (query_embedding, _) =
+                        semantic.embed_query(rephrased_query).await.map_err(|e| {
+                            error!("failed to embed query: {}", e);
+                            Error::internal(e)
+                        })?;
+                    query_embedding
+                } else {
+                    vec![]
+                };
+                let keywords = parser::parse_nl_cached(&params.q)
+                    .ok()
+                    .and_then(|q| q.target().map(|t| query_keywords(t)))
+                    .unwrap_or_default();
+
+                let definitions: &[String] = if params.prefer_definitions.unwrap_or(true) {
+                    &keywords
+                } else {
+                    &[]
+                };
+                let mut filtered_snippets =
+                    select_snippets(candidates, query_embedding, definitions, limit);
+
+                if params.fallback.unwrap_or(true)
+                    && filtered_snippets.len() < app.config.keyword_fallback_min_results
+                {
+                    let (matches, keyword_skipped) = keyword_snippets(
+                        &semantic,
+                        &params.q,
+                        &keywords,
+                        params.filter_logic,
+                        repo_refs.clone(),
+                        limit,
+                        params.strict,
+                    )
+                    .await?;
+                    skipped += keyword_skipped;
+                    let matches = semantic.post_process(&params.q, matches);
+
+                    info!("Retrieved {} keyword matches", matches.len());
+                    filtered_snippets =
+                        merge_keyword_matches(filtered_snippets, matches, &keywords, limit);
+                }
+
+                if params.debug {
+                    for snippet in &mut filtered_snippets {
+                        snippet.explanation = Some(explain(snippet, &keywords));
+                    }
+                }
 
                 event.write().await.stages.push(
                     Stage::new("filtered_semantic_results", &filtered_snippets)
@@ -457,7 +1290,7 @@ async fn handle_inner(
                             Please try again with different keywords or refine your search."
                             .to_string())
                     }));
-                    return Ok((snippets, stop_watch, selection_fail_stream));
+                    return Ok((snippets, skipped, None, stop_watch, selection_fail_stream));
                 }
 
                 let prompt =
@@ -474,6 +1307,7 @@ async fn handle_inner(
             }
             AnswerProgress::Explain(query) => {
                 let prompt = if let Some(snippet) = snippets.as_ref().unwrap().first() {
+                    app.record_answer(&[snippet.repo_ref.clone()]);
                     let grown = grow_snippet(snippet, &semantic, &app).await?;
                     app.with_prior_conversation(thread_id, |conversation| {
                         answer_api_client.build_explain_prompt(&grown, conversation, query)
@@ -509,6 +1343,29 @@ async fn handle_inner(
 
                 (prompt, max_tokens, 0.9, vec![])
             }
+            AnswerProgress::Patch(query) => {
+                let (prompt, files) = app.with_prior_conversation(thread_id, |conversation| {
+                    answer_api_client.build_patch_prompt(
+                        snippets.as_deref().unwrap_or_default(),
+                        conversation,
+                        query,
+                    )
+                });
+                cited_files = files;
+
+                let tokens_used = semantic.gpt2_token_count(&prompt.messages[0].content);
+                let max_tokens = 8000u32
+                    .saturating_sub(tokens_used as u32)
+                    .clamp(1, MAX_PATCH_TOKENS);
+
+                event
+                    .write()
+                    .await
+                    .stages
+                    .push(Stage::new("patch_prompt", &prompt).with_time(stop_watch.lap()));
+
+                (prompt, max_tokens, 0.0, vec![])
+            }
         };
 
         // This strange extraction of parameters from a tuple is due to lifetime issues. This
@@ -536,7 +1393,7 @@ async fn handle_inner(
                             .to_string(),
                     )
                 }));
-                return Ok((None, stop_watch, rephrase_fail_stream));
+                return Ok((None, skipped, None, stop_watch, rephrase_fail_stream));
             }
             event
                 .write()
@@ -547,6 +1404,30 @@ async fn handle_inner(
             continue;
         }
 
+        if let AnswerProgress::Patch(_) = &progress {
+            let diff: String = stream.try_collect().await?;
+            if diff.trim() == "N/A" {
+                let patch_fail_stream = Box::pin(stream::once(async {
+                    Ok(
+                        "Sorry, I couldn't write a patch for this from the code I found."
+                            .to_string(),
+                    )
+                }));
+                return Ok((snippets, skipped, None, stop_watch, patch_fail_stream));
+            }
+
+            let suggestion = review_patch(diff, &cited_files, &app).await?;
+            event
+                .write()
+                .await
+                .stages
+                .push(Stage::new("patch", &suggestion.hunks).with_time(stop_watch.lap()));
+
+            let diff = suggestion.diff.clone();
+            let diff_stream = Box::pin(stream::once(async move { Ok(diff) }));
+            return Ok((snippets, skipped, Some(suggestion), stop_watch, diff_stream));
+        }
+
         let mut collected = FirstToken::None;
         while let Some(token) = stream.try_next().await? {
             if let Ok(i) = token.trim().parse::<usize>() {
@@ -566,10 +1447,19 @@ async fn handle_inner(
                             let selection_fail_stream = Box::pin(stream::once(async {
                                 Ok("I'm not sure. One of these snippets might be relevant".to_string())
                             }));
-                            return Ok((snippets, stop_watch, selection_fail_stream));
+                            return Ok((
+                                snippets,
+                                skipped,
+                                None,
+                                stop_watch,
+                                selection_fail_stream,
+                            ));
                         };
                         snippets.as_mut().unwrap().swap(index, 0);
-                        AnswerProgress::Explain(query.clone())
+                        match params.mode {
+                            AnswerMode::Explain => AnswerProgress::Explain(query.clone()),
+                            AnswerMode::Patch => AnswerProgress::Patch(query.clone()),
+                        }
                     }
                     e => e,
                 }
@@ -577,6 +1467,8 @@ async fn handle_inner(
             FirstToken::Other(token) => {
                 return Ok((
                     snippets,
+                    skipped,
+                    None,
                     stop_watch,
                     Box::pin(stream::once(async move { Ok(token) }).chain(stream)),
                 ));
@@ -584,6 +1476,8 @@ async fn handle_inner(
             FirstToken::None => {
                 return Ok((
                     snippets,
+                    skipped,
+                    None,
                     stop_watch,
                     Box::pin(stream::once(async move { Ok("".to_string()) }).chain(stream)),
                 ));
@@ -598,7 +1492,7 @@ async fn _handle(
     app: Application,
     event: Arc<RwLock<QueryEvent>>,
     user: User,
-) -> Result<impl IntoResponse> {
+) -> Result<SharedBuffer<Frame>> {
     let query_id = uuid::Uuid::new_v4();
 
     info!("Raw query: {:?}", &params.q);
@@ -615,67 +1509,102 @@ async fn _handle(
             .push(Stage::new("raw_query", &params.q));
     }
 
-    let query = parse_query(&params.q)?;
+    let query = parse_query(&params.q, app.config.min_query_chars)?;
     info!("Parsed query target: {:?}", &query);
 
     let stop_watch = StopWatch::start();
     let params = Arc::new(params);
     let mut app = Arc::new(app);
-    let (snippets, mut stop_watch, mut text) = handle_inner(
+    let (snippets, skipped, patch, mut stop_watch, mut text) = handle_inner(
         &query,
         &params.thread_id,
         state,
         Arc::clone(&params),
         Arc::clone(&app),
         Arc::clone(&event),
+        &user,
         stop_watch,
     )
     .await?;
     Arc::make_mut(&mut app).add_conversation_entry(params.thread_id.clone(), query);
-    let initial_event = Event::default()
-        .json_data(super::Response::<'static>::from(AnswerResponse {
-            query_id,
-            session_id: params.thread_id.clone(),
-            snippets: snippets.as_ref().map(|matches| AnswerSnippets {
-                matches: matches.clone(),
-                answer_path: matches
-                    .first()
-                    .map(|s| &s.relative_path)
-                    .cloned()
-                    .unwrap_or_default(),
-            }),
-        }))
-        .map_err(Error::internal)?;
+    let initial_event = serde_json::to_string(&super::Response::<'static>::from(AnswerResponse {
+        query_id,
+        session_id: params.thread_id.clone(),
+        snippets: snippets.as_ref().map(|matches| AnswerSnippets {
+            matches: matches.clone(),
+            answer_path: matches
+                .first()
+                .map(|s| &s.relative_path)
+                .cloned()
+                .unwrap_or_default(),
+            skipped,
+        }),
+        patch,
+    }))
+    .map_err(Error::internal)?;
+
+    let events: SharedBuffer<Frame> = Arc::new(Mutex::new(ReplayBuffer::new(
+        query_id.to_string(),
+        app.config.answer_replay_events,
+    )));
+    events.lock().unwrap().push(Frame::Event(initial_event));
+    state.transcripts.insert(
+        query_id,
+        Transcript {
+            thread_id: params.thread_id.clone(),
+            events: Arc::clone(&events),
+        },
+    );
 
-    let mut expl = String::new();
+    // the answer is generated even if the client disconnects, so that it can resume the stream,
+    // until no client has followed it for `answer_abandon_secs`
+    let transcript = Arc::clone(&events);
+    let grace = Duration::from_secs(app.config.answer_abandon_secs);
+    tokio::spawn(async move {
+        let generate = async {
+            let mut expl = String::new();
+            while let Some(result) = text.next().await {
+                if let Ok(fragment) = &result {
+                    app.extend_conversation_answer(params.thread_id.clone(), fragment.trim_end())
+                }
+                let data =
+                    serde_json::to_string(&result.as_ref().map_err(|e| e.to_string())).unwrap();
+                transcript.lock().unwrap().push(Frame::Event(data));
 
-    let wrapped_stream = async_stream::stream! {
-        yield Ok(initial_event);
-        while let Some(result) = text.next().await {
-            if let Ok(fragment) = &result {
-                app.extend_conversation_answer(params.thread_id.clone(), fragment.trim_end())
+                match result {
+                    Ok(s) => expl += &s,
+                    Err(e) => {
+                        transcript
+                            .lock()
+                            .unwrap()
+                            .push(Frame::Failed(e.to_string()));
+                        return;
+                    }
+                }
             }
-            yield Ok(Event::default()
-                .json_data(result.as_ref().map_err(|e| e.to_string()))
-                .unwrap());
 
-            match result {
-                Ok(s) => expl += &s,
-                Err(e) => yield Err(e),
+            debug!("answer complete, closing SSE");
+            let mut event = event.write().await;
+            event
+                .stages
+                .push(Stage::new("answer", &expl).with_time(stop_watch.lap()));
+            app.track_query(&user, &event);
+
+            transcript.lock().unwrap().push(Frame::Done);
+        };
+
+        tokio::select! {
+            () = generate => {}
+            () = replay::abandoned(&transcript, grace) => {
+                debug!("no client is following the answer, cancelling it");
+                transcript.lock().unwrap().push(Frame::Failed(
+                    "the answer was cancelled, as no client was following it".to_owned(),
+                ));
             }
         }
+    });
 
-        debug!("answer complete, closing SSE");
-        let mut event = event.write().await;
-        event
-            .stages
-            .push(Stage::new("answer", &expl).with_time(stop_watch.lap()));
-        app.track_query(&user, &event);
-    };
-
-    Ok(Sse::new(wrapped_stream.chain(futures::stream::once(
-        async { Ok(Event::default().data("[DONE]")) },
-    ))))
+    Ok(events)
 }
 
 // grow the text of this snippet by `size` and return the new text
@@ -694,26 +1623,25 @@ fn grow(doc: &ContentDocument, snippet: &Snippet, size: usize) -> Option<String>
         return None;
     }
 
+    // widen bounds that fall inside a character, rather than giving up on growing
+    let (_, bounds) = slice_chars(content, snippet.start_byte..snippet.end_byte);
+
     // skip upwards `size` number of lines
-    let new_start_byte = content
-        .get(..snippet.start_byte)?
+    let new_start_byte = content[..bounds.start]
         .rmatch_indices('\n')
         .map(|(idx, _)| idx)
         .nth(size)
         .unwrap_or(0);
 
     // skip downwards `size` number of lines
-    let new_end_byte = content
-        .get(snippet.end_byte..)?
+    let new_end_byte = content[bounds.end..]
         .match_indices('\n')
         .map(|(idx, _)| idx)
         .nth(size)
-        .map(|s| s.saturating_add(snippet.end_byte)) // the index is off by `snippet.end_byte`
+        .map(|s| s.saturating_add(bounds.end)) // the index is off by `bounds.end`
         .unwrap_or(content.len());
 
-    content
-        .get(new_start_byte..new_end_byte)
-        .map(ToOwned::to_owned)
+    Some(content[new_start_byte..new_end_byte].to_owned())
 }
 
 struct AnswerAPIClient<'s> {
@@ -869,6 +1797,10 @@ impl<'s> AnswerAPIClient<'s> {
 }
 
 const DELIMITER: &str = "=========";
+
+/// Tokens the model may spend on a patch
+const MAX_PATCH_TOKENS: u32 = 1000;
+
 impl<'a> AnswerAPIClient<'a> {
     fn build_select_prompt(&self, query: &str, snippets: &[Snippet]) -> api::Messages {
         // snippets are 1-indexed so we can use index 0 where no snippets are relevant
@@ -959,6 +1891,109 @@ impl<'a> AnswerAPIClient<'a> {
 
         api::Messages { messages }
     }
+
+    /// The prompt for a patch to the files of `snippets`, and each of the files it quotes from,
+    /// by the [path it shows them at](CitedFile::diff_path).
+    ///
+    /// Snippets are quoted in order until the prompt is full. Notebook cells are left out, as
+    /// their lines don't line up with those of the notebook file.
+    fn build_patch_prompt(
+        &self,
+        snippets: &[Snippet],
+        conversation: &[(String, String)],
+        query: &str,
+    ) -> (api::Messages, Vec<(String, CitedFile)>) {
+        let budget = api::Provider::OpenAi.token_limit() - MAX_PATCH_TOKENS as usize;
+        let mut tokens = self
+            .semantic
+            .gpt2_token_count(include_str!("../prompt/patch.txt"));
+        let mut extracts = String::new();
+        let mut files = Vec::<(String, CitedFile)>::new();
+
+        let quotable = snippets
+            .iter()
+            .filter(|s| s.cell_index.is_none())
+            .map(|s| CitedFile {
+                repo_ref: s.repo_ref.clone(),
+                relative_path: s.relative_path.clone(),
+            })
+            .collect::<Vec<_>>();
+
+        for (snippet, file) in snippets
+            .iter()
+            .filter(|s| s.cell_index.is_none())
+            .zip(&quotable)
+        {
+            let path = file.diff_path(&quotable);
+            let entry = format!(
+                "Repository: {}\nPath: {}\nLines: {}-{}\n\n{}\n{DELIMITER}\n",
+                snippet.repo_name,
+                path,
+                snippet.start_line + 1,
+                snippet.end_line + 1,
+                snippet.text
+            );
+
+            let count = self.semantic.gpt2_token_count(&entry);
+            if tokens + count >= budget {
+                debug!("evicting a snippet!");
+                continue;
+            }
+
+            tokens += count;
+            extracts.push_str(&entry);
+            if !files.iter().any(|(_, cited)| cited == file) {
+                files.push((path, file.clone()));
+            }
+        }
+
+        let system = format!(include_str!("../prompt/patch.txt"), SNIPPETS = extracts);
+        let mut messages = vec![api::Message {
+            role: "system".to_string(),
+            content: system,
+        }];
+
+        for (question, answer) in conversation {
+            messages.push(api::Message {
+                role: "user".to_string(),
+                content: question.clone(),
+            });
+            messages.push(api::Message {
+                role: "assistant".to_string(),
+                content: answer.clone(),
+            });
+        }
+
+        messages.push(api::Message {
+            role: "user".to_string(),
+            content: query.to_string(),
+        });
+
+        (api::Messages { messages }, files)
+    }
+}
+
+/// Check the patch the model answered with against the indexed content of the files it was shown.
+async fn review_patch(
+    diff: String,
+    cited_files: &[(String, CitedFile)],
+    app: &Application,
+) -> Result<PatchSuggestion> {
+    let mut files = HashMap::new();
+    for (path, file) in cited_files {
+        let repo_ref = file.repo_ref.parse::<RepoRef>().map_err(Error::internal)?;
+        let indexed = app.indexes.file.by_path(&repo_ref, &file.relative_path);
+        match indexed.await {
+            Ok(doc) => {
+                files.insert(path.clone(), (file.clone(), doc.content));
+            }
+            // the patch is refused if it changes the file, as it can't be checked
+            Err(err) => warn!(?err, path, "cited file is not indexed"),
+        }
+    }
+
+    patch::review(diff, &files)
+        .map_err(|rejection| Error::with_body(StatusCode::UNPROCESSABLE_ENTITY, rejection))
 }
 
 fn build_rephrase_query_prompt(query: &str, conversation: &[(String, String)]) -> api::Messages {
@@ -1004,3 +2039,509 @@ impl StopWatch {
         duration
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn value(kind: Kind) -> Value {
+        Value { kind: Some(kind) }
+    }
+
+    fn snippet(relative_path: &str, symbol: Option<&str>, score: f32) -> Snippet {
+        Snippet {
+            lang: "rust".into(),
+            repo_name: "bloop".into(),
+            repo_ref: "local//bloop".into(),
+            relative_path: relative_path.into(),
+            text: String::new(),
+            start_line: 0,
+            end_line: 0,
+            start_byte: 0,
+            end_byte: 0,
+            cell_index: None,
+            symbol: symbol.map(ToOwned::to_owned),
+            kind: ChunkKind::Other,
+            definitions: vec![],
+            score,
+            normalized_score: score,
+            source: SnippetSource::Semantic,
+            explanation: None,
+            embedding: vec![],
+        }
+    }
+
+    #[test]
+    fn integer_payloads() {
+        assert_eq!(value_to_usize(value(Kind::IntegerValue(42))), Ok(42));
+        assert_eq!(
+            value_to_usize(value(Kind::IntegerValue(-1))),
+            Err(PayloadError::NotAnInteger(Some(Kind::IntegerValue(-1))))
+        );
+    }
+
+    #[test]
+    fn string_payloads() {
+        assert_eq!(
+            value_to_usize(value(Kind::StringValue("42".into()))),
+            Ok(42)
+        );
+    }
+
+    #[test]
+    fn non_numeric_payloads() {
+        let kind = Kind::StringValue("forty-two".into());
+        assert_eq!(
+            value_to_usize(value(kind.clone())),
+            Err(PayloadError::NotAnInteger(Some(kind)))
+        );
+        assert_eq!(
+            value_to_usize(Value { kind: None }),
+            Err(PayloadError::NotAnInteger(None))
+        );
+        assert!(value_to_usize(value(Kind::BoolValue(true))).is_err());
+    }
+
+    fn candidate(id: u64, fields: &[(&str, &str)]) -> ScoredPoint {
+        ScoredPoint {
+            id: Some(id.into()),
+            payload: fields
+                .iter()
+                .map(|(k, v)| (k.to_string(), value(Kind::StringValue(v.to_string()))))
+                .collect(),
+            score: 0.5,
+            vectors: Some(qdrant_client::qdrant::Vectors {
+                vectors_options: Some(vectors::VectorsOptions::Vector(
+                    qdrant_client::qdrant::Vector { data: vec![1.0] },
+                )),
+            }),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn malformed_candidates_are_skipped_unless_strict() {
+        let fields = [
+            ("lang", "rust"),
+            ("repo_name", "bloop"),
+            ("repo_ref", "local//bloop"),
+            ("relative_path", "src/lib.rs"),
+            ("snippet", "fn main() {}"),
+            ("start_line", "0"),
+            ("end_line", "1"),
+            ("start_byte", "0"),
+            ("end_byte", "12"),
+        ];
+        let without_snippet = fields
+            .iter()
+            .copied()
+            .filter(|(k, _)| *k != "snippet")
+            .collect::<Vec<_>>();
+        let mut non_numeric = fields.to_vec();
+        non_numeric[5].1 = "first";
+        let mut non_string_symbol = candidate(5, &fields);
+        non_string_symbol
+            .payload
+            .insert("symbol".into(), value(Kind::IntegerValue(7)));
+        let without_vectors = ScoredPoint {
+            vectors: None,
+            ..candidate(6, &fields)
+        };
+
+        let points = || {
+            vec![
+                candidate(1, &fields),
+                candidate(2, &without_snippet),
+                candidate(3, &non_numeric),
+                candidate(4, &fields),
+                non_string_symbol.clone(),
+                without_vectors.clone(),
+            ]
+        };
+        let schema = PayloadSchema::default();
+
+        let (snippets, skipped) = snippets_from_points(points(), &schema, false).unwrap();
+        assert_eq!(snippets.len(), 2);
+        assert_eq!(skipped, 4);
+        assert!(snippets.iter().all(|s| s.text == "fn main() {}"));
+
+        assert!(snippets_from_points(points(), &schema, true).is_err());
+    }
+
+    #[test]
+    fn snippets_of_the_same_symbol_collapse() {
+        let snippets = vec![
+            snippet("src/main.rs", Some("main"), 0.6),
+            snippet("src/lib.rs", None, 0.5),
+            snippet("src/main.rs", Some("main"), 0.9),
+            snippet("src/lib.rs", None, 0.4),
+            snippet("src/other.rs", Some("main"), 0.3),
+        ];
+
+        let collapsed = collapse_by_symbol(snippets)
+            .into_iter()
+            .map(|s| (s.relative_path, s.score))
+            .collect::<Vec<_>>();
+
+        assert_eq!(
+            collapsed,
+            vec![
+                ("src/main.rs".to_owned(), 0.9),
+                ("src/lib.rs".to_owned(), 0.5),
+                ("src/lib.rs".to_owned(), 0.4),
+                ("src/other.rs".to_owned(), 0.3),
+            ]
+        );
+    }
+
+    #[test]
+    fn query_keywords_drop_punctuation_and_short_words() {
+        assert_eq!(
+            query_keywords("where is `parse_nl_cached` defined? parse_nl_cached"),
+            vec!["where", "parse_nl_cached", "defined"]
+        );
+    }
+
+    #[test]
+    fn hybrid_results_explain_their_matched_terms() {
+        let keywords = query_keywords("where is parse_nl_cached defined?");
+        let semantic = vec![snippet("src/query/parser.rs", None, 0.8)];
+        let matches = vec![Snippet {
+            text: "// Parse_NL_cached is defined here, see parse_nl_cached".into(),
+            source: SnippetSource::Keyword,
+            ..snippet("src/query/cache.rs", None, 0.0)
+        }];
+
+        let explained = merge_keyword_matches(semantic, matches, &keywords, SNIPPET_COUNT)
+            .iter()
+            .map(|s| explain(s, &keywords))
+            .collect::<Vec<_>>();
+
+        assert!(matches!(explained[0], Explanation::Semantic { .. }));
+        // `where` is not in the text, and matching ignores case
+        assert_eq!(
+            explained[1],
+            Explanation::Keyword {
+                terms: vec![
+                    TermMatch {
+                        term: "parse_nl_cached".into(),
+                        positions: vec![3..18, 40..55],
+                    },
+                    TermMatch {
+                        term: "defined".into(),
+                        positions: vec![22..29],
+                    },
+                ]
+            }
+        );
+    }
+
+    #[test]
+    fn keyword_matches_fill_semantic_misses() {
+        let keywords = query_keywords("where is parse_nl_cached defined?");
+        let matches = || {
+            vec![
+                Snippet {
+                    text: "pub fn parse_nl_cached(query: &str)".into(),
+                    source: SnippetSource::Keyword,
+                    ..snippet("src/query/parser.rs", None, 0.0)
+                },
+                Snippet {
+                    text: "// parse_nl_cached is defined where the cache is".into(),
+                    source: SnippetSource::Keyword,
+                    ..snippet("src/query/cache.rs", None, 0.0)
+                },
+            ]
+        };
+
+        // semantic search found nothing
+        let filled = merge_keyword_matches(vec![], matches(), &keywords, SNIPPET_COUNT);
+        assert_eq!(
+            filled
+                .iter()
+                .map(|s| (s.relative_path.as_str(), s.source))
+                .collect::<Vec<_>>(),
+            vec![
+                ("src/query/cache.rs", SnippetSource::Keyword),
+                ("src/query/parser.rs", SnippetSource::Keyword),
+            ]
+        );
+        assert_eq!(filled[0].score, 1.0);
+
+        // chunks semantic search already found are not repeated
+        let semantic = vec![snippet("src/query/parser.rs", None, 0.8)];
+        let filled = merge_keyword_matches(semantic, matches(), &keywords, SNIPPET_COUNT);
+        assert_eq!(
+            filled
+                .iter()
+                .map(|s| (s.relative_path.as_str(), s.source))
+                .collect::<Vec<_>>(),
+            vec![
+                ("src/query/parser.rs", SnippetSource::Semantic),
+                ("src/query/cache.rs", SnippetSource::Keyword),
+            ]
+        );
+    }
+
+    fn deduplicate_snippets(
+        candidates: Vec<Snippet>,
+        query_embedding: Vec<f32>,
+        strategy: DedupStrategy,
+        definitions: &[String],
+        limit: usize,
+    ) -> Vec<Snippet> {
+        select_snippets(
+            collapse_duplicates(candidates, strategy),
+            query_embedding,
+            definitions,
+            limit,
+        )
+    }
+
+    #[test]
+    fn combined_widenings_fetch_their_widest_candidate_set() {
+        assert_eq!(candidate_count(10, []), 10);
+        assert_eq!(candidate_count(10, [Widening::Facets, Widening::Boost]), 40);
+
+        // facets alone fetch 4 candidates per chunk, repository groups of 3 fetch 6
+        let grouped = Widening::Repos { max_per_repo: 3 };
+        assert_eq!(candidate_count(10, [Widening::Facets, grouped]), 60);
+        assert_eq!(candidate_count(10, [grouped, Widening::Boost]), 60);
+        let single = Widening::Repos { max_per_repo: 1 };
+        assert_eq!(candidate_count(10, [Widening::Facets, single]), 40);
+        assert_eq!(candidate_count(u64::MAX, [Widening::Dedup]), u64::MAX);
+
+        assert_eq!(LimitKind::Results.candidates(10), 40);
+        assert_eq!(LimitKind::Candidates.candidates(10), 10);
+    }
+
+    #[test]
+    fn dedup_never_compares_more_than_the_cap() {
+        let candidates = (0..1000)
+            .map(|i| {
+                // scores are shuffled, so the best candidates are spread over the list
+                let score = ((i * 7919) % 1000) as f32 / 1000.0;
+                Snippet {
+                    start_byte: i,
+                    embedding: vec![(i as f32).cos(), (i as f32).sin()],
+                    ..snippet(&format!("src/{i}.rs"), None, score)
+                }
+            })
+            .collect::<Vec<_>>();
+
+        // the default configuration, as answers get it
+        let config = serde_json::from_value::<Configuration>(serde_json::json!({})).unwrap();
+        let max = config.dedup_max_candidates;
+        let capped = answer_candidates(candidates.clone(), DedupStrategy::Mmr, &config);
+        assert_eq!(capped.len(), max);
+        let min_score = (1000 - max) as f32 / 1000.0;
+        assert!(capped.iter().all(|s| s.score >= min_score));
+
+        // the order of the search is kept
+        let positions = capped.iter().map(|s| s.start_byte).collect::<Vec<_>>();
+        assert!(positions.windows(2).all(|w| w[0] < w[1]));
+
+        let selected = select_snippets(capped, vec![1.0, 0.0], &[], 10);
+        assert_eq!(selected.len(), 10);
+        assert!(selected.iter().all(|s| s.score >= min_score));
+
+        let config = serde_json::from_value::<Configuration>(serde_json::json!({
+            "dedup_max_candidates": 64,
+        }))
+        .unwrap();
+        let capped = answer_candidates(candidates.clone(), DedupStrategy::Mmr, &config);
+        assert_eq!(capped.len(), 64);
+        assert!(capped.iter().all(|s| s.score >= 0.936));
+
+        let few = answer_candidates(candidates[..10].to_vec(), DedupStrategy::Mmr, &config);
+        assert_eq!(few.len(), 10);
+    }
+
+    /// Ranked candidates for a query matching 10 symbols, with 4 near-duplicate chunks each.
+    fn near_duplicates() -> Vec<Snippet> {
+        (0..40)
+            .map(|i| {
+                let symbol = format!("symbol_{}", i / 4);
+                Snippet {
+                    start_byte: i * 100,
+                    embedding: vec![1.0, i as f32 * 0.01],
+                    ..snippet("src/lib.rs", Some(&symbol), 1.0 - i as f32 * 0.01)
+                }
+            })
+            .collect()
+    }
+
+    fn select(limit_kind: LimitKind, limit: usize) -> Vec<Snippet> {
+        let candidates = near_duplicates()
+            .into_iter()
+            .take(limit_kind.candidates(limit))
+            .collect();
+
+        deduplicate_snippets(
+            candidates,
+            vec![1.0, 0.0],
+            DedupStrategy::Symbol,
+            &[],
+            limit,
+        )
+    }
+
+    #[test]
+    fn results_limit_fills_up_after_dedup() {
+        let selected = select(LimitKind::Results, 5);
+        assert_eq!(selected.len(), 5);
+
+        let mut symbols = selected
+            .iter()
+            .map(|s| s.symbol.clone().unwrap())
+            .collect::<Vec<_>>();
+        symbols.sort();
+        assert_eq!(
+            symbols,
+            vec!["symbol_0", "symbol_1", "symbol_2", "symbol_3", "symbol_4"]
+        );
+    }
+
+    #[test]
+    fn candidates_limit_bounds_the_fetch() {
+        assert_eq!(LimitKind::Candidates.candidates(5), 5);
+        assert_eq!(LimitKind::Results.candidates(5), 20);
+
+        // the 5 candidates are 4 chunks of `symbol_0`, and one of `symbol_1`
+        let selected = select(LimitKind::Candidates, 5);
+        assert_eq!(selected.len(), 2);
+    }
+
+    #[test]
+    fn definitions_of_queried_symbols_are_preferred() {
+        let keywords = query_keywords("how does parse_query work?");
+        let candidates = || {
+            vec![
+                Snippet {
+                    text: "let q = parse_query(input)?;".into(),
+                    embedding: vec![1.0, 0.0],
+                    ..snippet("src/webserver/answer.rs", None, 0.9)
+                },
+                Snippet {
+                    text: "fn parse_query(query: &str) -> Result<String, Error> {".into(),
+                    kind: ChunkKind::Definition,
+                    definitions: vec!["parse_query".into()],
+                    embedding: vec![1.0, 0.3],
+                    ..snippet("src/query/parser.rs", None, 0.8)
+                },
+                Snippet {
+                    embedding: vec![0.0, 1.0],
+                    ..snippet("src/lib.rs", None, 0.1)
+                },
+            ]
+        };
+
+        let path = |selected: Vec<Snippet>| selected[0].relative_path.clone();
+        let query = vec![1.0, 0.0];
+        assert_eq!(
+            path(deduplicate_snippets(
+                candidates(),
+                query.clone(),
+                DedupStrategy::Mmr,
+                &[],
+                1
+            )),
+            "src/webserver/answer.rs"
+        );
+        assert_eq!(
+            path(deduplicate_snippets(
+                candidates(),
+                query.clone(),
+                DedupStrategy::Mmr,
+                &keywords,
+                1
+            )),
+            "src/query/parser.rs"
+        );
+
+        // with fewer candidates than the limit, definitions are moved to the front
+        assert_eq!(
+            path(deduplicate_snippets(
+                candidates(),
+                query,
+                DedupStrategy::Mmr,
+                &keywords,
+                5
+            )),
+            "src/query/parser.rs"
+        );
+    }
+
+    #[test]
+    fn few_candidates_skip_mmr() {
+        // embeddings of mismatched dimensions, which MMR can't compare
+        let candidates = vec![
+            Snippet {
+                embedding: vec![1.0],
+                ..snippet("src/a.rs", Some("a"), 0.9)
+            },
+            // the same chunk again
+            Snippet {
+                embedding: vec![],
+                ..snippet("src/a.rs", Some("a"), 0.8)
+            },
+            Snippet {
+                start_byte: 100,
+                embedding: vec![1.0, 0.0, 0.0],
+                ..snippet("src/a.rs", Some("a"), 0.75)
+            },
+            Snippet {
+                text: "fn c() {}".into(),
+                embedding: vec![1.0, 0.0],
+                ..snippet("src/c.rs", None, 0.6)
+            },
+            // a copy of the same code in another file
+            Snippet {
+                text: "fn c() {}".into(),
+                embedding: vec![0.0],
+                ..snippet("src/vendor/c.rs", None, 0.7)
+            },
+        ];
+        assert!(!needs_mmr(&candidates, 5));
+        assert!(needs_mmr(&candidates, 4));
+
+        let paths = |selected: Vec<Snippet>| {
+            selected
+                .into_iter()
+                .map(|s| (s.relative_path, s.score))
+                .collect::<Vec<_>>()
+        };
+
+        // nothing is embedded for the query, and only exact duplicates are collapsed, into the
+        // best scoring one in place of the first
+        assert_eq!(
+            paths(deduplicate_snippets(
+                candidates.clone(),
+                vec![],
+                DedupStrategy::Mmr,
+                &[],
+                5
+            )),
+            [
+                ("src/a.rs".to_owned(), 0.9),
+                ("src/a.rs".to_owned(), 0.75),
+                ("src/vendor/c.rs".to_owned(), 0.7),
+            ]
+        );
+
+        // chunks of the same symbol are still collapsed
+        assert_eq!(
+            paths(deduplicate_snippets(
+                candidates,
+                vec![],
+                DedupStrategy::Symbol,
+                &[],
+                10
+            )),
+            [
+                ("src/a.rs".to_owned(), 0.9),
+                ("src/vendor/c.rs".to_owned(), 0.7),
+            ]
+        );
+    }
+}
@@ -0,0 +1,541 @@
+//! Checking the unified diffs of `mode=patch` answers, before they are handed to the client.
+//!
+//! Nothing is written to disk: each hunk is checked against the indexed content of its file,
+//! the way `git apply --check` would, and applying the patch is left to the client.
+
+use std::collections::{BTreeSet, HashMap};
+
+use serde::Serialize;
+
+/// The changes a patch makes to one file.
+#[derive(Debug, PartialEq, Eq)]
+struct FilePatch {
+    path: String,
+    hunks: Vec<Hunk>,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+struct Hunk {
+    /// 1-based, as in the hunk header
+    old_start: usize,
+    old_lines: usize,
+    new_start: usize,
+    new_lines: usize,
+    /// The lines of the file the hunk replaces, context included
+    old: Vec<String>,
+}
+
+/// A file quoted to the model, which its patch may change.
+#[derive(Serialize, Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct CitedFile {
+    pub repo_ref: String,
+    pub relative_path: String,
+}
+
+impl CitedFile {
+    /// The path the model is shown this file at, and its diffs have to name it by.
+    ///
+    /// Paths are prefixed with the repository when files of several repositories are `quoted`,
+    /// so that every path names a single file.
+    pub fn diff_path(&self, quoted: &[CitedFile]) -> String {
+        if quoted.iter().any(|other| other.repo_ref != self.repo_ref) {
+            format!("{}/{}", self.repo_ref, self.relative_path)
+        } else {
+            self.relative_path.clone()
+        }
+    }
+}
+
+/// A patch whose every hunk applies to the indexed files.
+#[derive(Serialize, Debug)]
+pub struct PatchSuggestion {
+    /// The unified diff, as written by the model
+    pub diff: String,
+    /// The files the diff changes
+    pub files: Vec<CitedFile>,
+    pub hunks: Vec<HunkReport>,
+}
+
+/// Why a patch was refused.
+#[derive(Serialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum Rejection {
+    /// The model's output is not a unified diff
+    Malformed,
+    /// The diff changes files other than those of the cited snippets
+    UnknownFiles,
+    /// Some hunks don't match the indexed content of their file
+    DoesNotApply,
+}
+
+/// The body of the `422` response to a patch that was refused.
+#[derive(Serialize, Debug)]
+pub struct PatchRejection {
+    pub reason: Rejection,
+    pub message: String,
+    pub diff: String,
+    /// Paths of the files the diff changes, as the diff names them, that are not among those of
+    /// the cited snippets
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub unknown_files: Vec<String>,
+    /// Every hunk of the diff, for [`Rejection::DoesNotApply`]
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub hunks: Vec<HunkReport>,
+}
+
+#[derive(Serialize, Clone, Debug, PartialEq, Eq)]
+pub struct HunkReport {
+    pub repo_ref: String,
+    pub path: String,
+    pub old_start: usize,
+    pub old_lines: usize,
+    pub new_start: usize,
+    pub new_lines: usize,
+    #[serde(flatten)]
+    pub status: HunkStatus,
+}
+
+#[derive(Serialize, Clone, Debug, PartialEq, Eq)]
+#[serde(tag = "status", rename_all = "snake_case")]
+pub enum HunkStatus {
+    /// The hunk applies, `offset` lines away from where its header puts it
+    Applies {
+        offset: isize,
+    },
+    Conflicts {
+        reason: String,
+    },
+}
+
+/// Check `diff` against `files`, the files it may change and their indexed content, by the
+/// [path diffs name them by](CitedFile::diff_path).
+pub fn review(
+    diff: String,
+    files: &HashMap<String, (CitedFile, String)>,
+) -> Result<PatchSuggestion, PatchRejection> {
+    let reject = |reason, message: String, diff| PatchRejection {
+        reason,
+        message,
+        diff,
+        unknown_files: vec![],
+        hunks: vec![],
+    };
+
+    let patches = match parse(&diff) {
+        Ok(patches) if patches.is_empty() => {
+            return Err(reject(
+                Rejection::Malformed,
+                "the answer changes no files".to_owned(),
+                diff,
+            ))
+        }
+        Ok(patches) => patches,
+        Err(message) => return Err(reject(Rejection::Malformed, message, diff)),
+    };
+
+    let unknown_files = patches
+        .iter()
+        .map(|patch| &patch.path)
+        .filter(|path| !files.contains_key(*path))
+        .cloned()
+        .collect::<BTreeSet<_>>();
+    if !unknown_files.is_empty() {
+        let unknown_files = unknown_files.into_iter().collect::<Vec<_>>();
+        return Err(PatchRejection {
+            message: format!(
+                "the patch changes files outside of the cited snippets: {}",
+                unknown_files.join(", ")
+            ),
+            unknown_files,
+            ..reject(Rejection::UnknownFiles, String::new(), diff)
+        });
+    }
+
+    let hunks = patches
+        .iter()
+        .flat_map(|patch| {
+            let (file, content) = &files[&patch.path];
+            check(patch, file, content)
+        })
+        .collect::<Vec<_>>();
+    let conflicts = hunks
+        .iter()
+        .filter(|hunk| matches!(hunk.status, HunkStatus::Conflicts { .. }))
+        .count();
+    if conflicts > 0 {
+        return Err(PatchRejection {
+            hunks,
+            ..reject(
+                Rejection::DoesNotApply,
+                format!("{conflicts} hunks of the patch do not apply to the indexed files"),
+                diff,
+            )
+        });
+    }
+
+    let files = patches
+        .iter()
+        .map(|patch| files[&patch.path].0.clone())
+        .collect::<BTreeSet<_>>();
+    Ok(PatchSuggestion {
+        diff,
+        files: files.into_iter().collect(),
+        hunks,
+    })
+}
+
+/// Parse a unified diff, with or without git's extended headers.
+///
+/// Hunks end after the number of lines given in their header, so removed lines starting with
+/// `--` are not mistaken for file headers. Markdown code fences around the diff are skipped.
+fn parse(diff: &str) -> Result<Vec<FilePatch>, String> {
+    let mut patches = Vec::<FilePatch>::new();
+    let mut old_path = None;
+    // lines left in the current hunk, on the old and new side
+    let mut remaining = (0, 0);
+
+    for (n, line) in diff.lines().enumerate() {
+        let n = n + 1;
+
+        if remaining != (0, 0) {
+            let hunk = patches
+                .last_mut()
+                .and_then(|patch| patch.hunks.last_mut())
+                .expect("hunks are only open within a file");
+
+            // models often drop the space before blank context lines
+            let (tag, text) = match line.chars().next() {
+                Some(tag) => (tag, &line[tag.len_utf8()..]),
+                None => (' ', ""),
+            };
+            match tag {
+                ' ' if remaining.0 > 0 && remaining.1 > 0 => {
+                    hunk.old.push(text.to_owned());
+                    remaining = (remaining.0 - 1, remaining.1 - 1);
+                }
+                '-' if remaining.0 > 0 => {
+                    hunk.old.push(text.to_owned());
+                    remaining.0 -= 1;
+                }
+                '+' if remaining.1 > 0 => remaining.1 -= 1,
+                '\\' => {}
+                _ => {
+                    return Err(format!(
+                        "line {n}: `{line}` does not fit the line counts of the hunk header"
+                    ))
+                }
+            }
+            continue;
+        }
+
+        if let Some(path) = line.strip_prefix("--- ") {
+            old_path = Some(header_path(path));
+        } else if let Some(path) = line.strip_prefix("+++ ") {
+            let Some(old_path) = old_path.take() else {
+                return Err(format!("line {n}: `+++` without a preceding `---`"));
+            };
+            let path = match (old_path, header_path(path)) {
+                (Some(old), Some(new)) if old == new => new,
+                (Some(old), Some(new)) => {
+                    return Err(format!("line {n}: renames `{old}` to `{new}`"));
+                }
+                (old, new) => {
+                    let path = new.or(old).unwrap_or_default();
+                    return Err(format!("line {n}: creates or deletes `{path}`"));
+                }
+            };
+            patches.push(FilePatch {
+                path,
+                hunks: vec![],
+            });
+        } else if line.starts_with("@@") {
+            let Some(patch) = patches.last_mut() else {
+                return Err(format!("line {n}: hunk outside of a file"));
+            };
+            let Some(hunk) = hunk_header(line) else {
+                return Err(format!("line {n}: malformed hunk header `{line}`"));
+            };
+
+            remaining = (hunk.old_lines, hunk.new_lines);
+            patch.hunks.push(hunk);
+        } else if !is_preamble(line) {
+            return Err(format!("line {n}: unexpected `{line}` outside of a hunk"));
+        }
+    }
+
+    if remaining != (0, 0) {
+        return Err("the last hunk is shorter than its header says".to_owned());
+    }
+
+    Ok(patches)
+}
+
+/// Lines that may come before or between the files of a diff.
+fn is_preamble(line: &str) -> bool {
+    const PREFIXES: &[&str] = &[
+        "```",
+        "diff ",
+        "index ",
+        "old mode ",
+        "new mode ",
+        "similarity index ",
+    ];
+
+    line.trim().is_empty() || PREFIXES.iter().any(|prefix| line.starts_with(prefix))
+}
+
+/// The path of a `---` or `+++` header, `None` for `/dev/null`.
+fn header_path(header: &str) -> Option<String> {
+    // a timestamp may follow the path
+    let path = header.split('\t').next().unwrap_or_default().trim();
+    if path == "/dev/null" {
+        return None;
+    }
+
+    let path = path
+        .strip_prefix("a/")
+        .or_else(|| path.strip_prefix("b/"))
+        .unwrap_or(path);
+    Some(path.to_owned())
+}
+
+/// Parse `@@ -old_start[,old_lines] +new_start[,new_lines] @@`, which may be followed by the
+/// enclosing scope.
+fn hunk_header(line: &str) -> Option<Hunk> {
+    let ranges = line.strip_prefix("@@ ")?.split(" @@").next()?;
+    let (old, new) = ranges.split_once(' ')?;
+
+    let range = |range: &str| -> Option<(usize, usize)> {
+        match range.split_once(',') {
+            Some((start, lines)) => Some((start.parse().ok()?, lines.parse().ok()?)),
+            None => Some((range.parse().ok()?, 1)),
+        }
+    };
+    let (old_start, old_lines) = range(old.strip_prefix('-')?)?;
+    let (new_start, new_lines) = range(new.strip_prefix('+')?)?;
+
+    Some(Hunk {
+        old_start,
+        old_lines,
+        new_start,
+        new_lines,
+        old: vec![],
+    })
+}
+
+/// Check every hunk of `patch` against `content`, the indexed content of `file`, allowing hunks
+/// to be offset from their header like `git apply` does, as long as they keep their order and
+/// don't overlap.
+fn check(patch: &FilePatch, file: &CitedFile, content: &str) -> Vec<HunkReport> {
+    let lines = content.lines().collect::<Vec<_>>();
+    // the first line not changed by a previous hunk
+    let mut next = 0;
+
+    patch
+        .hunks
+        .iter()
+        .map(|hunk| {
+            // a header without lines on the old side inserts after `old_start`
+            let expected = if hunk.old_lines == 0 {
+                hunk.old_start
+            } else {
+                hunk.old_start.saturating_sub(1)
+            };
+
+            let status = match locate(&lines, &hunk.old, expected, next) {
+                Some(at) => {
+                    next = at + hunk.old.len();
+                    HunkStatus::Applies {
+                        offset: at as isize - expected as isize,
+                    }
+                }
+                None => HunkStatus::Conflicts {
+                    reason: "the hunk's context and removed lines are not in the indexed file, \
+                             after the previous hunk"
+                        .to_owned(),
+                },
+            };
+
+            HunkReport {
+                repo_ref: file.repo_ref.clone(),
+                path: file.relative_path.clone(),
+                old_start: hunk.old_start,
+                old_lines: hunk.old_lines,
+                new_start: hunk.new_start,
+                new_lines: hunk.new_lines,
+                status,
+            }
+        })
+        .collect()
+}
+
+/// Where `old` is in `lines`, at or after `from`, preferring the position closest to
+/// `expected`.
+fn locate(lines: &[&str], old: &[String], expected: usize, from: usize) -> Option<usize> {
+    let matches_at = |at: usize| {
+        lines.len() >= at + old.len()
+            && lines[at..at + old.len()]
+                .iter()
+                .zip(old)
+                .all(|(line, old)| line.trim_end() == old.trim_end())
+    };
+
+    (from..=lines.len())
+        .filter(|&at| matches_at(at))
+        .min_by_key(|&at| at.abs_diff(expected))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const FILE: &str = "\
+fn main() {
+    let client = Client::new();
+    let response = client.get(URL).send();
+    println!(\"{response:?}\");
+}
+";
+
+    fn cited(repo_ref: &str, relative_path: &str) -> CitedFile {
+        CitedFile {
+            repo_ref: repo_ref.to_owned(),
+            relative_path: relative_path.to_owned(),
+        }
+    }
+
+    fn files() -> HashMap<String, (CitedFile, String)> {
+        HashMap::from([(
+            "src/main.rs".to_owned(),
+            (cited("local//client", "src/main.rs"), FILE.to_owned()),
+        )])
+    }
+
+    #[test]
+    fn applicable_patches_are_reported_per_hunk() {
+        let diff = "\
+```diff
+--- a/src/main.rs
++++ b/src/main.rs
+@@ -1,3 +1,5 @@
+ fn main() {
+-    let client = Client::new();
++    let client = Client::builder()
++        .timeout(Duration::from_secs(10))
++        .build();
+     let response = client.get(URL).send();
+```
+";
+
+        let suggestion = review(diff.to_owned(), &files()).unwrap();
+        assert_eq!(suggestion.files, [cited("local//client", "src/main.rs")]);
+        assert_eq!(
+            suggestion.hunks,
+            [HunkReport {
+                repo_ref: "local//client".to_owned(),
+                path: "src/main.rs".to_owned(),
+                old_start: 1,
+                old_lines: 3,
+                new_start: 1,
+                new_lines: 5,
+                status: HunkStatus::Applies { offset: 0 },
+            }]
+        );
+    }
+
+    #[test]
+    fn hunks_may_be_offset_but_not_out_of_order() {
+        // line numbers are often off in generated diffs
+        let diff = "\
+--- a/src/main.rs
++++ b/src/main.rs
+@@ -3,2 +3,2 @@
+-    println!(\"{response:?}\");
++    dbg!(response);
+ }
+@@ -1,2 +1,2 @@
+-fn main() {
++pub fn main() {
+     let client = Client::new();
+";
+
+        let Err(rejection) = review(diff.to_owned(), &files()) else {
+            panic!("the patch applied");
+        };
+        assert_eq!(rejection.reason, Rejection::DoesNotApply);
+        assert_eq!(rejection.hunks[0].status, HunkStatus::Applies { offset: 1 });
+        assert!(matches!(
+            rejection.hunks[1].status,
+            HunkStatus::Conflicts { .. }
+        ));
+    }
+
+    #[test]
+    fn patches_to_other_files_are_refused() {
+        let diff = "\
+--- a/src/lib.rs
++++ b/src/lib.rs
+@@ -1 +1 @@
+-mod client;
++pub mod client;
+";
+
+        let Err(rejection) = review(diff.to_owned(), &files()) else {
+            panic!("the patch applied");
+        };
+        assert_eq!(rejection.reason, Rejection::UnknownFiles);
+        assert_eq!(rejection.unknown_files, ["src/lib.rs"]);
+        assert!(rejection.hunks.is_empty());
+    }
+
+    #[test]
+    fn files_of_several_repositories_are_named_with_their_repository() {
+        let quoted = [
+            cited("local//client", "src/main.rs"),
+            cited("local//server", "src/main.rs"),
+        ];
+        assert_eq!(quoted[1].diff_path(&quoted), "local//server/src/main.rs");
+        assert_eq!(quoted[0].diff_path(&quoted[..1]), "src/main.rs");
+
+        let files = quoted
+            .iter()
+            .map(|file| (file.diff_path(&quoted), (file.clone(), FILE.to_owned())))
+            .collect();
+        let diff = "\
+--- a/local//server/src/main.rs
++++ b/local//server/src/main.rs
+@@ -1 +1 @@
+-fn main() {
++pub fn main() {
+";
+
+        let suggestion = review(diff.to_owned(), &files).unwrap();
+        assert_eq!(suggestion.files, [cited("local//server", "src/main.rs")]);
+        assert_eq!(suggestion.hunks[0].repo_ref, "local//server");
+
+        // the bare path could be either file
+        let Err(rejection) = review(diff.replace("local//server/", ""), &files) else {
+            panic!("the patch applied");
+        };
+        assert_eq!(rejection.reason, Rejection::UnknownFiles);
+        assert_eq!(rejection.unknown_files, ["src/main.rs"]);
+    }
+
+    #[test]
+    fn malformed_diffs_are_refused() {
+        let malformed = [
+            "Add a timeout to the client builder.",
+            // the hunk claims more lines than it has
+            "--- a/src/main.rs\n+++ b/src/main.rs\n@@ -1,3 +1,3 @@\n fn main() {\n",
+            "--- /dev/null\n+++ b/src/timeout.rs\n@@ -0,0 +1 @@\n+const TIMEOUT: u64 = 10;\n",
+            "",
+        ];
+
+        for diff in malformed {
+            let Err(rejection) = review(diff.to_owned(), &files()) else {
+                panic!("{diff:?} applied");
+            };
+            assert_eq!(rejection.reason, Rejection::Malformed, "{diff:?}");
+        }
+    }
+}
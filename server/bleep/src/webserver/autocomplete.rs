@@ -1,6 +1,6 @@
 use std::sync::Arc;
 
-use super::prelude::*;
+use super::{prelude::*, workspaces};
 use crate::{
     indexes::{
         reader::{ContentReader, FileReader, RepoReader},
@@ -11,6 +11,7 @@ use crate::{
         parser::{Literal, Target},
     },
     webserver::query::{ApiQuery, ExecuteQuery, QueryResult},
+    Application,
 };
 
 use axum::{extract::Query, response::IntoResponse as IntoAxumResponse, Extension};
@@ -25,12 +26,14 @@ use utoipa::ToSchema;
     responses(
         (status = 200, description = "Execute query successfully", body = Response),
         (status = 400, description = "Bad request", body = EndpointError),
+        (status = 404, description = "Workspace not found", body = EndpointError),
         (status = 500, description = "Server error", body = EndpointError),
     ),
 )]
 pub(super) async fn handle(
     Query(mut api_params): Query<ApiQuery>,
     Extension(indexes): Extension<Arc<Indexes>>,
+    Extension(app): Extension<Application>,
 ) -> Result<impl IntoAxumResponse> {
     // Override page_size and set to low value
     api_params.page = 0;
@@ -69,9 +72,15 @@ pub(super) async fn handle(
 
     // If no flags completion, run a search with full query
     if autocomplete_results.is_empty() {
-        let contents = ContentReader.execute(&indexes.file, &queries, &api_params);
-        let repos = RepoReader.execute(&indexes.repo, &queries, &api_params);
-        let files = FileReader.execute(&indexes.file, &queries, &api_params);
+        let scope = match api_params.workspace.as_deref() {
+            Some(workspace) => Some(workspaces::resolve_disk_paths(&app, workspace).await?),
+            None => None,
+        };
+        let scope = scope.as_deref();
+
+        let contents = ContentReader.execute(&indexes.file, &queries, &api_params, scope);
+        let repos = RepoReader.execute(&indexes.repo, &queries, &api_params, scope);
+        let files = FileReader.execute(&indexes.file, &queries, &api_params, scope);
 
         autocomplete_results = stream::iter([contents, repos, files])
             // Buffer several readers at the same time. The exact number is not important; this is
@@ -0,0 +1,255 @@
+use std::{
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::Duration,
+};
+
+use git2::{BlameOptions, Oid, Repository};
+use tokio::sync::Semaphore;
+use tracing::debug;
+
+use super::prelude::*;
+use crate::cache::BoundedCache;
+
+/// Blames running at the same time, across all requests
+const MAX_CONCURRENT_BLAMES: usize = 4;
+
+/// How long the blame of a snippet may take, including the wait for a free slot
+const BLAME_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Blames kept, by file, commit and line range
+const MEMOIZED_BLAMES: usize = 1024;
+
+/// The most recent commit touching a line range.
+#[derive(Serialize, Clone, Debug, PartialEq, Eq)]
+pub(super) struct Blame {
+    pub(super) last_author: String,
+    pub(super) last_commit: String,
+    /// Unix timestamp of the commit, in seconds
+    pub(super) last_modified: i64,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+struct BlameKey {
+    repo: PathBuf,
+    commit: Oid,
+    relative_path: String,
+    start_line: usize,
+    end_line: usize,
+}
+
+/// Runs line-range blames with bounded concurrency, memoizing their results.
+pub(super) struct Blamer {
+    memo: BoundedCache<BlameKey, Option<Blame>>,
+    permits: Arc<Semaphore>,
+}
+
+impl Blamer {
+    pub(super) fn new() -> Self {
+        Self {
+            memo: BoundedCache::new(MEMOIZED_BLAMES),
+            permits: Arc::new(Semaphore::new(MAX_CONCURRENT_BLAMES)),
+        }
+    }
+
+    /// The commit checked out at `disk_path`, which is the one its index was last built from,
+    /// or `None` if it isn't a git repository.
+    pub(super) async fn head(disk_path: PathBuf) -> Option<Oid> {
+        tokio::task::spawn_blocking(move || {
+            let git = Repository::open(disk_path).ok()?;
+            let head = git.head().ok()?.peel_to_commit().ok()?;
+            Some(head.id())
+        })
+        .await
+        .ok()
+        .flatten()
+    }
+
+    /// Blame the 0-based lines `start_line..=end_line` of `relative_path` at `commit`.
+    ///
+    /// Blames that time out are not memoized, so they are tried again on the next request.
+    pub(super) async fn blame(
+        &self,
+        repo: &Path,
+        commit: Oid,
+        relative_path: &str,
+        start_line: usize,
+        end_line: usize,
+    ) -> Option<Blame> {
+        let key = BlameKey {
+            repo: repo.to_owned(),
+            commit,
+            relative_path: relative_path.to_owned(),
+            start_line,
+            end_line,
+        };
+
+        if let Some(blame) = self.memo.get(&key) {
+            return blame;
+        }
+
+        let permits = Arc::clone(&self.permits);
+        let job = {
+            let key = key.clone();
+            async move {
+                let permit = permits.acquire_owned().await.ok()?;
+                tokio::task::spawn_blocking(move || {
+                    let _permit = permit;
+                    blame_lines(&key)
+                })
+                .await
+                .ok()
+            }
+        };
+
+        match tokio::time::timeout(BLAME_TIMEOUT, job).await {
+            Ok(Some(blamed)) => {
+                let blame = blamed
+                    .map_err(|err| debug!(?err, ?key, "failed to blame snippet"))
+                    .ok()
+                    .flatten();
+                self.memo.insert(key, blame.clone());
+                blame
+            }
+            Ok(None) => None,
+            Err(_) => {
+                debug!(?key, "blame timed out");
+                None
+            }
+        }
+    }
+}
+
+/// `None` if the range is not within the file at that commit.
+fn blame_lines(key: &BlameKey) -> Result<Option<Blame>, git2::Error> {
+    let git = Repository::open(&key.repo)?;
+    let commit = git.find_commit(key.commit)?;
+    let path = Path::new(&key.relative_path);
+
+    let blob = commit
+        .tree()?
+        .get_path(path)?
+        .to_object(&git)?
+        .peel_to_blob()?;
+    let content = blob.content();
+    let lines =
+        content.iter().filter(|&&b| b == b'\n').count() + usize::from(!content.ends_with(b"\n"));
+    if key.start_line > key.end_line || key.end_line >= lines {
+        return Ok(None);
+    }
+
+    let mut options = BlameOptions::new();
+    options
+        .newest_commit(key.commit)
+        .min_line(key.start_line + 1)
+        .max_line(key.end_line + 1);
+    let blame = git.blame_file(path, Some(&mut options))?;
+
+    let Some(latest) = blame
+        .iter()
+        .filter_map(|hunk| git.find_commit(hunk.final_commit_id()).ok())
+        .max_by_key(|commit| commit.time().seconds())
+    else {
+        return Ok(None);
+    };
+
+    let author = latest.author();
+    Ok(Some(Blame {
+        last_author: author.name().unwrap_or_default().to_owned(),
+        last_commit: latest.id().to_string(),
+        last_modified: latest.time().seconds(),
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use git2::{Signature, Time};
+
+    fn commit(git: &Repository, author: &str, time: i64, contents: &str) -> Oid {
+        let workdir = git.workdir().unwrap();
+        std::fs::write(workdir.join("main.rs"), contents).unwrap();
+
+        let mut index = git.index().unwrap();
+        index.add_path(Path::new("main.rs")).unwrap();
+        let tree = git.find_tree(index.write_tree().unwrap()).unwrap();
+
+        let signature = Signature::new(author, "dev@bloop.ai", &Time::new(time, 0)).unwrap();
+        let parent = git.head().ok().map(|head| head.peel_to_commit().unwrap());
+        git.commit(
+            Some("HEAD"),
+            &signature,
+            &signature,
+            "change",
+            &tree,
+            parent.as_ref().into_iter().collect::<Vec<_>>().as_slice(),
+        )
+        .unwrap()
+    }
+
+    #[tokio::test]
+    async fn snippets_are_attributed_to_their_latest_commit() {
+        let dir = tempdir::TempDir::new("blame").unwrap();
+        let git = Repository::init(dir.path()).unwrap();
+
+        let first = commit(
+            &git,
+            "alice",
+            1_600_000_000,
+            "fn a() {}\nfn b() {}\nfn c() {}\n",
+        );
+        let second = commit(
+            &git,
+            "bob",
+            1_700_000_000,
+            "fn a() {}\nfn b() {}\nfn d() {}\n",
+        );
+        assert_eq!(Blamer::head(dir.path().to_owned()).await, Some(second));
+
+        let blamer = Blamer::new();
+        let blame = blamer.blame(dir.path(), second, "main.rs", 0, 1).await;
+        assert_eq!(
+            blame,
+            Some(Blame {
+                last_author: "alice".into(),
+                last_commit: first.to_string(),
+                last_modified: 1_600_000_000,
+            })
+        );
+
+        let blame = blamer.blame(dir.path(), second, "main.rs", 1, 2).await;
+        assert_eq!(blame.unwrap().last_author, "bob");
+
+        // results are memoized by file, commit and range
+        assert_eq!(
+            blamer
+                .memo
+                .get(&BlameKey {
+                    repo: dir.path().to_owned(),
+                    commit: second,
+                    relative_path: "main.rs".into(),
+                    start_line: 1,
+                    end_line: 2,
+                })
+                .flatten()
+                .unwrap()
+                .last_author,
+            "bob"
+        );
+
+        assert_eq!(
+            blamer.blame(dir.path(), second, "main.rs", 2, 10).await,
+            None
+        );
+        assert_eq!(
+            blamer.blame(dir.path(), second, "missing.rs", 0, 0).await,
+            None
+        );
+    }
+
+    #[tokio::test]
+    async fn plain_directories_have_no_head() {
+        let dir = tempdir::TempDir::new("blame").unwrap();
+        assert_eq!(Blamer::head(dir.path().to_owned()).await, None);
+    }
+}
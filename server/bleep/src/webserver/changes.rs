@@ -0,0 +1,553 @@
+use std::{collections::HashMap, path::Path};
+
+use git2::{Delta, DiffOptions, Oid, Patch, Repository};
+use tracing::debug;
+
+use super::{
+    answer::{
+        candidate_count, cap_candidates, default_limit, readable_snippets, snippet_from_payload,
+        Snippet, SnippetSource, Widening,
+    },
+    prelude::*,
+    semantic::check_query_length,
+    snippets::{self, AnchorStatus, Hunk, Range},
+};
+use crate::{
+    query::parser,
+    repo::{relative_path_variants, RepoRef},
+    semantic::{
+        filter::{FilterArgs, FilterLogic},
+        payload::PayloadFields,
+        weights::VectorWeights,
+        Semantic,
+    },
+    Application,
+};
+
+/// Files a diff may change before the search is refused
+const MAX_CHANGED_FILES: usize = 500;
+
+/// Lines between a snippet and a change for the snippet to count as adjacent to it
+const ADJACENT_LINES: usize = 3;
+
+#[derive(Deserialize)]
+pub(super) struct ChangesArgs {
+    repo_ref: RepoRef,
+    /// The commit the changes are made on top of
+    from: String,
+    /// The commit with the changes
+    to: String,
+    query: String,
+    #[serde(default = "default_limit")]
+    limit: u64,
+}
+
+/// How a snippet relates to the changes between the two commits.
+#[derive(Serialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub(super) enum Change {
+    /// Every line of the snippet was added
+    Added,
+    /// Some lines of the snippet were added, removed or rewritten
+    Modified,
+    /// The snippet is unchanged, but within a few lines of a change
+    Adjacent,
+    /// The snippet's file changed, but not which of its lines did
+    ChangedFile,
+}
+
+#[derive(Serialize, Debug)]
+pub(super) struct ChangedSnippet {
+    #[serde(flatten)]
+    snippet: Snippet,
+    change: Change,
+}
+
+#[derive(Serialize, Debug)]
+pub(super) struct ChangesResponse {
+    /// The resolved `from` commit
+    from: String,
+    /// The resolved `to` commit
+    to: String,
+    /// The commit the repository was indexed at, which snippet lines refer to
+    indexed_commit: Option<String>,
+    /// Files changed between the two commits, not counting deleted files
+    changed_files: usize,
+    /// Whether every snippet was matched against the changed lines, rather than only its file
+    line_level: bool,
+    snippets: Vec<ChangedSnippet>,
+    /// Candidates left out because their payload couldn't be read as a snippet
+    skipped: usize,
+}
+
+impl super::ApiResponse for ChangesResponse {}
+
+/// The files changed between two commits.
+struct ChangeSet {
+    from: Oid,
+    to: Oid,
+    /// Files by their path at `to`
+    files: HashMap<String, FileChange>,
+}
+
+struct FileChange {
+    /// Whether the file was created between the two commits
+    added: bool,
+    /// Changed regions of the file at `to`, `None` if they couldn't be computed
+    hunks: Option<Vec<Hunk>>,
+}
+
+fn diff_commits(disk_path: &Path, from: &str, to: &str) -> Result<ChangeSet> {
+    let git = Repository::open(disk_path)
+        .map_err(|_| Error::user("the repository has no git history"))?;
+    let commit = |rev: &str| {
+        git.revparse_single(rev)
+            .and_then(|object| object.peel_to_commit())
+            .map_err(|_| Error::user(format!("unknown commit `{rev}`")))
+    };
+    let (from, to) = (commit(from)?, commit(to)?);
+
+    let mut opts = DiffOptions::new();
+    opts.context_lines(0);
+
+    let mut diff = git
+        .diff_tree_to_tree(
+            Some(&from.tree().map_err(Error::internal)?),
+            Some(&to.tree().map_err(Error::internal)?),
+            Some(&mut opts),
+        )
+        .map_err(Error::internal)?;
+    diff.find_similar(None).map_err(Error::internal)?;
+
+    let changed = diff.deltas().len();
+    if changed > MAX_CHANGED_FILES {
+        return Err(Error::user(format!(
+            "{changed} files changed between the commits, more than the \
+             {MAX_CHANGED_FILES} that can be searched; pick closer commits"
+        )));
+    }
+
+    let files = diff
+        .deltas()
+        .enumerate()
+        .filter(|(_, delta)| delta.status() != Delta::Deleted)
+        .filter_map(|(i, delta)| {
+            let path = delta.new_file().path()?.to_str()?.to_owned();
+            let hunks = Patch::from_diff(&diff, i)
+                .ok()
+                .flatten()
+                .filter(|patch| !patch.delta().flags().is_binary())
+                .and_then(|patch| snippets::patch_hunks(&patch).ok());
+
+            let added = delta.status() == Delta::Added;
+            Some((path, FileChange { added, hunks }))
+        })
+        .collect();
+
+    Ok(ChangeSet {
+        from: from.id(),
+        to: to.id(),
+        files,
+    })
+}
+
+/// How the lines `range` of a file at `to` relate to its changed `hunks`, or `None` if they are
+/// away from all of them.
+fn classify(hunks: &[Hunk], range: Range) -> Option<Change> {
+    let mut inserted = 0;
+    let mut modified = false;
+    let mut adjacent = false;
+
+    for hunk in hunks {
+        let (first, last) = (hunk.first_new_line(), hunk.last_new_line());
+
+        if hunk.new_lines == 0 {
+            // the removed lines were between `last` and `first`
+            modified |= range.start <= last && first <= range.end;
+        } else {
+            let overlap = (last.min(range.end) + 1).saturating_sub(first.max(range.start));
+            if hunk.old_lines == 0 {
+                inserted += overlap;
+            } else {
+                modified |= overlap > 0;
+            }
+        }
+
+        adjacent |= first <= range.end + ADJACENT_LINES && range.start <= last + ADJACENT_LINES;
+    }
+
+    let total = range.end - range.start + 1;
+    if modified || (inserted > 0 && inserted < total) {
+        Some(Change::Modified)
+    } else if inserted > 0 {
+        Some(Change::Added)
+    } else if adjacent {
+        Some(Change::Adjacent)
+    } else {
+        None
+    }
+}
+
+/// Annotate `snippets` with how they relate to `changes`, dropping those away from the changes.
+///
+/// Snippet lines refer to the `indexed` commit, so when that isn't the `to` commit, they are
+/// first re-anchored to it. Snippets that can't be located line by line are only matched by
+/// file.
+fn annotate(
+    disk_path: &Path,
+    changes: &ChangeSet,
+    indexed: Option<Oid>,
+    snippets: Vec<Snippet>,
+) -> Vec<ChangedSnippet> {
+    let mut contents = HashMap::<String, Option<(String, String)>>::new();
+
+    snippets
+        .into_iter()
+        .filter_map(|snippet| {
+            let file = changes.files.get(&snippet.relative_path)?;
+            let range = Range {
+                start: snippet.start_line + 1,
+                end: snippet.end_line + 1,
+            };
+
+            let change = match (&file.hunks, indexed) {
+                _ if file.added => Some(Change::Added),
+                // notebook snippet lines are relative to their cell
+                (Some(hunks), Some(indexed)) if snippet.cell_index.is_none() => {
+                    if indexed == changes.to {
+                        classify(hunks, range)
+                    } else {
+                        let versions = contents
+                            .entry(snippet.relative_path.clone())
+                            .or_insert_with(|| {
+                                file_versions(
+                                    disk_path,
+                                    indexed,
+                                    changes.to,
+                                    &snippet.relative_path,
+                                )
+                            });
+
+                        match versions.as_ref().map(|(original, current)| {
+                            snippets::reanchor_by_diff(original, current, range)
+                        }) {
+                            Some(Ok(anchor)) => match (anchor.start_line, anchor.end_line) {
+                                (Some(start), Some(end)) => classify(hunks, Range { start, end }),
+                                // the snippet no longer exists at `to`
+                                _ if anchor.status == AnchorStatus::Deleted => None,
+                                _ => Some(Change::ChangedFile),
+                            },
+                            _ => Some(Change::ChangedFile),
+                        }
+                    }
+                }
+                _ => Some(Change::ChangedFile),
+            }?;
+
+            Some(ChangedSnippet { snippet, change })
+        })
+        .collect()
+}
+
+/// The contents of `relative_path` at the `indexed` and `to` commits.
+fn file_versions(
+    disk_path: &Path,
+    indexed: Oid,
+    to: Oid,
+    relative_path: &str,
+) -> Option<(String, String)> {
+    let at = |commit: Oid| {
+        snippets::file_at_commit(disk_path, &commit.to_string(), relative_path)
+            .map_err(|err| debug!(?err, %commit, relative_path, "file is unavailable"))
+            .ok()
+    };
+
+    Some((at(indexed)?, at(to)?))
+}
+
+/// Search the code changed between two commits of a repository
+///
+/// Snippets that were added or modified come first, followed by those adjacent to a change, in
+/// order of relevance. Diffs touching too many files are rejected.
+//
+#[utoipa::path(get, path = "/semantic/changes",
+    responses(
+        (status = 200, description = "Execute query successfully", body = ChangesResponse),
+        (status = 400, description = "Bad request", body = EndpointError),
+        (status = 404, description = "Repository not found", body = EndpointError),
+        (status = 500, description = "Server error", body = EndpointError),
+    ),
+)]
+pub(super) async fn search(
+    Query(args): Query<ChangesArgs>,
+    Extension(semantic): Extension<Option<Semantic>>,
+    Extension(app): Extension<Application>,
+) -> Result<impl IntoResponse> {
+    let Some(semantic) = semantic else {
+        return Err(Error::new(
+            ErrorKind::Configuration,
+            "Qdrant not configured",
+        ));
+    };
+
+    let ChangesArgs {
+        repo_ref,
+        from,
+        to,
+        query,
+        limit,
+    } = args;
+
+    let parsed = parser::parse_nl_cached(&query).map_err(Error::user)?;
+    let Some(target) = parsed.target() else {
+        return Err(Error::user("empty search"));
+    };
+    check_query_length(target, app.config.min_query_chars)?;
+
+    let Some((disk_path, indexed)) = app
+        .repo_pool
+        .read_async(&repo_ref, |_, repo| {
+            (repo.disk_path.clone(), repo.indexed_commit.clone())
+        })
+        .await
+    else {
+        return Err(Error::new(ErrorKind::NotFound, "Repo not found"));
+    };
+
+    let changes = {
+        let disk_path = disk_path.clone();
+        tokio::task::spawn_blocking(move || diff_commits(&disk_path, &from, &to))
+            .await
+            .map_err(Error::internal)??
+    };
+
+    let mut response = ChangesResponse {
+        from: changes.from.to_string(),
+        to: changes.to.to_string(),
+        indexed_commit: indexed.clone(),
+        changed_files: changes.files.len(),
+        line_level: true,
+        snippets: vec![],
+        skipped: 0,
+    };
+    let indexed = indexed.and_then(|commit| Oid::from_str(&commit).ok());
+
+    // with no paths to filter on, the search would cover the whole repository
+    if changes.files.is_empty() {
+        return Ok(json(response));
+    }
+
+    let filters = FilterArgs::from_query(&parsed, FilterLogic::And)
+        .within_repos([repo_ref])
+        .keyword(
+            "relative_path",
+            changes
+                .files
+                .keys()
+                .flat_map(|path| relative_path_variants(path)),
+        );
+
+    let points = semantic
+        .search(
+            &parsed,
+            filters,
+            VectorWeights::default(),
+            PayloadFields::snippet(),
+            candidate_count(limit, [Widening::Dedup]),
+            false,
+        )
+        .await
+        .map_err(Error::search)?;
+
+    // malformed candidates are logged and left out, as in answers
+    let (snippets, skipped) = readable_snippets(
+        points.into_iter().map(|point| {
+            let snippet = snippet_from_payload(
+                point.payload,
+                semantic.payload_schema(),
+                point.score,
+                vec![],
+                SnippetSource::Semantic,
+            );
+            (point.id, snippet)
+        }),
+        false,
+    )?;
+    response.skipped = skipped;
+    let snippets = cap_candidates(snippets, app.config.dedup_max_candidates);
+    let snippets = semantic.post_process(&query, snippets);
+
+    let mut snippets =
+        tokio::task::spawn_blocking(move || annotate(&disk_path, &changes, indexed, snippets))
+            .await
+            .map_err(Error::internal)?;
+
+    // the search order is kept within each group
+    snippets.sort_by_key(|snippet| snippet.change == Change::Adjacent);
+    snippets.truncate(limit as usize);
+
+    response.line_level = snippets
+        .iter()
+        .all(|snippet| snippet.change != Change::ChangedFile);
+    response.snippets = snippets;
+
+    Ok(json(response))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use git2::{Signature, Time};
+
+    fn hunk(old_start: usize, old_lines: usize, new_start: usize, new_lines: usize) -> Hunk {
+        Hunk {
+            old_start,
+            old_lines,
+            new_start,
+            new_lines,
+        }
+    }
+
+    fn range(start: usize, end: usize) -> Range {
+        Range { start, end }
+    }
+
+    #[test]
+    fn snippets_are_classified_by_their_changed_lines() {
+        // lines 10-12 inserted, line 20 rewritten, and the line after line 30 removed
+        let hunks = [hunk(9, 0, 10, 3), hunk(17, 1, 20, 1), hunk(28, 1, 30, 0)];
+
+        assert_eq!(classify(&hunks, range(10, 12)), Some(Change::Added));
+        assert_eq!(classify(&hunks, range(8, 12)), Some(Change::Modified));
+        assert_eq!(classify(&hunks, range(18, 22)), Some(Change::Modified));
+        assert_eq!(classify(&hunks, range(29, 31)), Some(Change::Modified));
+        assert_eq!(classify(&hunks, range(14, 16)), Some(Change::Adjacent));
+        assert_eq!(classify(&hunks, range(31, 40)), Some(Change::Adjacent));
+        assert_eq!(classify(&hunks, range(40, 50)), None);
+        assert_eq!(classify(&[], range(1, 5)), None);
+    }
+
+    fn commit(git: &Repository, files: &[(&str, &str)]) -> Oid {
+        let workdir = git.workdir().unwrap();
+        let mut index = git.index().unwrap();
+        for (path, contents) in files {
+            std::fs::write(workdir.join(path), contents).unwrap();
+            index.add_path(Path::new(path)).unwrap();
+        }
+        let tree = git.find_tree(index.write_tree().unwrap()).unwrap();
+
+        let signature = Signature::new("dev", "dev@bloop.ai", &Time::new(0, 0)).unwrap();
+        let parent = git.head().ok().map(|head| head.peel_to_commit().unwrap());
+        git.commit(
+            Some("HEAD"),
+            &signature,
+            &signature,
+            "change",
+            &tree,
+            parent.as_ref().into_iter().collect::<Vec<_>>().as_slice(),
+        )
+        .unwrap()
+    }
+
+    fn snippet(relative_path: &str, start_line: usize, end_line: usize) -> Snippet {
+        Snippet {
+            relative_path: relative_path.into(),
+            start_line,
+            end_line,
+            ..Default::default()
+        }
+    }
+
+    fn changes_of(annotated: &[ChangedSnippet]) -> Vec<(&str, usize, Change)> {
+        annotated
+            .iter()
+            .map(|s| {
+                (
+                    s.snippet.relative_path.as_str(),
+                    s.snippet.start_line,
+                    s.change,
+                )
+            })
+            .collect()
+    }
+
+    #[test]
+    fn snippets_are_reanchored_to_the_changed_commit() {
+        let dir = tempdir::TempDir::new("changes").unwrap();
+        let git = Repository::init(dir.path()).unwrap();
+
+        let original = (1..=20).map(|i| format!("line {i}\n")).collect::<String>();
+        let edited = original.replace("line 15\n", "changed 15\n");
+        let v1 = commit(&git, &[("main.rs", original.as_str())]);
+        let v2 = commit(
+            &git,
+            &[("main.rs", edited.as_str()), ("new.rs", "fn new() {}\n")],
+        );
+
+        let changes = diff_commits(dir.path(), &v1.to_string(), "HEAD")
+            .ok()
+            .expect("the commits exist");
+        assert_eq!(changes.to, v2);
+        assert_eq!(changes.files.len(), 2);
+        assert!(changes.files["new.rs"].added);
+
+        let snippets = || {
+            vec![
+                snippet("main.rs", 0, 4),
+                snippet("main.rs", 10, 12),
+                snippet("main.rs", 13, 15),
+                snippet("new.rs", 0, 0),
+            ]
+        };
+
+        let annotated = annotate(dir.path(), &changes, Some(v2), snippets());
+        assert_eq!(
+            changes_of(&annotated),
+            [
+                ("main.rs", 10, Change::Adjacent),
+                ("main.rs", 13, Change::Modified),
+                ("new.rs", 0, Change::Added),
+            ]
+        );
+
+        // indexed before two lines were inserted at the top, which moves every snippet down
+        let moved = format!("top\ntop\n{edited}");
+        let v3 = commit(&git, &[("main.rs", moved.as_str())]);
+        let changes = diff_commits(dir.path(), &v1.to_string(), &v3.to_string())
+            .ok()
+            .expect("the commits exist");
+        let annotated = annotate(dir.path(), &changes, Some(v2), snippets());
+        assert_eq!(
+            changes_of(&annotated),
+            [
+                ("main.rs", 0, Change::Adjacent),
+                ("main.rs", 10, Change::Adjacent),
+                ("main.rs", 13, Change::Modified),
+                ("new.rs", 0, Change::Added),
+            ]
+        );
+
+        // without an indexed commit, lines can't be trusted
+        let annotated = annotate(dir.path(), &changes, None, snippets());
+        assert!(annotated
+            .iter()
+            .filter(|s| s.snippet.relative_path == "main.rs")
+            .all(|s| s.change == Change::ChangedFile));
+    }
+
+    #[test]
+    fn large_diffs_are_rejected() {
+        let dir = tempdir::TempDir::new("changes").unwrap();
+        let git = Repository::init(dir.path()).unwrap();
+
+        let first = commit(&git, &[("main.rs", "fn main() {}\n")]);
+        let paths = (0..=MAX_CHANGED_FILES)
+            .map(|i| format!("{i}.rs"))
+            .collect::<Vec<_>>();
+        let files = paths.iter().map(|p| (p.as_str(), "")).collect::<Vec<_>>();
+        commit(&git, &files);
+
+        assert!(diff_commits(dir.path(), &first.to_string(), "HEAD").is_err());
+        assert!(diff_commits(dir.path(), "does-not-exist", "HEAD").is_err());
+    }
+}
@@ -13,6 +13,11 @@ pub(super) struct ConfigResponse {
     schema_version: String,
     tracking_id: String,
     device_id: String,
+    /// The qdrant collection of the semantic index, if semantic search is enabled
+    semantic_collection: Option<String>,
+    /// The collection shared by older versions, if some of its points may still belong here.
+    /// They are moved into `semantic_collection` with `POST /admin/semantic/migrate`
+    legacy_semantic_collection: Option<String>,
 }
 
 impl super::ApiResponse for ConfigResponse {}
@@ -47,5 +52,11 @@ pub(super) async fn handle(
         device_id,
         org_name,
         tracking_id,
+        semantic_collection: app.semantic.as_ref().map(|s| s.collection().to_owned()),
+        legacy_semantic_collection: app
+            .semantic
+            .as_ref()
+            .and_then(|s| s.legacy_collection())
+            .map(ToOwned::to_owned),
     })
 }
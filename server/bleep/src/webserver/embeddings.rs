@@ -0,0 +1,318 @@
+use axum::{
+    body::{Bytes, StreamBody},
+    http::header,
+};
+use futures::Stream;
+use qdrant_client::qdrant::{
+    point_id::PointIdOptions, value::Kind, vectors::VectorsOptions, RetrievedPoint,
+};
+use rand::{rngs::StdRng, Rng, SeedableRng};
+
+use super::{answer::value_to_usize, prelude::*};
+use crate::{
+    repo::{normalize_relative_path, RepoRef},
+    semantic::{self, payload::PayloadSchema, Semantic},
+    Application,
+};
+
+/// Number of points fetched from qdrant at a time
+const PAGE_SIZE: u32 = 256;
+
+/// Leading bytes of a binary export
+const MAGIC: &[u8; 4] = b"BLPE";
+const BINARY_VERSION: u8 = 1;
+
+/// Encoding of an embeddings export.
+///
+/// Both formats open with a header declaring the embedding dimension, followed by one record
+/// per point:
+///
+/// - `ndjson`: a `{"dimension": N}` line, then one JSON object per line
+/// - `binary`: the magic bytes `BLPE`, a version byte and the dimension as a `u32`. Each record
+///   is the point id and relative path, both as a `u32` length followed by UTF-8 bytes, the
+///   start and end lines as `u32`s, and `N` `f32`s. All integers and floats are little-endian.
+#[derive(Deserialize, Default, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub(super) enum ExportFormat {
+    #[default]
+    Ndjson,
+    Binary,
+}
+
+#[derive(Deserialize)]
+pub(super) struct ExportParams {
+    #[serde(default)]
+    format: ExportFormat,
+
+    /// Export a uniform random sample of this many points, instead of every point
+    sample: Option<usize>,
+}
+
+#[derive(Serialize, Debug, PartialEq)]
+struct Record {
+    point_id: String,
+    relative_path: String,
+    start_line: usize,
+    end_line: usize,
+    embedding: Vec<f32>,
+}
+
+impl Record {
+    /// The record of `point`, whose payload is keyed by `schema`.
+    fn from_point(mut point: RetrievedPoint, schema: &PayloadSchema) -> Option<Self> {
+        let point_id = match point.id?.point_id_options? {
+            PointIdOptions::Uuid(uuid) => uuid,
+            PointIdOptions::Num(num) => num.to_string(),
+        };
+
+        let relative_path = match schema.take(&mut point.payload, "relative_path")?.kind? {
+            Kind::StringValue(path) => normalize_relative_path(&path).into_owned(),
+            _ => return None,
+        };
+
+        let embedding = match point.vectors?.vectors_options? {
+            VectorsOptions::Vector(v) => v.data,
+            _ => return None,
+        };
+
+        Some(Self {
+            point_id,
+            relative_path,
+            start_line: value_to_usize(schema.take(&mut point.payload, "start_line")?).ok()?,
+            end_line: value_to_usize(schema.take(&mut point.payload, "end_line")?).ok()?,
+            embedding,
+        })
+    }
+}
+
+impl ExportFormat {
+    fn content_type(self) -> &'static str {
+        match self {
+            Self::Ndjson => "application/x-ndjson",
+            Self::Binary => "application/octet-stream",
+        }
+    }
+
+    fn header(self, dimension: u32) -> Vec<u8> {
+        match self {
+            Self::Ndjson => {
+                let mut out = serde_json::to_vec(&serde_json::json!({ "dimension": dimension }))
+                    .expect("header serialization can't fail");
+                out.push(b'\n');
+                out
+            }
+            Self::Binary => {
+                let mut out = MAGIC.to_vec();
+                out.push(BINARY_VERSION);
+                out.extend_from_slice(&dimension.to_le_bytes());
+                out
+            }
+        }
+    }
+
+    fn encode(self, records: &[Record]) -> Vec<u8> {
+        let mut out = vec![];
+
+        for record in records {
+            match self {
+                Self::Ndjson => {
+                    serde_json::to_writer(&mut out, record).expect("writing to a Vec can't fail");
+                    out.push(b'\n');
+                }
+                Self::Binary => {
+                    for s in [&record.point_id, &record.relative_path] {
+                        out.extend_from_slice(&(s.len() as u32).to_le_bytes());
+                        out.extend_from_slice(s.as_bytes());
+                    }
+
+                    out.extend_from_slice(&(record.start_line as u32).to_le_bytes());
+                    out.extend_from_slice(&(record.end_line as u32).to_le_bytes());
+
+                    for x in &record.embedding {
+                        out.extend_from_slice(&x.to_le_bytes());
+                    }
+                }
+            }
+        }
+
+        out
+    }
+}
+
+/// Keeps a uniform random sample of at most `size` items, using reservoir sampling.
+struct Reservoir<T> {
+    size: usize,
+    seen: usize,
+    items: Vec<T>,
+    rng: StdRng,
+}
+
+impl<T> Reservoir<T> {
+    fn new(size: usize) -> Self {
+        Self {
+            size,
+            seen: 0,
+            items: Vec::with_capacity(size),
+            rng: StdRng::from_entropy(),
+        }
+    }
+
+    fn push(&mut self, item: T) {
+        self.seen += 1;
+
+        if self.items.len() < self.size {
+            self.items.push(item);
+        } else {
+            let i = self.rng.gen_range(0..self.seen);
+            if i < self.size {
+                self.items[i] = item;
+            }
+        }
+    }
+
+    fn into_inner(self) -> Vec<T> {
+        self.items
+    }
+}
+
+/// Pages of records for every point of `repo_ref`, fetched as the stream is polled.
+fn pages(semantic: Semantic, repo_ref: String) -> impl Stream<Item = anyhow::Result<Vec<Record>>> {
+    async_stream::try_stream! {
+        let mut offset = None;
+
+        loop {
+            let (points, next) = semantic.scroll_chunks(&repo_ref, offset, PAGE_SIZE).await?;
+            let schema = semantic.payload_schema();
+            yield points
+                .into_iter()
+                .filter_map(|point| Record::from_point(point, schema))
+                .collect::<Vec<_>>();
+
+            match next {
+                Some(next) => offset = Some(next),
+                None => break,
+            }
+        }
+    }
+}
+
+/// Stream the embeddings of a repository.
+///
+/// Without `sample`, records are encoded page by page as the client reads them, so the
+/// collection is never buffered. With `sample`, only the sampled records are kept in memory
+/// until the scan completes.
+pub(super) async fn export(
+    app: &Application,
+    reporef: &RepoRef,
+    params: ExportParams,
+) -> Result<axum::response::Response> {
+    let Some(semantic) = app.semantic.clone() else {
+        return Err(Error::new(
+            ErrorKind::Configuration,
+            "Qdrant not configured",
+        ));
+    };
+
+    let ExportParams { format, sample } = params;
+    let pages = pages(semantic, reporef.to_string());
+
+    Ok((
+        [(header::CONTENT_TYPE, format.content_type())],
+        StreamBody::new(encode(format, sample, pages)),
+    )
+        .into_response())
+}
+
+fn encode(
+    format: ExportFormat,
+    sample: Option<usize>,
+    pages: impl Stream<Item = anyhow::Result<Vec<Record>>>,
+) -> impl Stream<Item = anyhow::Result<Bytes>> {
+    async_stream::try_stream! {
+        yield Bytes::from(format.header(semantic::EMBEDDING_DIM as u32));
+
+        match sample {
+            None => {
+                for await page in pages {
+                    yield Bytes::from(format.encode(&page?));
+                }
+            }
+            Some(size) => {
+                let mut reservoir = Reservoir::new(size);
+                for await page in pages {
+                    for record in page? {
+                        reservoir.push(record);
+                    }
+                }
+
+                yield Bytes::from(format.encode(&reservoir.into_inner()));
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn record(point_id: &str) -> Record {
+        Record {
+            point_id: point_id.to_owned(),
+            relative_path: "src/main.rs".to_owned(),
+            start_line: 1,
+            end_line: 12,
+            embedding: vec![0.5, -1.0],
+        }
+    }
+
+    #[test]
+    fn ndjson_export() {
+        let mut out = ExportFormat::Ndjson.header(2);
+        out.extend(ExportFormat::Ndjson.encode(&[record("a"), record("b")]));
+
+        let lines = String::from_utf8(out).unwrap();
+        let lines = lines.lines().collect::<Vec<_>>();
+
+        assert_eq!(lines.len(), 3);
+        assert_eq!(lines[0], r#"{"dimension":2}"#);
+        assert_eq!(
+            lines[1],
+            r#"{"point_id":"a","relative_path":"src/main.rs","start_line":1,"end_line":12,"embedding":[0.5,-1.0]}"#
+        );
+    }
+
+    #[test]
+    fn binary_export() {
+        let mut out = ExportFormat::Binary.header(2);
+        out.extend(ExportFormat::Binary.encode(&[record("a")]));
+
+        let mut expected = b"BLPE\x01".to_vec();
+        expected.extend(2u32.to_le_bytes());
+        expected.extend(1u32.to_le_bytes());
+        expected.extend(b"a");
+        expected.extend(11u32.to_le_bytes());
+        expected.extend(b"src/main.rs");
+        expected.extend(1u32.to_le_bytes());
+        expected.extend(12u32.to_le_bytes());
+        expected.extend(0.5f32.to_le_bytes());
+        expected.extend((-1.0f32).to_le_bytes());
+
+        assert_eq!(out, expected);
+    }
+
+    #[test]
+    fn reservoir_keeps_a_bounded_sample() {
+        let mut reservoir = Reservoir::new(10);
+        (0..5).for_each(|i| reservoir.push(i));
+        assert_eq!(reservoir.into_inner(), (0..5).collect::<Vec<_>>());
+
+        let mut reservoir = Reservoir::new(10);
+        (0..1000).for_each(|i| reservoir.push(i));
+        let mut sample = reservoir.into_inner();
+        sample.sort();
+        sample.dedup();
+
+        assert_eq!(sample.len(), 10);
+        assert!(sample.iter().all(|i| (0..1000).contains(i)));
+    }
+}
@@ -0,0 +1,115 @@
+use super::prelude::*;
+use crate::{eviction::EvictionReport, maintenance::MaintenanceReport, Application};
+
+impl super::ApiResponse for MaintenanceReport {}
+
+impl super::ApiResponse for EvictionReport {}
+
+#[derive(Serialize)]
+pub(super) struct MigrationReport {
+    collection: String,
+    legacy_collection: String,
+    repos: Vec<MigratedRepo>,
+}
+
+#[derive(Serialize)]
+struct MigratedRepo {
+    repo_ref: String,
+    /// Points moved out of the legacy collection
+    moved: usize,
+}
+
+impl super::ApiResponse for MigrationReport {}
+
+/// Garbage collect orphaned semantic index points and compact the search indexes
+//
+#[utoipa::path(post, path = "/admin/maintenance/run",
+    responses(
+        (status = 200, description = "Maintenance finished", body = MaintenanceReport),
+        (status = 403, description = "Forbidden", body = EndpointError),
+        (status = 409, description = "Maintenance already running", body = EndpointError),
+        (status = 500, description = "Server error", body = EndpointError),
+    ),
+)]
+pub(super) async fn run(Extension(app): Extension<Application>) -> Result<impl IntoResponse> {
+    match app.maintenance.run(&app).await? {
+        Some(report) => Ok(json(report)),
+        None => {
+            Err(Error::user("maintenance is already running").with_status(StatusCode::CONFLICT))
+        }
+    }
+}
+
+/// Evict the least recently searched repositories until disk usage fits in `eviction_budget_mb`
+//
+#[utoipa::path(post, path = "/admin/eviction/run",
+    responses(
+        (status = 200, description = "Eviction finished", body = EvictionReport),
+        (status = 403, description = "Forbidden", body = EndpointError),
+        (status = 500, description = "Server error", body = EndpointError),
+    ),
+)]
+pub(super) async fn evict(Extension(app): Extension<Application>) -> Result<impl IntoResponse> {
+    if app.config.eviction_budget_mb.is_none() {
+        return Err(Error::new(
+            ErrorKind::Configuration,
+            "no `eviction_budget_mb` configured",
+        ));
+    }
+
+    Ok(json(crate::eviction::run(&app).await?))
+}
+
+/// Move the semantic index points of this instance's repositories out of the collection shared
+/// by older versions, into the configured `qdrant_collection`
+///
+/// Points of repositories this instance doesn't know about are left in the shared collection,
+/// for the other instances to migrate. Repositories are not reindexed in the meantime.
+//
+#[utoipa::path(post, path = "/admin/semantic/migrate",
+    responses(
+        (status = 200, description = "Migration finished", body = MigrationReport),
+        (status = 400, description = "No legacy collection", body = EndpointError),
+        (status = 403, description = "Forbidden", body = EndpointError),
+        (status = 500, description = "Server error", body = EndpointError),
+    ),
+)]
+pub(super) async fn migrate_semantic(
+    Extension(app): Extension<Application>,
+) -> Result<impl IntoResponse> {
+    let Some(semantic) = app.semantic.as_ref() else {
+        return Err(Error::new(
+            ErrorKind::Configuration,
+            "Qdrant not configured",
+        ));
+    };
+
+    let Some(legacy) = semantic.legacy_collection() else {
+        return Err(Error::user(format!(
+            "there is no legacy collection to migrate into `{}`",
+            semantic.collection()
+        )));
+    };
+
+    // hold off indexing, so no points are written to either collection while they move
+    let _writers = app.indexes.writers().await?;
+
+    let mut repo_refs = vec![];
+    app.repo_pool
+        .scan_async(|reporef, _| repo_refs.push(reporef.to_string()))
+        .await;
+
+    let repos = semantic
+        .migrate_legacy(&repo_refs)
+        .await
+        .map_err(Error::internal)?
+        .into_iter()
+        .map(|(repo_ref, moved)| MigratedRepo { repo_ref, moved })
+        .collect();
+
+    Ok(json(MigrationReport {
+        collection: semantic.collection().to_owned(),
+        legacy_collection: legacy.to_owned(),
+        repos,
+    }))
+}
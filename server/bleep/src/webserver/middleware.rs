@@ -1,5 +1,5 @@
 use super::prelude::*;
-use crate::Application;
+use crate::{env::Feature, Application};
 
 use axum::{
     extract::State,
@@ -26,3 +26,30 @@ async fn local_user_mw<B>(
 
     next.run(request).await
 }
+
+/// Restrict `router` to administrators.
+///
+/// Every user of a local installation is an administrator. When authorization is required,
+/// only bot requests authenticated with `bot_secret` are.
+///
+/// This must be applied before the middleware providing the `User` extension.
+pub fn admin_only(router: Router, app: Application) -> Router {
+    router.route_layer(from_fn_with_state(app, admin_only_mw))
+}
+
+async fn admin_only_mw<B>(
+    State(app): State<Application>,
+    request: Request<B>,
+    next: Next<B>,
+) -> Response {
+    let is_admin = match request.extensions().get::<User>() {
+        Some(User(user)) => !app.env.allow(Feature::AuthorizationRequired) || user.is_none(),
+        None => false,
+    };
+
+    if is_admin {
+        next.run(request).await
+    } else {
+        StatusCode::FORBIDDEN.into_response()
+    }
+}
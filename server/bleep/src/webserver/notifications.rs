@@ -0,0 +1,67 @@
+use super::prelude::*;
+use crate::{
+    notifications::{DeadLetter, Delivery, Event},
+    Application,
+};
+
+fn default_limit() -> usize {
+    100
+}
+
+#[derive(Deserialize)]
+pub(super) struct EventsParams {
+    #[serde(default = "default_limit")]
+    limit: usize,
+}
+
+#[derive(Serialize)]
+pub(super) struct EventsResponse {
+    /// Alerts on failing and stale repositories, most recent first
+    events: Vec<Event>,
+    /// Alerts that could not be delivered to a webhook, most recent first
+    dead_letters: Vec<DeadLetter>,
+}
+
+impl super::ApiResponse for EventsResponse {}
+
+#[derive(Serialize)]
+pub(super) struct TestResponse {
+    /// The outcome of posting the test event to each webhook
+    deliveries: Vec<Delivery>,
+}
+
+impl super::ApiResponse for TestResponse {}
+
+/// The most recent repository alerts, whether webhooks are configured or not
+//
+#[utoipa::path(get, path = "/admin/events",
+    responses(
+        (status = 200, description = "Execute query successfully", body = EventsResponse),
+        (status = 403, description = "Forbidden", body = EndpointError),
+        (status = 500, description = "Server error", body = EndpointError),
+    ),
+)]
+pub(super) async fn events(
+    Query(params): Query<EventsParams>,
+    Extension(app): Extension<Application>,
+) -> impl IntoResponse {
+    json(EventsResponse {
+        events: app.notifications.events(params.limit),
+        dead_letters: app.notifications.dead_letters(params.limit),
+    })
+}
+
+/// Post a test event to every configured webhook, without retries
+//
+#[utoipa::path(post, path = "/admin/notifications/test",
+    responses(
+        (status = 200, description = "Test event sent", body = TestResponse),
+        (status = 403, description = "Forbidden", body = EndpointError),
+        (status = 500, description = "Server error", body = EndpointError),
+    ),
+)]
+pub(super) async fn test(Extension(app): Extension<Application>) -> impl IntoResponse {
+    json(TestResponse {
+        deliveries: app.notifications.test().await,
+    })
+}
@@ -1,18 +1,22 @@
 use std::{
+    borrow::Cow,
     collections::{HashMap, HashSet},
-    path::MAIN_SEPARATOR,
+    path::PathBuf,
     sync::Arc,
 };
 
-use super::prelude::*;
+use super::{middleware::User, prelude::*, workspaces};
 use crate::{
     collector::{BytesFilterCollector, FrequencyCollector},
+    history::SearchEntry,
     indexes::{
         reader::{base_name, ContentReader, FileReader, OpenReader, RepoReader},
         DocumentRead, File, Indexable, Indexer, Indexes, Repo,
     },
     query::{parser, ranking::DocumentTweaker},
+    repo::{normalize_relative_path, PATH_SEPARATOR},
     snippet::{HighlightedString, SnippedFile, Snipper},
+    Application,
 };
 
 use async_trait::async_trait;
@@ -74,9 +78,25 @@ pub struct ApiQuery {
     /// The number of lines of context in the snippet after the search result
     #[serde(alias = "ca", default = "default_context")]
     context_after: usize,
+
+    /// Only search the repositories of this workspace
+    pub workspace: Option<String>,
 }
 
 impl ApiQuery {
+    /// The first page of `page_size` results for `q`, without totals.
+    pub(super) fn first_page(q: String, page_size: usize) -> Self {
+        Self {
+            q,
+            page: 0,
+            page_size,
+            calculate_totals: false,
+            context_before: default_context(),
+            context_after: default_context(),
+            workspace: None,
+        }
+    }
+
     fn limit(&self) -> usize {
         // do not permit a page-size of 0
         self.page_size.max(1)
@@ -86,8 +106,13 @@ impl ApiQuery {
         self.page_size * self.page
     }
 
-    async fn query(self: Arc<Self>, indexes: Arc<Indexes>) -> Result<QueryResponse> {
+    async fn query(
+        self: Arc<Self>,
+        indexes: Arc<Indexes>,
+        scope: Option<Vec<PathBuf>>,
+    ) -> Result<QueryResponse> {
         let queries = parser::parse(&self.q).map_err(Error::user)?;
+        let scope = scope.as_deref();
 
         // FIXME: this for-loop prevents us from ever producing heterogenous
         // results.
@@ -116,22 +141,22 @@ impl ApiQuery {
         for q in &queries {
             if ContentReader.query_matches(q) {
                 return ContentReader
-                    .execute(&indexes.file, &queries, &self)
+                    .execute(&indexes.file, &queries, &self, scope)
                     .await
                     .map_err(Error::internal);
             } else if RepoReader.query_matches(q) {
                 return RepoReader
-                    .execute(&indexes.repo, &queries, &self)
+                    .execute(&indexes.repo, &queries, &self, scope)
                     .await
                     .map_err(Error::internal);
             } else if FileReader.query_matches(q) {
                 return FileReader
-                    .execute(&indexes.file, &queries, &self)
+                    .execute(&indexes.file, &queries, &self, scope)
                     .await
                     .map_err(Error::internal);
             } else if OpenReader.query_matches(q) {
                 return OpenReader
-                    .execute(&indexes.file, &queries, &self)
+                    .execute(&indexes.file, &queries, &self, scope)
                     .await
                     .map_err(Error::internal);
             }
@@ -148,14 +173,41 @@ impl ApiQuery {
     responses(
         (status = 200, description = "Execute query successfully", body = Response),
         (status = 400, description = "Bad request", body = EndpointError),
+        (status = 404, description = "Workspace not found", body = EndpointError),
         (status = 500, description = "Server error", body = EndpointError),
     ),
 )]
 pub(super) async fn handle(
     Query(api_params): Query<ApiQuery>,
     Extension(indexes): Extension<Arc<Indexes>>,
+    Extension(app): Extension<Application>,
+    Extension(user): Extension<User>,
 ) -> impl IntoAxumResponse {
-    Arc::new(api_params).query(indexes).await.map(json)
+    let scope = match api_params.workspace.as_deref() {
+        Some(workspace) => Some(workspaces::resolve_disk_paths(&app, workspace).await?),
+        None => None,
+    };
+
+    if let Ok(parsed) = parser::parse(&api_params.q) {
+        let repos = parsed.iter().filter_map(|q| q.repo.as_ref()?.as_plain());
+        super::repos::reject_evicted(&app, repos.map(|r| r.as_ref())).await?;
+    }
+
+    let api_params = Arc::new(api_params);
+    let response = Arc::clone(&api_params).query(indexes, scope).await?;
+
+    if let Ok(parsed) = parser::parse(&api_params.q) {
+        let repos = response
+            .data
+            .iter()
+            .filter_map(QueryResult::repo_ref)
+            .map(ToOwned::to_owned);
+        app.record_search(
+            SearchEntry::lexical(&api_params.q, &parsed, response.count, user.0).with_repos(repos),
+        );
+    }
+
+    Ok::<_, Error>(json(response))
 }
 
 #[derive(Serialize, ToSchema)]
@@ -260,6 +312,20 @@ pub enum QueryResult {
     Lang(String),
 }
 
+impl QueryResult {
+    /// The repository the result comes from, if it comes from one.
+    fn repo_ref(&self) -> Option<&str> {
+        match self {
+            Self::Snippets(file) => Some(&file.repo_ref),
+            Self::RepositoryResult(repo) => Some(&repo.repo_ref),
+            Self::FileResult(file) => Some(&file.repo_ref),
+            Self::File(file) => Some(&file.repo_ref),
+            Self::Directory(dir) => Some(&dir.repo_ref),
+            Self::Flag(_) | Self::Lang(_) => None,
+        }
+    }
+}
+
 #[derive(Serialize, ToSchema)]
 pub struct RepositoryResultData {
     name: HighlightedString,
@@ -313,6 +379,7 @@ pub trait ExecuteQuery {
         indexer: &Indexer<Self::Index>,
         queries: &[parser::Query<'_>],
         q: &ApiQuery,
+        scope: Option<&[PathBuf]>,
     ) -> anyhow::Result<QueryResponse>;
 }
 
@@ -325,6 +392,7 @@ impl ExecuteQuery for ContentReader {
         indexer: &Indexer<Self::Index>,
         queries: &[parser::Query<'_>],
         q: &ApiQuery,
+        scope: Option<&[PathBuf]>,
     ) -> anyhow::Result<QueryResponse> {
         // queries that produce content results
         let relevant_queries = queries.iter().filter(|q| self.query_matches(q));
@@ -375,7 +443,9 @@ impl ExecuteQuery for ContentReader {
             (top_k, metadata_collector),
         );
 
-        let mut results = indexer.query(queries.iter(), self, collector).await?;
+        let mut results = indexer
+            .query(queries.iter(), self, scope, collector)
+            .await?;
         let data = results
             .docs
             .filter_map(|doc| {
@@ -434,6 +504,7 @@ impl ExecuteQuery for FileReader {
         indexer: &Indexer<File>,
         queries: &[parser::Query<'_>],
         q: &ApiQuery,
+        scope: Option<&[PathBuf]>,
     ) -> anyhow::Result<QueryResponse> {
         let (filter_regexes, byte_filter_regexes): (Vec<_>, Vec<_>) = queries
             .iter()
@@ -474,7 +545,9 @@ impl ExecuteQuery for FileReader {
             (top_k, metadata_collector),
         );
 
-        let mut results = indexer.query(queries.iter(), self, collector).await?;
+        let mut results = indexer
+            .query(queries.iter(), self, scope, collector)
+            .await?;
 
         let data = results
             .docs
@@ -522,6 +595,7 @@ impl ExecuteQuery for RepoReader {
         indexer: &Indexer<Self::Index>,
         queries: &[parser::Query<'_>],
         q: &ApiQuery,
+        scope: Option<&[PathBuf]>,
     ) -> anyhow::Result<QueryResponse> {
         let (filter_regexes, byte_filter_regexes): (Vec<_>, Vec<_>) = queries
             .iter()
@@ -550,7 +624,9 @@ impl ExecuteQuery for RepoReader {
             (top_k, metadata_collector),
         );
 
-        let mut results = indexer.query(queries.iter(), self, collector).await?;
+        let mut results = indexer
+            .query(queries.iter(), self, scope, collector)
+            .await?;
 
         let data = results
             .docs
@@ -594,10 +670,11 @@ impl ExecuteQuery for OpenReader {
         indexer: &Indexer<Self::Index>,
         queries: &[parser::Query<'_>],
         _q: &ApiQuery,
+        scope: Option<&[PathBuf]>,
     ) -> anyhow::Result<QueryResponse> {
         #[derive(Debug)]
         struct Directive<'a> {
-            relative_path: &'a str,
+            relative_path: Cow<'a, str>,
             repo_name: &'a str,
         }
 
@@ -607,8 +684,8 @@ impl ExecuteQuery for OpenReader {
             .filter_map(|q| {
                 Some(Directive {
                     relative_path: match q.path.as_ref() {
-                        None => "",
-                        Some(parser::Literal::Plain(p)) => p,
+                        None => "".into(),
+                        Some(parser::Literal::Plain(p)) => normalize_relative_path(p),
                         Some(parser::Literal::Regex(..)) => return None,
                     },
                     repo_name: q.repo.as_ref()?.as_plain()?,
@@ -621,7 +698,7 @@ impl ExecuteQuery for OpenReader {
 
         let relative_paths = open_directives
             .iter()
-            .map(|d| d.relative_path.to_owned())
+            .map(|d| d.relative_path.clone().into_owned())
             .collect::<Vec<_>>();
 
         let collector = BytesFilterCollector::new(
@@ -634,22 +711,16 @@ impl ExecuteQuery for OpenReader {
                 // Check if *any* of the relative paths match. We can't compare repositories here
                 // because the `BytesFilterCollector` operates on one field. So we sort through this
                 // later. It's unlikely that a search will use more than one open query.
-                relative_paths.iter().any(|rp| {
-                    let rp = rp.trim_end_matches(|c| c != MAIN_SEPARATOR);
-
-                    matches!(
-                        // Trim trailing suffix and avoid returning results for an empty string
-                        // (this means that the document we are looking at is the folder itself; a
-                        // redundant result).
-                        relative_path.strip_prefix(rp).map(|p| p.trim_end_matches(MAIN_SEPARATOR)),
-                        Some(p) if !p.is_empty() && !p.contains(MAIN_SEPARATOR)
-                    )
-                })
+                relative_paths
+                    .iter()
+                    .any(|rp| is_directory_entry(relative_path, rp))
             },
             (top_docs, empty_collector),
         );
 
-        let results = indexer.query(queries.iter(), self, collector).await?;
+        let results = indexer
+            .query(queries.iter(), self, scope, collector)
+            .await?;
 
         // Map of (repo_name, relative_path) -> (String, entry set)
         //
@@ -661,8 +732,8 @@ impl ExecuteQuery for OpenReader {
         // Set of (repo_name, relative_path) that should be returned.
         let directories = open_directives
             .iter()
-            .filter(|d| d.relative_path.is_empty() || d.relative_path.ends_with(MAIN_SEPARATOR))
-            .map(|d| (d.repo_name, d.relative_path))
+            .filter(|d| d.relative_path.is_empty() || d.relative_path.ends_with(PATH_SEPARATOR))
+            .map(|d| (d.repo_name, d.relative_path.as_ref()))
             .collect::<HashSet<_>>();
 
         // Iterate over each combination of (document, directive).
@@ -675,7 +746,7 @@ impl ExecuteQuery for OpenReader {
                 .filter(|d| d.repo_name == doc.repo_name)
             {
                 // Exact hit.
-                if directive.relative_path == doc.relative_path {
+                if directive.relative_path == doc.relative_path.as_str() {
                     files.push(FileData {
                         repo_name: doc.repo_name.clone(),
                         relative_path: doc.relative_path.clone(),
@@ -688,12 +759,12 @@ impl ExecuteQuery for OpenReader {
                     continue;
                 }
 
-                let relative_path = base_name(directive.relative_path);
+                let relative_path = base_name(&directive.relative_path);
 
                 if let Some(entry) = doc
                     .relative_path
                     .strip_prefix(relative_path)
-                    .and_then(|s| s.split_inclusive(MAIN_SEPARATOR).next())
+                    .and_then(|s| s.split_inclusive(PATH_SEPARATOR).next())
                 {
                     dir_entries
                         .entry((directive.repo_name, relative_path))
@@ -701,7 +772,7 @@ impl ExecuteQuery for OpenReader {
                         .1
                         .insert(DirEntry {
                             name: entry.to_owned(),
-                            entry_data: if entry.contains(MAIN_SEPARATOR) {
+                            entry_data: if entry.contains(PATH_SEPARATOR) {
                                 EntryData::Directory
                             } else {
                                 EntryData::File {
@@ -751,6 +822,23 @@ impl ExecuteQuery for OpenReader {
     }
 }
 
+/// Whether the file or directory at `relative_path` is listed when opening `directory`.
+///
+/// Only direct children of the directory are entries. A `directory` with a file name lists the
+/// siblings of the file.
+fn is_directory_entry(relative_path: &str, directory: &str) -> bool {
+    let directory = directory.trim_end_matches(|c| c != PATH_SEPARATOR);
+
+    matches!(
+        // Trim trailing suffix and avoid returning results for an empty string (this means that
+        // the document we are looking at is the folder itself; a redundant result).
+        relative_path
+            .strip_prefix(directory)
+            .map(|p| p.trim_end_matches(PATH_SEPARATOR)),
+        Some(p) if !p.is_empty() && !p.contains(PATH_SEPARATOR)
+    )
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -829,4 +917,24 @@ mod tests {
 
         assert_eq!(expected, observed);
     }
+
+    #[test]
+    fn windows_directories_list_their_entries() {
+        let directory = normalize_relative_path(r"src\webserver\");
+        assert_eq!(directory, "src/webserver/");
+
+        assert!(is_directory_entry("src/webserver/query.rs", &directory));
+        assert!(is_directory_entry("src/webserver/api/", &directory));
+        assert!(!is_directory_entry(
+            "src/webserver/api/answer.rs",
+            &directory
+        ));
+        assert!(!is_directory_entry("src/webserver/", &directory));
+        assert!(!is_directory_entry("src/lib.rs", &directory));
+
+        // opening a file lists its siblings
+        let file = normalize_relative_path(r"src\webserver\query.rs");
+        assert!(is_directory_entry("src/webserver/file.rs", &file));
+        assert_eq!(base_name(&file), "src/webserver/");
+    }
 }
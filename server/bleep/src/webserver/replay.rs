@@ -0,0 +1,324 @@
+//! Resumable server-sent event streams.
+//!
+//! Every event of a resumable stream carries an id of the form `<stream>:<seq>`, where `seq`
+//! increases by one with every event. Clients reconnecting with a `Last-Event-ID` header get the
+//! events they missed replayed from a bounded buffer, or a `restart` event if these are gone.
+
+use std::{
+    collections::VecDeque,
+    fmt,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use axum::{
+    http::HeaderMap,
+    response::sse::{Event, KeepAlive},
+};
+use futures::Stream;
+use tokio::sync::broadcast;
+
+use crate::Configuration;
+
+/// Events sent to live subscribers before slow ones start lagging, and catch up from the buffer
+const LIVE_CAPACITY: usize = 64;
+
+/// Keep-alive comments for idle streams, so that proxies don't drop the connection.
+pub(super) fn keep_alive(config: &Configuration) -> KeepAlive {
+    KeepAlive::new()
+        .interval(Duration::from_secs(config.sse_keep_alive_secs.max(1)))
+        .text("keep-alive")
+}
+
+/// The event telling a client that the events it asked for are no longer available, and that
+/// it should start over.
+pub(super) fn restart() -> Event {
+    Event::default()
+        .event("restart")
+        .data("events after Last-Event-ID are no longer available")
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(super) struct EventId {
+    pub(super) stream: String,
+    pub(super) seq: u64,
+}
+
+impl EventId {
+    /// The `Last-Event-ID` header of a reconnecting client, if it is well-formed.
+    pub(super) fn last(headers: &HeaderMap) -> Option<Self> {
+        let value = headers.get("last-event-id")?.to_str().ok()?;
+        let (stream, seq) = value.trim().rsplit_once(':')?;
+
+        Some(Self {
+            stream: stream.to_owned(),
+            seq: seq.parse().ok()?,
+        })
+    }
+}
+
+impl fmt::Display for EventId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}:{}", self.stream, self.seq)
+    }
+}
+
+/// The events replayed for a `Last-Event-ID`.
+#[derive(Debug, PartialEq, Eq)]
+pub(super) enum Replay<T> {
+    Events(Vec<(u64, T)>),
+    /// Some of the events after the id were evicted, or the id belongs to another stream
+    Evicted,
+}
+
+/// The last `capacity` events of a stream, broadcast to live subscribers as they are pushed.
+pub(super) struct ReplayBuffer<T> {
+    stream: String,
+    capacity: usize,
+    next_seq: u64,
+    events: VecDeque<(u64, T)>,
+    live: broadcast::Sender<(u64, T)>,
+}
+
+impl<T: Clone> ReplayBuffer<T> {
+    pub(super) fn new(stream: impl Into<String>, capacity: usize) -> Self {
+        Self {
+            stream: stream.into(),
+            capacity: capacity.max(1),
+            next_seq: 1,
+            events: VecDeque::new(),
+            live: broadcast::channel(LIVE_CAPACITY).0,
+        }
+    }
+
+    pub(super) fn stream(&self) -> &str {
+        &self.stream
+    }
+
+    /// Append an event, returning its sequence number.
+    pub(super) fn push(&mut self, event: T) -> u64 {
+        let seq = self.next_seq;
+        self.next_seq += 1;
+
+        self.events.push_back((seq, event.clone()));
+        while self.events.len() > self.capacity {
+            self.events.pop_front();
+        }
+
+        // no subscribers is fine, the event is still buffered
+        _ = self.live.send((seq, event));
+        seq
+    }
+
+    /// Events pushed after `seq`.
+    pub(super) fn since(&self, seq: u64) -> Replay<T> {
+        let oldest = self.events.front().map_or(self.next_seq, |(seq, _)| *seq);
+        if seq >= self.next_seq || seq + 1 < oldest {
+            return Replay::Evicted;
+        }
+
+        Replay::Events(
+            self.events
+                .iter()
+                .filter(|(s, _)| *s > seq)
+                .cloned()
+                .collect(),
+        )
+    }
+
+    /// Events pushed after `last`, or [`Replay::Evicted`] if it is from another stream.
+    pub(super) fn replay(&self, last: &EventId) -> Replay<T> {
+        if last.stream != self.stream {
+            return Replay::Evicted;
+        }
+
+        self.since(last.seq)
+    }
+
+    fn last_seq(&self) -> u64 {
+        self.next_seq - 1
+    }
+
+    /// Number of streams currently following this buffer, see [`follow`].
+    fn followers(&self) -> usize {
+        self.live.receiver_count()
+    }
+}
+
+pub(super) type SharedBuffer<T> = Arc<Mutex<ReplayBuffer<T>>>;
+
+/// Resolve once no stream has followed `buffer` for `grace`, such as when every client
+/// disconnected and none came back.
+///
+/// Followers are checked a few times per `grace`, so this resolves up to a quarter of it late.
+pub(super) async fn abandoned<T>(buffer: &SharedBuffer<T>, grace: Duration) {
+    let step = (grace / 4).max(Duration::from_millis(1));
+    let mut unfollowed = Duration::ZERO;
+
+    while unfollowed < grace {
+        tokio::time::sleep(step).await;
+        if buffer.lock().unwrap().followers() > 0 {
+            unfollowed = Duration::ZERO;
+        } else {
+            unfollowed += step;
+        }
+    }
+}
+
+/// An event of a followed stream, see [`follow`].
+#[derive(Debug, PartialEq, Eq)]
+pub(super) enum Followed<T> {
+    Event(EventId, T),
+    /// Events were lost, either before the client reconnected or while it was lagging behind
+    Restart,
+}
+
+/// Follow the events of `buffer`, starting after `last` if set, or with the next event
+/// otherwise.
+///
+/// The stream never ends on its own, callers stop when they see their final event.
+pub(super) fn follow<T>(
+    buffer: SharedBuffer<T>,
+    last: Option<EventId>,
+) -> impl Stream<Item = Followed<T>>
+where
+    T: Clone + Send + 'static,
+{
+    async_stream::stream! {
+        let (mut live, stream, replay, mut seen) = {
+            let buffer = buffer.lock().unwrap();
+            let replay = last.as_ref().map(|last| buffer.replay(last));
+            (
+                buffer.live.subscribe(),
+                buffer.stream().to_owned(),
+                replay,
+                buffer.last_seq(),
+            )
+        };
+        let id = |seq| EventId { stream: stream.clone(), seq };
+
+        match replay {
+            None => {}
+            Some(Replay::Evicted) => yield Followed::Restart,
+            Some(Replay::Events(events)) => {
+                for (seq, event) in events {
+                    yield Followed::Event(id(seq), event);
+                }
+            }
+        }
+
+        loop {
+            match live.recv().await {
+                Ok((seq, _)) if seq <= seen => {}
+                Ok((seq, event)) => {
+                    seen = seq;
+                    yield Followed::Event(id(seq), event);
+                }
+                Err(broadcast::error::RecvError::Lagged(_)) => {
+                    let replay = buffer.lock().unwrap().since(seen);
+                    match replay {
+                        Replay::Events(events) => {
+                            for (seq, event) in events {
+                                seen = seq;
+                                yield Followed::Event(id(seq), event);
+                            }
+                        }
+                        Replay::Evicted => {
+                            seen = buffer.lock().unwrap().last_seq();
+                            yield Followed::Restart;
+                        }
+                    }
+                }
+                Err(broadcast::error::RecvError::Closed) => break,
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::StreamExt;
+
+    fn ids(events: Replay<&str>) -> Vec<u64> {
+        match events {
+            Replay::Events(events) => events.into_iter().map(|(seq, _)| seq).collect(),
+            Replay::Evicted => panic!("unexpected eviction"),
+        }
+    }
+
+    #[test]
+    fn buffers_replay_events_until_evicted() {
+        let mut buffer = ReplayBuffer::new("index", 3);
+        for event in ["a", "b", "c", "d"] {
+            buffer.push(event);
+        }
+
+        assert_eq!(ids(buffer.since(1)), vec![2, 3, 4]);
+        assert_eq!(ids(buffer.since(3)), vec![4]);
+        assert_eq!(ids(buffer.since(4)), Vec::<u64>::new());
+
+        // event 1 was evicted, and 5 doesn't exist yet
+        assert_eq!(buffer.since(0), Replay::Evicted);
+        assert_eq!(buffer.since(5), Replay::Evicted);
+
+        let other = EventId {
+            stream: "answer".into(),
+            seq: 3,
+        };
+        assert_eq!(buffer.replay(&other), Replay::Evicted);
+    }
+
+    #[test]
+    fn event_ids_round_trip_through_headers() {
+        let id = EventId {
+            stream: "8f5d3ca2-1b3e-4c2e-9d0a-6b3f7e1c2d4a".into(),
+            seq: 12,
+        };
+
+        let mut headers = HeaderMap::new();
+        headers.insert("last-event-id", id.to_string().parse().unwrap());
+        assert_eq!(EventId::last(&headers), Some(id));
+
+        headers.insert("last-event-id", "12".parse().unwrap());
+        assert_eq!(EventId::last(&headers), None);
+    }
+
+    #[tokio::test]
+    async fn followers_resume_after_their_last_event() {
+        let buffer: SharedBuffer<&str> = Arc::new(Mutex::new(ReplayBuffer::new("index", 2)));
+        for event in ["a", "b", "c"] {
+            buffer.lock().unwrap().push(event);
+        }
+
+        let id = |seq| EventId {
+            stream: "index".into(),
+            seq,
+        };
+
+        let resumed = follow(buffer.clone(), Some(id(2)));
+        let evicted = follow(buffer.clone(), Some(id(0)));
+        futures::pin_mut!(resumed, evicted);
+
+        assert_eq!(resumed.next().await, Some(Followed::Event(id(3), "c")));
+        assert_eq!(evicted.next().await, Some(Followed::Restart));
+
+        buffer.lock().unwrap().push("d");
+        assert_eq!(resumed.next().await, Some(Followed::Event(id(4), "d")));
+        assert_eq!(evicted.next().await, Some(Followed::Event(id(4), "d")));
+    }
+
+    #[tokio::test]
+    async fn buffers_are_abandoned_once_nobody_follows_them() {
+        let buffer: SharedBuffer<&str> = Arc::new(Mutex::new(ReplayBuffer::new("answer", 2)));
+        let grace = Duration::from_millis(40);
+
+        let follower = buffer.lock().unwrap().live.subscribe();
+        let watched = tokio::time::timeout(grace * 3, abandoned(&buffer, grace)).await;
+        assert!(watched.is_err());
+
+        drop(follower);
+        let unwatched = tokio::time::timeout(grace * 3, abandoned(&buffer, grace)).await;
+        assert!(unwatched.is_ok());
+    }
+}
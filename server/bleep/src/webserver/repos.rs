@@ -1,21 +1,38 @@
-use std::{collections::HashSet, hash::Hash, time::Duration};
+use std::{
+    collections::{HashMap, HashSet},
+    hash::Hash,
+    sync::Mutex,
+};
 
 use crate::{
-    repo::{Backend, RepoRef, Repository, SyncStatus},
+    background::RunState,
+    indexes::{DryRunReport, Progress},
+    repo::{Backend, LanguageCount, RepoRef, Repository, SyncStatus},
+    semantic::{
+        retry::{ChunkFailure, FailureCounts, RetryReport},
+        Semantic,
+    },
     state::RepositoryPool,
     Application,
 };
 use axum::{
-    extract::{Path, Query},
-    http::StatusCode,
+    extract::{Path, Query, State},
+    http::{HeaderMap, StatusCode},
     response::{sse, IntoResponse, Sse},
     Extension, Json,
 };
 use chrono::{DateTime, NaiveDateTime, Utc};
+use futures::StreamExt;
 use serde::{Deserialize, Serialize};
+use tokio::sync::broadcast::error::RecvError;
+use tracing::warn;
 use utoipa::{IntoParams, ToSchema};
 
-use super::prelude::*;
+use super::{
+    embeddings,
+    prelude::*,
+    replay::{self, EventId, Followed, ReplayBuffer, SharedBuffer},
+};
 
 #[derive(Serialize, ToSchema, Debug, Eq)]
 pub(super) struct Repo {
@@ -28,6 +45,11 @@ pub(super) struct Repo {
     pub(super) last_update: DateTime<Utc>,
     pub(super) last_index: Option<DateTime<Utc>>,
     pub(super) most_common_lang: Option<String>,
+    /// Pinned repositories are never evicted to save disk space
+    pub(super) pinned: bool,
+    /// Chunks missing from the semantic index, if there is one
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(super) chunk_failures: Option<FailureCounts>,
 }
 
 impl From<(&RepoRef, &Repository)> for Repo {
@@ -52,6 +74,8 @@ impl From<(&RepoRef, &Repository)> for Repo {
                 ),
             },
             most_common_lang: repo.most_common_lang.clone(),
+            pinned: repo.pinned,
+            chunk_failures: None,
         }
     }
 }
@@ -71,8 +95,19 @@ impl Repo {
             last_update: origin.pushed_at.unwrap(),
             last_index: None,
             most_common_lang: None,
+            pinned: false,
+            chunk_failures: None,
         }
     }
+
+    /// Attach the chunk failure counts of the repository, if there is a semantic index.
+    fn with_chunk_failures(mut self, app: &Application) -> Self {
+        self.chunk_failures = app
+            .semantic
+            .as_ref()
+            .map(|semantic| semantic.failures().counts(&self.repo_ref.to_string()));
+        self
+    }
 }
 
 impl Hash for Repo {
@@ -92,14 +127,110 @@ impl PartialEq for Repo {
 pub(super) enum ReposResponse {
     List(Vec<Repo>),
     Item(Repo),
+    Languages(LanguageStats),
+    IndexRun(Option<RunState>),
     SyncQueued,
+    DryRun(DryRunReport),
+    Failures(Vec<ChunkFailure>),
+    Retried(RetryReport),
     Deleted,
 }
 
 impl super::ApiResponse for ReposResponse {}
 
+#[derive(Serialize, ToSchema, Debug, PartialEq)]
+pub(super) struct LanguageStats {
+    /// Languages sorted by indexed bytes, largest first
+    languages: Vec<LanguageShare>,
+    total: LanguageCount,
+}
+
+#[derive(Serialize, ToSchema, Debug, PartialEq)]
+pub(super) struct LanguageShare {
+    /// Lowercase language name, or `other` if it was not detected
+    lang: String,
+    #[serde(flatten)]
+    count: LanguageCount,
+    /// Percentage of indexed bytes in this language
+    bytes_percent: f64,
+    /// Percentage of chunks in this language
+    chunks_percent: f64,
+}
+
+impl LanguageStats {
+    fn new(stats: &HashMap<String, LanguageCount>) -> Self {
+        let total = stats
+            .values()
+            .fold(LanguageCount::default(), |acc, c| LanguageCount {
+                files: acc.files + c.files,
+                bytes: acc.bytes + c.bytes,
+                chunks: acc.chunks + c.chunks,
+            });
+
+        let percent = |part: usize, whole: usize| match whole {
+            0 => 0.0,
+            whole => part as f64 * 100.0 / whole as f64,
+        };
+
+        let mut languages = stats
+            .iter()
+            .map(|(lang, count)| LanguageShare {
+                lang: lang.clone(),
+                count: *count,
+                bytes_percent: percent(count.bytes, total.bytes),
+                chunks_percent: percent(count.chunks, total.chunks),
+            })
+            .collect::<Vec<_>>();
+
+        languages.sort_by(|a, b| {
+            b.count
+                .bytes
+                .cmp(&a.count.bytes)
+                .then_with(|| a.lang.cmp(&b.lang))
+        });
+
+        Self { languages, total }
+    }
+}
+
+/// Index progress events, kept for clients resuming the index status stream.
+pub(super) struct IndexStatus {
+    events: SharedBuffer<Progress>,
+}
+
+impl IndexStatus {
+    /// Start recording the progress events of `app`.
+    pub(super) fn start(app: &Application) -> Self {
+        let events: SharedBuffer<Progress> = Arc::new(Mutex::new(ReplayBuffer::new(
+            uuid::Uuid::new_v4().to_string(),
+            app.config.index_status_replay_events,
+        )));
+
+        let mut receiver = app.indexes.subscribe();
+        let buffer = Arc::clone(&events);
+        tokio::spawn(async move {
+            loop {
+                match receiver.recv().await {
+                    Ok(event) => {
+                        buffer.lock().unwrap().push(event);
+                    }
+                    Err(RecvError::Lagged(skipped)) => {
+                        warn!(skipped, "index status events dropped before being recorded")
+                    }
+                    Err(RecvError::Closed) => break,
+                }
+            }
+        });
+
+        Self { events }
+    }
+}
+
 /// Get a stream of status notifications about the indexing of each repository
 /// This endpoint opens an SSE stream
+///
+/// Clients reconnecting with a `Last-Event-ID` header get the events they missed, or a
+/// `restart` event if these are no longer buffered, before live events.
 //
 #[utoipa::path(get, path = "/repos/index-status",
     responses(
@@ -108,21 +239,23 @@ impl super::ApiResponse for ReposResponse {}
         (status = 500, description = "Server error", body = EndpointError),
     ),
 )]
-pub(super) async fn index_status(Extension(app): Extension<Application>) -> impl IntoResponse {
-    let mut receiver = app.indexes.subscribe();
-
-    Sse::new(async_stream::stream! {
-        while let Ok(event) = receiver.recv().await {
-            yield sse::Event::default().json_data(event).map_err(|err| {
-                <_ as Into<Box<dyn std::error::Error + Send + Sync>>>::into(err)
-            });
+pub(super) async fn index_status(
+    State(status): State<Arc<IndexStatus>>,
+    Extension(app): Extension<Application>,
+    headers: HeaderMap,
+) -> impl IntoResponse {
+    let events = replay::follow(Arc::clone(&status.events), EventId::last(&headers));
+
+    Sse::new(events.map(|event| {
+        match event {
+            Followed::Event(id, progress) => sse::Event::default()
+                .id(id.to_string())
+                .json_data(progress)
+                .map_err(<_ as Into<Box<dyn std::error::Error + Send + Sync>>>::into),
+            Followed::Restart => Ok(replay::restart()),
         }
-    })
-    .keep_alive(
-        sse::KeepAlive::new()
-            .interval(Duration::from_secs(5))
-            .event(sse::Event::default().event("heartbeat")),
-    )
+    }))
+    .keep_alive(replay::keep_alive(&app.config))
 }
 
 /// Retrieve all indexed repositories
@@ -137,13 +270,20 @@ pub(super) async fn index_status(Extension(app): Extension<Application>) -> impl
 pub(super) async fn indexed(Extension(app): Extension<Application>) -> impl IntoResponse {
     let mut repos = vec![];
     app.repo_pool
-        .scan_async(|k, v| repos.push(Repo::from((k, v))))
+        .scan_async(|k, v| repos.push(Repo::from((k, v)).with_chunk_failures(&app)))
         .await;
 
     json(ReposResponse::List(repos))
 }
 
+/// The repository at the wildcard `path` of a route.
+fn repo_at(app: &Application, path: Vec<String>) -> Result<RepoRef> {
+    RepoRef::from_components(&app.config.source.directory(), path)
+        .map_err(|_| Error::new(ErrorKind::NotFound, "Can't find repository"))
+}
+
 /// Get details of an indexed repository based on their id
+//
 #[utoipa::path(get, path = "/repos/indexed/:ref",
     responses(
         (status = 200, description = "Execute query successfully", body = Response),
@@ -155,14 +295,14 @@ pub(super) async fn get_by_id(
     Path(path): Path<Vec<String>>,
     Extension(app): Extension<Application>,
 ) -> Result<impl IntoResponse> {
-    let Ok(reporef) = RepoRef::from_components(&app.config.source.directory(), path) else {
-        return Err(Error::new(ErrorKind::NotFound, "Can't find repository"));
-    };
+    let reporef = repo_at(&app, path)?;
 
     match app
         .repo_pool
         .read_async(&reporef, |k, v| {
-            json(ReposResponse::Item(Repo::from((k, v))))
+            json(ReposResponse::Item(
+                Repo::from((k, v)).with_chunk_failures(&app),
+            ))
         })
         .await
     {
@@ -171,6 +311,274 @@ pub(super) async fn get_by_id(
     }
 }
 
+/// Get the indexed language statistics of a repository
+//
+#[utoipa::path(get, path = "/repos/languages/:ref",
+    responses(
+        (status = 200, description = "Execute query successfully", body = Response),
+        (status = 404, description = "Repository not found", body = EndpointError),
+        (status = 500, description = "Server error", body = EndpointError),
+    ),
+)]
+pub(super) async fn languages(
+    Path(path): Path<Vec<String>>,
+    Extension(app): Extension<Application>,
+) -> Result<impl IntoResponse> {
+    let reporef = repo_at(&app, path)?;
+
+    app.repo_pool
+        .read_async(&reporef, |_, v| {
+            json(ReposResponse::Languages(LanguageStats::new(&v.lang_stats)))
+        })
+        .await
+        .ok_or_else(|| Error::new(ErrorKind::NotFound, "Can't find repository"))
+}
+
+/// Get the sync & index run of a repository currently in progress, if any
+//
+#[utoipa::path(get, path = "/repos/index-run/:ref",
+    responses(
+        (status = 200, description = "Execute query successfully", body = Response),
+        (status = 404, description = "Repository not found", body = EndpointError),
+        (status = 500, description = "Server error", body = EndpointError),
+    ),
+)]
+pub(super) async fn index_run(
+    Path(path): Path<Vec<String>>,
+    Extension(app): Extension<Application>,
+) -> Result<impl IntoResponse> {
+    let reporef = repo_at(&app, path)?;
+
+    app.repo_pool
+        .read_async(&reporef, |k, _| {
+            json(ReposResponse::IndexRun(app.index_runs.get(k)))
+        })
+        .await
+        .ok_or_else(|| Error::new(ErrorKind::NotFound, "Can't find repository"))
+}
+
+/// Stream the embeddings of every chunk of a repository, see `embeddings::ExportFormat`
+//
+#[utoipa::path(get, path = "/repos/embeddings/:ref",
+    responses(
+        (status = 200, description = "Execute query successfully"),
+        (status = 404, description = "Repository not found", body = EndpointError),
+        (status = 500, description = "Server error", body = EndpointError),
+    ),
+)]
+pub(super) async fn export_embeddings(
+    Path(path): Path<Vec<String>>,
+    Query(export): Query<embeddings::ExportParams>,
+    Extension(app): Extension<Application>,
+) -> Result<axum::response::Response> {
+    let reporef = repo_at(&app, path)?;
+    if !app.repo_pool.contains_async(&reporef).await {
+        return Err(Error::new(ErrorKind::NotFound, "Can't find repository"));
+    }
+
+    embeddings::export(&app, &reporef, export).await
+}
+
+/// List the chunks of a repository that failed to make it into the semantic index, including
+/// the ones that are no longer retried
+//
+#[utoipa::path(get, path = "/repos/failures/:ref",
+    responses(
+        (status = 200, description = "Execute query successfully", body = Response),
+        (status = 404, description = "Repository not found", body = EndpointError),
+        (status = 500, description = "Server error", body = EndpointError),
+    ),
+)]
+pub(super) async fn failures(
+    Path(path): Path<Vec<String>>,
+    Extension(app): Extension<Application>,
+) -> Result<impl IntoResponse> {
+    let reporef = repo_at(&app, path)?;
+    let semantic = indexed_semantic(&app, &reporef).await?;
+    let failures = semantic.failures().list(&reporef.to_string());
+    Ok(json(ReposResponse::Failures(failures)))
+}
+
+/// Retry every chunk of a repository that failed to make it into the semantic index
+/// immediately, including the ones that are no longer retried automatically
+//
+#[utoipa::path(post, path = "/repos/failures/:ref",
+    responses(
+        (status = 200, description = "Execute query successfully", body = Response),
+        (status = 404, description = "Repository not found", body = EndpointError),
+        (status = 500, description = "Server error", body = EndpointError),
+    ),
+)]
+pub(super) async fn retry_failures(
+    Path(path): Path<Vec<String>>,
+    Extension(app): Extension<Application>,
+) -> Result<impl IntoResponse> {
+    let reporef = repo_at(&app, path)?;
+    let semantic = indexed_semantic(&app, &reporef).await?;
+    let report = semantic
+        .retry_failures(&reporef.to_string(), true)
+        .await
+        .map_err(Error::internal)?;
+
+    Ok(json(ReposResponse::Retried(report)))
+}
+
+/// Queue an evicted repository to be cloned and indexed again
+//
+#[utoipa::path(post, path = "/repos/restore/:ref",
+    responses(
+        (status = 200, description = "Execute query successfully", body = Response),
+        (status = 404, description = "Repository not found", body = EndpointError),
+        (status = 409, description = "Repository is not evicted", body = EndpointError),
+        (status = 500, description = "Server error", body = EndpointError),
+    ),
+)]
+pub(super) async fn restore_by_id(
+    Path(path): Path<Vec<String>>,
+    Extension(app): Extension<Application>,
+) -> Result<impl IntoResponse> {
+    let reporef = repo_at(&app, path)?;
+    restore(&app, reporef).await
+}
+
+/// Keep a repository from being evicted to save disk space
+//
+#[utoipa::path(post, path = "/repos/pin/:ref",
+    responses(
+        (status = 200, description = "Execute query successfully", body = Response),
+        (status = 404, description = "Repository not found", body = EndpointError),
+        (status = 500, description = "Server error", body = EndpointError),
+    ),
+)]
+pub(super) async fn pin_by_id(
+    Path(path): Path<Vec<String>>,
+    Extension(app): Extension<Application>,
+) -> Result<impl IntoResponse> {
+    let reporef = repo_at(&app, path)?;
+    pin(&app, &reporef, true).await
+}
+
+/// Let a repository be evicted to save disk space again
+//
+#[utoipa::path(post, path = "/repos/unpin/:ref",
+    responses(
+        (status = 200, description = "Execute query successfully", body = Response),
+        (status = 404, description = "Repository not found", body = EndpointError),
+        (status = 500, description = "Server error", body = EndpointError),
+    ),
+)]
+pub(super) async fn unpin_by_id(
+    Path(path): Path<Vec<String>>,
+    Extension(app): Extension<Application>,
+) -> Result<impl IntoResponse> {
+    let reporef = repo_at(&app, path)?;
+    pin(&app, &reporef, false).await
+}
+
+/// Queue an evicted repository to be cloned and indexed again.
+async fn restore(app: &Application, reporef: RepoRef) -> Result<Json<super::Response<'static>>> {
+    let restored = app
+        .repo_pool
+        .update_async(&reporef, |_, repo| {
+            let evicted = repo.sync_status == SyncStatus::Evicted;
+            if evicted {
+                repo.mark_queued();
+            }
+            evicted
+        })
+        .await;
+
+    match restored {
+        Some(true) => {
+            app.write_index().queue_sync_and_index(vec![reporef]);
+            Ok(json(ReposResponse::SyncQueued))
+        }
+        Some(false) => Err(Error::user("repository is not evicted, sync it instead")
+            .with_status(StatusCode::CONFLICT)),
+        None => Err(Error::new(ErrorKind::NotFound, "Can't find repository")),
+    }
+}
+
+/// Set whether a repository may be evicted to save disk space.
+async fn pin(
+    app: &Application,
+    reporef: &RepoRef,
+    pinned: bool,
+) -> Result<Json<super::Response<'static>>> {
+    let Some(repo) = app
+        .repo_pool
+        .update_async(reporef, |k, repo| {
+            repo.pinned = pinned;
+            Repo::from((k, &*repo))
+        })
+        .await
+    else {
+        return Err(Error::new(ErrorKind::NotFound, "Can't find repository"));
+    };
+
+    app.config
+        .source
+        .save_pool(app.repo_pool.clone())
+        .map_err(Error::internal)?;
+
+    Ok(json(ReposResponse::Item(repo)))
+}
+
+/// Reject searches of repositories that were evicted, which have nothing left to search.
+///
+/// `names` are repository names as given in `repo:` filters, or full repository refs.
+pub(super) async fn reject_evicted<'a>(
+    app: &Application,
+    names: impl IntoIterator<Item = &'a str>,
+) -> Result<()> {
+    let names = names.into_iter().collect::<HashSet<_>>();
+    if names.is_empty() {
+        return Ok(());
+    }
+
+    let mut evicted = vec![];
+    app.repo_pool
+        .scan_async(|k, v| {
+            if v.sync_status == SyncStatus::Evicted {
+                evicted.push(k.clone());
+            }
+        })
+        .await;
+
+    match first_evicted(&names, &evicted) {
+        Some(reporef) => Err(evicted_error(reporef)),
+        None => Ok(()),
+    }
+}
+
+fn evicted_error(reporef: &RepoRef) -> Error {
+    Error::user(format!(
+        "repository `{}` was evicted to save disk space and must be re-indexed, \
+         restore it with `POST /repos/restore/{reporef}`",
+        reporef.display_name()
+    ))
+    .with_status(StatusCode::CONFLICT)
+    .with_code(ErrorCode::RepoEvicted)
+}
+
+fn first_evicted<'a>(names: &HashSet<&str>, evicted: &'a [RepoRef]) -> Option<&'a RepoRef> {
+    evicted.iter().find(|reporef| {
+        names.contains(reporef.indexed_name().as_str())
+            || names.contains(reporef.to_string().as_str())
+    })
+}
+
+/// The semantic index, provided `reporef` is in the pool.
+async fn indexed_semantic(app: &Application, reporef: &RepoRef) -> Result<Semantic> {
+    if !app.repo_pool.contains_async(reporef).await {
+        return Err(Error::new(ErrorKind::NotFound, "Can't find repository"));
+    }
+
+    app.semantic
+        .clone()
+        .ok_or_else(|| Error::new(ErrorKind::Configuration, "Qdrant not configured"))
+}
+
 /// Delete a repository from the disk and any indexes
 //
 #[utoipa::path(delete, path = "/repos/indexed/:ref",
@@ -184,9 +592,7 @@ pub(super) async fn delete_by_id(
     Path(path): Path<Vec<String>>,
     Extension(app): Extension<Application>,
 ) -> impl IntoResponse {
-    let Ok(reporef) = RepoRef::from_components(&app.config.source.directory(), path) else {
-        return Err(Error::new(ErrorKind::NotFound, "Can't find repository"));
-    };
+    let reporef = repo_at(&app, path)?;
 
     match app
         .repo_pool
@@ -201,22 +607,50 @@ pub(super) async fn delete_by_id(
     }
 }
 
+#[derive(Deserialize, IntoParams)]
+pub(super) struct SyncParams {
+    /// Report what indexing would do instead, without syncing or writing to any index
+    #[serde(default)]
+    dry_run: bool,
+}
+
 /// Synchronize a repo by its id
+///
+/// With `dry_run`, the repository is scanned and chunked in place, and the response is sent once
+/// the scan is complete. Progress is reported on `/repos/status` as for a regular run.
 #[utoipa::path(get, path = "/repos/sync/:ref",
+    params(SyncParams),
     responses(
         (status = 200, description = "Execute query successfully", body = Response),
         (status = 400, description = "Bad request", body = EndpointError),
+        (status = 409, description = "Repository is already being indexed", body = EndpointError),
         (status = 500, description = "Server error", body = EndpointError),
     ),
 )]
 pub(super) async fn sync(
     Path(path): Path<Vec<String>>,
+    Query(params): Query<SyncParams>,
     Extension(app): Extension<Application>,
 ) -> impl IntoResponse {
     let Ok(reporef) = RepoRef::from_components(&app.config.source.directory(), path) else {
         return Err(Error::new(ErrorKind::NotFound, "Can't find repository"));
     };
 
+    if params.dry_run {
+        let report = app.dry_run(&reporef).await.map_err(Error::user)?;
+        return Ok(json(ReposResponse::DryRun(report)));
+    }
+
+    if app.config.reject_concurrent_sync {
+        if let Some(run) = app.index_runs.get(&reporef) {
+            return Err(Error::user(format!(
+                "repository is already being indexed by run {}",
+                run.run_id
+            ))
+            .with_status(StatusCode::CONFLICT));
+        }
+    }
+
     app.write_index().queue_sync_and_index(vec![reporef]);
     Ok(json(ReposResponse::SyncQueued))
 }
@@ -361,11 +795,86 @@ async fn list_unique_repos(repo_pool: RepositoryPool, other: HashSet<Repo>) -> V
 
 #[cfg(test)]
 mod test {
-    use std::collections::HashSet;
+    use std::{
+        collections::{HashMap, HashSet},
+        path::Path,
+    };
+
+    use crate::repo::{
+        GitProtocol, GitRemote, LanguageCount, RepoRef, RepoRemote::Git, Repository, SyncStatus,
+    };
 
-    use crate::repo::{GitProtocol, GitRemote, RepoRef, RepoRemote::Git, Repository, SyncStatus};
+    use super::{
+        evicted_error, first_evicted, list_unique_repos, ErrorCode, LanguageStats, Repo,
+        RepositoryPool, StatusCode,
+    };
 
-    use super::{list_unique_repos, Repo, RepositoryPool};
+    #[test]
+    fn repos_named_like_resources_are_addressable() {
+        // sub-resources are routed by prefix, so the whole path is the repository
+        for name in ["languages", "failures", "pin", "restore", "embeddings"] {
+            let path = vec![format!("github.com/org/{name}")];
+            assert_eq!(
+                RepoRef::from_components(Path::new("/"), path).unwrap(),
+                RepoRef::try_from(format!("github.com/org/{name}").as_str()).unwrap()
+            );
+        }
+    }
+
+    #[test]
+    fn evicted_repos_match_filters_by_name_or_ref() {
+        let evicted = [
+            RepoRef::try_from("github.com/org/old").unwrap(),
+            RepoRef::try_from("local//code/scratch").unwrap(),
+        ];
+
+        let find = |names: &[&str]| first_evicted(&names.iter().copied().collect(), &evicted);
+        assert_eq!(find(&["github.com/org/old"]), Some(&evicted[0]));
+        assert_eq!(find(&["github.com/org/new", "scratch"]), Some(&evicted[1]));
+        assert_eq!(find(&["local//code/scratch"]), Some(&evicted[1]));
+        assert_eq!(find(&["github.com/org/new", "org/old"]), None);
+
+        let err = evicted_error(&evicted[0]);
+        assert_eq!(err.status, StatusCode::CONFLICT);
+        assert_eq!(err.code(), Some(ErrorCode::RepoEvicted));
+    }
+
+    #[test]
+    fn language_stats_sorted_with_percentages() {
+        let stats = HashMap::from([
+            (
+                "rust".to_owned(),
+                LanguageCount {
+                    files: 2,
+                    bytes: 300,
+                    chunks: 6,
+                },
+            ),
+            (
+                "other".to_owned(),
+                LanguageCount {
+                    files: 1,
+                    bytes: 100,
+                    chunks: 2,
+                },
+            ),
+        ]);
+
+        let stats = LanguageStats::new(&stats);
+        assert_eq!(
+            stats.total,
+            LanguageCount {
+                files: 3,
+                bytes: 400,
+                chunks: 8,
+            }
+        );
+        assert_eq!(stats.languages[0].lang, "rust");
+        assert_eq!(stats.languages[0].bytes_percent, 75.0);
+        assert_eq!(stats.languages[1].lang, "other");
+        assert_eq!(stats.languages[1].chunks_percent, 25.0);
+        assert_eq!(LanguageStats::new(&HashMap::new()).languages, vec![]);
+    }
 
     #[tokio::test]
     async fn unique_repos_only() {
@@ -382,8 +891,12 @@ mod test {
                     }),
                     sync_status: SyncStatus::Done,
                     last_commit_unix_secs: 123456,
+                    indexed_commit: None,
                     last_index_unix_secs: 123456,
                     most_common_lang: None,
+                    lang_stats: Default::default(),
+                    pinned: false,
+                    usage: Default::default(),
                 },
             )
             .unwrap();
@@ -399,8 +912,12 @@ mod test {
                     }),
                     sync_status: SyncStatus::Done,
                     last_commit_unix_secs: 123456,
+                    indexed_commit: None,
                     last_index_unix_secs: 123456,
                     most_common_lang: None,
+                    lang_stats: Default::default(),
+                    pinned: false,
+                    usage: Default::default(),
                 },
             )
             .unwrap();
@@ -418,8 +935,12 @@ mod test {
                     }),
                     sync_status: SyncStatus::Uninitialized,
                     last_commit_unix_secs: 123456,
+                    indexed_commit: None,
                     last_index_unix_secs: 0,
                     most_common_lang: None,
+                    lang_stats: Default::default(),
+                    pinned: false,
+                    usage: Default::default(),
                 },
             )
                 .into(),
@@ -436,8 +957,12 @@ mod test {
                 }),
                 sync_status: SyncStatus::Uninitialized,
                 last_commit_unix_secs: 123456,
+                indexed_commit: None,
                 last_index_unix_secs: 0,
                 most_common_lang: None,
+                lang_stats: Default::default(),
+                pinned: false,
+                usage: Default::default(),
             },
         )
             .into();
@@ -0,0 +1,99 @@
+use super::{middleware::User, prelude::*};
+use crate::{
+    history::{SearchEntry, TopSearch},
+    Application,
+};
+
+const SECS_PER_DAY: u64 = 60 * 60 * 24;
+
+fn default_recent_limit() -> usize {
+    10
+}
+
+fn default_top_limit() -> usize {
+    50
+}
+
+fn default_days() -> u64 {
+    7
+}
+
+#[derive(Deserialize)]
+pub(super) struct RecentParams {
+    #[serde(default = "default_recent_limit")]
+    limit: usize,
+}
+
+#[derive(Deserialize)]
+pub(super) struct TopParams {
+    #[serde(default = "default_days")]
+    days: u64,
+    #[serde(default = "default_top_limit")]
+    limit: usize,
+}
+
+#[derive(Serialize)]
+pub(super) struct RecentSearches {
+    searches: Vec<SearchEntry>,
+}
+
+impl super::ApiResponse for RecentSearches {}
+
+#[derive(Serialize)]
+pub(super) struct TopSearches {
+    searches: Vec<TopSearch>,
+}
+
+impl super::ApiResponse for TopSearches {}
+
+/// The caller's own most recent distinct searches, most recent first
+//
+#[utoipa::path(get, path = "/searches/recent",
+    responses(
+        (status = 200, description = "Execute query successfully", body = RecentSearches),
+        (status = 400, description = "Bad request", body = EndpointError),
+        (status = 500, description = "Server error", body = EndpointError),
+    ),
+)]
+pub(super) async fn recent(
+    Query(params): Query<RecentParams>,
+    Extension(app): Extension<Application>,
+    Extension(user): Extension<User>,
+) -> impl IntoResponse {
+    let searches = app
+        .search_history
+        .as_ref()
+        .map(|history| history.recent(user.0.as_deref(), params.limit))
+        .unwrap_or_default();
+
+    json(RecentSearches { searches })
+}
+
+/// The most frequent searches across all users over the last `days` days
+//
+#[utoipa::path(get, path = "/admin/searches/top",
+    responses(
+        (status = 200, description = "Execute query successfully", body = TopSearches),
+        (status = 400, description = "Bad request", body = EndpointError),
+        (status = 403, description = "Forbidden", body = EndpointError),
+        (status = 500, description = "Server error", body = EndpointError),
+    ),
+)]
+pub(super) async fn top(
+    Query(params): Query<TopParams>,
+    Extension(app): Extension<Application>,
+) -> impl IntoResponse {
+    let since = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap()
+        .as_secs()
+        .saturating_sub(params.days.saturating_mul(SECS_PER_DAY));
+
+    let searches = app
+        .search_history
+        .as_ref()
+        .map(|history| history.top(since, params.limit))
+        .unwrap_or_default();
+
+    json(TopSearches { searches })
+}
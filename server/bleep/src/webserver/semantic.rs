@@ -1,65 +1,1613 @@
-use super::prelude::*;
-use crate::{query::parser, semantic::Semantic};
-use tracing::error;
+use super::{
+    answer::{candidate_count, snippet_from_payload, Snippet, SnippetSource, Widening},
+    blame::Blamer,
+    middleware::User,
+    prelude::*,
+    replay, workspaces,
+};
+use crate::{
+    cache::{BoundedCache, CachePolicy},
+    history::SearchEntry,
+    query::parser,
+    repo::{relative_path_variants, RepoRef, OTHER_LANG},
+    semantic::{
+        breaker::BreakerOpen,
+        filter::{FilterArgs, FilterLogic},
+        kind::ChunkKind,
+        limit::EmbedTimeout,
+        payload::{PayloadFields, PayloadSchema, CHUNK_FIELDS},
+        trace::{elapsed_ms, SearchTrace},
+        weights::{VectorWeights, BODY_VECTOR},
+        CollectionStats, Semantic,
+    },
+    state::SCHEMA_VERSION,
+    Application, Configuration,
+};
+use axum::{
+    extract::{Path, State},
+    http::{header::CACHE_CONTROL, HeaderMap, HeaderValue},
+    response::{Response, Sse},
+    Json,
+};
+use tracing::{error, warn};
 
-use qdrant_client::qdrant::value::Kind;
-use std::collections::HashMap;
+use futures::future;
+
+mod context;
+mod progress;
+
+use context::ContextMode;
+use progress::{Progress, Reporter};
+use qdrant_client::qdrant::{
+    value::Kind, vectors::VectorsOptions, PointId, PointStruct, RetrievedPoint, ScoredPoint,
+};
+use std::{
+    collections::{BTreeMap, HashMap},
+    future::Future,
+    time::{Duration, Instant},
+};
 
 #[derive(Deserialize)]
 pub(super) struct Args {
     limit: u64,
     query: String,
+    /// How filters on different fields are combined, `and` by default
+    #[serde(default)]
+    filter_logic: FilterLogic,
+    /// Weight of the code body vector, for multi-vector collections
+    #[serde(default = "VectorWeights::default_body_weight")]
+    body_weight: f32,
+    /// Weight of the docstring vector, for multi-vector collections
+    #[serde(default)]
+    doc_weight: f32,
+    /// Only search the repositories of this workspace
+    workspace: Option<String>,
+    /// Report the languages of the candidate chunks in `facets`
+    #[serde(default)]
+    with_facets: bool,
+    /// Only return chunks of this kind, e.g. `definition`. Chunks indexed before kinds were
+    /// recorded never match.
+    kind: Option<ChunkKind>,
+    /// Return byte-identical responses for the same query against an unchanged index, for
+    /// evaluating search quality, off by default.
+    ///
+    /// This runs an exact search over every chunk matching the filters instead of an HNSW
+    /// search, embeds the query outside of the batching queue, and refetches the collection
+    /// stats. Exact search slows down linearly with the number of matching chunks, so on large
+    /// collections these queries are much slower, and they should not be used interactively.
+    /// They bypass every cache, like requests with `no_store`.
+    deterministic: Option<bool>,
+    /// Payload fields to return besides the location and text of each chunk, see [`FieldSet`]
+    fields: Option<FieldSet>,
+    /// Report how the search ran in `diagnostics`, off by default
+    #[serde(default)]
+    explain: bool,
+    /// Attribute each chunk to the latest commit touching its lines, in `last_author`,
+    /// `last_commit` and `last_modified`, off by default. These are `null` for repositories
+    /// without git history, notebook cells, and chunks whose blame timed out.
+    #[serde(default)]
+    include_blame: bool,
+    /// Strip trailing whitespace and collapse runs of blank lines in the `snippet` of each
+    /// chunk, for compact display, off by default.
+    ///
+    /// Line and byte bounds still refer to the indexed source, so they no longer line up with
+    /// the collapsed snippet.
+    collapse_whitespace: Option<bool>,
+    /// Return the surroundings of each chunk in its file in `context`, as `lines:N` or
+    /// `bytes:N` around it, or `enclosing_symbol` for the definition enclosing it. Notebook
+    /// cells get no context.
+    context: Option<ContextMode>,
+    /// Report the size of the file of each chunk, in `file_line_count` and `file_byte_count`,
+    /// off by default. These are `null` for chunks of files that are no longer indexed.
+    with_file_stats: Option<bool>,
+    /// Drop chunks whose raw Qdrant score is below this, see `Snippet::score`
+    min_score: Option<f32>,
+    /// Respond with `204 No Content` instead of an empty list when no chunk qualifies, off by
+    /// default
+    strict_empty: Option<bool>,
+    /// Comma-separated repository refs whose chunks have their score multiplied by
+    /// `Configuration::repo_boost`, ranking them above slightly closer chunks of other
+    /// repositories without filtering those out.
+    ///
+    /// Boosted scores are returned as the `score` of each chunk, and are compared against
+    /// `min_score` unboosted. More candidates are fetched than `limit`, so chunks of a boosted
+    /// repository can overtake ones that would otherwise have been returned. There is no cap on
+    /// the chunks returned per repository, so a boosted repository may fill every result.
+    #[serde(default, deserialize_with = "comma_separated")]
+    boost_repos: Option<Vec<String>>,
+    /// Return the files of the matching chunks in `files` instead of the chunks themselves, off
+    /// by default. See [`FileMatch`].
+    ///
+    /// `limit` caps the files returned, which are picked from several candidate chunks per file.
+    /// The text of the chunks is not fetched, so `fields` and `context` can't be combined with
+    /// this.
+    files_only: Option<bool>,
+    /// Return the matching chunks bucketed by repository in `repos`, instead of in `chunks`, off
+    /// by default. See [`RepoGroup`].
+    ///
+    /// `limit` caps the repositories returned, and `max_per_repo` the chunks of each. This can't
+    /// be combined with `files_only`.
+    group_by_repo: Option<bool>,
+    /// Most chunks returned per repository with `group_by_repo`, 3 by default
+    max_per_repo: Option<usize>,
+    /// Compute the response afresh, without serving any cached query parse, query embedding or
+    /// response, off by default. Like a `Cache-Control: no-cache` header, what is computed is
+    /// still cached for later requests.
+    no_cache: Option<bool>,
+    /// Like `no_cache`, but without caching what is computed either, off by default. Like a
+    /// `Cache-Control: no-store` header, this implies `no_cache`.
+    no_store: Option<bool>,
 }
 
-#[derive(Serialize)]
+/// A comma-separated list, with empty items dropped.
+fn comma_separated<'de, D: serde::Deserializer<'de>>(
+    deserializer: D,
+) -> Result<Option<Vec<String>>, D::Error> {
+    let list = Option::<String>::deserialize(deserializer)?;
+    Ok(list.map(|list| {
+        list.split(',')
+            .map(str::trim)
+            .filter(|item| !item.is_empty())
+            .map(ToOwned::to_owned)
+            .collect()
+    }))
+}
+
+/// The fields returned with each chunk of a search.
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize)]
+#[serde(from = "String")]
+pub(super) enum FieldSet {
+    /// `summary`, only the location of each chunk and its `score`, for clients that merely rank
+    /// chunks. The text of the chunks is not fetched at all.
+    Summary,
+    /// `*`, the whole payload
+    All,
+    /// The location and text of each chunk, along with these comma-separated payload fields,
+    /// such as `kind,definitions`
+    Listed(String),
+}
+
+impl From<String> for FieldSet {
+    fn from(input: String) -> Self {
+        match input.trim() {
+            "summary" => Self::Summary,
+            "*" => Self::All,
+            _ => Self::Listed(input),
+        }
+    }
+}
+
+/// Fields of the chunks of `fields=summary` responses, besides `score`.
+///
+/// Lines of notebook cells are relative to their cell, so they keep their `cell_index`.
+const SUMMARY_FIELDS: &[&str] = &[
+    "repo_ref",
+    "relative_path",
+    "start_line",
+    "end_line",
+    "cell_index",
+];
+
+/// Fields fetched for `files_only` searches
+const FILE_FIELDS: &[&str] = &["repo_ref", "relative_path"];
+
+#[derive(Serialize, Clone, Default)]
 pub(super) struct SemanticResponse {
+    /// Empty for `files_only` and `group_by_repo` searches
     chunks: Vec<serde_json::Value>,
+    /// The files of the matching chunks, for `files_only` searches
+    #[serde(skip_serializing_if = "Option::is_none")]
+    files: Option<Vec<FileMatch>>,
+    /// The matching chunks by repository, for `group_by_repo` searches
+    #[serde(skip_serializing_if = "Option::is_none")]
+    repos: Option<Vec<RepoGroup>>,
+    /// Whether some collections of a sharded index could not be searched, so chunks may be
+    /// missing. These are listed in `warnings`, and in `diagnostics`
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    partial: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    facets: Option<Facets>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    diagnostics: Option<Diagnostics>,
+    /// Issues that did not fail the search, but may affect its results. Left out if there were
+    /// none
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    warnings: Vec<Warning>,
+}
+
+impl SemanticResponse {
+    /// How many chunks, or files for `files_only` searches, were returned. The chunks of every
+    /// repository are counted for `group_by_repo` searches.
+    fn returned(&self) -> usize {
+        match (&self.files, &self.repos) {
+            (Some(files), _) => files.len(),
+            (_, Some(repos)) => repos.iter().map(|repo| repo.snippets.len()).sum(),
+            _ => self.chunks.len(),
+        }
+    }
+
+    /// The repositories of the chunks or files returned.
+    fn repos(&self) -> Vec<String> {
+        if let Some(repos) = &self.repos {
+            return repos.iter().map(|repo| repo.repo_ref.clone()).collect();
+        }
+
+        match &self.files {
+            Some(files) => files.iter().map(|file| file.repo_ref.clone()).collect(),
+            None => self
+                .chunks
+                .iter()
+                .filter_map(|chunk| chunk_field(chunk, "repo_ref"))
+                .map(ToOwned::to_owned)
+                .collect(),
+        }
+    }
+}
+
+/// An issue that did not fail a search, but may affect its results, see
+/// [`SemanticResponse::warnings`].
+#[derive(Serialize, Clone, Debug, PartialEq, Eq)]
+pub(super) struct Warning {
+    code: WarningCode,
+    message: String,
+}
+
+#[derive(Serialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub(super) enum WarningCode {
+    /// Some collections of a sharded index could not be searched, so chunks may be missing
+    PartialResults,
+    /// Candidates without a `repo_ref` or `relative_path` were left out
+    MalformedPoints,
+}
+
+/// The warnings of a search that left out `malformed` candidates, and could not search the
+/// `unavailable_collections`.
+fn warnings(malformed: usize, unavailable_collections: &[String]) -> Vec<Warning> {
+    let mut warnings = vec![];
+    if !unavailable_collections.is_empty() {
+        warnings.push(Warning {
+            code: WarningCode::PartialResults,
+            message: format!(
+                "collections could not be searched: {}",
+                unavailable_collections.join(", ")
+            ),
+        });
+    }
+    if malformed > 0 {
+        warnings.push(Warning {
+            code: WarningCode::MalformedPoints,
+            message: format!(
+                "left out {malformed} malformed candidate(s) without a `repo_ref` or \
+                 `relative_path`"
+            ),
+        });
+    }
+    warnings
+}
+
+/// A file of a `files_only` search, ranked by the best scoring chunk it contains.
+#[derive(Serialize, Clone, Debug, PartialEq)]
+pub(super) struct FileMatch {
+    repo_ref: String,
+    relative_path: String,
+    /// The raw Qdrant score of the best chunk of the file, after boosting
+    best_score: f32,
+    /// Candidate chunks of the query in the file
+    match_count: usize,
+}
+
+/// A repository of a `group_by_repo` search, ranked by the best scoring chunk it contains.
+#[derive(Serialize, Clone, Debug, PartialEq)]
+pub(super) struct RepoGroup {
+    repo_ref: String,
+    repo_name: String,
+    /// The raw Qdrant score of the best chunk of the repository, after boosting
+    best_score: f32,
+    /// The best scoring chunks of the repository, at most `max_per_repo`, in the shape of
+    /// `chunks`
+    snippets: Vec<serde_json::Value>,
 }
 
+/// How a search ran, reported with `explain`.
+///
+/// Raw chunks are not deduplicated, so candidates are only dropped by the payload filters within
+/// Qdrant, by `min_score`, for being malformed, and by the truncation to `limit` after boosting
+/// and counting facets. Malformed candidates are reported in `warnings`.
+#[derive(Serialize, Clone, Debug, PartialEq)]
+pub(super) struct Diagnostics {
+    params: EffectiveParams,
+    /// Values matched on each payload field. Qdrant applies these during the search, and does not
+    /// report how many candidates each of them dropped.
+    filters: BTreeMap<&'static str, Vec<String>>,
+    /// Repositories the search was scoped to, for searches within a workspace
+    repos: Option<Vec<String>>,
+    search: SearchTrace,
+    /// Candidates dropped for scoring below `min_score`, before counting facets
+    below_min_score: usize,
+    /// Candidates dropped after boosting and counting facets, to return at most `limit` chunks,
+    /// or files dropped for `files_only` searches, or repositories for `group_by_repo` ones
+    truncated: usize,
+    returned: usize,
+    /// Time spent on the whole request, in milliseconds
+    total_ms: f64,
+}
+
+/// The parameters of a search, after defaults were applied.
+#[derive(Serialize, Clone, Debug, PartialEq)]
+pub(super) struct EffectiveParams {
+    /// The text that was embedded, without the filters of the query
+    target: String,
+    limit: u64,
+    /// Candidates fetched, more than `limit` when counting facets, boosting repositories,
+    /// returning files or grouping by repository
+    candidates: u64,
+    filter_logic: FilterLogic,
+    body_weight: f32,
+    doc_weight: f32,
+    workspace: Option<String>,
+    with_facets: bool,
+    kind: Option<ChunkKind>,
+    deterministic: bool,
+    /// Payload fields fetched, `null` for the whole payload
+    fields: PayloadFields,
+    include_blame: bool,
+    collapse_whitespace: bool,
+    context: Option<ContextMode>,
+    with_file_stats: bool,
+    min_score: Option<f32>,
+    boost_repos: Option<Vec<String>>,
+    files_only: bool,
+    group_by_repo: bool,
+    max_per_repo: usize,
+}
+
+/// Distribution of the candidate chunks of a search, for building filters.
+#[derive(Serialize, Clone, Debug, PartialEq)]
+pub(super) struct Facets {
+    /// Chunks by lowercase language, or `other` if it was not detected
+    lang: BTreeMap<String, usize>,
+}
+
+/// Chunks returned per repository of `group_by_repo` searches, unless `max_per_repo` is given
+const DEFAULT_MAX_PER_REPO: usize = 3;
+
 impl super::ApiResponse for SemanticResponse {}
+impl super::ApiResponse for Snippet {}
+impl super::ApiResponse for BrowseResponse {}
+
+#[derive(Deserialize)]
+pub(super) struct BrowseArgs {
+    repo_ref: String,
+    /// Only return chunks whose path contains this
+    path: Option<String>,
+    lang: Option<String>,
+    /// Chunks to skip, in browsing order
+    #[serde(default)]
+    offset: usize,
+    #[serde(default = "default_browse_limit")]
+    limit: usize,
+}
+
+const fn default_browse_limit() -> usize {
+    100
+}
+
+/// Chunks matching the filters of a browse, more than that are rejected rather than sorted
+const MAX_BROWSED_CHUNKS: usize = 10_000;
+
+#[derive(Serialize, Debug)]
+pub(super) struct BrowseResponse {
+    snippets: Vec<Snippet>,
+    /// Chunks matching the filters, across all pages
+    total: usize,
+    /// The `offset` of the next page, if there is one
+    next_offset: Option<usize>,
+}
+
+#[derive(Deserialize, ToSchema)]
+pub(super) struct UpdateChunk {
+    /// The new snippet of the chunk
+    text: String,
+}
+
+const POINTS_HEADER: &str = "x-bleep-collection-points";
+const VERSION_HEADER: &str = "x-bleep-index-version";
+
+/// Describe the collection a search ran against, so clients can tell when they hit an old
+/// index without another request.
+///
+/// The index version is the schema version, followed by the time of the last write to the
+/// collection.
+fn stats_headers(stats: CollectionStats) -> HeaderMap {
+    let version = format!("{SCHEMA_VERSION}-{}", stats.last_write);
+
+    let mut headers = HeaderMap::new();
+    headers.insert(POINTS_HEADER, HeaderValue::from(stats.points));
+    headers.insert(
+        VERSION_HEADER,
+        HeaderValue::from_str(&version).expect("version is a valid header value"),
+    );
+    headers
+}
+
+const CACHE_HEADER: &str = "x-bleep-cache";
 
-/// Get details of an indexed repository based on their id
+/// State shared by all `/semantic/chunks` requests
+pub(super) struct ChunksState {
+    cache: ResponseCache,
+    blamer: Blamer,
+}
+
+impl ChunksState {
+    pub(super) fn new(config: &Configuration) -> Self {
+        Self {
+            cache: ResponseCache::new(config),
+            blamer: Blamer::new(),
+        }
+    }
+}
+
+/// Responses of `/semantic/chunks`, reused for identical searches against an unchanged index.
+///
+/// Entries are keyed on the [`Semantic::generation`] they were computed at, so they are never
+/// hit once the index changes, and are otherwise reused for at most `semantic_cache_ttl_secs`.
+pub(super) struct ResponseCache {
+    ttl: Option<Duration>,
+    entries: BoundedCache<String, (Instant, CachedResponse)>,
+}
+
+#[derive(Clone)]
+struct CachedResponse {
+    headers: HeaderMap,
+    response: SemanticResponse,
+    /// For recording cache hits in the search history
+    top_score: Option<f32>,
+}
+
+impl ResponseCache {
+    pub(super) fn new(config: &Configuration) -> Self {
+        Self {
+            ttl: config.semantic_cache_ttl_secs.map(Duration::from_secs),
+            entries: BoundedCache::new(config.semantic_cache_entries),
+        }
+    }
+
+    fn enabled(&self) -> bool {
+        self.ttl.is_some()
+    }
+
+    fn get(&self, key: &str, policy: CachePolicy) -> Option<CachedResponse> {
+        let ttl = self.ttl.filter(|_| policy.reads())?;
+        let (stored, cached) = self.entries.get(key)?;
+        (stored.elapsed() < ttl).then_some(cached)
+    }
+
+    fn insert(&self, key: String, cached: CachedResponse, policy: CachePolicy) {
+        if policy.writes() {
+            self.entries.insert(key, (Instant::now(), cached));
+        }
+    }
+}
+
+/// Everything a search response depends on, so requests that differ only in how they are
+/// written share a cache entry.
+#[derive(Serialize)]
+struct CacheKey<'a> {
+    generation: u64,
+    target: &'a str,
+    filters: BTreeMap<&'static str, Vec<String>>,
+    repos: Option<Vec<String>>,
+    limit: u64,
+    filter_logic: FilterLogic,
+    body_weight: f32,
+    doc_weight: f32,
+    with_facets: bool,
+    fields: &'a PayloadFields,
+    include_blame: bool,
+    collapse_whitespace: bool,
+    context: Option<ContextMode>,
+    with_file_stats: bool,
+    min_score: Option<f32>,
+    boost_repos: Option<Vec<String>>,
+    files_only: bool,
+    group_by_repo: bool,
+    max_per_repo: usize,
+}
+
+impl CacheKey<'_> {
+    /// Each filter matches any of its values, and each boosted repository is boosted alike, so
+    /// their order does not matter.
+    fn canonical(mut self) -> String {
+        let lists = self.filters.values_mut().chain(&mut self.repos);
+        for values in lists.chain(&mut self.boost_repos) {
+            values.sort_unstable();
+            values.dedup();
+        }
+
+        serde_json::to_string(&self).expect("cache keys serialize")
+    }
+}
+
+/// How a request uses the caches, from its `no_cache` and `no_store` arguments and the
+/// directives of its `Cache-Control` headers.
+fn cache_policy(no_cache: bool, no_store: bool, headers: &HeaderMap) -> CachePolicy {
+    let directive = |name: &str| {
+        headers
+            .get_all(CACHE_CONTROL)
+            .iter()
+            .filter_map(|value| value.to_str().ok())
+            .flat_map(|value| value.split(','))
+            .any(|directive| directive.trim().eq_ignore_ascii_case(name))
+    };
+
+    if no_store || directive("no-store") {
+        CachePolicy::Bypass
+    } else if no_cache || directive("no-cache") {
+        CachePolicy::Refresh
+    } else {
+        CachePolicy::Reuse
+    }
+}
+
+fn count_facets(candidates: &[ScoredPoint], schema: &PayloadSchema) -> Facets {
+    let mut lang = BTreeMap::new();
+    for point in candidates {
+        let name = match str_field(point, schema, "lang") {
+            Some(name) if !name.is_empty() => name.to_ascii_lowercase(),
+            _ => OTHER_LANG.to_owned(),
+        };
+
+        *lang.entry(name).or_default() += 1;
+    }
+
+    Facets { lang }
+}
+
+/// Search the semantic index for the chunks matching a query
+///
+/// With `Accept: text/event-stream`, the search is streamed as `progress` events followed by a
+/// `result` event holding the JSON response, see [`progress::stream`]. The headers of the JSON
+/// response, and the `204 No Content` of `strict_empty`, are left out of streams.
 //
 #[utoipa::path(get, path = "/repos/indexed/:ref",
     responses(
         (status = 200, description = "Execute query successfully", body = SemanticResponse),
+        (status = 204, description = "No chunk qualified, with `strict_empty`"),
         (status = 400, description = "Bad request", body = EndpointError),
+        (status = 404, description = "Workspace not found", body = EndpointError),
         (status = 500, description = "Server error", body = EndpointError),
     ),
 )]
 pub(super) async fn raw_chunks(
+    State(state): State<Arc<ChunksState>>,
     Query(args): Query<Args>,
+    request_headers: HeaderMap,
     Extension(semantic): Extension<Option<Semantic>>,
-) -> impl IntoResponse {
+    Extension(app): Extension<Application>,
+    Extension(user): Extension<User>,
+) -> Result<Response> {
+    let strict_empty = args.strict_empty.unwrap_or_default();
+    if !progress::requested(&request_headers) {
+        let reporter = Reporter::default();
+        let (headers, response) =
+            search_chunks(state, args, request_headers, semantic, app, user, reporter).await?;
+        return Ok(respond(headers, response, strict_empty));
+    }
+
+    let keep_alive = replay::keep_alive(&app.config);
+    let (reporter, events) = Reporter::channel();
+    let search = search_chunks(state, args, request_headers, semantic, app, user, reporter);
+    let stream = progress::stream(search, events).await?;
+    Ok(Sse::new(stream).keep_alive(keep_alive).into_response())
+}
+
+/// The search of [`raw_chunks`], along with the headers of its response.
+async fn search_chunks(
+    state: Arc<ChunksState>,
+    args: Args,
+    request_headers: HeaderMap,
+    semantic: Option<Semantic>,
+    app: Application,
+    user: User,
+    progress: Reporter,
+) -> Result<(HeaderMap, SemanticResponse)> {
     if let Some(semantic) = semantic {
-        let Args { ref query, limit } = args;
-        let query = parser::parse_nl(query).unwrap();
-        let result = semantic.search(&query, limit).await.and_then(|raw| {
-            raw.into_iter()
-                .map(|v| {
-                    v.payload
-                        .into_iter()
-                        .map(|(k, v)| (k, kind_to_value(v.kind)))
-                        .collect::<HashMap<_, _>>()
-                })
-                .map(serde_json::to_value)
-                .collect::<Result<Vec<_>, _>>()
-                .map_err(|e| e.into())
+        let Args {
+            ref query,
+            limit,
+            filter_logic,
+            body_weight,
+            doc_weight,
+            workspace,
+            with_facets,
+            kind,
+            deterministic,
+            fields,
+            explain,
+            include_blame,
+            collapse_whitespace,
+            context,
+            with_file_stats,
+            min_score,
+            strict_empty: _,
+            boost_repos,
+            files_only,
+            group_by_repo,
+            max_per_repo,
+            no_cache,
+            no_store,
+        } = args;
+        let ChunksState { cache, blamer } = &*state;
+        let start = Instant::now();
+        let deterministic = deterministic.unwrap_or_default();
+        let collapse_whitespace = collapse_whitespace.unwrap_or_default();
+        let with_file_stats = with_file_stats.unwrap_or_default();
+        let files_only = files_only.unwrap_or_default();
+        let group_by_repo = group_by_repo.unwrap_or_default();
+        let max_per_repo = max_per_repo.unwrap_or(DEFAULT_MAX_PER_REPO).max(1);
+        // deterministic searches run from scratch, so that they never see a cached result
+        let policy = if deterministic {
+            CachePolicy::Bypass
+        } else {
+            cache_policy(
+                no_cache.unwrap_or_default(),
+                no_store.unwrap_or_default(),
+                &request_headers,
+            )
+        };
+        let weights = VectorWeights {
+            body_weight,
+            doc_weight,
+        };
+        let parsed = parser::parse_nl_with_policy(query, policy)
+            .map_err(|err| Error::user(err).with_code(ErrorCode::InvalidQuery))?;
+        let Some(target) = parsed.target() else {
+            return Err(empty_search());
+        };
+        check_query_length(target, app.config.min_query_chars)?;
+        super::repos::reject_evicted(&app, parsed.repos().map(|r| r.as_ref())).await?;
+
+        let mut filters = FilterArgs::from_query(&parsed, filter_logic)
+            .keyword("kind", kind.map(ChunkKind::as_str));
+        if let Some(workspace) = &workspace {
+            filters = filters.within_repos(workspaces::resolve(&app, workspace)?);
+        }
+
+        let boost_repos = boost_repos.filter(|repos| !repos.is_empty());
+        let widenings = [
+            with_facets.then_some(Widening::Facets),
+            files_only.then_some(Widening::Files),
+            group_by_repo.then_some(Widening::Repos { max_per_repo }),
+            boost_repos.is_some().then_some(Widening::Boost),
+        ];
+        let candidates = candidate_count(limit, widenings.into_iter().flatten());
+
+        let summary = fields == Some(FieldSet::Summary);
+        check_summary_fields(summary, context)?;
+        check_files_only(files_only, fields.as_ref(), context)?;
+        check_group_by_repo(group_by_repo, files_only)?;
+
+        let mut fields = if files_only {
+            PayloadFields::only(FILE_FIELDS.iter().copied())
+        } else {
+            payload_fields(fields.as_ref())
+        };
+        // fields are named here, and fetched by their key in the payload schema
+        if with_facets {
+            // facets count the languages of the candidates
+            fields = fields.with(["lang"]);
+        }
+        if boost_repos.is_some() {
+            fields = fields.with(["repo_ref"]);
+        }
+        if group_by_repo {
+            fields = fields.with(["repo_ref", "repo_name"]);
+        }
+        if with_file_stats {
+            fields = fields.with(["repo_ref", "relative_path"]);
+        }
+
+        // diagnostics describe how this very request ran, so explained searches are not cached
+        let cache_key = (cache.enabled() && !explain && !deterministic).then(|| {
+            CacheKey {
+                generation: semantic.generation(),
+                target,
+                filters: filters.fields(),
+                repos: filters.repos().map(<[_]>::to_vec),
+                limit,
+                filter_logic,
+                body_weight,
+                doc_weight,
+                with_facets,
+                fields: &fields,
+                include_blame,
+                collapse_whitespace,
+                context,
+                with_file_stats,
+                min_score,
+                boost_repos: boost_repos.clone(),
+                files_only,
+                group_by_repo,
+                max_per_repo,
+            }
+            .canonical()
+        });
+
+        if let Some(key) = cache_key.as_deref() {
+            if let Some(hit) = cache.get(key, policy) {
+                app.record_search(
+                    SearchEntry::semantic(
+                        query,
+                        &parsed,
+                        hit.response.returned(),
+                        hit.top_score,
+                        user.0.clone(),
+                    )
+                    .with_repos(hit.response.repos()),
+                );
+
+                let mut headers = hit.headers;
+                headers.insert(CACHE_HEADER, HeaderValue::from_static("hit"));
+                return Ok((headers, hit.response));
+            }
+        }
+
+        let explained = explain.then(|| {
+            let params = EffectiveParams {
+                target: target.to_string(),
+                limit,
+                candidates,
+                filter_logic,
+                body_weight,
+                doc_weight,
+                workspace,
+                with_facets,
+                kind,
+                deterministic,
+                fields: fields.clone(),
+                include_blame,
+                collapse_whitespace,
+                context,
+                with_file_stats,
+                min_score,
+                boost_repos: boost_repos.clone(),
+                files_only,
+                group_by_repo,
+                max_per_repo,
+            };
+            (params, filters.fields(), filters.repos().map(<[_]>::to_vec))
         });
 
+        let mut facets = None;
+        let mut trace = SearchTrace::default();
+        let mut below_min_score = 0;
+        let mut malformed = 0;
+        let mut truncated = 0;
+        let mut top_score = None;
+        progress.report(Progress::Searching { candidates });
+        let result = semantic
+            .search_traced(
+                &parsed,
+                filters,
+                weights,
+                fields,
+                candidates,
+                deterministic,
+                policy,
+            )
+            .await
+            .and_then(|(raw, search)| {
+                trace = search;
+                let fetched = raw.len();
+                let schema = semantic.payload_schema();
+                let (raw, dropped) = well_formed(raw, schema);
+                malformed = dropped;
+                let (mut raw, dropped) = above_min_score(raw, min_score);
+                below_min_score = dropped;
+                progress.report(Progress::Ranked {
+                    fetched,
+                    kept: raw.len(),
+                });
+                if let Some(repos) = &boost_repos {
+                    boost(&mut raw, repos, app.config.repo_boost, schema);
+                }
+                // hidden chunks are left out of facets, files and repositories too
+                let mut raw = semantic.post_process(query, raw);
+                if with_facets {
+                    facets = Some(count_facets(&raw, schema));
+                }
+
+                if files_only {
+                    let mut files = rank_files(&raw, schema);
+                    truncated = files.len().saturating_sub(limit as usize);
+                    files.truncate(limit as usize);
+
+                    top_score = files.first().map(|file| file.best_score);
+                    let repos = files.iter().map(|file| file.repo_ref.clone());
+                    app.record_search(
+                        SearchEntry::semantic(
+                            query,
+                            &parsed,
+                            files.len(),
+                            top_score,
+                            user.0.clone(),
+                        )
+                        .with_repos(repos),
+                    );
+                    return Ok((vec![], Some(files), None));
+                }
+
+                if group_by_repo {
+                    let mut groups = group_repos(raw, max_per_repo, schema);
+                    truncated = groups.len().saturating_sub(limit as usize);
+                    groups.truncate(limit as usize);
+
+                    top_score = groups.first().map(|(group, _)| group.best_score);
+                    let returned = groups.iter().map(|(_, points)| points.len()).sum();
+                    let repos = groups.iter().map(|(group, _)| group.repo_ref.clone());
+                    app.record_search(
+                        SearchEntry::semantic(query, &parsed, returned, top_score, user.0.clone())
+                            .with_repos(repos),
+                    );
+
+                    let groups = groups
+                        .into_iter()
+                        .map(|(group, points)| {
+                            let snippets = if summary {
+                                summarize(points, schema)
+                            } else {
+                                to_chunks(points, schema)?
+                            };
+                            Ok(RepoGroup { snippets, ..group })
+                        })
+                        .collect::<serde_json::Result<_>>()?;
+                    return Ok((vec![], None, Some(groups)));
+                }
+
+                truncated = raw.len().saturating_sub(limit as usize);
+                raw.truncate(limit as usize);
+
+                top_score = raw.first().map(|r| r.score);
+                app.record_search(
+                    SearchEntry::semantic(query, &parsed, raw.len(), top_score, user.0.clone())
+                        .with_repos(point_repos(&raw, schema)),
+                );
+
+                if summary {
+                    Ok((summarize(raw, schema), None, None))
+                } else {
+                    Ok((to_chunks(raw, schema)?, None, None))
+                }
+            });
+
         if let Err(err) = result {
+            if err.is::<BreakerOpen>() || err.is::<EmbedTimeout>() {
+                return Err(Error::search(err));
+            }
+
             error!(?err, "qdrant query failed");
-            return Err(Error::new(ErrorKind::UpstreamService, "error"));
+            return Err(
+                Error::new(ErrorKind::UpstreamService, "error").with_code(ErrorCode::SearchFailed)
+            );
         };
 
-        Ok(json(SemanticResponse {
-            chunks: result.unwrap(),
-        }))
+        let stats = if deterministic {
+            semantic.fresh_collection_stats().await
+        } else {
+            semantic.collection_stats().await
+        };
+
+        let mut headers = match stats {
+            Ok(stats) => stats_headers(stats),
+            Err(err) => {
+                warn!(?err, "failed to fetch collection stats");
+                HeaderMap::new()
+            }
+        };
+
+        let partial = !trace.unavailable_collections.is_empty();
+        let (mut chunks, files, mut repos) = result.unwrap();
+        if include_blame || context.is_some() || with_file_stats {
+            let grouped = repos.iter().flatten().map(|repo| repo.snippets.len());
+            progress.report(Progress::Attaching {
+                chunks: chunks.len() + grouped.sum::<usize>(),
+            });
+        }
+        let grouped = repos.iter_mut().flatten().map(|repo| &mut repo.snippets);
+        for chunks in std::iter::once(&mut chunks).chain(grouped) {
+            if include_blame {
+                attach_blame(&app, blamer, chunks).await;
+            }
+            if let Some(mode) = context {
+                attach_context(&app, mode, chunks).await;
+            }
+            if collapse_whitespace {
+                collapse_snippet_whitespace(chunks);
+            }
+            if with_file_stats {
+                let files = &app.indexes.file;
+                attach_file_stats(chunks, |repo_ref, relative_path| async move {
+                    let repo_ref = repo_ref.parse::<RepoRef>().ok()?;
+                    let doc = files.by_path(&repo_ref, &relative_path).await;
+                    doc.ok().map(|doc| doc.content)
+                })
+                .await;
+            }
+        }
+
+        let warnings = warnings(malformed, &trace.unavailable_collections);
+        let mut response = SemanticResponse {
+            chunks,
+            files,
+            repos,
+            partial,
+            facets,
+            diagnostics: None,
+            warnings,
+        };
+        response.diagnostics = explained.map(|(params, filters, repos)| Diagnostics {
+            params,
+            filters,
+            repos,
+            search: trace,
+            below_min_score,
+            truncated,
+            returned: response.returned(),
+            total_ms: elapsed_ms(start),
+        });
+
+        // partial results are not cached, so the next search tries every collection again
+        if let Some(key) = cache_key.filter(|_| !partial) {
+            cache.insert(
+                key,
+                CachedResponse {
+                    headers: headers.clone(),
+                    response: response.clone(),
+                    top_score,
+                },
+                policy,
+            );
+        }
+
+        if cache.enabled() {
+            let status = if policy.reads() { "miss" } else { "bypass" };
+            headers.insert(CACHE_HEADER, HeaderValue::from_static(status));
+        }
+
+        Ok((headers, response))
     } else {
-        Err(Error::new(
+        Err(
+            Error::new(ErrorKind::Configuration, "Qdrant not configured")
+                .with_code(ErrorCode::SemanticSearchUnavailable),
+        )
+    }
+}
+
+/// The `candidates` with a `repo_ref` and a `relative_path`, and how many were dropped.
+///
+/// Chunks without those can't be attributed to a file, which points written by another indexer
+/// may lack.
+fn well_formed(
+    mut candidates: Vec<ScoredPoint>,
+    schema: &PayloadSchema,
+) -> (Vec<ScoredPoint>, usize) {
+    let before = candidates.len();
+    candidates.retain(|c| {
+        ["repo_ref", "relative_path"]
+            .into_iter()
+            .all(|field| matches!(str_field(c, schema, field), Some(value) if !value.is_empty()))
+    });
+    let dropped = before - candidates.len();
+    (candidates, dropped)
+}
+
+/// The `candidates` scoring at least `min_score`, and how many were dropped.
+fn above_min_score(
+    mut candidates: Vec<ScoredPoint>,
+    min_score: Option<f32>,
+) -> (Vec<ScoredPoint>, usize) {
+    let Some(min_score) = min_score else {
+        return (candidates, 0);
+    };
+
+    let before = candidates.len();
+    candidates.retain(|c| c.score >= min_score);
+    let dropped = before - candidates.len();
+    (candidates, dropped)
+}
+
+/// Multiply the score of the `candidates` of the `repos` by `factor`, and rank them again.
+///
+/// The sort is stable, so candidates keep their order among equal scores.
+fn boost(candidates: &mut [ScoredPoint], repos: &[String], factor: f32, schema: &PayloadSchema) {
+    for candidate in candidates.iter_mut() {
+        let repo_ref = str_field(candidate, schema, "repo_ref");
+        if matches!(repo_ref, Some(r) if repos.iter().any(|repo| repo == r)) {
+            candidate.score *= factor;
+        }
+    }
+
+    candidates.sort_by(|a, b| b.score.total_cmp(&a.score));
+}
+
+/// Respond with `response`, or with `204 No Content` if it has no chunks and `strict_empty`
+/// is set, so clients can tell "nothing qualified" apart from a broken search.
+fn respond(headers: HeaderMap, response: SemanticResponse, strict_empty: bool) -> Response {
+    if strict_empty && response.returned() == 0 {
+        return (StatusCode::NO_CONTENT, headers).into_response();
+    }
+
+    (headers, json(response)).into_response()
+}
+
+/// Reject search targets too short to embed meaningfully, before anything is embedded.
+pub(super) fn check_query_length(target: &str, min_chars: usize) -> Result<()> {
+    if target.trim().chars().count() < min_chars {
+        return Err(Error::user(format!(
+            "query too short, search for at least {min_chars} characters"
+        ))
+        .with_code(ErrorCode::QueryTooShort));
+    }
+
+    Ok(())
+}
+
+/// The error of queries made up of filters only, which leave nothing to embed.
+fn empty_search() -> Error {
+    Error::user("empty search").with_code(ErrorCode::EmptyQuery)
+}
+
+fn check_files_only(
+    files_only: bool,
+    fields: Option<&FieldSet>,
+    context: Option<ContextMode>,
+) -> Result<()> {
+    if files_only && (fields.is_some() || context.is_some()) {
+        return Err(
+            Error::user("`fields` and `context` are not returned with `files_only`")
+                .with_code(ErrorCode::IncompatibleParams),
+        );
+    }
+
+    Ok(())
+}
+
+fn check_group_by_repo(group_by_repo: bool, files_only: bool) -> Result<()> {
+    if group_by_repo && files_only {
+        return Err(
+            Error::user("`group_by_repo` and `files_only` can't be combined")
+                .with_code(ErrorCode::IncompatibleParams),
+        );
+    }
+
+    Ok(())
+}
+
+fn check_summary_fields(summary: bool, context: Option<ContextMode>) -> Result<()> {
+    if summary && context.is_some() {
+        return Err(
+            Error::user("`context` is not returned with `fields=summary`")
+                .with_code(ErrorCode::IncompatibleParams),
+        );
+    }
+
+    Ok(())
+}
+
+/// Browse the indexed chunks matching a set of filters, without a query
+///
+/// Chunks are ordered by path and position within their file, and carry no score.
+//
+#[utoipa::path(get, path = "/semantic/browse",
+    responses(
+        (status = 200, description = "Execute query successfully", body = BrowseResponse),
+        (status = 400, description = "Bad request", body = EndpointError),
+        (status = 500, description = "Server error", body = EndpointError),
+    ),
+)]
+pub(super) async fn browse(
+    Query(args): Query<BrowseArgs>,
+    Extension(semantic): Extension<Option<Semantic>>,
+) -> Result<impl IntoResponse> {
+    let Some(semantic) = semantic else {
+        return Err(Error::new(
             ErrorKind::Configuration,
             "Qdrant not configured",
+        ));
+    };
+
+    let BrowseArgs {
+        repo_ref,
+        path,
+        lang,
+        offset,
+        limit,
+    } = args;
+
+    let filters = FilterArgs::new(FilterLogic::And)
+        .keyword("repo_ref", [repo_ref])
+        .text(
+            "relative_path",
+            path.as_deref().into_iter().flat_map(relative_path_variants),
+        )
+        .keyword("lang", lang);
+
+    let Some(points) = semantic
+        .browse(filters, MAX_BROWSED_CHUNKS)
+        .await
+        .map_err(Error::search)?
+    else {
+        return Err(Error::user(format!(
+            "more than {MAX_BROWSED_CHUNKS} chunks match, narrow the search by `path` or `lang`"
+        )));
+    };
+
+    let points = semantic.post_process("", points);
+    Ok(json(browse_page(
+        points,
+        semantic.payload_schema(),
+        offset,
+        limit,
+    )?))
+}
+
+/// The `limit` chunks after `offset` of `points`, in browsing order.
+fn browse_page(
+    points: Vec<RetrievedPoint>,
+    schema: &PayloadSchema,
+    offset: usize,
+    limit: usize,
+) -> Result<BrowseResponse> {
+    let mut snippets = points
+        .into_iter()
+        .map(|point| {
+            snippet_from_payload(point.payload, schema, 0.0, vec![], SnippetSource::Semantic)
+        })
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(Error::internal)?;
+
+    snippets.sort_by(|a, b| {
+        (&a.relative_path, a.cell_index, a.start_byte).cmp(&(
+            &b.relative_path,
+            b.cell_index,
+            b.start_byte,
         ))
+    });
+
+    let total = snippets.len();
+    let snippets = snippets
+        .into_iter()
+        .skip(offset)
+        .take(limit)
+        .collect::<Vec<_>>();
+    let next_offset = Some(offset.saturating_add(limit)).filter(|&next| next < total);
+
+    Ok(BrowseResponse {
+        snippets,
+        total,
+        next_offset,
+    })
+}
+
+/// Re-embed a single chunk with new text, keeping the rest of its payload
+///
+/// The chunk keeps its location in the file, as recorded at index time, and is replaced on the
+/// next index of its file.
+//
+#[utoipa::path(put, path = "/semantic/chunk/:id", request_body = UpdateChunk,
+    responses(
+        (status = 200, description = "Execute query successfully", body = Snippet),
+        (status = 400, description = "Bad request", body = EndpointError),
+        (status = 404, description = "Chunk not found", body = EndpointError),
+        (status = 500, description = "Server error", body = EndpointError),
+    ),
+)]
+pub(super) async fn update_chunk(
+    Path(id): Path<String>,
+    Extension(semantic): Extension<Option<Semantic>>,
+    Json(UpdateChunk { text }): Json<UpdateChunk>,
+) -> Result<impl IntoResponse> {
+    let Some(semantic) = semantic else {
+        return Err(Error::new(
+            ErrorKind::Configuration,
+            "Qdrant not configured",
+        ));
+    };
+
+    let Ok(id) = uuid::Uuid::parse_str(&id) else {
+        return Err(Error::user(format!("invalid chunk id `{id}`")));
+    };
+
+    if text.trim().is_empty() {
+        return Err(Error::user("chunk text must not be empty"));
+    }
+
+    let point = semantic
+        .update_chunk(PointId::from(id.to_string()), &text)
+        .await
+        .map_err(Error::search)?
+        .ok_or_else(|| Error::new(ErrorKind::NotFound, "Can't find chunk"))?;
+
+    Ok(json(updated_snippet(point, semantic.payload_schema())?))
+}
+
+fn updated_snippet(point: PointStruct, schema: &PayloadSchema) -> Result<Snippet> {
+    let embedding = match point.vectors.and_then(|v| v.vectors_options) {
+        Some(VectorsOptions::Vector(v)) => v.data,
+        Some(VectorsOptions::Vectors(mut named)) => named
+            .vectors
+            .remove(BODY_VECTOR)
+            .map(|v| v.data)
+            .unwrap_or_default(),
+        None => vec![],
+    };
+
+    snippet_from_payload(
+        point.payload,
+        schema,
+        0.0,
+        embedding,
+        SnippetSource::Semantic,
+    )
+    .map_err(Error::internal)
+}
+
+/// The payload fields of the chunks returned for a search with the `requested` fields.
+fn payload_fields(requested: Option<&FieldSet>) -> PayloadFields {
+    match requested {
+        Some(FieldSet::Summary) => PayloadFields::only(SUMMARY_FIELDS.iter().copied()),
+        Some(FieldSet::All) => PayloadFields::all(),
+        Some(FieldSet::Listed(listed)) => PayloadFields::only(CHUNK_FIELDS.iter().copied()).with(
+            listed
+                .split(',')
+                .map(str::trim)
+                .filter(|field| !field.is_empty()),
+        ),
+        None => PayloadFields::only(CHUNK_FIELDS.iter().copied()),
+    }
+}
+
+/// The files of `candidates`, ranked by their best scoring chunk.
+///
+/// Files whose best chunks score alike keep the order their first chunk was found in.
+fn rank_files(candidates: &[ScoredPoint], schema: &PayloadSchema) -> Vec<FileMatch> {
+    let mut files: Vec<FileMatch> = vec![];
+    let mut by_path = HashMap::new();
+
+    for point in candidates {
+        let field = |field| str_field(point, schema, field).unwrap_or_default();
+        let path = (field("repo_ref"), field("relative_path"));
+
+        match by_path.get(&path) {
+            Some(&i) => {
+                let file: &mut FileMatch = &mut files[i];
+                file.best_score = file.best_score.max(point.score);
+                file.match_count += 1;
+            }
+            None => {
+                by_path.insert(path, files.len());
+                files.push(FileMatch {
+                    repo_ref: path.0.to_owned(),
+                    relative_path: path.1.to_owned(),
+                    best_score: point.score,
+                    match_count: 1,
+                });
+            }
+        }
+    }
+
+    files.sort_by(|a, b| b.best_score.total_cmp(&a.best_score));
+    files
+}
+
+/// The `candidates` bucketed by repository, each with its `max_per_repo` best scoring chunks.
+///
+/// Repositories are ranked by their best chunk, and keep the order their first chunk was found
+/// in when those score alike. Chunks keep the order of `candidates` within their repository.
+fn group_repos(
+    candidates: Vec<ScoredPoint>,
+    max_per_repo: usize,
+    schema: &PayloadSchema,
+) -> Vec<(RepoGroup, Vec<ScoredPoint>)> {
+    let mut groups: Vec<(RepoGroup, Vec<ScoredPoint>)> = vec![];
+    let mut by_repo = HashMap::new();
+
+    for point in candidates {
+        let field = |field| {
+            str_field(&point, schema, field)
+                .unwrap_or_default()
+                .to_owned()
+        };
+        let repo_ref = field("repo_ref");
+
+        let i = *by_repo.entry(repo_ref.clone()).or_insert_with(|| {
+            groups.push((
+                RepoGroup {
+                    repo_ref,
+                    repo_name: field("repo_name"),
+                    best_score: point.score,
+                    snippets: vec![],
+                },
+                vec![],
+            ));
+            groups.len() - 1
+        });
+
+        let (group, points) = &mut groups[i];
+        group.best_score = group.best_score.max(point.score);
+        points.push(point);
+    }
+
+    for (_, points) in &mut groups {
+        points.sort_by(|a, b| b.score.total_cmp(&a.score));
+        points.truncate(max_per_repo);
+    }
+    groups.sort_by(|a, b| b.0.best_score.total_cmp(&a.0.best_score));
+    groups
+}
+
+/// The repositories of `points`, as far as their `repo_ref` was fetched.
+fn point_repos(points: &[ScoredPoint], schema: &PayloadSchema) -> Vec<String> {
+    points
+        .iter()
+        .filter_map(|point| str_field(point, schema, "repo_ref"))
+        .map(str::to_owned)
+        .collect()
+}
+
+/// The string `field` of `point`, read from its key in `schema`.
+fn str_field<'a>(point: &'a ScoredPoint, schema: &PayloadSchema, field: &str) -> Option<&'a str> {
+    match point.payload.get(schema.key(field))?.kind.as_ref()? {
+        Kind::StringValue(value) => Some(value),
+        _ => None,
+    }
+}
+
+/// The payloads of `points`, with fields named by field rather than by the key `schema` stores
+/// them under, and sorted by name.
+///
+/// Chunks are read by field name from then on, such as to attach their blame or context.
+fn to_chunks(
+    points: Vec<ScoredPoint>,
+    schema: &PayloadSchema,
+) -> serde_json::Result<Vec<serde_json::Value>> {
+    points
+        .into_iter()
+        .map(|v| {
+            schema
+                .load(v.payload)
+                .into_iter()
+                .map(|(k, v)| (k, kind_to_value(v.kind)))
+                .collect::<BTreeMap<_, _>>()
+        })
+        .map(serde_json::to_value)
+        .collect()
+}
+
+/// The [`SUMMARY_FIELDS`] of `points`, named as in [`to_chunks`], along with their raw Qdrant
+/// `score`.
+///
+/// Fields fetched for other purposes, such as breaking ties or counting facets, are dropped.
+fn summarize(points: Vec<ScoredPoint>, schema: &PayloadSchema) -> Vec<serde_json::Value> {
+    points
+        .into_iter()
+        .map(|point| {
+            let mut summary = schema
+                .load(point.payload)
+                .into_iter()
+                .filter(|(k, _)| SUMMARY_FIELDS.contains(&k.as_str()))
+                .map(|(k, v)| (k, kind_to_value(v.kind)))
+                .collect::<serde_json::Map<_, _>>();
+            summary.insert("score".into(), point.score.into());
+            summary.into()
+        })
+        .collect()
+}
+
+/// Add the latest commit touching each chunk to it, or `null`s where that is unknown.
+async fn attach_blame(app: &Application, blamer: &Blamer, chunks: &mut [serde_json::Value]) {
+    let mut heads = HashMap::new();
+    for repo_ref in chunks
+        .iter()
+        .filter_map(|chunk| chunk_field(chunk, "repo_ref"))
+    {
+        if heads.contains_key(repo_ref) {
+            continue;
+        }
+
+        let disk_path = match repo_ref.parse::<RepoRef>() {
+            Ok(reporef) => {
+                app.repo_pool
+                    .read_async(&reporef, |_, repo| repo.disk_path.clone())
+                    .await
+            }
+            Err(_) => None,
+        };
+
+        let head = match disk_path {
+            Some(disk_path) => Blamer::head(disk_path.clone())
+                .await
+                .map(|commit| (disk_path, commit)),
+            None => None,
+        };
+        heads.insert(repo_ref.to_owned(), head);
+    }
+
+    let blames = future::join_all(chunks.iter().map(|chunk| {
+        let head =
+            chunk_field(chunk, "repo_ref").and_then(|repo_ref| heads.get(repo_ref)?.as_ref());
+        async move {
+            // lines of notebook cells are relative to the cell, rather than the file
+            if chunk.get("cell_index").is_some() {
+                return None;
+            }
+
+            let (disk_path, commit) = head?;
+            let relative_path = chunk_field(chunk, "relative_path")?;
+            let start_line = chunk_field(chunk, "start_line")?.parse().ok()?;
+            let end_line = chunk_field(chunk, "end_line")?.parse().ok()?;
+
+            blamer
+                .blame(disk_path, *commit, relative_path, start_line, end_line)
+                .await
+        }
+    }))
+    .await;
+
+    for (chunk, blame) in chunks.iter_mut().zip(blames) {
+        if let Some(chunk) = chunk.as_object_mut() {
+            let (author, commit, modified) = match blame {
+                Some(blame) => (
+                    Some(blame.last_author),
+                    Some(blame.last_commit),
+                    Some(blame.last_modified),
+                ),
+                None => (None, None, None),
+            };
+
+            chunk.insert("last_author".into(), author.into());
+            chunk.insert("last_commit".into(), commit.into());
+            chunk.insert("last_modified".into(), modified.into());
+        }
+    }
+}
+
+/// Attach the `context` of each chunk, from the file it was indexed from.
+///
+/// This is `null` for notebook cells, whose bounds are relative to the cell, and for chunks of
+/// files that are no longer indexed or whose bounds no longer fit in the file.
+async fn attach_context(app: &Application, mode: ContextMode, chunks: &mut [serde_json::Value]) {
+    let mut docs = HashMap::new();
+
+    for chunk in chunks.iter_mut() {
+        let location = match (
+            chunk_field(chunk, "repo_ref"),
+            chunk_field(chunk, "relative_path"),
+            chunk_usize(chunk, "start_byte"),
+            chunk_usize(chunk, "end_byte"),
+        ) {
+            (Some(repo_ref), Some(relative_path), Some(start), Some(end))
+                if chunk.get("cell_index").is_none() =>
+            {
+                Some(((repo_ref.to_owned(), relative_path.to_owned()), start..end))
+            }
+            _ => None,
+        };
+
+        let mut context = None;
+        if let Some((file, bytes)) = location {
+            if !docs.contains_key(&file) {
+                let doc = match file.0.parse::<RepoRef>() {
+                    Ok(repo_ref) => app.indexes.file.by_path(&repo_ref, &file.1).await.ok(),
+                    Err(_) => None,
+                };
+                docs.insert(file.clone(), doc);
+            }
+
+            context = docs[&file]
+                .as_ref()
+                .and_then(|doc| context::of(doc, bytes, mode));
+        }
+
+        if let Some(chunk) = chunk.as_object_mut() {
+            chunk.insert(
+                "context".into(),
+                serde_json::to_value(context).expect("contexts serialize"),
+            );
+        }
+    }
+}
+
+/// Attach the `file_line_count` and `file_byte_count` of the file of each chunk, as indexed.
+///
+/// `read` returns the indexed content of a file given its repository and path, and is called
+/// once per file. Chunks of files it returns `None` for get `null` stats.
+async fn attach_file_stats<F, Fut>(chunks: &mut [serde_json::Value], mut read: F)
+where
+    F: FnMut(String, String) -> Fut,
+    Fut: Future<Output = Option<String>>,
+{
+    let mut stats = HashMap::new();
+
+    for chunk in chunks.iter_mut() {
+        let file = match (
+            chunk_field(chunk, "repo_ref"),
+            chunk_field(chunk, "relative_path"),
+        ) {
+            (Some(repo_ref), Some(relative_path)) => {
+                Some((repo_ref.to_owned(), relative_path.to_owned()))
+            }
+            _ => None,
+        };
+
+        let mut file_stats = None;
+        if let Some(file) = file {
+            if !stats.contains_key(&file) {
+                let content = read(file.0.clone(), file.1.clone()).await;
+                let counts = content.map(|content| (content.lines().count(), content.len()));
+                stats.insert(file.clone(), counts);
+            }
+            file_stats = stats[&file];
+        }
+
+        if let Some(chunk) = chunk.as_object_mut() {
+            let lines = file_stats.map(|(lines, _)| lines);
+            let bytes = file_stats.map(|(_, bytes)| bytes);
+            chunk.insert("file_line_count".into(), lines.into());
+            chunk.insert("file_byte_count".into(), bytes.into());
+        }
+    }
+}
+
+/// Collapse the whitespace in the `snippet` of each chunk, see [`collapse_whitespace`].
+fn collapse_snippet_whitespace(chunks: &mut [serde_json::Value]) {
+    for chunk in chunks {
+        if let Some(serde_json::Value::String(snippet)) = chunk.get_mut("snippet") {
+            *snippet = collapse_whitespace(snippet);
+        }
+    }
+}
+
+/// Strip trailing whitespace from every line of `text`, and collapse runs of blank lines into
+/// a single one. Leading and trailing blank lines are dropped altogether.
+///
+/// Indentation is kept, so code stays readable.
+fn collapse_whitespace(text: &str) -> String {
+    let mut collapsed = String::with_capacity(text.len());
+    let mut blank_lines = 0;
+
+    for line in text.lines().map(str::trim_end) {
+        if line.is_empty() {
+            blank_lines += 1;
+            continue;
+        }
+
+        if !collapsed.is_empty() {
+            collapsed.push('\n');
+            if blank_lines > 0 {
+                collapsed.push('\n');
+            }
+        }
+
+        blank_lines = 0;
+        collapsed.push_str(line);
+    }
+
+    collapsed
+}
+
+/// A text field of a chunk, as named by [`to_chunks`].
+fn chunk_field<'a>(chunk: &'a serde_json::Value, key: &str) -> Option<&'a str> {
+    chunk.get(key)?.as_str()
+}
+
+/// An integer field of a chunk, which older chunks store as a string.
+fn chunk_usize(chunk: &serde_json::Value, key: &str) -> Option<usize> {
+    match chunk.get(key)? {
+        serde_json::Value::Number(n) => n.as_u64()?.try_into().ok(),
+        serde_json::Value::String(s) => s.parse().ok(),
+        _ => None,
     }
 }
 
@@ -82,3 +1630,961 @@ fn kind_to_value(kind: Option<Kind>) -> serde_json::Value {
         None => serde_json::Value::Null,
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::semantic::{
+        self,
+        kind::ChunkKind,
+        post_process::{ResultPostProcessor, SearchResult},
+        retry::ChunkPayload,
+        sort_deterministically,
+        store::VectorStore,
+    };
+    use axum::body::HttpBody;
+    use qdrant_client::qdrant::point_id::PointIdOptions;
+
+    #[test]
+    fn responses_carry_collection_stats() {
+        let stats = CollectionStats {
+            points: 1234,
+            last_write: 1_680_000_000,
+        };
+        let response = (stats_headers(stats), json(SemanticResponse::default())).into_response();
+
+        let headers = response.headers();
+        assert_eq!(headers["X-Bleep-Collection-Points"], "1234");
+
+        let version = headers["X-Bleep-Index-Version"].to_str().unwrap();
+        let (schema, last_write) = version.rsplit_once('-').unwrap();
+        assert_eq!(schema, SCHEMA_VERSION);
+        assert_eq!(last_write.parse::<u64>().unwrap(), stats.last_write);
+    }
+
+    fn candidate(lang: Option<&str>) -> ScoredPoint {
+        ScoredPoint {
+            payload: lang
+                .map(|l| ("lang".to_owned(), l.into()))
+                .into_iter()
+                .collect(),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn facets_count_candidate_languages() {
+        let candidates = [
+            Some("rust"),
+            Some("Rust"),
+            Some("python"),
+            Some("rust"),
+            Some(""),
+            None,
+        ]
+        .map(candidate);
+
+        assert_eq!(
+            count_facets(&candidates, &PayloadSchema::default()),
+            Facets {
+                lang: BTreeMap::from([
+                    ("rust".to_owned(), 3),
+                    ("python".to_owned(), 1),
+                    ("other".to_owned(), 2),
+                ]),
+            }
+        );
+
+        let response = SemanticResponse::default();
+        assert_eq!(
+            serde_json::to_value(response).unwrap(),
+            serde_json::json!({ "chunks": [] })
+        );
+    }
+
+    fn cache_key(query: &str, generation: u64) -> String {
+        let parsed = parser::parse_nl(query).unwrap();
+        let filters = FilterArgs::from_query(&parsed, FilterLogic::And);
+        CacheKey {
+            generation,
+            target: parsed.target().unwrap(),
+            filters: filters.fields(),
+            repos: None,
+            limit: 10,
+            filter_logic: FilterLogic::And,
+            body_weight: 1.0,
+            doc_weight: 0.0,
+            with_facets: false,
+            deterministic: false,
+            fields: &PayloadFields::all(),
+            include_blame: false,
+            collapse_whitespace: false,
+            context: None,
+            with_file_stats: false,
+            min_score: None,
+            boost_repos: None,
+            files_only: false,
+            group_by_repo: false,
+            max_per_repo: DEFAULT_MAX_PER_REPO,
+        }
+        .canonical()
+    }
+
+    #[test]
+    fn equivalent_searches_share_cache_keys() {
+        assert_eq!(
+            cache_key("lang:rust lang:python parse query", 1),
+            cache_key("lang:python parse query lang:rust lang:rust", 1)
+        );
+        assert_ne!(
+            cache_key("lang:rust parse query", 1),
+            cache_key("lang:python parse query", 1)
+        );
+
+        // writes to the index move searches on to fresh entries
+        assert_ne!(cache_key("parse query", 1), cache_key("parse query", 2));
+    }
+
+    #[test]
+    fn cached_responses_expire() {
+        let response = CachedResponse {
+            headers: HeaderMap::new(),
+            response: SemanticResponse::default(),
+            top_score: Some(0.5),
+        };
+
+        let cache = ResponseCache {
+            ttl: Some(Duration::from_secs(60)),
+            entries: BoundedCache::new(4),
+        };
+        cache.insert("key".into(), response.clone(), CachePolicy::Reuse);
+        assert_eq!(
+            cache.get("key", CachePolicy::Reuse).unwrap().top_score,
+            Some(0.5)
+        );
+
+        let expired = ResponseCache {
+            ttl: Some(Duration::ZERO),
+            ..cache
+        };
+        assert!(expired.get("key", CachePolicy::Reuse).is_none());
+
+        let disabled = ResponseCache {
+            ttl: None,
+            entries: BoundedCache::new(4),
+        };
+        disabled.insert("key".into(), response, CachePolicy::Reuse);
+        assert!(!disabled.enabled());
+        assert!(disabled.get("key", CachePolicy::Reuse).is_none());
+    }
+
+    #[test]
+    fn no_cache_requests_bypass_the_cache() {
+        let mut headers = HeaderMap::new();
+        assert_eq!(cache_policy(false, false, &headers), CachePolicy::Reuse);
+        assert_eq!(cache_policy(true, false, &headers), CachePolicy::Refresh);
+        assert_eq!(cache_policy(false, true, &headers), CachePolicy::Bypass);
+
+        headers.insert(CACHE_CONTROL, HeaderValue::from_static("max-age=0"));
+        assert_eq!(cache_policy(false, false, &headers), CachePolicy::Reuse);
+
+        headers.insert(
+            CACHE_CONTROL,
+            HeaderValue::from_static("max-age=0, No-Cache"),
+        );
+        assert_eq!(cache_policy(false, false, &headers), CachePolicy::Refresh);
+
+        headers.insert(CACHE_CONTROL, HeaderValue::from_static("no-store"));
+        assert_eq!(cache_policy(true, false, &headers), CachePolicy::Bypass);
+    }
+
+    #[test]
+    fn no_cache_requests_recompute_cached_entries() {
+        let response = |top_score| CachedResponse {
+            headers: HeaderMap::new(),
+            response: SemanticResponse::default(),
+            top_score: Some(top_score),
+        };
+        let cache = ResponseCache {
+            ttl: Some(Duration::from_secs(60)),
+            entries: BoundedCache::new(4),
+        };
+        cache.insert("key".into(), response(0.5), CachePolicy::Reuse);
+
+        // the entry is there, but not served
+        assert!(cache.get("key", CachePolicy::Refresh).is_none());
+        assert!(cache.get("key", CachePolicy::Bypass).is_none());
+
+        // only refreshing requests replace it with what they computed
+        cache.insert("key".into(), response(0.7), CachePolicy::Bypass);
+        let top_score = |cache: &ResponseCache| cache.get("key", CachePolicy::Reuse)?.top_score;
+        assert_eq!(top_score(&cache), Some(0.5));
+        cache.insert("key".into(), response(0.9), CachePolicy::Refresh);
+        assert_eq!(top_score(&cache), Some(0.9));
+    }
+
+    #[test]
+    fn short_queries_are_rejected_before_embedding() {
+        let err = check_query_length(" a ", 2).unwrap_err();
+        assert_eq!(err.status, StatusCode::BAD_REQUEST);
+        assert_eq!(
+            err.message(),
+            "query too short, search for at least 2 characters"
+        );
+
+        // characters are counted, rather than bytes
+        assert!(check_query_length("é", 2).is_err());
+        assert!(check_query_length(" ab ", 2).is_ok());
+        assert!(check_query_length("a", 0).is_ok());
+    }
+
+    #[test]
+    fn rejected_searches_carry_their_code() {
+        let codes = [
+            (empty_search(), ErrorCode::EmptyQuery),
+            (
+                check_query_length("a", 2).unwrap_err(),
+                ErrorCode::QueryTooShort,
+            ),
+            (
+                check_summary_fields(true, Some(ContextMode::Lines(2))).unwrap_err(),
+                ErrorCode::IncompatibleParams,
+            ),
+        ];
+
+        for (err, code) in codes {
+            assert_eq!(err.status, StatusCode::BAD_REQUEST);
+            assert_eq!(err.code(), Some(code));
+        }
+
+        assert!(check_summary_fields(true, None).is_ok());
+        assert!(check_summary_fields(false, Some(ContextMode::EnclosingSymbol)).is_ok());
+    }
+
+    fn chunk(id: u64, relative_path: &str, start_byte: usize, score: f32) -> ScoredPoint {
+        ScoredPoint {
+            id: Some(PointId::from(id)),
+            payload: [
+                ("repo_ref", "github.com/bloopai/bloop".to_owned()),
+                ("relative_path", relative_path.to_owned()),
+                ("start_byte", start_byte.to_string()),
+                ("lang", "rust".to_owned()),
+                ("snippet", format!("chunk {id}")),
+            ]
+            .into_iter()
+            .map(|(k, v)| (k.to_owned(), v.into()))
+            .collect(),
+            score,
+            ..Default::default()
+        }
+    }
+
+    fn repo_chunk(repo_ref: &str, id: u64, score: f32) -> ScoredPoint {
+        let mut chunk = chunk(id, "src/lib.rs", 0, score);
+        chunk
+            .payload
+            .insert("repo_ref".into(), repo_ref.to_owned().into());
+        chunk
+    }
+
+    #[test]
+    fn malformed_points_are_skipped_with_a_warning() {
+        let mut pathless = chunk(2, "src/main.rs", 0, 0.8);
+        pathless.payload.remove("relative_path");
+        let candidates = vec![chunk(1, "src/lib.rs", 0, 0.9), pathless];
+
+        let (candidates, malformed) = well_formed(candidates, &PayloadSchema::default());
+        let response = SemanticResponse {
+            chunks: to_chunks(candidates, &PayloadSchema::default()).unwrap(),
+            warnings: warnings(malformed, &[]),
+            ..Default::default()
+        };
+
+        assert_eq!(response.returned(), 1);
+        assert_eq!(
+            response.warnings.iter().map(|w| w.code).collect::<Vec<_>>(),
+            [WarningCode::MalformedPoints]
+        );
+        assert!(response.warnings[0].message.contains("1 malformed"));
+
+        // well formed results of complete searches have no warnings
+        assert!(warnings(0, &[]).is_empty());
+        assert_eq!(
+            warnings(0, &["shard-2".to_owned()])[0].code,
+            WarningCode::PartialResults
+        );
+    }
+
+    #[test]
+    fn files_are_ranked_by_their_best_chunk() {
+        let candidates = vec![
+            chunk(1, "src/lib.rs", 0, 0.9),
+            chunk(2, "src/main.rs", 0, 0.8),
+            chunk(3, "src/main.rs", 100, 0.7),
+            chunk(4, "src/query.rs", 0, 0.6),
+            chunk(5, "src/main.rs", 200, 0.5),
+            repo_chunk("github.com/org/fork", 6, 0.85),
+        ];
+
+        let files = rank_files(&candidates, &PayloadSchema::default())
+            .into_iter()
+            .map(|f| (f.repo_ref, f.relative_path, f.best_score, f.match_count))
+            .collect::<Vec<_>>();
+
+        let bloop = "github.com/bloopai/bloop".to_owned();
+        assert_eq!(
+            files,
+            [
+                (bloop.clone(), "src/lib.rs".to_owned(), 0.9, 1),
+                // the same path in another repository is another file
+                (
+                    "github.com/org/fork".to_owned(),
+                    "src/lib.rs".to_owned(),
+                    0.85,
+                    1
+                ),
+                (bloop.clone(), "src/main.rs".to_owned(), 0.8, 3),
+                (bloop, "src/query.rs".to_owned(), 0.6, 1),
+            ]
+        );
+    }
+
+    #[test]
+    fn chunks_are_grouped_by_repository() {
+        let candidates = vec![
+            repo_chunk("github.com/org/a", 1, 0.7),
+            repo_chunk("github.com/org/b", 2, 0.9),
+            repo_chunk("github.com/org/a", 3, 0.85),
+            repo_chunk("github.com/org/c", 4, 0.5),
+            repo_chunk("github.com/org/b", 5, 0.6),
+            repo_chunk("github.com/org/a", 6, 0.8),
+            repo_chunk("github.com/org/b", 7, 0.4),
+        ];
+
+        let groups = group_repos(candidates, 2, &PayloadSchema::default());
+        let repos = groups
+            .iter()
+            .map(|(group, _)| (group.repo_ref.as_str(), group.best_score))
+            .collect::<Vec<_>>();
+        let ids = groups
+            .iter()
+            .map(|(_, points)| points.iter().map(|p| p.id.clone().unwrap()).collect())
+            .collect::<Vec<Vec<_>>>();
+
+        // repositories by their best chunk, each with its two best chunks
+        assert_eq!(
+            repos,
+            [
+                ("github.com/org/b", 0.9),
+                ("github.com/org/a", 0.85),
+                ("github.com/org/c", 0.5)
+            ]
+        );
+        assert_eq!(ids[0], [2, 5].map(PointId::from));
+        assert_eq!(ids[1], [3, 6].map(PointId::from));
+        assert_eq!(ids[2], [PointId::from(4)]);
+
+        let err = check_group_by_repo(true, true).unwrap_err();
+        assert_eq!(err.status, StatusCode::BAD_REQUEST);
+        assert_eq!(err.code(), Some(ErrorCode::IncompatibleParams));
+        assert!(check_group_by_repo(true, false).is_ok());
+    }
+
+    #[test]
+    fn files_only_searches_skip_chunk_fields() {
+        let err = check_files_only(true, None, Some(ContextMode::Lines(2))).unwrap_err();
+        assert_eq!(err.code(), Some(ErrorCode::IncompatibleParams));
+        assert!(check_files_only(true, Some(&FieldSet::Summary), None).is_err());
+        assert!(check_files_only(true, None, None).is_ok());
+        assert!(check_files_only(false, Some(&FieldSet::All), None).is_ok());
+    }
+
+    #[test]
+    fn boosted_repos_overtake_slightly_closer_chunks() {
+        let mut candidates = vec![
+            repo_chunk("github.com/org/other", 1, 0.82),
+            repo_chunk("github.com/org/mine", 2, 0.8),
+            repo_chunk("github.com/org/other", 3, 0.5),
+            repo_chunk("github.com/org/mine", 4, 0.3),
+        ];
+
+        let mine = ["github.com/org/mine".to_owned()];
+        boost(&mut candidates, &mine, 1.2, &PayloadSchema::default());
+
+        let ids = candidates
+            .iter()
+            .map(|c| c.id.clone().unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(ids, [2, 1, 3, 4].map(PointId::from));
+        assert!((candidates[0].score - 0.96).abs() < 1e-6);
+        // chunks of other repositories keep their raw score
+        assert_eq!(candidates[1].score, 0.82);
+    }
+
+    #[test]
+    fn remapped_payload_keys_are_read_through_the_schema() {
+        let schema = PayloadSchema::parse(&[
+            "repo_ref=repository".to_owned(),
+            "relative_path=path".to_owned(),
+        ])
+        .unwrap();
+        let mut candidates = [
+            ("github.com/org/other", 1, 0.82),
+            ("github.com/org/mine", 2, 0.8),
+        ]
+        .map(|(repo_ref, id, score)| {
+            let mut point = repo_chunk(repo_ref, id, score);
+            for (field, key) in [("repo_ref", "repository"), ("relative_path", "path")] {
+                let value = point.payload.remove(field).unwrap();
+                point.payload.insert(key.to_owned(), value);
+            }
+            point
+        })
+        .to_vec();
+
+        let mine = ["github.com/org/mine".to_owned()];
+        boost(&mut candidates, &mine, 1.2, &schema);
+        assert_eq!(candidates[0].id, Some(PointId::from(2)));
+
+        assert_eq!(
+            point_repos(&candidates, &schema),
+            ["github.com/org/mine", "github.com/org/other"]
+        );
+
+        let files = rank_files(&candidates, &schema);
+        assert_eq!(files.len(), 2);
+        assert_eq!(files[0].repo_ref, "github.com/org/mine");
+        assert_eq!(files[0].relative_path, "src/lib.rs");
+
+        // endpoints return fields by name, so they are read the same way from then on
+        let chunks = to_chunks(candidates.clone(), &schema).unwrap();
+        assert_eq!(
+            chunk_field(&chunks[0], "repo_ref"),
+            Some("github.com/org/mine")
+        );
+        assert_eq!(chunk_field(&chunks[0], "relative_path"), Some("src/lib.rs"));
+        assert_eq!(
+            summarize(candidates.clone(), &schema)[0]["relative_path"],
+            "src/lib.rs"
+        );
+
+        // under the default keys, the remapped fields are missing
+        assert!(point_repos(&candidates, &PayloadSchema::default()).is_empty());
+    }
+
+    /// Serialize a deterministic response, as for candidates returned in the given order.
+    fn deterministic_response(mut candidates: Vec<ScoredPoint>) -> String {
+        sort_deterministically(&mut candidates, &PayloadSchema::default());
+        let facets = Some(count_facets(&candidates, &PayloadSchema::default()));
+        candidates.truncate(3);
+
+        serde_json::to_string(&SemanticResponse {
+            chunks: to_chunks(candidates, &PayloadSchema::default()).unwrap(),
+            facets,
+            ..Default::default()
+        })
+        .unwrap()
+    }
+
+    #[test]
+    fn deterministic_responses_are_identical() {
+        // three chunks tie on score, and are returned in a different order each time
+        let first = deterministic_response(vec![
+            chunk(1, "src/lib.rs", 100, 0.5),
+            chunk(2, "src/lib.rs", 0, 0.5),
+            chunk(3, "src/main.rs", 0, 0.9),
+            chunk(4, "src/bin.rs", 0, 0.5),
+        ]);
+        let second = deterministic_response(vec![
+            chunk(4, "src/bin.rs", 0, 0.5),
+            chunk(3, "src/main.rs", 0, 0.9),
+            chunk(1, "src/lib.rs", 100, 0.5),
+            chunk(2, "src/lib.rs", 0, 0.5),
+        ]);
+
+        assert_eq!(first, second);
+
+        let chunks = serde_json::from_str::<serde_json::Value>(&first).unwrap()["chunks"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|c| c["snippet"].as_str().unwrap().to_owned())
+            .collect::<Vec<_>>();
+        assert_eq!(chunks, vec!["chunk 3", "chunk 4", "chunk 2"]);
+    }
+
+    #[tokio::test]
+    async fn updated_chunks_come_back_from_search() {
+        let dir = tempdir::TempDir::new("semantic").unwrap();
+        let (semantic, store) = semantic::local_for_tests(dir.path()).await;
+
+        let payload = ChunkPayload {
+            repo_name: "bloop".into(),
+            repo_ref: "github.com/bloopai/bloop".into(),
+            relative_path: "src/main.rs".into(),
+            lang: "rust".into(),
+            branches: vec!["head".into()],
+            snippet: "fn main() {}".into(),
+            start_line: 0,
+            end_line: 1,
+            start_byte: 0,
+            end_byte: 12,
+            cell_index: None,
+            kind: ChunkKind::Definition,
+            definitions: vec!["main".into()],
+        };
+        let id = uuid::Uuid::new_v4().to_string();
+        let indexed = PointStruct {
+            id: Some(PointId::from(id.clone())),
+            vectors: Some(semantic.embed("fn main() {}").await.unwrap().into()),
+            payload: payload.into_qdrant(),
+        };
+        store
+            .upsert(semantic.collection(), vec![indexed])
+            .await
+            .unwrap();
+
+        let response = update_chunk(
+            Path(id),
+            Extension(Some(semantic.clone())),
+            Json(UpdateChunk {
+                text: "fn main() { run() }".into(),
+            }),
+        )
+        .await
+        .unwrap()
+        .into_response();
+        let updated: serde_json::Value = serde_json::from_slice(&body(response).await).unwrap();
+        assert_eq!(updated["text"], "fn main() { run() }");
+
+        // searching for the new text finds the chunk with its new text and embedding
+        let embedding = semantic
+            .embed("bloop\tsrc/main.rs\nfn main() { run() }")
+            .await
+            .unwrap();
+        let (found, _) = semantic
+            .search_with_vector(
+                embedding.clone(),
+                FilterArgs::new(FilterLogic::And),
+                VectorWeights::default(),
+                PayloadFields::snippet(),
+                10,
+                false,
+            )
+            .await
+            .unwrap();
+        assert_eq!(found.len(), 1);
+
+        let Some(VectorsOptions::Vector(vector)) =
+            found[0].vectors.clone().and_then(|v| v.vectors_options)
+        else {
+            panic!("expected an unnamed vector");
+        };
+        let snippet = snippet_from_payload(
+            found[0].payload.clone(),
+            semantic.payload_schema(),
+            found[0].score,
+            vector.data,
+            SnippetSource::Semantic,
+        )
+        .unwrap();
+
+        assert_eq!(snippet.text, "fn main() { run() }");
+        assert_eq!(snippet.embedding, embedding);
+        assert_eq!(snippet.relative_path, "src/main.rs");
+        assert_eq!((snippet.start_byte, snippet.end_byte), (0, 12));
+        assert_eq!(snippet.definitions, vec!["main"]);
+    }
+
+    #[test]
+    fn snippets_are_read_from_aliased_keys() {
+        let mut payload = indexed_chunk("src/main.rs", 100).payload;
+        for (field, key) in [("snippet", "content"), ("relative_path", "path")] {
+            let value = payload.remove(field).unwrap();
+            payload.insert(key.into(), value);
+        }
+
+        let schema =
+            PayloadSchema::parse(&["snippet=content".into(), "relative_path=path".into()]).unwrap();
+        let snippet =
+            snippet_from_payload(payload, &schema, 0.5, vec![], SnippetSource::Semantic).unwrap();
+
+        assert_eq!(snippet.text, "chunk at 100");
+        assert_eq!(snippet.relative_path, "src/main.rs");
+        assert_eq!(snippet.start_byte, 100);
+        assert_eq!(snippet.score, 0.5);
+    }
+
+    fn indexed_chunk(relative_path: &str, start_byte: usize) -> RetrievedPoint {
+        let payload = ChunkPayload {
+            repo_name: "bloop".into(),
+            repo_ref: "github.com/bloopai/bloop".into(),
+            relative_path: relative_path.into(),
+            lang: "rust".into(),
+            branches: vec!["head".into()],
+            snippet: format!("chunk at {start_byte}"),
+            start_line: start_byte / 10,
+            end_line: start_byte / 10 + 1,
+            start_byte,
+            end_byte: start_byte + 10,
+            cell_index: None,
+            kind: ChunkKind::Other,
+            definitions: vec![],
+        };
+
+        RetrievedPoint {
+            id: Some(PointId::from(uuid::Uuid::new_v4().to_string())),
+            payload: payload.into_qdrant(),
+            vectors: None,
+        }
+    }
+
+    #[test]
+    fn browsed_chunks_come_back_in_byte_order() {
+        // qdrant scrolls in point id order, which is unrelated to the position of chunks
+        let points = vec![
+            indexed_chunk("src/main.rs", 200),
+            indexed_chunk("src/main.rs", 0),
+            indexed_chunk("src/main.rs", 300),
+            indexed_chunk("src/main.rs", 100),
+        ];
+
+        let page = browse_page(points.clone(), &PayloadSchema::default(), 0, 3).unwrap();
+        assert_eq!(page.total, 4);
+        assert_eq!(page.next_offset, Some(3));
+        assert_eq!(
+            page.snippets
+                .iter()
+                .map(|s| (s.relative_path.as_str(), s.start_byte))
+                .collect::<Vec<_>>(),
+            vec![
+                ("src/main.rs", 0),
+                ("src/main.rs", 100),
+                ("src/main.rs", 200)
+            ]
+        );
+        assert!(page.snippets.iter().all(|s| s.score == 0.0));
+
+        let last = browse_page(points, &PayloadSchema::default(), 3, 3).unwrap();
+        assert_eq!(last.next_offset, None);
+        assert_eq!(last.snippets.len(), 1);
+        assert_eq!(last.snippets[0].start_byte, 300);
+        assert_eq!(last.snippets[0].text, "chunk at 300");
+    }
+
+    #[tokio::test]
+    async fn post_processors_hide_chunks_from_endpoints() {
+        struct HidePath(&'static str);
+
+        impl ResultPostProcessor for HidePath {
+            fn process(&self, _query: &str, results: &[SearchResult<'_>]) -> Vec<usize> {
+                (0..results.len())
+                    .filter(|&i| results[i].relative_path != self.0)
+                    .collect()
+            }
+        }
+
+        let dir = tempdir::TempDir::new("semantic").unwrap();
+        let (semantic, store) = semantic::local_for_tests(dir.path()).await;
+        let semantic = semantic.with_post_processor(HidePath("src/secret.rs"));
+
+        let mut ids = vec![];
+        let mut points = vec![];
+        for (relative_path, start_byte) in [("src/secret.rs", 0), ("src/main.rs", 100)] {
+            let chunk = indexed_chunk(relative_path, start_byte);
+            let embedding = semantic.embed(relative_path).await.unwrap();
+            ids.push(chunk.id.clone().unwrap());
+            points.push(PointStruct {
+                id: chunk.id,
+                vectors: Some(embedding.into()),
+                payload: chunk.payload,
+            });
+        }
+        store.upsert(semantic.collection(), points).await.unwrap();
+
+        let args = BrowseArgs {
+            repo_ref: "github.com/bloopai/bloop".into(),
+            path: None,
+            lang: None,
+            offset: 0,
+            limit: 10,
+        };
+        let response = browse(Query(args), Extension(Some(semantic.clone())))
+            .await
+            .unwrap()
+            .into_response();
+        let page: serde_json::Value = serde_json::from_slice(&body(response).await).unwrap();
+        assert_eq!(page["total"], 1);
+        assert_eq!(page["snippets"][0]["relative_path"], "src/main.rs");
+
+        let Some(PointIdOptions::Uuid(secret)) = ids[0].point_id_options.clone() else {
+            panic!("expected a uuid point id");
+        };
+        let err = update_chunk(
+            Path(secret),
+            Extension(Some(semantic.clone())),
+            Json(UpdateChunk {
+                text: "fn leak() {}".into(),
+            }),
+        )
+        .await
+        .err()
+        .unwrap();
+        assert_eq!(err.status, StatusCode::NOT_FOUND);
+
+        // the search still finds the chunk, which the endpoints then hide
+        let embedding = semantic.embed("src/secret.rs").await.unwrap();
+        let (found, _) = semantic
+            .search_with_vector(
+                embedding,
+                FilterArgs::new(FilterLogic::And),
+                VectorWeights::default(),
+                PayloadFields::snippet(),
+                10,
+                false,
+            )
+            .await
+            .unwrap();
+        assert_eq!(found.len(), 2);
+        let shown = semantic.post_process("where are secrets kept?", found);
+        assert_eq!(shown.len(), 1);
+        assert_eq!(shown[0].payload["relative_path"], "src/main.rs".into());
+    }
+
+    #[tokio::test]
+    async fn file_stats_are_read_once_per_file() {
+        let mut chunks = to_chunks(
+            vec![
+                chunk(1, "src/lib.rs", 0, 0.9),
+                chunk(2, "src/main.rs", 0, 0.8),
+                chunk(3, "src/lib.rs", 100, 0.7),
+                chunk(4, "src/gone.rs", 0, 0.6),
+            ],
+            &PayloadSchema::default(),
+        )
+        .unwrap();
+
+        let mut reads = vec![];
+        attach_file_stats(&mut chunks, |repo_ref, relative_path| {
+            reads.push((repo_ref, relative_path.clone()));
+            let content = match relative_path.as_str() {
+                "src/lib.rs" => Some("fn a() {}\n\nfn b() {}\n".to_owned()),
+                "src/main.rs" => Some("fn main() {}".to_owned()),
+                _ => None,
+            };
+            future::ready(content)
+        })
+        .await;
+
+        assert_eq!(reads.len(), 3);
+        let stats = chunks
+            .iter()
+            .map(|c| (c["file_line_count"].as_u64(), c["file_byte_count"].as_u64()))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            stats,
+            [
+                (Some(3), Some(21)),
+                (Some(1), Some(12)),
+                (Some(3), Some(21)),
+                (None, None),
+            ]
+        );
+    }
+
+    #[test]
+    fn whitespace_is_only_collapsed_on_request() {
+        let args = serde_json::from_value::<Args>(serde_json::json!({
+            "limit": 10,
+            "query": "parse query",
+        }))
+        .unwrap();
+        assert_eq!(args.collapse_whitespace, None);
+
+        let text = "\n    fn a() {}   \n\n\n\n    fn b() {\t\n        b()\n    }\n\n";
+        let mut point = chunk(1, "src/lib.rs", 100, 0.5);
+        point.payload.insert("snippet".into(), text.into());
+        point.payload.insert("end_byte".into(), "156".into());
+
+        let original = to_chunks(vec![point], &PayloadSchema::default()).unwrap();
+        let mut collapsed = original.clone();
+        collapse_snippet_whitespace(&mut collapsed);
+
+        assert_eq!(original[0]["snippet"], text);
+        assert_eq!(
+            collapsed[0]["snippet"],
+            "    fn a() {}\n\n    fn b() {\n        b()\n    }"
+        );
+
+        // the bounds still refer to the indexed source
+        let mut restored = collapsed[0].clone();
+        restored["snippet"] = text.into();
+        assert_eq!(restored, original[0]);
+        assert_eq!(collapsed[0]["start_byte"], "100");
+        assert_eq!(collapsed[0]["end_byte"], "156");
+    }
+
+    async fn body(response: Response) -> Vec<u8> {
+        let mut body = response.into_body();
+        let mut bytes = vec![];
+        while let Some(chunk) = body.data().await {
+            bytes.extend_from_slice(&chunk.unwrap());
+        }
+        bytes
+    }
+
+    #[tokio::test]
+    async fn streamed_searches_report_progress_before_the_result() {
+        let response = || SemanticResponse {
+            chunks: to_chunks(
+                vec![
+                    chunk(1, "src/lib.rs", 0, 0.9),
+                    chunk(2, "src/main.rs", 0, 0.8),
+                ],
+                &PayloadSchema::default(),
+            )
+            .unwrap(),
+            ..Default::default()
+        };
+        let plain = body(respond(HeaderMap::new(), response(), false)).await;
+
+        let (reporter, events) = Reporter::channel();
+        let search = async move {
+            reporter.report(Progress::Searching { candidates: 20 });
+            tokio::task::yield_now().await;
+            reporter.report(Progress::Ranked {
+                fetched: 20,
+                kept: 2,
+            });
+            Ok((HeaderMap::new(), response()))
+        };
+        let stream = progress::stream(search, events).await.unwrap();
+        let streamed = body(Sse::new(stream).into_response()).await;
+
+        let streamed = String::from_utf8(streamed).unwrap();
+        let events = streamed
+            .split("\n\n")
+            .filter(|event| !event.is_empty())
+            .map(|event| {
+                let field = |name| {
+                    event
+                        .lines()
+                        .find_map(|line| line.strip_prefix(name))
+                        .map(str::trim_start)
+                        .unwrap()
+                };
+                (field("event:"), field("data:"))
+            })
+            .collect::<Vec<_>>();
+
+        assert_eq!(
+            events,
+            [
+                ("progress", r#"{"stage":"searching","candidates":20}"#),
+                ("progress", r#"{"stage":"ranked","fetched":20,"kept":2}"#),
+                ("result", std::str::from_utf8(&plain).unwrap()),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn invalid_streamed_searches_fail_with_their_status() {
+        let (_, events) = Reporter::channel();
+        let search = future::ready(Err(empty_search()));
+        let Err(err) = progress::stream(search, events).await else {
+            panic!("the search failed before reporting progress");
+        };
+        assert_eq!(err.status, StatusCode::BAD_REQUEST);
+
+        let mut headers = HeaderMap::new();
+        assert!(!progress::requested(&headers));
+        headers.insert(
+            "accept",
+            "application/json, text/event-stream".parse().unwrap(),
+        );
+        assert!(progress::requested(&headers));
+    }
+
+    /// The response to a search whose candidates all score below `min_score`.
+    fn sub_threshold_response(strict_empty: bool) -> Response {
+        let candidates = vec![
+            chunk(1, "src/lib.rs", 0, 0.3),
+            chunk(2, "src/main.rs", 0, 0.2),
+        ];
+        let (qualifying, dropped) = above_min_score(candidates, Some(0.5));
+        assert_eq!((qualifying.len(), dropped), (0, 2));
+
+        let response = SemanticResponse {
+            chunks: to_chunks(qualifying, &PayloadSchema::default()).unwrap(),
+            ..Default::default()
+        };
+        respond(HeaderMap::new(), response, strict_empty)
+    }
+
+    #[tokio::test]
+    async fn empty_results_are_an_empty_list_by_default() {
+        let response = sub_threshold_response(false);
+        assert_eq!(response.status(), StatusCode::OK);
+
+        let body = serde_json::from_slice::<serde_json::Value>(&body(response).await).unwrap();
+        assert_eq!(body, serde_json::json!({ "chunks": [] }));
+    }
+
+    #[tokio::test]
+    async fn strict_empty_results_have_no_content() {
+        let response = sub_threshold_response(true);
+        assert_eq!(response.status(), StatusCode::NO_CONTENT);
+
+        assert!(body(response).await.is_empty());
+
+        // chunks at or above the floor are kept
+        let (kept, dropped) = above_min_score(
+            vec![chunk(1, "a.rs", 0, 0.5), chunk(2, "b.rs", 0, 0.4)],
+            Some(0.5),
+        );
+        assert_eq!((kept.len(), dropped), (1, 1));
+        assert_eq!(above_min_score(kept, None).1, 0);
+    }
+
+    fn requested_fields(fields: &str) -> PayloadFields {
+        payload_fields(Some(&FieldSet::from(fields.to_owned())))
+    }
+
+    #[test]
+    fn chunks_fetch_the_requested_fields() {
+        assert_eq!(
+            payload_fields(None),
+            PayloadFields::only(CHUNK_FIELDS.iter().copied())
+        );
+        assert_eq!(requested_fields(" ,"), payload_fields(None));
+        assert_eq!(
+            requested_fields("kind, definitions"),
+            PayloadFields::only(CHUNK_FIELDS.iter().copied().chain(["kind", "definitions"]))
+        );
+        assert_eq!(requested_fields("*"), PayloadFields::all());
+    }
+
+    #[test]
+    fn summaries_only_locate_and_score_chunks() {
+        let fields = serde_json::to_value(requested_fields("summary")).unwrap();
+        let fields = fields.as_array().unwrap();
+        assert!(fields.iter().all(|field| field != "snippet"));
+        assert!(fields.contains(&"relative_path".into()));
+
+        // the text, and fields fetched to break ties or count facets, are left out
+        let mut point = chunk(1, "src/main.rs", 120, 0.75);
+        point.payload.insert("start_line".into(), 4_i64.into());
+        point.payload.insert("end_line".into(), 6_i64.into());
+
+        assert_eq!(
+            summarize(vec![point], &PayloadSchema::default()),
+            vec![serde_json::json!({
+                "repo_ref": "github.com/bloopai/bloop",
+                "relative_path": "src/main.rs",
+                "start_line": 4,
+                "end_line": 6,
+                "score": 0.75,
+            })]
+        );
+    }
+}
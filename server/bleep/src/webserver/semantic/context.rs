@@ -0,0 +1,273 @@
+use std::{fmt, ops::Range};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    indexes::reader::ContentDocument,
+    symbol::Symbol,
+    text_range::{slice_chars, TextRange},
+};
+
+/// Lines of context around chunks whose file has no symbol ranges, for `enclosing_symbol`
+const FALLBACK_LINES: usize = 5;
+
+/// Kinds of the symbols whose scope is a definition worth returning whole
+const ENCLOSING_KINDS: &[&str] = &[
+    "class",
+    "enum",
+    "func",
+    "function",
+    "interface",
+    "method",
+    "module",
+    "struct",
+    "union",
+];
+
+/// How much of the surrounding file is returned with each chunk, in its `context`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub(super) enum ContextMode {
+    /// `lines:N`, the chunk along with `N` lines above and below it
+    Lines(usize),
+    /// `bytes:N`, the chunk along with `N` bytes before and after it
+    Bytes(usize),
+    /// `enclosing_symbol`, the whole definition enclosing the chunk, such as a function or a
+    /// class. Chunks of files without symbol ranges, or outside of any definition, get a few
+    /// lines of context instead.
+    EnclosingSymbol,
+}
+
+impl fmt::Display for ContextMode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Lines(n) => write!(f, "lines:{n}"),
+            Self::Bytes(n) => write!(f, "bytes:{n}"),
+            Self::EnclosingSymbol => f.write_str("enclosing_symbol"),
+        }
+    }
+}
+
+impl From<ContextMode> for String {
+    fn from(val: ContextMode) -> Self {
+        val.to_string()
+    }
+}
+
+impl TryFrom<String> for ContextMode {
+    type Error = &'static str;
+
+    fn try_from(input: String) -> Result<Self, &'static str> {
+        const EXPECTED: &str = "context should be `lines:N`, `bytes:N` or `enclosing_symbol`";
+
+        match input.split_once(':') {
+            Some(("lines", n)) => n.parse().map(Self::Lines).map_err(|_| EXPECTED),
+            Some(("bytes", n)) => n.parse().map(Self::Bytes).map_err(|_| EXPECTED),
+            None if input == "enclosing_symbol" => Ok(Self::EnclosingSymbol),
+            _ => Err(EXPECTED),
+        }
+    }
+}
+
+/// A chunk along with its surroundings in the indexed file.
+#[derive(Serialize, Debug, PartialEq, Eq)]
+pub(super) struct Context {
+    /// How the context was taken, which is `lines` if `enclosing_symbol` fell back to it
+    mode: ContextMode,
+    text: String,
+    start_line: usize,
+    end_line: usize,
+    start_byte: usize,
+    end_byte: usize,
+}
+
+/// The context of the chunk spanning the `chunk` bytes of `doc`, or `None` if those are out of
+/// the bounds of the file.
+pub(super) fn of(doc: &ContentDocument, chunk: Range<usize>, mode: ContextMode) -> Option<Context> {
+    let (symbols, scopes) = match mode {
+        ContextMode::EnclosingSymbol => (
+            doc.symbol_locations.list(),
+            doc.symbol_locations.nested_scopes(),
+        ),
+        _ => (vec![], vec![]),
+    };
+
+    context_of(&doc.content, &symbols, &scopes, chunk, mode)
+}
+
+fn context_of(
+    content: &str,
+    symbols: &[Symbol],
+    scopes: &[TextRange],
+    chunk: Range<usize>,
+    mode: ContextMode,
+) -> Option<Context> {
+    if chunk.start > chunk.end || chunk.end > content.len() {
+        return None;
+    }
+
+    // widen bounds that fall inside a character
+    let (_, chunk) = slice_chars(content, chunk);
+    let (mode, bytes) = match mode {
+        ContextMode::Lines(n) => (mode, around_lines(content, &chunk, n)),
+        ContextMode::Bytes(n) => {
+            let (_, bytes) = slice_chars(
+                content,
+                chunk.start.saturating_sub(n)..chunk.end.saturating_add(n),
+            );
+            (mode, bytes)
+        }
+        ContextMode::EnclosingSymbol => match enclosing_definition(symbols, scopes, &chunk) {
+            Some(definition) => (mode, around_lines(content, &definition, 0)),
+            None => (
+                ContextMode::Lines(FALLBACK_LINES),
+                around_lines(content, &chunk, FALLBACK_LINES),
+            ),
+        },
+    };
+
+    Some(Context {
+        mode,
+        text: content[bytes.clone()].to_owned(),
+        start_line: content[..bytes.start].matches('\n').count(),
+        end_line: content[..bytes.end].matches('\n').count(),
+        start_byte: bytes.start,
+        end_byte: bytes.end,
+    })
+}
+
+/// The smallest scope spanning `chunk` that is the definition of a symbol of one of the
+/// [`ENCLOSING_KINDS`].
+///
+/// A scope is taken to define the symbols named on its first line, such as `parse` in
+/// `fn parse() {`, whereas the function body, which is a scope of its own, starts after the
+/// name.
+fn enclosing_definition(
+    symbols: &[Symbol],
+    scopes: &[TextRange],
+    chunk: &Range<usize>,
+) -> Option<Range<usize>> {
+    scopes
+        .iter()
+        .filter(|scope| scope.start.byte <= chunk.start && chunk.end <= scope.end.byte)
+        .filter(|scope| {
+            symbols.iter().any(|symbol| {
+                ENCLOSING_KINDS.contains(&symbol.kind.as_str())
+                    && symbol.range.start.line == scope.start.line
+                    && scope.start.byte <= symbol.range.start.byte
+                    && symbol.range.end.byte <= scope.end.byte
+            })
+        })
+        .min_by_key(|scope| scope.end.byte - scope.start.byte)
+        .map(|scope| scope.start.byte..scope.end.byte)
+}
+
+/// `range`, widened to whole lines, along with `n` lines above and below it.
+fn around_lines(content: &str, range: &Range<usize>, n: usize) -> Range<usize> {
+    let start = content[..range.start]
+        .rmatch_indices('\n')
+        .nth(n)
+        .map_or(0, |(i, _)| i + 1);
+    let end = content[range.end..]
+        .match_indices('\n')
+        .nth(n)
+        .map_or(content.len(), |(i, _)| range.end + i);
+
+    start..end
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::intelligence::TreeSitterFile;
+
+    const SOURCE: &str = "\
+use std::fmt;
+
+/// Split a query into its terms
+fn parse(query: &str) -> Vec<String> {
+    let mut terms = vec![];
+    for term in query.split(' ') {
+        terms.push(term.to_owned());
+    }
+    terms
+}
+
+fn main() {}
+";
+
+    fn chunk_of(text: &str) -> Range<usize> {
+        let start = SOURCE.find(text).unwrap();
+        start..start + text.len()
+    }
+
+    #[test]
+    fn enclosing_symbols_span_the_whole_definition() {
+        let graph = TreeSitterFile::try_build(SOURCE.as_bytes(), "Rust")
+            .and_then(TreeSitterFile::scope_graph)
+            .unwrap();
+        let chunk = chunk_of("terms.push(term.to_owned());");
+
+        let context = context_of(
+            SOURCE,
+            &graph.symbols(),
+            &graph.nested_scopes(),
+            chunk,
+            ContextMode::EnclosingSymbol,
+        )
+        .unwrap();
+
+        // the loop around the chunk is a smaller scope, but it only defines a variable
+        let start = SOURCE.find("fn parse").unwrap();
+        let end = SOURCE.find("}\n\nfn main").unwrap() + 1;
+        assert_eq!(context.mode, ContextMode::EnclosingSymbol);
+        assert_eq!(context.text, &SOURCE[start..end]);
+        assert!(context.text.starts_with("fn parse(query: &str)"));
+        assert!(context.text.ends_with("    terms\n}"));
+        assert_eq!((context.start_line, context.end_line), (3, 9));
+        assert_eq!((context.start_byte, context.end_byte), (start, end));
+    }
+
+    #[test]
+    fn files_without_symbols_fall_back_to_lines() {
+        let chunk = chunk_of("terms.push(term.to_owned());");
+
+        let context = context_of(
+            SOURCE,
+            &[],
+            &[],
+            chunk.clone(),
+            ContextMode::EnclosingSymbol,
+        )
+        .unwrap();
+        assert_eq!(context.mode, ContextMode::Lines(FALLBACK_LINES));
+        assert_eq!(context.start_line, 1);
+        assert_eq!(context.end_line, 11);
+
+        let context = context_of(SOURCE, &[], &[], chunk.clone(), ContextMode::Lines(1)).unwrap();
+        assert_eq!(
+            context.text,
+            "    for term in query.split(' ') {\n        terms.push(term.to_owned());\n    }"
+        );
+
+        let context = context_of(SOURCE, &[], &[], chunk, ContextMode::Bytes(6)).unwrap();
+        assert_eq!(context.text, "      terms.push(term.to_owned());\n    }");
+
+        assert!(context_of(SOURCE, &[], &[], 0..SOURCE.len() + 1, ContextMode::Lines(1)).is_none());
+    }
+
+    #[test]
+    fn modes_are_parsed_from_their_names() {
+        for mode in [
+            ContextMode::Lines(3),
+            ContextMode::Bytes(120),
+            ContextMode::EnclosingSymbol,
+        ] {
+            assert_eq!(ContextMode::try_from(mode.to_string()), Ok(mode));
+        }
+
+        assert!(ContextMode::try_from("lines".to_owned()).is_err());
+        assert!(ContextMode::try_from("lines:many".to_owned()).is_err());
+        assert!(ContextMode::try_from("function".to_owned()).is_err());
+    }
+}
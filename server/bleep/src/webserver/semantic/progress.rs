@@ -0,0 +1,110 @@
+use std::future::Future;
+
+use axum::{
+    http::{header::ACCEPT, HeaderMap},
+    response::sse::Event,
+};
+use futures::{
+    future::{self, Either},
+    Stream,
+};
+use tokio::sync::mpsc;
+
+use super::SemanticResponse;
+use crate::webserver::prelude::*;
+
+/// A step of a search streamed as server-sent events, sent in a `progress` event.
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+#[serde(tag = "stage", rename_all = "snake_case")]
+pub(super) enum Progress {
+    /// The query is valid, and up to `candidates` chunks are being fetched
+    Searching { candidates: u64 },
+    /// `fetched` candidates came back from every collection, of which `kept` qualify
+    Ranked { fetched: usize, kept: usize },
+    /// Blame, context or file stats are being attached to the `chunks` returned
+    Attaching { chunks: usize },
+}
+
+/// Where a search reports its progress, which is nowhere unless it is streamed.
+#[derive(Default)]
+pub(super) struct Reporter(Option<mpsc::UnboundedSender<Progress>>);
+
+impl Reporter {
+    /// A reporter sending its progress to the returned receiver.
+    pub(super) fn channel() -> (Self, mpsc::UnboundedReceiver<Progress>) {
+        let (sender, receiver) = mpsc::unbounded_channel();
+        (Self(Some(sender)), receiver)
+    }
+
+    pub(super) fn report(&self, progress: Progress) {
+        if let Some(sender) = &self.0 {
+            // the stream is gone if the client disconnected, along with the search
+            let _ = sender.send(progress);
+        }
+    }
+}
+
+/// Whether the client asked for `text/event-stream`, rather than a plain JSON response.
+pub(super) fn requested(headers: &HeaderMap) -> bool {
+    headers
+        .get_all(ACCEPT)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .any(|media| media.trim().starts_with("text/event-stream"))
+}
+
+/// Stream the `progress` reported by `search` as it runs, followed by a `result` event
+/// holding the JSON response, or an `error` event holding the error.
+///
+/// Searches failing before reporting any progress, such as invalid ones, fail with their own
+/// status rather than with an event. Searches answered from the cache only send `result`. The
+/// stream polls `search`, which is dropped along with it when the client disconnects.
+pub(super) async fn stream<F>(
+    search: F,
+    mut events: mpsc::UnboundedReceiver<Progress>,
+) -> Result<impl Stream<Item = Result<Event, axum::Error>>>
+where
+    F: Future<Output = Result<(HeaderMap, SemanticResponse)>>,
+{
+    let mut search = Box::pin(search);
+    let (first, done) = match future::select(Box::pin(events.recv()), &mut search).await {
+        Either::Left((Some(first), _)) => (Some(first), None),
+        // the reporter is gone, so the search can't report any progress
+        Either::Left((None, search)) => (None, Some(search.await?.1)),
+        Either::Right((result, _)) => (None, Some(result?.1)),
+    };
+
+    Ok(async_stream::stream! {
+        let result = match done {
+            Some(response) => Ok(response),
+            None => {
+                if let Some(progress) = first {
+                    yield event(&progress);
+                }
+
+                loop {
+                    match future::select(Box::pin(events.recv()), &mut search).await {
+                        Either::Left((Some(progress), _)) => yield event(&progress),
+                        Either::Left((None, search)) => break search.await.map(|(_, r)| r),
+                        Either::Right((result, _)) => break result.map(|(_, r)| r),
+                    }
+                }
+            }
+        };
+
+        // progress reported in the same poll as the search finished
+        while let Ok(progress) = events.try_recv() {
+            yield event(&progress);
+        }
+
+        match result {
+            Ok(response) => yield Event::default().event("result").json_data(&response),
+            Err(err) => yield Event::default().event("error").json_data(&err.body.0),
+        }
+    })
+}
+
+fn event(progress: &Progress) -> Result<Event, axum::Error> {
+    Event::default().event("progress").json_data(progress)
+}
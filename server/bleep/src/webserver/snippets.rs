@@ -0,0 +1,479 @@
+use std::collections::HashMap;
+
+use axum::Json;
+use git2::{DiffOptions, Patch};
+use tracing::debug;
+
+use super::prelude::*;
+use crate::{repo::RepoRef, Application};
+
+/// Least fraction of the original lines a window must contain to count as a fuzzy match
+const MIN_TEXT_SIMILARITY: f32 = 0.5;
+
+#[derive(Deserialize)]
+pub(super) struct ReanchorRequest {
+    repo_ref: RepoRef,
+    relative_path: String,
+
+    /// The commit the line range was taken at
+    commit: Option<String>,
+
+    /// 1-indexed line number at which the original range starts
+    start_line: usize,
+
+    /// 1-indexed line number at which the original range ends, inclusive
+    end_line: usize,
+
+    /// Text of the original range, matched against the current file if `commit` is unavailable
+    text: Option<String>,
+}
+
+#[derive(Serialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub(super) enum AnchorStatus {
+    /// The range is at the same lines, with the same content
+    Unchanged,
+    /// The range was shifted by edits elsewhere in the file, or was itself edited
+    Moved,
+    /// The range, or its whole file, no longer exists
+    Deleted,
+}
+
+/// Current location of a line range.
+#[derive(Serialize, Debug, PartialEq)]
+pub(super) struct Anchor {
+    pub(super) status: AnchorStatus,
+    pub(super) start_line: Option<usize>,
+    pub(super) end_line: Option<usize>,
+
+    /// How likely this location is to be right, from 0 to 1
+    confidence: f32,
+}
+
+impl super::ApiResponse for Anchor {}
+
+impl Anchor {
+    fn at(status: AnchorStatus, start_line: usize, end_line: usize, confidence: f32) -> Self {
+        Self {
+            status,
+            start_line: Some(start_line),
+            end_line: Some(end_line),
+            confidence,
+        }
+    }
+
+    fn deleted(confidence: f32) -> Self {
+        Self {
+            status: AnchorStatus::Deleted,
+            start_line: None,
+            end_line: None,
+            confidence,
+        }
+    }
+}
+
+/// A line range of the original file, 1-indexed and inclusive.
+#[derive(Clone, Copy, Debug)]
+pub(super) struct Range {
+    pub(super) start: usize,
+    pub(super) end: usize,
+}
+
+/// A changed region of the file, as reported by a diff without context lines.
+///
+/// Like in unified diffs, the start of an empty side is the line *before* the change.
+#[derive(Clone, Copy, Debug)]
+pub(super) struct Hunk {
+    pub(super) old_start: usize,
+    pub(super) old_lines: usize,
+    pub(super) new_start: usize,
+    pub(super) new_lines: usize,
+}
+
+enum LineMap {
+    Kept(usize),
+    Changed(Hunk),
+}
+
+impl Hunk {
+    /// Whether `line` of the original file was removed or rewritten by this hunk.
+    fn changes(&self, line: usize) -> bool {
+        self.old_lines > 0 && (self.old_start..self.old_start + self.old_lines).contains(&line)
+    }
+
+    /// Whether this hunk lies entirely before `line` of the original file.
+    fn precedes(&self, line: usize) -> bool {
+        if self.old_lines > 0 {
+            self.old_start + self.old_lines <= line
+        } else {
+            self.old_start < line
+        }
+    }
+
+    /// First line of the new side, or the line after an empty new side.
+    pub(super) fn first_new_line(&self) -> usize {
+        if self.new_lines > 0 {
+            self.new_start
+        } else {
+            self.new_start + 1
+        }
+    }
+
+    /// Last line of the new side, or the line before an empty new side.
+    pub(super) fn last_new_line(&self) -> usize {
+        self.new_start + self.new_lines.saturating_sub(1)
+    }
+}
+
+fn map_line(line: usize, hunks: &[Hunk]) -> LineMap {
+    let mut shift = 0isize;
+
+    for hunk in hunks {
+        if hunk.changes(line) {
+            return LineMap::Changed(*hunk);
+        }
+
+        if !hunk.precedes(line) {
+            break;
+        }
+
+        shift += hunk.new_lines as isize - hunk.old_lines as isize;
+    }
+
+    LineMap::Kept((line as isize + shift) as usize)
+}
+
+fn diff_hunks(original: &str, current: &str) -> Result<Vec<Hunk>> {
+    let mut opts = DiffOptions::new();
+    opts.context_lines(0);
+
+    let patch = Patch::from_buffers(
+        original.as_bytes(),
+        None,
+        current.as_bytes(),
+        None,
+        Some(&mut opts),
+    )
+    .map_err(Error::internal)?;
+
+    patch_hunks(&patch)
+}
+
+pub(super) fn patch_hunks(patch: &Patch<'_>) -> Result<Vec<Hunk>> {
+    (0..patch.num_hunks())
+        .map(|i| {
+            let (hunk, _) = patch.hunk(i).map_err(Error::internal)?;
+            Ok(Hunk {
+                old_start: hunk.old_start() as usize,
+                old_lines: hunk.old_lines() as usize,
+                new_start: hunk.new_start() as usize,
+                new_lines: hunk.new_lines() as usize,
+            })
+        })
+        .collect()
+}
+
+/// Follow `range` through the diff between the `original` file and its `current` contents.
+///
+/// The confidence is the share of the range's lines that survived, counting lines inserted
+/// inside the range against it.
+pub(super) fn reanchor_by_diff(original: &str, current: &str, range: Range) -> Result<Anchor> {
+    if range.end > original.lines().count() {
+        return Err(Error::user(
+            "line range is past the end of the original file",
+        ));
+    }
+
+    let hunks = diff_hunks(original, current)?;
+
+    let total = range.end - range.start + 1;
+    let kept = (range.start..=range.end)
+        .filter(|l| matches!(map_line(*l, &hunks), LineMap::Kept(_)))
+        .count();
+    let inserted = hunks
+        .iter()
+        .filter(|h| h.old_lines == 0 && (range.start..range.end).contains(&h.old_start))
+        .map(|h| h.new_lines)
+        .sum::<usize>();
+
+    if kept == 0 {
+        return Ok(Anchor::deleted(1.0));
+    }
+
+    let start = match map_line(range.start, &hunks) {
+        LineMap::Kept(l) => l,
+        LineMap::Changed(hunk) => hunk.first_new_line(),
+    };
+    let end = match map_line(range.end, &hunks) {
+        LineMap::Kept(l) => l,
+        LineMap::Changed(hunk) => hunk.last_new_line(),
+    };
+
+    let status = if kept == total && inserted == 0 && start == range.start {
+        AnchorStatus::Unchanged
+    } else {
+        AnchorStatus::Moved
+    };
+
+    Ok(Anchor::at(
+        status,
+        start,
+        end.max(start),
+        kept as f32 / (total + inserted) as f32,
+    ))
+}
+
+/// Find the window of `current` that shares the most lines with the `original` text.
+///
+/// Lines are compared with surrounding whitespace trimmed, and regardless of order, so small
+/// edits and reindentation still match. Ties go to the window closest to the original range.
+fn reanchor_by_text(original: &str, current: &str, range: Range) -> Anchor {
+    let needle = original.lines().map(str::trim).collect::<Vec<_>>();
+    let haystack = current.lines().map(str::trim).collect::<Vec<_>>();
+    if needle.is_empty() || haystack.is_empty() {
+        return Anchor::deleted(1.0);
+    }
+
+    let mut wanted = HashMap::<&str, usize>::new();
+    for line in &needle {
+        *wanted.entry(line).or_default() += 1;
+    }
+
+    let width = needle.len().min(haystack.len());
+    let mut best = None::<(usize, usize)>;
+
+    for start in 0..=haystack.len() - width {
+        let mut remaining = wanted.clone();
+        let shared = haystack[start..start + width]
+            .iter()
+            .filter(|line| match remaining.get_mut(*line) {
+                Some(n) if *n > 0 => {
+                    *n -= 1;
+                    true
+                }
+                _ => false,
+            })
+            .count();
+
+        let distance = |s: usize| (s + 1).abs_diff(range.start);
+        best = match best {
+            Some((s, n)) if n > shared || (n == shared && distance(s) <= distance(start)) => {
+                Some((s, n))
+            }
+            _ => Some((start, shared)),
+        };
+    }
+
+    let (start, shared) = best.expect("the haystack is not empty");
+    let similarity = shared as f32 / needle.len() as f32;
+    if similarity < MIN_TEXT_SIMILARITY {
+        return Anchor::deleted(1.0 - similarity);
+    }
+
+    let status = if shared == needle.len() && start + 1 == range.start {
+        AnchorStatus::Unchanged
+    } else {
+        AnchorStatus::Moved
+    };
+
+    Anchor::at(status, start + 1, start + width, similarity)
+}
+
+/// Locate `range` in the `current` file, which is `None` if the file was deleted.
+///
+/// The `original` file is diffed against the current one if it's known, otherwise the original
+/// `text` is fuzzy matched.
+fn locate(
+    current: Option<&str>,
+    original: Option<&str>,
+    text: Option<&str>,
+    range: Range,
+) -> Result<Anchor> {
+    if range.start == 0 || range.end < range.start {
+        return Err(Error::user("invalid line range"));
+    }
+
+    let Some(current) = current else {
+        return Ok(Anchor::deleted(1.0));
+    };
+
+    match (original, text) {
+        (Some(original), _) => reanchor_by_diff(original, current, range),
+        (None, Some(text)) => Ok(reanchor_by_text(text, current, range)),
+        (None, None) => Err(Error::user(
+            "the original commit is unavailable, and no original text was given",
+        )),
+    }
+}
+
+pub(super) fn file_at_commit(
+    disk_path: &std::path::Path,
+    commit: &str,
+    relative_path: &str,
+) -> anyhow::Result<String> {
+    let git = git2::Repository::open(disk_path)?;
+    let tree = git.revparse_single(commit)?.peel_to_commit()?.tree()?;
+    let blob = tree
+        .get_path(std::path::Path::new(relative_path))?
+        .to_object(&git)?
+        .peel_to_blob()?;
+
+    Ok(String::from_utf8_lossy(blob.content()).into_owned())
+}
+
+/// Find where a line range taken at an older commit is now
+///
+/// The file at `commit` is diffed against the indexed version. If that commit is no longer
+/// available, the original `text` is fuzzy matched against the indexed file instead.
+//
+#[utoipa::path(post, path = "/snippets/reanchor",
+    responses(
+        (status = 200, description = "Execute query successfully", body = Anchor),
+        (status = 400, description = "Bad request", body = EndpointError),
+        (status = 404, description = "Repository not found", body = EndpointError),
+        (status = 500, description = "Server error", body = EndpointError),
+    ),
+)]
+pub(super) async fn reanchor(
+    Extension(app): Extension<Application>,
+    Json(req): Json<ReanchorRequest>,
+) -> Result<impl IntoResponse> {
+    let Some(disk_path) = app
+        .repo_pool
+        .read_async(&req.repo_ref, |_, repo| repo.disk_path.clone())
+        .await
+    else {
+        return Err(Error::new(ErrorKind::NotFound, "Repo not found"));
+    };
+
+    let current = app
+        .indexes
+        .file
+        .by_path(&req.repo_ref, &req.relative_path)
+        .await
+        .ok();
+
+    let original = match req.commit {
+        Some(commit) => {
+            let relative_path = req.relative_path.clone();
+            tokio::task::spawn_blocking(move || {
+                file_at_commit(&disk_path, &commit, &relative_path)
+                    .map_err(|err| debug!(?err, %commit, "original file is unavailable"))
+                    .ok()
+            })
+            .await
+            .map_err(Error::internal)?
+        }
+        None => None,
+    };
+
+    let anchor = locate(
+        current.as_ref().map(|doc| doc.content.as_str()),
+        original.as_deref(),
+        req.text.as_deref(),
+        Range {
+            start: req.start_line,
+            end: req.end_line,
+        },
+    )?;
+
+    Ok(json(anchor))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ORIGINAL: &str = "\
+use std::fs;
+
+fn main() {
+    let config = fs::read_to_string(\"config.toml\").unwrap();
+    println!(\"{config}\");
+}
+";
+
+    fn range(start: usize, end: usize) -> Range {
+        Range { start, end }
+    }
+
+    fn located(
+        current: Option<&str>,
+        original: Option<&str>,
+        text: Option<&str>,
+        range: Range,
+    ) -> Anchor {
+        locate(current, original, text, range)
+            .ok()
+            .expect("the request is valid")
+    }
+
+    #[test]
+    fn untouched_ranges_are_unchanged() {
+        let anchor = located(Some(ORIGINAL), Some(ORIGINAL), None, range(3, 6));
+        assert_eq!(anchor, Anchor::at(AnchorStatus::Unchanged, 3, 6, 1.0));
+    }
+
+    #[test]
+    fn insertions_above_move_the_range() {
+        let current = format!("//! Entry point\n\nuse std::env;\n{ORIGINAL}");
+
+        let anchor = located(Some(&current), Some(ORIGINAL), None, range(3, 6));
+        assert_eq!(anchor, Anchor::at(AnchorStatus::Moved, 6, 9, 1.0));
+
+        // insertions below don't affect the range
+        let current = format!("{ORIGINAL}\nfn helper() {{}}\n");
+        let anchor = located(Some(&current), Some(ORIGINAL), None, range(3, 6));
+        assert_eq!(anchor, Anchor::at(AnchorStatus::Unchanged, 3, 6, 1.0));
+    }
+
+    #[test]
+    fn edits_in_range_lower_confidence() {
+        let current = ORIGINAL.replace("println!(\"{config}\");", "dbg!(config);");
+
+        let anchor = located(Some(&current), Some(ORIGINAL), None, range(3, 6));
+        assert_eq!(anchor, Anchor::at(AnchorStatus::Moved, 3, 6, 0.75));
+
+        let current = ORIGINAL.replace(
+            "    println!",
+            "    let config = config.trim();\n    println!",
+        );
+        let anchor = located(Some(&current), Some(ORIGINAL), None, range(3, 6));
+        assert_eq!(anchor, Anchor::at(AnchorStatus::Moved, 3, 7, 0.8));
+    }
+
+    #[test]
+    fn deleted_ranges_and_files() {
+        let current = "use std::fs;\n";
+        let anchor = located(Some(current), Some(ORIGINAL), None, range(3, 6));
+        assert_eq!(anchor, Anchor::deleted(1.0));
+
+        let anchor = located(None, Some(ORIGINAL), None, range(3, 6));
+        assert_eq!(anchor, Anchor::deleted(1.0));
+    }
+
+    #[test]
+    fn text_is_matched_without_a_commit() {
+        let text = ORIGINAL
+            .lines()
+            .skip(2)
+            .take(4)
+            .collect::<Vec<_>>()
+            .join("\n");
+        let current = format!("// header\n\n{}", ORIGINAL.replace("    ", "  "));
+
+        let anchor = located(Some(&current), None, Some(&text), range(3, 6));
+        assert_eq!(anchor, Anchor::at(AnchorStatus::Moved, 5, 8, 1.0));
+
+        let anchor = located(Some("fn other() {}\n"), None, Some(&text), range(3, 6));
+        assert_eq!(anchor.status, AnchorStatus::Deleted);
+    }
+
+    #[test]
+    fn invalid_requests() {
+        assert!(locate(Some(ORIGINAL), Some(ORIGINAL), None, range(0, 2)).is_err());
+        assert!(locate(Some(ORIGINAL), Some(ORIGINAL), None, range(4, 3)).is_err());
+        assert!(locate(Some(ORIGINAL), Some(ORIGINAL), None, range(3, 60)).is_err());
+        assert!(locate(Some(ORIGINAL), None, None, range(3, 6)).is_err());
+    }
+}
@@ -0,0 +1,505 @@
+use std::{future::Future, path::PathBuf, time::Instant};
+
+use axum::response::{sse::Event, Sse};
+use futures::future::{self, Either};
+
+use super::{
+    answer::{
+        answer_candidates, candidate_count, default_limit, query_keywords, select_snippets,
+        snippets_from_points, DedupStrategy, Snippet, Widening,
+    },
+    prelude::*,
+    query::{ApiQuery, ExecuteQuery, QueryResult},
+    replay,
+    semantic::check_query_length,
+    workspaces,
+};
+use crate::{
+    indexes::reader::ContentReader,
+    query::parser::{self, Literal, NLQuery, Target},
+    repo::normalize_relative_path,
+    semantic::{
+        filter::{FilterArgs, FilterLogic},
+        payload::PayloadFields,
+        trace::elapsed_ms,
+        weights::VectorWeights,
+        Semantic,
+    },
+    snippet::SnippedFile,
+    Application, Configuration,
+};
+
+/// Most results of each stage, whatever `limit` was requested
+const MAX_LIMIT: u64 = 100;
+
+#[derive(Deserialize)]
+pub(super) struct TieredArgs {
+    q: String,
+    /// Maximum number of results of each stage, up to 100
+    #[serde(default = "default_limit")]
+    limit: u64,
+    /// How filters on different fields are combined, `and` by default
+    #[serde(default)]
+    filter_logic: FilterLogic,
+    /// Only search the repositories of this workspace
+    workspace: Option<String>,
+}
+
+/// A file matching the keywords of the query, sent in the `lexical` event.
+#[derive(Serialize, Debug)]
+pub(super) struct LexicalHit {
+    /// Identifies the file within this search, see [`SemanticHit::duplicate_of`]
+    id: String,
+    #[serde(flatten)]
+    file: SnippedFile,
+}
+
+/// A semantic snippet, sent in the `semantic` event.
+#[derive(Serialize, Debug)]
+pub(super) struct SemanticHit {
+    #[serde(flatten)]
+    snippet: Snippet,
+    /// The lexical hit whose snippets overlap this one, so clients can merge the two instead of
+    /// rendering the lines twice
+    #[serde(skip_serializing_if = "Option::is_none")]
+    duplicate_of: Option<String>,
+}
+
+/// The last event of the stream.
+#[derive(Serialize, Debug)]
+pub(super) struct Summary {
+    lexical: StageSummary,
+    semantic: StageSummary,
+    /// Semantic snippets marked as duplicates of lexical hits
+    duplicates: usize,
+    /// Time spent on the whole search, in milliseconds
+    total_ms: f64,
+}
+
+#[derive(Serialize, Debug)]
+pub(super) struct StageSummary {
+    /// Time the stage ran for, in milliseconds. Stages run concurrently, so these overlap
+    ms: f64,
+    results: usize,
+    /// Why the stage failed, in which case its event was not sent
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+}
+
+struct Timed<T> {
+    result: Result<T>,
+    ms: f64,
+}
+
+impl<T> Timed<Vec<T>> {
+    fn summary(&self) -> StageSummary {
+        StageSummary {
+            ms: self.ms,
+            results: self.result.as_ref().map_or(0, Vec::len),
+            error: self
+                .result
+                .as_ref()
+                .err()
+                .map(|err| err.message().to_owned()),
+        }
+    }
+}
+
+async fn timed<T>(stage: impl Future<Output = Result<T>>) -> Timed<T> {
+    let start = Instant::now();
+    let result = stage.await;
+    Timed {
+        result,
+        ms: elapsed_ms(start),
+    }
+}
+
+/// Search lexically first, then semantically, streaming the results of each as they are ready
+///
+/// The `lexical` event lists the files literally matching the keywords of the query, and
+/// usually arrives well before the `semantic` event, which lists the semantically ranked,
+/// deduplicated snippets. Both stages run concurrently on the same query and filters, and a
+/// `summary` event with the timings of each ends the stream. Disconnecting cancels the stages
+/// still running.
+//
+#[utoipa::path(get, path = "/search/tiered",
+    responses(
+        (status = 200, description = "Execute query successfully"),
+        (status = 400, description = "Bad request", body = EndpointError),
+        (status = 404, description = "Workspace not found", body = EndpointError),
+        (status = 500, description = "Server error", body = EndpointError),
+    ),
+)]
+pub(super) async fn handle(
+    Query(args): Query<TieredArgs>,
+    Extension(indexes): Extension<Arc<Indexes>>,
+    Extension(semantic): Extension<Option<Semantic>>,
+    Extension(app): Extension<Application>,
+) -> Result<impl IntoResponse> {
+    let start = Instant::now();
+
+    let parsed = parser::parse_nl_cached(&args.q).map_err(Error::user)?;
+    let Some(target) = parsed.target() else {
+        return Err(Error::user("empty search"));
+    };
+    check_query_length(target, app.config.min_query_chars)?;
+    let target = target.to_string();
+
+    let (repo_refs, scope) = match args.workspace.as_deref() {
+        Some(workspace) => (
+            Some(workspaces::resolve(&app, workspace)?),
+            Some(workspaces::resolve_disk_paths(&app, workspace).await?),
+        ),
+        None => (None, None),
+    };
+
+    let queries = lexical_queries(&parsed, args.filter_logic);
+    let mut filters = FilterArgs::from_query(&parsed, args.filter_logic);
+    if let Some(repo_refs) = repo_refs {
+        filters = filters.within_repos(repo_refs);
+    }
+
+    let limit = args.limit.min(MAX_LIMIT) as usize;
+    let lexical = timed(lexical_stage(indexes, args.q, queries, scope, limit));
+    let semantic = timed(semantic_stage(
+        semantic,
+        Arc::clone(&app.config),
+        target,
+        filters,
+        limit,
+    ));
+
+    // the stages are polled by the stream, so they are dropped along with it when the client
+    // disconnects
+    let stream = async_stream::stream! {
+        futures::pin_mut!(lexical, semantic);
+
+        let (lexical, semantic) = match future::select(lexical, semantic).await {
+            Either::Left((lexical, semantic)) => {
+                if let Ok(hits) = &lexical.result {
+                    yield Event::default().event("lexical").json_data(hits);
+                }
+                (lexical, semantic.await)
+            }
+            Either::Right((semantic, lexical)) => {
+                let lexical = lexical.await;
+                if let Ok(hits) = &lexical.result {
+                    yield Event::default().event("lexical").json_data(hits);
+                }
+                (lexical, semantic)
+            }
+        };
+
+        let mut summary = Summary {
+            lexical: lexical.summary(),
+            semantic: semantic.summary(),
+            duplicates: 0,
+            total_ms: 0.0,
+        };
+
+        if let Ok(snippets) = semantic.result {
+            let hits = lexical.result.as_deref().unwrap_or_default();
+            let snippets = mark_duplicates(snippets, hits);
+            summary.duplicates = snippets.iter().filter(|s| s.duplicate_of.is_some()).count();
+
+            yield Event::default().event("semantic").json_data(snippets);
+        }
+
+        summary.total_ms = elapsed_ms(start);
+        yield Event::default().event("summary").json_data(summary);
+    };
+
+    Ok(Sse::new(stream).keep_alive(replay::keep_alive(&app.config)))
+}
+
+async fn lexical_stage(
+    indexes: Arc<Indexes>,
+    q: String,
+    queries: Vec<parser::Query<'static>>,
+    scope: Option<Vec<PathBuf>>,
+    limit: usize,
+) -> Result<Vec<LexicalHit>> {
+    if queries.is_empty() {
+        return Ok(vec![]);
+    }
+
+    let response = ContentReader
+        .execute(
+            &indexes.file,
+            &queries,
+            &ApiQuery::first_page(q, limit),
+            scope.as_deref(),
+        )
+        .await
+        .map_err(Error::internal)?;
+
+    Ok(response
+        .data
+        .into_iter()
+        .filter_map(|result| match result {
+            QueryResult::Snippets(file) => Some(LexicalHit {
+                id: format!("{}:{}", file.repo_ref, file.relative_path),
+                file,
+            }),
+            _ => None,
+        })
+        .collect())
+}
+
+async fn semantic_stage(
+    semantic: Option<Semantic>,
+    config: Arc<Configuration>,
+    target: String,
+    filters: FilterArgs,
+    limit: usize,
+) -> Result<Vec<Snippet>> {
+    let Some(semantic) = semantic else {
+        return Err(Error::new(
+            ErrorKind::Configuration,
+            "Qdrant not configured",
+        ));
+    };
+
+    let (vector, _) = semantic
+        .embed_query(&target)
+        .await
+        .map_err(Error::internal)?;
+    let (points, _) = semantic
+        .search_with_vector(
+            vector.clone(),
+            filters,
+            VectorWeights::default(),
+            PayloadFields::snippet(),
+            candidate_count(limit as u64, [Widening::Dedup]),
+            false,
+        )
+        .await
+        .map_err(Error::search)?;
+
+    // malformed candidates are logged and left out, and the candidates compared are capped, as
+    // in answers
+    let (snippets, _) = snippets_from_points(points, semantic.payload_schema(), false)?;
+    let candidates = semantic.post_process(
+        &target,
+        answer_candidates(snippets, DedupStrategy::Mmr, &config),
+    );
+    Ok(select_snippets(candidates, vector, &[], limit))
+}
+
+/// Content queries matching any keyword of `parsed`, with the same filters as its semantic
+/// search, or none if it has no keywords.
+///
+/// A lexical query filters on a single value of each field, and the queries of a list are
+/// alternatives, so filtering on every field takes their cross product.
+fn lexical_queries(parsed: &NLQuery<'_>, logic: FilterLogic) -> Vec<parser::Query<'static>> {
+    let keywords = parsed
+        .target()
+        .map(|t| query_keywords(t))
+        .unwrap_or_default();
+    if keywords.is_empty() {
+        return vec![];
+    }
+
+    let pattern = keywords
+        .iter()
+        .map(|k| regex::escape(k))
+        .collect::<Vec<_>>()
+        .join("|");
+    let base = parser::Query {
+        case_sensitive: Some(false),
+        target: Some(Target::Content(Literal::Regex(pattern.into()))),
+        ..Default::default()
+    };
+
+    let repos = parsed.repos.iter().cloned().map(Literal::into_owned);
+    let paths = parsed.paths.iter().cloned().map(Literal::into_owned);
+    let langs = parsed.langs.iter().map(|l| l.clone().into_owned().into());
+    let branches = parsed.branch.iter().cloned().map(Literal::into_owned);
+
+    match logic {
+        FilterLogic::And => {
+            let mut queries = vec![base];
+            queries = cross(queries, repos, |q, repo| q.repo = Some(repo));
+            queries = cross(queries, paths, |q, path| q.path = Some(path));
+            queries = cross(queries, langs, |q, lang| q.lang = Some(lang));
+            cross(queries, branches, |q, branch| q.branch = Some(branch))
+        }
+        FilterLogic::Or => {
+            let queries = repos
+                .map(|repo| parser::Query {
+                    repo: Some(repo),
+                    ..base.clone()
+                })
+                .chain(paths.map(|path| parser::Query {
+                    path: Some(path),
+                    ..base.clone()
+                }))
+                .chain(langs.map(|lang| parser::Query {
+                    lang: Some(lang),
+                    ..base.clone()
+                }))
+                .chain(branches.map(|branch| parser::Query {
+                    branch: Some(branch),
+                    ..base.clone()
+                }))
+                .collect::<Vec<_>>();
+
+            if queries.is_empty() {
+                vec![base]
+            } else {
+                queries
+            }
+        }
+    }
+}
+
+/// Copies of each query for each of `values`, or the queries as they are if there are none.
+fn cross<T>(
+    queries: Vec<parser::Query<'static>>,
+    values: impl Iterator<Item = T>,
+    set: impl Fn(&mut parser::Query<'static>, T),
+) -> Vec<parser::Query<'static>>
+where
+    T: Clone,
+{
+    let values = values.collect::<Vec<_>>();
+    if values.is_empty() {
+        return queries;
+    }
+
+    queries
+        .iter()
+        .flat_map(|query| {
+            values.iter().map(|value| {
+                let mut query = query.clone();
+                set(&mut query, value.clone());
+                query
+            })
+        })
+        .collect()
+}
+
+/// Mark the semantic `snippets` that overlap the lexical `hits` already sent.
+///
+/// Lines of notebook snippets are relative to their cell, so they are never marked.
+fn mark_duplicates(snippets: Vec<Snippet>, hits: &[LexicalHit]) -> Vec<SemanticHit> {
+    snippets
+        .into_iter()
+        .map(|snippet| {
+            let duplicate_of = hits
+                .iter()
+                .find(|hit| snippet.cell_index.is_none() && overlaps(&hit.file, &snippet))
+                .map(|hit| hit.id.clone());
+
+            SemanticHit {
+                snippet,
+                duplicate_of,
+            }
+        })
+        .collect()
+}
+
+fn overlaps(file: &SnippedFile, snippet: &Snippet) -> bool {
+    file.repo_ref == snippet.repo_ref
+        && normalize_relative_path(&file.relative_path) == snippet.relative_path
+        && file.snippets.iter().any(|s| {
+            s.line_range.start <= snippet.end_line && snippet.start_line <= s.line_range.end
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::snippet;
+
+    fn target(query: &parser::Query<'_>) -> String {
+        query
+            .target
+            .as_ref()
+            .unwrap()
+            .literal()
+            .regex_str()
+            .into_owned()
+    }
+
+    #[test]
+    fn lexical_queries_match_any_keyword_within_the_filters() {
+        let parsed =
+            parser::parse_nl("repo:bloop lang:rust lang:go where is the query parsed").unwrap();
+
+        let mut langs = parsed.langs().cloned().collect::<Vec<_>>();
+        langs.sort();
+
+        let mut queries = lexical_queries(&parsed, FilterLogic::And);
+        queries.sort_by_key(|q| q.lang.clone());
+        assert_eq!(queries.len(), 2);
+        for (query, lang) in queries.iter().zip(langs) {
+            assert_eq!(target(query), "where|the|query|parsed");
+            assert_eq!(query.case_sensitive, Some(false));
+            assert_eq!(query.repo, Some(Literal::Plain("bloop".into())));
+            assert_eq!(query.lang, Some(lang));
+        }
+
+        // each filter value is a query of its own
+        let queries = lexical_queries(&parsed, FilterLogic::Or);
+        assert_eq!(queries.len(), 3);
+        assert_eq!(queries.iter().filter(|q| q.repo.is_some()).count(), 1);
+        assert_eq!(queries.iter().filter(|q| q.lang.is_some()).count(), 2);
+
+        // short words match nearly every file
+        let parsed = parser::parse_nl("lang:rust is it").unwrap();
+        assert!(lexical_queries(&parsed, FilterLogic::And).is_empty());
+    }
+
+    #[test]
+    fn semantic_snippets_overlapping_lexical_hits_are_marked() {
+        let hit = LexicalHit {
+            id: "github.com/bloopai/bloop:src/lib.rs".to_owned(),
+            file: SnippedFile {
+                relative_path: "src/lib.rs".to_owned(),
+                repo_name: "bloop".to_owned(),
+                repo_ref: "github.com/bloopai/bloop".to_owned(),
+                lang: Some("Rust".to_owned()),
+                snippets: vec![snippet::Snippet {
+                    data: "fn main() {}".to_owned(),
+                    highlights: vec![],
+                    symbols: vec![],
+                    line_range: 10..14,
+                }],
+            },
+        };
+        let snippet = |relative_path: &str, start_line, end_line| Snippet {
+            repo_ref: "github.com/bloopai/bloop".to_owned(),
+            relative_path: relative_path.to_owned(),
+            start_line,
+            end_line,
+            ..Default::default()
+        };
+
+        let marked = mark_duplicates(
+            vec![
+                snippet("src/lib.rs", 12, 30),
+                snippet("src/lib.rs", 20, 30),
+                snippet("src/main.rs", 10, 14),
+                Snippet {
+                    cell_index: Some(0),
+                    ..snippet("src/lib.rs", 10, 14)
+                },
+            ],
+            &[hit],
+        );
+
+        assert_eq!(
+            marked
+                .iter()
+                .map(|s| s.duplicate_of.as_deref())
+                .collect::<Vec<_>>(),
+            [
+                Some("github.com/bloopai/bloop:src/lib.rs"),
+                None,
+                None,
+                None
+            ]
+        );
+    }
+}
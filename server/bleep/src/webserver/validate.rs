@@ -0,0 +1,375 @@
+use std::collections::BTreeMap;
+
+use axum::Json;
+use qdrant_client::qdrant::{condition::ConditionOneOf, r#match::MatchValue, Condition, Filter};
+use serde::de::DeserializeOwned;
+
+use super::{prelude::*, semantic::check_query_length, workspaces};
+use crate::{
+    query::parser::{self, NLQuery, ParseError},
+    repo::RepoRef,
+    semantic::{
+        filter::{build_filter, FilterArgs, FilterLogic, Folding},
+        kind::ChunkKind,
+        payload::PayloadSchema,
+        Semantic,
+    },
+    Application,
+};
+
+/// The parameters of a `/semantic/chunks` search that are checked without running it.
+///
+/// Enumerations are taken as plain strings, so invalid values are reported along with every
+/// other problem rather than rejecting the request.
+#[derive(Deserialize)]
+pub(super) struct ValidateArgs {
+    query: String,
+    /// `and` or `or`, `and` by default
+    filter_logic: Option<String>,
+    workspace: Option<String>,
+    kind: Option<String>,
+}
+
+#[derive(Serialize, Debug)]
+pub(super) struct Validation {
+    /// Whether the search would run, which is when there are no `errors`
+    valid: bool,
+    errors: Vec<ValidationError>,
+    /// The query as parsed, if it parsed
+    query: Option<InterpretedQuery>,
+    /// The filters of the search, if the query parsed
+    filter: Option<InterpretedFilter>,
+}
+
+#[derive(Serialize, Debug, PartialEq, Eq)]
+pub(super) struct ValidationError {
+    /// The parameter at fault
+    field: &'static str,
+    message: String,
+}
+
+#[derive(Serialize, Debug, PartialEq, Eq)]
+pub(super) struct InterpretedQuery {
+    /// The text that would be embedded, `null` if the query is only filters
+    target: Option<String>,
+    repos: Vec<String>,
+    paths: Vec<String>,
+    langs: Vec<String>,
+    branches: Vec<String>,
+    /// Quoted phrases every chunk must contain, in query order
+    phrases: Vec<String>,
+}
+
+#[derive(Serialize, Debug)]
+pub(super) struct InterpretedFilter {
+    logic: FilterLogic,
+    /// Values matched on each payload field
+    fields: BTreeMap<&'static str, Vec<String>>,
+    /// Repositories the search is scoped to, for searches within a workspace
+    repos: Option<Vec<String>>,
+    /// The filter sent to Qdrant, in the JSON form of its REST API, `null` if nothing is
+    /// filtered
+    qdrant: Option<serde_json::Value>,
+}
+
+impl super::ApiResponse for Validation {}
+
+/// Check that a semantic search would run, without embedding or searching anything
+///
+/// Problems are reported in the response rather than as an error status, so query builders can
+/// show all of them at once.
+//
+#[utoipa::path(post, path = "/semantic/validate",
+    responses(
+        (status = 200, description = "Execute query successfully", body = Validation),
+        (status = 500, description = "Server error", body = EndpointError),
+    ),
+)]
+pub(super) async fn handle(
+    Extension(app): Extension<Application>,
+    Extension(semantic): Extension<Option<Semantic>>,
+    Json(args): Json<ValidateArgs>,
+) -> impl IntoResponse {
+    let folding = Folding::new(&app.config);
+    let schema = semantic
+        .as_ref()
+        .map(|semantic| semantic.payload_schema().clone())
+        .unwrap_or_default();
+    json(validate(
+        &args,
+        app.config.min_query_chars,
+        folding,
+        &schema,
+        |name| workspaces::resolve(&app, name),
+    ))
+}
+
+fn validate(
+    args: &ValidateArgs,
+    min_query_chars: usize,
+    folding: Folding,
+    schema: &PayloadSchema,
+    resolve_workspace: impl Fn(&str) -> Result<Vec<RepoRef>>,
+) -> Validation {
+    let mut errors = vec![];
+    let mut report = |field, message: String| errors.push(ValidationError { field, message });
+
+    let logic = parse_enum::<FilterLogic>(args.filter_logic.as_deref())
+        .unwrap_or_else(|err| {
+            report("filter_logic", err);
+            None
+        })
+        .unwrap_or_default();
+    let kind = parse_enum::<ChunkKind>(args.kind.as_deref()).unwrap_or_else(|err| {
+        report("kind", err);
+        None
+    });
+    let repos = args.workspace.as_deref().and_then(|workspace| {
+        resolve_workspace(workspace)
+            .map_err(|err| report("workspace", err.message().to_owned()))
+            .ok()
+    });
+
+    let (query, filter) = match parser::parse_nl(&args.query) {
+        Ok(parsed) => {
+            match parsed.target() {
+                Some(target) => {
+                    if let Err(err) = check_query_length(target, min_query_chars) {
+                        report("query", err.message().to_owned());
+                    }
+                }
+                None => report("query", "empty search".to_owned()),
+            }
+
+            let mut filters = FilterArgs::from_query(&parsed, logic)
+                .keyword("kind", kind.map(ChunkKind::as_str))
+                .folded(folding)
+                .keyed(schema);
+            if let Some(repos) = repos {
+                filters = filters.within_repos(repos);
+            }
+
+            let filter = InterpretedFilter {
+                logic,
+                fields: filters.fields(),
+                repos: filters.repos().map(<[_]>::to_vec),
+                qdrant: build_filter(&filters).as_ref().map(filter_json),
+            };
+            (Some(interpret(&parsed)), Some(filter))
+        }
+        Err(err) => {
+            report("query", parse_error_message(err));
+            (None, None)
+        }
+    };
+
+    Validation {
+        valid: errors.is_empty(),
+        errors,
+        query,
+        filter,
+    }
+}
+
+/// Parse `value` as one of the variants of `T`, as it would be deserialized from a search.
+fn parse_enum<T: DeserializeOwned>(value: Option<&str>) -> Result<Option<T>, String> {
+    value
+        .map(|v| serde_json::from_value(serde_json::Value::String(v.to_owned())))
+        .transpose()
+        .map_err(|err| err.to_string())
+}
+
+fn parse_error_message(err: ParseError) -> String {
+    match err {
+        // pest pinpoints the error with a caret under the query when displayed
+        ParseError::Pest(err) => err.to_string(),
+        err => err.to_string(),
+    }
+}
+
+fn interpret(parsed: &NLQuery<'_>) -> InterpretedQuery {
+    InterpretedQuery {
+        target: parsed.target().map(|t| t.to_string()),
+        repos: sorted(parsed.repos()),
+        paths: sorted(parsed.paths()),
+        langs: sorted(parsed.langs()),
+        branches: sorted(parsed.branch()),
+        phrases: parsed.phrases().map(|p| p.to_string()).collect(),
+    }
+}
+
+/// The values of a query field, which are unordered in the query.
+fn sorted(values: impl Iterator<Item = impl ToString>) -> Vec<String> {
+    let mut values = values.map(|v| v.to_string()).collect::<Vec<_>>();
+    values.sort();
+    values
+}
+
+fn filter_json(filter: &Filter) -> serde_json::Value {
+    let clauses = [
+        ("must", &filter.must),
+        ("should", &filter.should),
+        ("must_not", &filter.must_not),
+    ];
+
+    clauses
+        .into_iter()
+        .filter(|(_, conditions)| !conditions.is_empty())
+        .map(|(clause, conditions)| {
+            let conditions = conditions.iter().map(condition_json).collect();
+            (clause.to_owned(), serde_json::Value::Array(conditions))
+        })
+        .collect::<serde_json::Map<_, _>>()
+        .into()
+}
+
+fn condition_json(condition: &Condition) -> serde_json::Value {
+    match condition.condition_one_of.as_ref() {
+        Some(ConditionOneOf::Field(field)) => {
+            let value = match field.r#match.as_ref().and_then(|m| m.match_value.as_ref()) {
+                Some(MatchValue::Keyword(keyword)) => serde_json::json!({ "value": keyword }),
+                Some(MatchValue::Text(text)) => serde_json::json!({ "text": text }),
+                Some(MatchValue::Integer(integer)) => serde_json::json!({ "value": integer }),
+                Some(MatchValue::Boolean(boolean)) => serde_json::json!({ "value": boolean }),
+                other => serde_json::json!(format!("{other:?}")),
+            };
+            serde_json::json!({ "key": field.key, "match": value })
+        }
+        Some(ConditionOneOf::Filter(filter)) => filter_json(filter),
+        other => serde_json::json!(format!("{other:?}")),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(query: &str) -> ValidateArgs {
+        ValidateArgs {
+            query: query.to_owned(),
+            filter_logic: None,
+            workspace: None,
+            kind: None,
+        }
+    }
+
+    fn no_workspaces(name: &str) -> Result<Vec<RepoRef>> {
+        Err(Error::new(
+            ErrorKind::NotFound,
+            format!("unknown workspace `{name}`"),
+        ))
+    }
+
+    fn fields(validation: &Validation) -> Vec<&'static str> {
+        validation.errors.iter().map(|e| e.field).collect()
+    }
+
+    #[test]
+    fn valid_queries_are_interpreted() {
+        let args = ValidateArgs {
+            kind: Some("definition".to_owned()),
+            ..args("lang:rust path:src/ parse the query")
+        };
+        let validation = validate(
+            &args,
+            3,
+            Folding::default(),
+            &PayloadSchema::default(),
+            no_workspaces,
+        );
+
+        assert!(validation.valid);
+        assert!(validation.errors.is_empty());
+        assert_eq!(
+            validation.query.unwrap(),
+            InterpretedQuery {
+                target: Some("parse the query".to_owned()),
+                repos: vec![],
+                paths: vec!["src/".to_owned()],
+                langs: vec!["rust".to_owned()],
+                branches: vec![],
+                phrases: vec![],
+            }
+        );
+
+        let filter = validation.filter.unwrap();
+        assert_eq!(filter.logic, FilterLogic::And);
+        assert_eq!(filter.fields["kind"], ["definition"]);
+        assert_eq!(filter.fields["lang"], ["rust"]);
+        assert_eq!(filter.repos, None);
+        assert_eq!(
+            filter.qdrant.unwrap()["must"]
+                .as_array()
+                .map(|conditions| conditions.len()),
+            Some(filter.fields.len())
+        );
+    }
+
+    #[test]
+    fn malformed_queries_are_reported() {
+        let validation = validate(
+            &args("parse (the query"),
+            3,
+            Folding::default(),
+            &PayloadSchema::default(),
+            no_workspaces,
+        );
+        assert!(!validation.valid);
+        assert_eq!(fields(&validation), ["query"]);
+        assert!(validation.query.is_none());
+        assert!(validation.filter.is_none());
+
+        // filters alone parse, but leave nothing to embed
+        let validation = validate(
+            &args("lang:rust"),
+            3,
+            Folding::default(),
+            &PayloadSchema::default(),
+            no_workspaces,
+        );
+        assert!(!validation.valid);
+        assert_eq!(fields(&validation), ["query"]);
+        assert_eq!(validation.errors[0].message, "empty search");
+        assert_eq!(validation.query.unwrap().target, None);
+
+        let validation = validate(
+            &args("ab"),
+            3,
+            Folding::default(),
+            &PayloadSchema::default(),
+            no_workspaces,
+        );
+        assert_eq!(fields(&validation), ["query"]);
+    }
+
+    #[test]
+    fn invalid_filters_are_reported_together() {
+        let args = ValidateArgs {
+            filter_logic: Some("xor".to_owned()),
+            workspace: Some("missing".to_owned()),
+            kind: Some("function".to_owned()),
+            ..args("parse the query")
+        };
+        let validation = validate(
+            &args,
+            3,
+            Folding::default(),
+            &PayloadSchema::default(),
+            no_workspaces,
+        );
+
+        assert!(!validation.valid);
+        assert_eq!(fields(&validation), ["filter_logic", "kind", "workspace"]);
+        assert!(validation.errors[0].message.contains("xor"));
+        assert_eq!(validation.errors[2].message, "unknown workspace `missing`");
+
+        // the rest of the search is still interpreted
+        assert_eq!(
+            validation.query.unwrap().target.as_deref(),
+            Some("parse the query")
+        );
+        let filter = validation.filter.unwrap();
+        assert_eq!(filter.logic, FilterLogic::And);
+        assert!(filter.qdrant.is_none());
+    }
+}
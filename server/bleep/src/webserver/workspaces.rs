@@ -0,0 +1,143 @@
+use std::path::PathBuf;
+
+use axum::{extract::Path, Json};
+
+use super::prelude::*;
+use crate::{repo::RepoRef, workspaces::Workspace, Application};
+
+#[derive(Deserialize, ToSchema)]
+pub(super) struct SetWorkspace {
+    /// Refs of the repositories in this workspace
+    repos: Vec<RepoRef>,
+}
+
+#[derive(Serialize)]
+pub(super) struct WorkspacesResponse {
+    workspaces: Vec<Workspace>,
+}
+
+impl super::ApiResponse for WorkspacesResponse {}
+impl super::ApiResponse for Workspace {}
+
+/// The repositories of the workspace `name`, as of this request.
+pub(super) fn resolve(app: &Application, name: &str) -> Result<Vec<RepoRef>> {
+    app.workspaces
+        .get(name)
+        .ok_or_else(|| unknown_workspace(app, name))
+}
+
+fn unknown_workspace(app: &Application, name: &str) -> Error {
+    Error::new(
+        ErrorKind::NotFound,
+        format!(
+            "unknown workspace `{name}`, known workspaces: [{}]",
+            app.workspaces.names().join(", ")
+        ),
+    )
+    .with_code(ErrorCode::UnknownWorkspace)
+}
+
+/// Disk paths of the repositories in the workspace `name`, for scoping lexical queries.
+///
+/// Repositories missing from the pool are skipped, so a workspace whose repositories are all
+/// gone matches nothing.
+pub(super) async fn resolve_disk_paths(app: &Application, name: &str) -> Result<Vec<PathBuf>> {
+    let mut disk_paths = vec![];
+    for reporef in resolve(app, name)? {
+        if let Some(disk_path) = app
+            .repo_pool
+            .read_async(&reporef, |_, repo| repo.disk_path.clone())
+            .await
+        {
+            disk_paths.push(disk_path);
+        }
+    }
+
+    Ok(disk_paths)
+}
+
+/// List all workspaces
+//
+#[utoipa::path(get, path = "/workspaces",
+    responses(
+        (status = 200, description = "Execute query successfully", body = WorkspacesResponse),
+        (status = 500, description = "Server error", body = EndpointError),
+    ),
+)]
+pub(super) async fn list(Extension(app): Extension<Application>) -> impl IntoResponse {
+    json(WorkspacesResponse {
+        workspaces: app.workspaces.list(),
+    })
+}
+
+/// Get a workspace by name
+//
+#[utoipa::path(get, path = "/workspaces/:name",
+    responses(
+        (status = 200, description = "Execute query successfully", body = Workspace),
+        (status = 404, description = "Workspace not found", body = EndpointError),
+        (status = 500, description = "Server error", body = EndpointError),
+    ),
+)]
+pub(super) async fn get(
+    Path(name): Path<String>,
+    Extension(app): Extension<Application>,
+) -> Result<impl IntoResponse> {
+    let repos = resolve(&app, &name)?;
+    Ok(json(Workspace { name, repos }))
+}
+
+/// Create a workspace, or replace the repositories of an existing one
+//
+#[utoipa::path(put, path = "/workspaces/:name", request_body = SetWorkspace,
+    responses(
+        (status = 200, description = "Execute query successfully", body = Workspace),
+        (status = 400, description = "Bad request", body = EndpointError),
+        (status = 500, description = "Server error", body = EndpointError),
+    ),
+)]
+pub(super) async fn set(
+    Path(name): Path<String>,
+    Extension(app): Extension<Application>,
+    Json(SetWorkspace { repos }): Json<SetWorkspace>,
+) -> Result<impl IntoResponse> {
+    let unknown = repos
+        .iter()
+        .filter(|r| !app.repo_pool.contains(r))
+        .map(ToString::to_string)
+        .collect::<Vec<_>>();
+
+    if !unknown.is_empty() {
+        return Err(Error::user(format!(
+            "unknown repositories: [{}]",
+            unknown.join(", ")
+        )));
+    }
+
+    app.workspaces.set(&name, repos)?;
+
+    let repos = resolve(&app, &name)?;
+    Ok(json(Workspace { name, repos }))
+}
+
+/// Delete a workspace
+//
+#[utoipa::path(delete, path = "/workspaces/:name",
+    responses(
+        (status = 200, description = "Execute query successfully", body = WorkspacesResponse),
+        (status = 404, description = "Workspace not found", body = EndpointError),
+        (status = 500, description = "Server error", body = EndpointError),
+    ),
+)]
+pub(super) async fn delete(
+    Path(name): Path<String>,
+    Extension(app): Extension<Application>,
+) -> Result<impl IntoResponse> {
+    if !app.workspaces.delete(&name)? {
+        return Err(unknown_workspace(&app, &name));
+    }
+
+    Ok(json(WorkspacesResponse {
+        workspaces: app.workspaces.list(),
+    }))
+}
@@ -0,0 +1,167 @@
+use std::{
+    collections::{HashMap, HashSet},
+    sync::RwLock,
+};
+
+use anyhow::Result;
+use serde::Serialize;
+
+use crate::{
+    repo::RepoRef,
+    state::{PersistedState, StateSource},
+};
+
+/// A named set of repositories that searches can be restricted to.
+#[derive(Serialize, Debug, PartialEq, Eq)]
+pub struct Workspace {
+    pub name: String,
+    pub repos: Vec<RepoRef>,
+}
+
+/// Workspaces defined on this instance.
+///
+/// Only repository refs are stored, so searches over a workspace always use the latest index of
+/// each of its repositories.
+#[derive(Clone)]
+pub struct Workspaces {
+    sets: PersistedState<RwLock<HashMap<String, Vec<RepoRef>>>>,
+}
+
+impl Workspaces {
+    pub fn load(source: &StateSource) -> Result<Self> {
+        Ok(Self {
+            sets: source.load_or_default("workspaces")?,
+        })
+    }
+
+    /// All workspaces, sorted by name.
+    pub fn list(&self) -> Vec<Workspace> {
+        let mut workspaces = self
+            .sets
+            .read()
+            .unwrap()
+            .iter()
+            .map(|(name, repos)| Workspace {
+                name: name.clone(),
+                repos: repos.clone(),
+            })
+            .collect::<Vec<_>>();
+
+        workspaces.sort_by(|a, b| a.name.cmp(&b.name));
+        workspaces
+    }
+
+    /// Names of all workspaces, sorted.
+    pub fn names(&self) -> Vec<String> {
+        let mut names = self
+            .sets
+            .read()
+            .unwrap()
+            .keys()
+            .cloned()
+            .collect::<Vec<_>>();
+        names.sort();
+        names
+    }
+
+    pub fn get(&self, name: &str) -> Option<Vec<RepoRef>> {
+        self.sets.read().unwrap().get(name).cloned()
+    }
+
+    /// Create the workspace `name`, or replace its repositories if it already exists.
+    pub fn set(&self, name: &str, mut repos: Vec<RepoRef>) -> Result<()> {
+        let mut seen = HashSet::new();
+        repos.retain(|r| seen.insert(r.clone()));
+
+        self.sets.write().unwrap().insert(name.to_owned(), repos);
+
+        self.sets.store()
+    }
+
+    /// Delete the workspace `name`, returning whether it existed.
+    pub fn delete(&self, name: &str) -> Result<bool> {
+        let existed = self.sets.write().unwrap().remove(name).is_some();
+        if existed {
+            self.sets.store()?;
+        }
+
+        Ok(existed)
+    }
+
+    /// Drop `reporef` from every workspace that contains it.
+    pub fn remove_repo(&self, reporef: &RepoRef) -> Result<()> {
+        let mut changed = false;
+        for repos in self.sets.write().unwrap().values_mut() {
+            let len = repos.len();
+            repos.retain(|r| r != reporef);
+            changed |= repos.len() != len;
+        }
+
+        if changed {
+            self.sets.store()?;
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempdir::TempDir;
+
+    fn workspaces(dir: &TempDir) -> Workspaces {
+        let mut source = StateSource::default();
+        source.set_default_dir(dir.path());
+        Workspaces::load(&source).unwrap()
+    }
+
+    fn repo(name: &str) -> RepoRef {
+        format!("github.com/bloopai/{name}").parse().unwrap()
+    }
+
+    #[test]
+    fn workspaces_are_persisted() {
+        let dir = TempDir::new("workspaces").unwrap();
+        let store = workspaces(&dir);
+
+        store
+            .set("web", vec![repo("app"), repo("ui"), repo("app")])
+            .unwrap();
+        store.set("api", vec![repo("bleep")]).unwrap();
+
+        let store = workspaces(&dir);
+        assert_eq!(store.names(), vec!["api", "web"]);
+        assert_eq!(store.get("web"), Some(vec![repo("app"), repo("ui")]));
+
+        assert!(store.delete("api").unwrap());
+        assert!(!store.delete("api").unwrap());
+        assert_eq!(workspaces(&dir).names(), vec!["web"]);
+    }
+
+    #[test]
+    fn removed_repos_leave_every_workspace() {
+        let dir = TempDir::new("workspaces").unwrap();
+        let store = workspaces(&dir);
+
+        store.set("web", vec![repo("app"), repo("ui")]).unwrap();
+        store
+            .set("all", vec![repo("app"), repo("ui"), repo("bleep")])
+            .unwrap();
+        store.remove_repo(&repo("ui")).unwrap();
+
+        assert_eq!(
+            workspaces(&dir).list(),
+            vec![
+                Workspace {
+                    name: "all".into(),
+                    repos: vec![repo("app"), repo("bleep")],
+                },
+                Workspace {
+                    name: "web".into(),
+                    repos: vec![repo("app")],
+                },
+            ]
+        );
+    }
+}